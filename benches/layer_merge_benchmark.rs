@@ -1,5 +1,5 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use squash::docker::{LayerInfo, LayerMerger};
+use squash::docker::{LayerInfo, LayerMerger, MergeOptions};
 use std::fs;
 use tempfile::TempDir;
 
@@ -14,6 +14,34 @@ fn create_test_layer(temp_dir: &TempDir, name: &str, size: usize) -> LayerInfo {
         digest: format!("sha256:{}", name),
         size: size as u64,
         tar_path,
+        data: None,
+        archive_offset: None,
+    }
+}
+
+/// Create a real layer tar holding `files` files of `file_size` bytes each
+fn create_tar_layer(temp_dir: &TempDir, name: &str, files: usize, file_size: usize) -> LayerInfo {
+    let tar_path = temp_dir.path().join(format!("{}.tar", name));
+    let mut builder = tar::Builder::new(fs::File::create(&tar_path).unwrap());
+    let content = vec![b'x'; file_size];
+
+    for i in 0..files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(file_size as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, format!("{}/file{}", name, i), content.as_slice())
+            .unwrap();
+    }
+    builder.finish().unwrap();
+    drop(builder);
+
+    LayerInfo {
+        digest: format!("sha256:{}", name),
+        size: fs::metadata(&tar_path).unwrap().len(),
+        tar_path,
+        data: None,
+        archive_offset: None,
     }
 }
 
@@ -48,6 +76,8 @@ fn benchmark_layer_info_creation(c: &mut Criterion) {
                 digest: black_box("sha256:test123".to_string()),
                 size: black_box(9),
                 tar_path: black_box(tar_path.clone()),
+                data: None,
+                archive_offset: None,
             };
             
             black_box(layer_info)
@@ -86,6 +116,25 @@ fn benchmark_multiple_layers(c: &mut Criterion) {
         );
     }
     
+    // Merging a 20-layer image, reading layers one at a time or in parallel
+    let temp_dir = TempDir::new().unwrap();
+    let layers: Vec<LayerInfo> = (0..20)
+        .map(|i| create_tar_layer(&temp_dir, &format!("layer{}", i), 200, 16 * 1024))
+        .collect();
+
+    for jobs in [1, 0] {
+        let label = if jobs == 1 { "sequential" } else { "parallel" };
+        group.bench_function(format!("merge_20_layers_{}", label), |b| {
+            let options = MergeOptions { jobs, ..Default::default() };
+            let merger = LayerMerger::new(layers.clone(), temp_dir.path().to_path_buf()).with_options(options);
+            b.iter(|| {
+                let (merged, _) = merger.merge_latest_layers(black_box(layers.len())).unwrap();
+                fs::remove_file(&merged.tar_path).unwrap();
+                black_box(merged)
+            })
+        });
+    }
+
     group.finish();
 }
 
@@ -1,5 +1,7 @@
+use crate::docker::{Backend, DEFAULT_DOCKER_RETRIES, DEFAULT_FILE_LOG_LIMIT, DEFAULT_MEM_FILE_THRESHOLD, ImageFormat, LayerCompression, LongPathnameMode, Platform, RetainedLayerCompression, Runtime, SourceFormat};
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "squash")]
@@ -10,6 +12,8 @@ pub struct Cli {
     pub command: Commands,
 }
 
+// Parsed once per run, so the size of the squash variant does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 pub enum Commands {
     /// Squash Docker image layers
@@ -18,26 +22,502 @@ pub enum Commands {
         #[arg(short, long)]
         source: String,
 
-        /// Output file path (required if not using --load)
+        /// How to read --source instead of autodetecting it: `docker-archive` or `oci-archive` read the file, `docker-daemon` exports the image with docker save
+        #[arg(long, value_enum)]
+        source_format: Option<SourceFormat>,
+
+        /// Read layers in place from an uncompressed --source archive instead of extracting them to the temp directory; the archive must not change during the run
+        #[arg(long)]
+        no_extract: bool,
+
+        /// Image to squash (name:tag) when the source archive contains several images
+        #[arg(long)]
+        image_ref: Option<String>,
+
+        /// Platform to squash (<os>/<arch>[/<variant>]) when the archive holds several
+        #[arg(long)]
+        platform: Option<Platform>,
+
+        /// Output file path (required if not using --load); `-` writes to stdout
         #[arg(short, long)]
         output: Option<PathBuf>,
 
-        /// Load result into Docker with name:tag
+        /// Stream the squashed archive to stdout (same as --output -)
+        #[arg(long, conflicts_with = "output")]
+        output_stdout: bool,
+
+        /// Replace the --output file if it already exists instead of refusing to write it
+        #[arg(long)]
+        force: bool,
+
+        /// After saving, hash the --output file and write its sha256 to <output>.sha256
+        #[arg(long, conflicts_with = "output_stdout")]
+        checksum: bool,
+
+        /// Layout of the output archive: `docker` (manifest.json) or `oci` (oci-layout, index.json and blobs); defaults to that of the source
+        #[arg(long, value_enum)]
+        output_format: Option<ImageFormat>,
+
+        /// Load result into Docker with name:tag (a skopeo destination with --runtime skopeo)
         #[arg(long)]
         load: Option<String>,
 
+        /// Keep the temporary squash-temp-* tag used by --load instead of removing it
+        #[arg(long, requires = "load")]
+        no_load_cleanup: bool,
+
+        /// Temporary directory for intermediate files
+        #[arg(short, long)]
+        temp_dir: Option<PathBuf>,
+
+        /// Backend used to fetch the source and publish to --load (skopeo accepts any skopeo transport)
+        #[arg(long, value_enum, default_value_t = Runtime::Docker)]
+        runtime: Runtime,
+
+        /// How to reach the Docker daemon: the docker CLI or the Engine API socket
+        #[arg(long, value_enum, default_value_t = Backend::Cli)]
+        backend: Backend,
+
+        /// Retries of docker save/load when the daemon cannot be reached (0 fails at once)
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_DOCKER_RETRIES)]
+        docker_retries: u32,
+
+        /// Wait before the first docker retry, doubled before each further one, e.g. `500ms` or `2s`
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "1s")]
+        docker_retry_delay: Duration,
+
+        /// Layer specification: number (merge latest n layers), comma-separated indexes (e.g. 2,3,4), layer ID, to:<id> (top layers down to <id>, inclusive), or <n>:to:<id> (latest n layers, failing if that passes <id>)
+        #[cfg_attr(not(feature = "interactive"), arg(
+            required_unless_present_any = ["from", "to", "all", "merge_window", "merge_layers_matching_size", "max_merged_size", "since_command"]
+        ))]
+        #[arg(
+            short,
+            long,
+            conflicts_with_all = ["from", "to", "all", "merge_window", "merge_layers_matching_size", "max_merged_size", "since_command"]
+        )]
+        layers: Option<String>,
+
+        /// Merge the earliest n layers given by --layers instead of the latest
+        #[arg(long, requires = "layers")]
+        from_start: bool,
+
+        /// Squash every layer into a single layer
+        #[arg(long, visible_alias = "squash-all", conflicts_with_all = ["from", "to", "merge_window", "merge_layers_matching_size", "max_merged_size"])]
+        all: bool,
+
+        /// Merge each run of adjacent layers whose history timestamps are at most this many seconds apart
+        #[arg(long, value_name = "SECONDS", conflicts_with_all = ["from", "to", "merge_layers_matching_size", "max_merged_size"])]
+        merge_window: Option<u64>,
+
+        /// Merge the trailing run of layers each smaller than this size (bytes or K/M/G suffix), stopping at the first larger layer
+        #[arg(long, value_name = "SIZE", value_parser = parse_byte_size, conflicts_with_all = ["from", "to", "max_merged_size"])]
+        merge_layers_matching_size: Option<u64>,
+
+        /// Merge as many of the latest layers as fit within this combined size (bytes or K/M/G suffix)
+        #[arg(long, value_name = "SIZE", value_parser = parse_byte_size, conflicts_with_all = ["from", "to"])]
+        max_merged_size: Option<u64>,
+
+        /// Layer to keep out of the merge (index or layer ID, repeatable); a range containing it is merged as separate parts around it
+        #[arg(long, value_name = "LAYER")]
+        preserve_layer: Vec<String>,
+
+        /// First layer of a contiguous range to merge: index (0 is the base layer) or layer ID
+        #[arg(long)]
+        from: Option<String>,
+
+        /// Merge from the layer whose history command contains this text to the latest layer
+        #[arg(
+            long,
+            value_name = "SUBSTRING",
+            conflicts_with_all = ["from", "to", "all", "merge_window", "merge_layers_matching_size", "max_merged_size"]
+        )]
+        since_command: Option<String>,
+
+        /// Last layer of a contiguous range to merge: index (0 is the base layer) or layer ID
+        #[arg(long)]
+        to: Option<String>,
+
+        /// Verify each layer against its config diff_id while loading
+        #[arg(long)]
+        verify: bool,
+
+        /// Only accept input layers with these media types (comma separated or repeated)
+        #[arg(long, value_delimiter = ',')]
+        input_allowlist: Vec<String>,
+
+        /// Reject input layers with these media types (comma separated or repeated)
+        #[arg(long, value_delimiter = ',')]
+        input_denylist: Vec<String>,
+
+        /// Abort if the image contains foreign layers referenced by URL
+        #[arg(long, conflicts_with = "download_foreign_layers")]
+        fail_on_foreign_layer: bool,
+
+        /// Download foreign layers from their URLs so they can be squashed
+        #[arg(long)]
+        download_foreign_layers: bool,
+
+        /// Encoding for paths and link names longer than 100 bytes in the merged layer
+        #[arg(long, value_enum, default_value_t = LongPathnameMode::Pax)]
+        tar_long_pathname_mode: LongPathnameMode,
+
+        /// Copy headers of unmodified entries verbatim instead of recomputing checksums
+        #[arg(long)]
+        no_tar_cksum_recompute: bool,
+
+        /// Annotate OCI layer descriptors with the history command that created each layer
+        #[arg(long)]
+        output_layer_annotations_from_history: bool,
+
+        /// Carry SBOM and other attestation manifests over to the squashed OCI image
+        #[arg(long)]
+        sbom_passthrough: bool,
+
+        /// Compression of squashed layers in the output archive; zstd needs the `zstd` feature
+        #[arg(long, value_enum, default_value_t = LayerCompression::None)]
+        output_compression: LayerCompression,
+
+        /// Level for --output-compression: 0 to 9 for gzip, 1 to 19 for zstd
+        #[arg(long, value_name = "LEVEL")]
+        compression_level: Option<u32>,
+
+        /// Whether retained layers keep their stored compression or match --output-compression
+        #[arg(long, value_enum, default_value_t = RetainedLayerCompression::Keep)]
+        output_compression_for_retained_layers: RetainedLayerCompression,
+
+        /// Write manifest.json and the image config as canonical JSON (sorted keys, compact)
+        #[arg(long)]
+        canonical_json: bool,
+
+        /// Byte-identical output across runs: clamp file mtimes and date the merged history at SOURCE_DATE_EPOCH (0 when unset)
+        #[arg(long)]
+        reproducible: bool,
+
+        /// created_by of the merged history entry (default: "squash: merged N layers")
+        #[arg(long, value_name = "TEXT")]
+        history_comment: Option<String>,
+
+        /// author of the merged history entry
+        #[arg(long, value_name = "TEXT")]
+        history_author: Option<String>,
+
+        /// mediaType of the OCI output manifest (e.g. application/vnd.docker.distribution.manifest.v2+json)
+        #[arg(long)]
+        output_manifest_mediatype: Option<String>,
+
+        /// mediaType of the OCI output config (e.g. application/vnd.docker.container.image.v1+json)
+        #[arg(long)]
+        output_config_mediatype: Option<String>,
+
+        /// Add the legacy `repositories` file (repo -> tag -> top layer id) to Docker archive output
+        #[arg(long)]
+        legacy_repositories: bool,
+
+        /// Scan text files for secrets (AWS keys, private keys, .env values) while merging
+        #[arg(long)]
+        verify_no_secrets: bool,
+
+        /// Additional secret pattern as NAME=REGEX (repeatable)
+        #[arg(long, value_name = "NAME=REGEX", requires = "verify_no_secrets")]
+        secret_pattern: Vec<String>,
+
+        /// Fail when the secret scan finds anything
+        #[arg(long, requires = "verify_no_secrets")]
+        fail_on_secret: bool,
+
+        /// Report world-writable files in the squashed layers
+        #[arg(long)]
+        audit_permissions: bool,
+
+        /// Fail when the permission audit finds anything (implies --audit-permissions)
+        #[arg(long)]
+        strict_permissions: bool,
+
+        /// Also audit world-writable directories without the sticky bit
+        #[arg(long)]
+        audit_directories: bool,
+
+        /// Reference image squashed by another tool (name:tag or file path) to compare content against
+        #[arg(long)]
+        compare_with_docker_squash: Option<String>,
+
+        /// Verbose output (same as --log-level debug)
+        #[arg(short, long)]
+        verbose: bool,
+
+        /// Log level; overrides RUST_LOG and --verbose
+        #[arg(long, value_enum, conflicts_with = "verbose")]
+        log_level: Option<LogLevel>,
+
+        /// Only report errors (same as --log-level error); the --dry-run plan and --format json report are still printed
+        #[arg(short, long, conflicts_with_all = ["verbose", "log_level"])]
+        quiet: bool,
+
+        /// Files logged individually at trace level per merge phase before switching to periodic summaries
+        #[arg(long, default_value_t = DEFAULT_FILE_LOG_LIMIT)]
+        file_log_limit: usize,
+
+        /// Layer tars read in parallel while merging (0 uses every available core)
+        #[arg(short, long, default_value_t = 0)]
+        jobs: usize,
+
+        /// Largest file held in memory while merging, in bytes or with a K/M/G suffix; larger files stream from disk (0 streams every file)
+        #[arg(long, value_name = "BYTES", value_parser = parse_byte_size, default_value_t = DEFAULT_MEM_FILE_THRESHOLD)]
+        mem_file_threshold: u64,
+
+        /// Assemble merged layers in memory instead of writing them to the temp directory first
+        #[arg(long)]
+        in_memory: bool,
+
+        /// Fail when a layer contains a path that climbs out of the root with `..` instead of skipping it
+        #[arg(long)]
+        strict: bool,
+
+        /// Fail when a merged layer is larger than the layers it replaces instead of only warning
+        #[arg(long)]
+        fail_on_grow: bool,
+
+        /// Leave paths matching this glob out of the squashed layer, e.g. `**/*.pyc` or `/var/cache/**` (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// Replace the command of the output image: a JSON array such as `["serve","--port","80"]` or a string run with `/bin/sh -c` (`[]` clears it)
+        #[arg(long, value_name = "COMMAND", value_parser = parse_command, allow_hyphen_values = true)]
+        set_cmd: Option<::std::vec::Vec<String>>,
+
+        /// Replace the entrypoint of the output image, in the same forms as --set-cmd
+        #[arg(long, value_name = "COMMAND", value_parser = parse_command, allow_hyphen_values = true)]
+        set_entrypoint: Option<::std::vec::Vec<String>>,
+
+        /// Set an environment variable in the output image, replacing an existing one of the same name (repeatable)
+        #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env_var)]
+        set_env: Vec<(String, String)>,
+
+        /// Replace the working directory of the output image
+        #[arg(long, value_name = "DIR")]
+        set_workdir: Option<String>,
+
+        /// Add a label to the output image, next to the org.squash.merged-layers and org.squash.source-digest provenance labels (repeatable)
+        #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env_var)]
+        label: Vec<(String, String)>,
+
+        /// Keep the extracted image and intermediate layer tars on disk and print where they are
+        #[arg(long)]
+        keep_temp: bool,
+
+        /// Keep `docker save` archives of image sources in this directory and reuse them while the image ID is unchanged
+        #[arg(long, value_name = "PATH")]
+        cache_dir: Option<PathBuf>,
+
+        /// Checkpoint merge progress under --temp-dir and continue an interrupted merge of the same layers
+        #[arg(long, requires = "temp_dir")]
+        resume: bool,
+
+        /// Print which layers would be merged and the resulting counts, without writing anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Format of the summary printed after a successful squash
+        #[arg(long, value_enum, default_value_t = ReportFormat::Text)]
+        format: ReportFormat,
+    },
+
+    /// Combine the layers of one image with the runtime config of another
+    MergeConfigs {
+        /// Image providing the layers (name:tag or file path)
+        #[arg(long)]
+        filesystem_from: String,
+
+        /// Image providing env, cmd, entrypoint, labels and other runtime config (name:tag or file path)
+        #[arg(long)]
+        config_from: String,
+
+        /// How to read --filesystem-from instead of autodetecting it: `docker-archive`, `oci-archive` or `docker-daemon`
+        #[arg(long, value_enum)]
+        filesystem_format: Option<SourceFormat>,
+
+        /// How to read --config-from instead of autodetecting it: `docker-archive`, `oci-archive` or `docker-daemon`
+        #[arg(long, value_enum)]
+        config_format: Option<SourceFormat>,
+
+        /// Image to take the layers from (name:tag) when the --filesystem-from archive contains several images
+        #[arg(long)]
+        filesystem_image_ref: Option<String>,
+
+        /// Image to take the config from (name:tag) when the --config-from archive contains several images
+        #[arg(long)]
+        config_image_ref: Option<String>,
+
+        /// Platform to load from both images (<os>/<arch>[/<variant>]) when an archive holds several
+        #[arg(long)]
+        platform: Option<Platform>,
+
+        /// Output file path; `-` writes to stdout
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Temporary directory for intermediate files
+        #[arg(short, long)]
+        temp_dir: Option<PathBuf>,
+
+        /// Verbose output (same as --log-level debug)
+        #[arg(short, long)]
+        verbose: bool,
+
+        /// Only report errors
+        #[arg(short, long, conflicts_with = "verbose")]
+        quiet: bool,
+    },
+
+    /// Check that an image archive is consistent and loadable, without squashing it
+    Verify {
+        /// Image to check (name:tag or file path)
+        #[arg(short, long)]
+        source: String,
+
+        /// How to read --source instead of autodetecting it: `docker-archive`, `oci-archive` or `docker-daemon`
+        #[arg(long, value_enum)]
+        source_format: Option<SourceFormat>,
+
+        /// Image to check (name:tag) when the source archive contains several images
+        #[arg(long)]
+        image_ref: Option<String>,
+
+        /// Platform to check (<os>/<arch>[/<variant>]) when the archive holds several
+        #[arg(long)]
+        platform: Option<Platform>,
+
+        /// Temporary directory for intermediate files
+        #[arg(short, long)]
+        temp_dir: Option<PathBuf>,
+
+        /// Verbose output (same as --log-level debug)
+        #[arg(short, long)]
+        verbose: bool,
+
+        /// Only report errors; the check results are still printed
+        #[arg(short, long, conflicts_with = "verbose")]
+        quiet: bool,
+    },
+
+    /// Print the size of each layer and what merging the top layers would save, without squashing
+    Report {
+        /// Image to report on (name:tag or file path)
+        #[arg(short, long)]
+        source: String,
+
+        /// How to read --source instead of autodetecting it: `docker-archive`, `oci-archive` or `docker-daemon`
+        #[arg(long, value_enum)]
+        source_format: Option<SourceFormat>,
+
+        /// Image to report on (name:tag) when the source archive contains several images
+        #[arg(long)]
+        image_ref: Option<String>,
+
+        /// Platform to report on (<os>/<arch>[/<variant>]) when the archive holds several
+        #[arg(long)]
+        platform: Option<Platform>,
+
+        /// Output format of the report
+        #[arg(long, value_enum, default_value_t = SizeReportFormat::Table)]
+        format: SizeReportFormat,
+
         /// Temporary directory for intermediate files
         #[arg(short, long)]
         temp_dir: Option<PathBuf>,
 
-        /// Layer specification: number (merge latest n layers) or layer ID
+        /// Verbose output (same as --log-level debug)
+        #[arg(short, long)]
+        verbose: bool,
+
+        /// Only report errors; the report is still printed
+        #[arg(short, long, conflicts_with = "verbose")]
+        quiet: bool,
+    },
+
+    /// List files that later layers delete or overwrite, the space squashing would reclaim
+    Analyze {
+        /// Image to analyze (name:tag or file path)
         #[arg(short, long)]
-        layers: String,
+        source: String,
+
+        /// How to read --source instead of autodetecting it: `docker-archive`, `oci-archive` or `docker-daemon`
+        #[arg(long, value_enum)]
+        source_format: Option<SourceFormat>,
+
+        /// Image to analyze (name:tag) when the source archive contains several images
+        #[arg(long)]
+        image_ref: Option<String>,
+
+        /// Platform to analyze (<os>/<arch>[/<variant>]) when the archive holds several
+        #[arg(long)]
+        platform: Option<Platform>,
 
-        /// Verbose output
+        /// Output format of the analysis
+        #[arg(long, value_enum, default_value_t = SizeReportFormat::Table)]
+        format: SizeReportFormat,
+
+        /// Temporary directory for intermediate files
+        #[arg(short, long)]
+        temp_dir: Option<PathBuf>,
+
+        /// Verbose output (same as --log-level debug)
         #[arg(short, long)]
         verbose: bool,
+
+        /// Only report errors; the analysis is still printed
+        #[arg(short, long, conflicts_with = "verbose")]
+        quiet: bool,
     },
+
+    /// Print a shell completion script to stdout
+    Completions {
+        /// Shell to generate completions for
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+}
+
+/// How the squash summary is reported
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    /// Human-readable log lines on stderr
+    #[default]
+    Text,
+    /// A single JSON object on stdout
+    Json,
+}
+
+/// How the `report` and `analyze` subcommands print their results
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SizeReportFormat {
+    /// Aligned columns on stdout
+    #[default]
+    Table,
+    /// A single JSON object on stdout
+    Json,
+}
+
+/// Minimum severity of log messages written to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Trace => log::LevelFilter::Trace,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Error => log::LevelFilter::Error,
+        }
+    }
 }
 
 impl Cli {
@@ -45,3 +525,59 @@ impl Cli {
         Self::parse()
     }
 }
+
+/// Parse a command given as a JSON array (exec form) or a string run with `/bin/sh -c`
+pub fn parse_command(value: &str) -> Result<Vec<String>, String> {
+    if value.trim_start().starts_with('[') {
+        serde_json::from_str(value).map_err(|e| format!("invalid JSON array {}: {}", value, e))
+    } else {
+        Ok(vec!["/bin/sh".to_string(), "-c".to_string(), value.to_string()])
+    }
+}
+
+/// Parse an environment variable or label given as `KEY=VALUE`
+pub fn parse_env_var(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, val)) if !key.is_empty() => Ok((key.to_string(), val.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got {}", value)),
+    }
+}
+
+/// Parse a duration such as `500ms`, `2s` or `1m`; a bare number is in seconds
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits);
+    let number: u64 = number.parse().map_err(|_| format!("invalid duration: {}", value))?;
+
+    match unit.trim_start() {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => number
+            .checked_mul(60)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("duration {} is too large", value)),
+        _ => Err(format!("invalid duration unit in {}, expected ms, s or m", value)),
+    }
+}
+
+/// Parse a byte count such as `512`, `64K`, `4M` or `1GiB` (binary multiples)
+pub fn parse_byte_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, suffix) = value.split_at(digits);
+    let number: u64 = number.parse().map_err(|_| format!("invalid size: {}", value))?;
+
+    let unit = suffix.trim_start().to_ascii_uppercase();
+    let shift = match unit.trim_end_matches("IB").trim_end_matches('B') {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("invalid size suffix in {}, expected K, M, G or T", value)),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {} is too large", value))
+}
@@ -0,0 +1,493 @@
+use crate::error::{Result, SquashError};
+use log::warn;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+/// Default number of retries of a daemon operation that could not reach the daemon
+pub const DEFAULT_DOCKER_RETRIES: u32 = 3;
+
+/// Default wait before the first retry of a daemon operation
+pub const DEFAULT_DOCKER_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Error messages meaning the daemon could not be reached, matched case-insensitively
+const TRANSIENT_DOCKER_ERRORS: &[&str] = &[
+    "cannot connect to the docker daemon",
+    "is the docker daemon running",
+    "error during connect",
+    "error trying to connect",
+    "dial unix",
+    "connection refused",
+    "connection reset",
+    "broken pipe",
+    "i/o timeout",
+];
+
+/// Operations squash needs from the Docker daemon
+///
+/// Paths are handed over as they are, so they need not be valid UTF-8.
+pub trait DockerBackend {
+    /// Write image `image_name` to a `docker save` archive at `output_path`
+    fn save(&self, image_name: &str, output_path: &Path) -> Result<()>;
+
+    /// Import a `docker save` archive into the daemon
+    fn load(&self, archive_path: &Path) -> Result<()>;
+
+    /// Add tag `target` to image `source`
+    fn tag(&self, source: &str, target: &str) -> Result<()>;
+
+    /// Remove image or tag `image_name`
+    fn remove_image(&self, image_name: &str) -> Result<()>;
+
+    /// Whether the daemon has an image or tag named `image_name`
+    fn image_exists(&self, image_name: &str) -> Result<bool>;
+
+    /// ID of image `image_name`, the digest of its config (`sha256:<hex>`)
+    fn image_id(&self, image_name: &str) -> Result<String>;
+
+    /// Whether `export` and `import` stream archives without a temporary file
+    fn streams_archives(&self) -> bool {
+        false
+    }
+
+    /// Write image `image_name` as a `docker save` archive into `writer`
+    ///
+    /// By default the archive is saved to a temporary file and copied.
+    fn export(&self, image_name: &str, writer: &mut dyn Write) -> Result<()> {
+        let archive = tempfile::NamedTempFile::new()?;
+        self.save(image_name, archive.path())?;
+        std::io::copy(&mut File::open(archive.path())?, writer)?;
+        Ok(())
+    }
+
+    /// Import a `docker save` archive read from `reader`
+    ///
+    /// By default the archive is copied to a temporary file and loaded.
+    fn import(&self, mut reader: Box<dyn Read + Send>) -> Result<()> {
+        let mut archive = tempfile::NamedTempFile::new()?;
+        std::io::copy(&mut reader, archive.as_file_mut())?;
+        self.load(archive.path())
+    }
+}
+
+/// Selects how squash talks to the Docker daemon
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// Run the `docker` command line tool
+    #[default]
+    Cli,
+    /// Use the Docker Engine API over the daemon socket (requires the `api` feature)
+    Api,
+}
+
+impl Backend {
+    /// Create the selected backend
+    pub fn connect(self) -> Result<Box<dyn DockerBackend>> {
+        match self {
+            Backend::Cli => Ok(Box::new(CliBackend)),
+            #[cfg(feature = "api")]
+            Backend::Api => Ok(Box::new(ApiBackend::connect()?)),
+            #[cfg(not(feature = "api"))]
+            Backend::Api => Err(SquashError::InvalidInput(
+                "The api backend requires squash to be built with the `api` feature".to_string(),
+            )),
+        }
+    }
+}
+
+impl Backend {
+    /// Create the selected backend, retrying operations that cannot reach the daemon
+    pub fn connect_with_retry(self, policy: RetryPolicy) -> Result<Box<dyn DockerBackend>> {
+        let inner = self.connect()?;
+        if policy.retries == 0 {
+            return Ok(inner);
+        }
+        Ok(Box::new(RetryingBackend { inner, policy }))
+    }
+}
+
+/// How daemon operations are retried when the daemon cannot be reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one; 0 disables retrying
+    pub retries: u32,
+    /// Wait before the first retry, doubled before each further one
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: DEFAULT_DOCKER_RETRIES,
+            delay: DEFAULT_DOCKER_RETRY_DELAY,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Run `operation`, retrying transient failures with exponential backoff
+    ///
+    /// Errors other than an unreachable daemon are returned at once; after
+    /// the last retry the last error is returned.
+    pub fn run<T>(&self, what: &str, mut operation: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut delay = self.delay;
+        let mut attempt = 0;
+        loop {
+            match operation() {
+                Err(err) if attempt < self.retries && is_transient_docker_error(&err) => {
+                    attempt += 1;
+                    warn!("{} failed, retry {}/{} in {:?}: {}", what, attempt, self.retries, delay, err);
+                    std::thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether `err` means the daemon could not be reached, as opposed to a
+/// request the daemon rejected such as a missing image
+pub fn is_transient_docker_error(err: &SquashError) -> bool {
+    match err {
+        SquashError::DockerError(msg) => {
+            let msg = msg.to_ascii_lowercase();
+            TRANSIENT_DOCKER_ERRORS.iter().any(|pattern| msg.contains(pattern))
+        }
+        _ => false,
+    }
+}
+
+/// Backend that retries the operations of another backend per a [`RetryPolicy`]
+struct RetryingBackend {
+    inner: Box<dyn DockerBackend>,
+    policy: RetryPolicy,
+}
+
+impl DockerBackend for RetryingBackend {
+    fn save(&self, image_name: &str, output_path: &Path) -> Result<()> {
+        self.policy.run("docker save", || self.inner.save(image_name, output_path))
+    }
+
+    fn load(&self, archive_path: &Path) -> Result<()> {
+        self.policy.run("docker load", || self.inner.load(archive_path))
+    }
+
+    fn tag(&self, source: &str, target: &str) -> Result<()> {
+        self.policy.run("docker tag", || self.inner.tag(source, target))
+    }
+
+    fn remove_image(&self, image_name: &str) -> Result<()> {
+        self.policy.run("docker rmi", || self.inner.remove_image(image_name))
+    }
+
+    fn image_exists(&self, image_name: &str) -> Result<bool> {
+        self.policy.run("docker images", || self.inner.image_exists(image_name))
+    }
+
+    fn image_id(&self, image_name: &str) -> Result<String> {
+        self.policy.run("docker inspect", || self.inner.image_id(image_name))
+    }
+
+    // A partly streamed archive cannot be sent again, so streams are not retried
+
+    fn streams_archives(&self) -> bool {
+        self.inner.streams_archives()
+    }
+
+    fn export(&self, image_name: &str, writer: &mut dyn Write) -> Result<()> {
+        self.inner.export(image_name, writer)
+    }
+
+    fn import(&self, reader: Box<dyn Read + Send>) -> Result<()> {
+        self.inner.import(reader)
+    }
+}
+
+/// Backend that shells out to the `docker` command line tool
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CliBackend;
+
+impl CliBackend {
+    /// `docker <command> <args>`, passing paths through without a lossy conversion
+    fn command<I, S>(command: &str, args: I) -> Command
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut docker = Command::new("docker");
+        docker.arg(command).args(args);
+        docker
+    }
+
+    /// Run `docker <command> <args>`, mapping failures to DockerError
+    fn run<I, S>(&self, command: &str, args: I) -> Result<()>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.output(command, args).map(|_| ())
+    }
+
+    /// Run `docker <command> <args>` and return its standard output
+    fn output<I, S>(&self, command: &str, args: I) -> Result<Vec<u8>>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let output = Self::command(command, args)
+            .output()
+            .map_err(|e| SquashError::DockerError(format!("Failed to run docker {}: {}", command, e)))?;
+
+        if !output.status.success() {
+            return Err(SquashError::DockerError(format!(
+                "docker {} failed: {}",
+                command,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+
+        Ok(output.stdout)
+    }
+}
+
+impl DockerBackend for CliBackend {
+    fn save(&self, image_name: &str, output_path: &Path) -> Result<()> {
+        self.run("save", [OsStr::new("-o"), output_path.as_os_str(), OsStr::new(image_name)])
+    }
+
+    fn load(&self, archive_path: &Path) -> Result<()> {
+        self.run("load", [OsStr::new("-i"), archive_path.as_os_str()])
+    }
+
+    fn tag(&self, source: &str, target: &str) -> Result<()> {
+        self.run("tag", [source, target])
+    }
+
+    fn remove_image(&self, image_name: &str) -> Result<()> {
+        self.run("rmi", [image_name])
+    }
+
+    fn image_exists(&self, image_name: &str) -> Result<bool> {
+        // Prints the image ID when the image exists and nothing otherwise
+        let ids = self.output("images", ["--quiet", image_name])?;
+        Ok(!ids.trim_ascii().is_empty())
+    }
+
+    fn image_id(&self, image_name: &str) -> Result<String> {
+        let id = self.output("image", ["inspect", "--format", "{{.Id}}", image_name])?;
+        Ok(String::from_utf8_lossy(&id).trim().to_string())
+    }
+}
+
+/// Size of the chunks an archive is sent to the daemon in
+#[cfg(feature = "api")]
+const IMPORT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Backend that talks to the Docker Engine API through bollard
+#[cfg(feature = "api")]
+pub struct ApiBackend {
+    docker: bollard::Docker,
+    runtime: tokio::runtime::Runtime,
+}
+
+#[cfg(feature = "api")]
+impl ApiBackend {
+    /// Connect to the daemon at DOCKER_HOST or the default local socket
+    pub fn connect() -> Result<Self> {
+        let docker = bollard::Docker::connect_with_defaults()
+            .map_err(|e| SquashError::DockerError(format!("Failed to connect to Docker: {}", e)))?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        Ok(ApiBackend { docker, runtime })
+    }
+}
+
+#[cfg(feature = "api")]
+impl DockerBackend for ApiBackend {
+    fn save(&self, image_name: &str, output_path: &Path) -> Result<()> {
+        self.export(image_name, &mut File::create(output_path)?)
+    }
+
+    fn load(&self, archive_path: &Path) -> Result<()> {
+        self.import(Box::new(File::open(archive_path)?))
+    }
+
+    fn streams_archives(&self) -> bool {
+        true
+    }
+
+    fn export(&self, image_name: &str, writer: &mut dyn Write) -> Result<()> {
+        use futures_util::StreamExt;
+
+        self.runtime.block_on(async {
+            let mut stream = self.docker.export_image(image_name);
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk.map_err(|e| SquashError::DockerError(format!("Image export failed: {}", e)))?;
+                writer.write_all(&chunk)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn import(&self, reader: Box<dyn Read + Send>) -> Result<()> {
+        use bollard::query_parameters::ImportImageOptionsBuilder;
+        use futures_util::StreamExt;
+
+        // Blocking reads are fine here: the runtime only drives this request
+        let chunks = futures_util::stream::unfold(Some(reader), |reader| async move {
+            let mut reader = reader?;
+            let mut chunk = vec![0; IMPORT_CHUNK_SIZE];
+            match reader.read(&mut chunk) {
+                Ok(0) => None,
+                Ok(read) => {
+                    chunk.truncate(read);
+                    Some((Ok(chunk.into()), Some(reader)))
+                }
+                Err(e) => Some((Err(e), None)),
+            }
+        });
+
+        self.runtime.block_on(async {
+            let options = ImportImageOptionsBuilder::default().quiet(true).build();
+            let mut stream = self.docker.import_image(options, bollard::body_try_stream(chunks), None);
+            while let Some(info) = stream.next().await {
+                let info = info.map_err(|e| SquashError::DockerError(format!("Image load failed: {}", e)))?;
+                if let Some(message) = info.error_detail.and_then(|detail| detail.message) {
+                    return Err(SquashError::DockerError(format!("Image load failed: {}", message)));
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn tag(&self, source: &str, target: &str) -> Result<()> {
+        use crate::docker::reference::ImageReference;
+        use bollard::query_parameters::TagImageOptionsBuilder;
+
+        let reference: ImageReference = target.parse().map_err(SquashError::InvalidInput)?;
+        if reference.digest.is_some() {
+            return Err(SquashError::InvalidInput(format!("Cannot tag an image as digest reference '{}'", target)));
+        }
+        let options = TagImageOptionsBuilder::default()
+            .repo(&reference.name())
+            .tag(reference.tag.as_deref().unwrap_or("latest"))
+            .build();
+        self.runtime
+            .block_on(self.docker.tag_image(source, Some(options)))
+            .map_err(|e| SquashError::DockerError(format!("Image tag failed: {}", e)))
+    }
+
+    fn remove_image(&self, image_name: &str) -> Result<()> {
+        self.runtime
+            .block_on(self.docker.remove_image(
+                image_name,
+                None::<bollard::query_parameters::RemoveImageOptions>,
+                None,
+            ))
+            .map(|_| ())
+            .map_err(|e| SquashError::DockerError(format!("Image removal failed: {}", e)))
+    }
+
+    fn image_exists(&self, image_name: &str) -> Result<bool> {
+        match self.runtime.block_on(self.docker.inspect_image(image_name)) {
+            Ok(_) => Ok(true),
+            Err(bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }) => Ok(false),
+            Err(e) => Err(SquashError::DockerError(format!("Image inspect failed: {}", e))),
+        }
+    }
+
+    fn image_id(&self, image_name: &str) -> Result<String> {
+        let image = self.runtime
+            .block_on(self.docker.inspect_image(image_name))
+            .map_err(|e| SquashError::DockerError(format!("Image inspect failed: {}", e)))?;
+        image.id.ok_or_else(|| SquashError::DockerError(format!("Image inspect returned no ID for {}", image_name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transient_docker_errors() {
+        let transient = [
+            "docker save failed: Cannot connect to the Docker daemon at unix:///var/run/docker.sock. Is the docker daemon running?",
+            "docker load failed: error during connect: Post \"http://%2Fvar%2Frun%2Fdocker.sock/v1.45/images/load\": EOF",
+            "docker tag failed: dial unix /var/run/docker.sock: connect: connection refused",
+            "Image export failed: error trying to connect: Connection reset by peer",
+        ];
+        for msg in transient {
+            assert!(is_transient_docker_error(&SquashError::DockerError(msg.to_string())), "{}", msg);
+        }
+
+        let permanent = [
+            "docker save failed: Error response from daemon: reference does not exist",
+            "docker save failed: Error response from daemon: No such image: app:missing",
+            "Failed to run docker save: No such file or directory (os error 2)",
+        ];
+        for msg in permanent {
+            assert!(!is_transient_docker_error(&SquashError::DockerError(msg.to_string())), "{}", msg);
+        }
+        assert!(!is_transient_docker_error(&SquashError::InvalidInput("connection refused".to_string())));
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy { retries: 2, delay: Duration::ZERO };
+        let unreachable = || SquashError::DockerError("dial unix /var/run/docker.sock: connect: connection refused".to_string());
+
+        // Succeeds once the daemon is back
+        let mut attempts = 0;
+        let result = policy.run("docker save", || {
+            attempts += 1;
+            if attempts < 3 { Err(unreachable()) } else { Ok(attempts) }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        // The last error is returned once the retries are used up
+        let mut attempts = 0;
+        let result: Result<()> = policy.run("docker save", || {
+            attempts += 1;
+            Err(unreachable())
+        });
+        assert!(matches!(result, Err(SquashError::DockerError(_))));
+        assert_eq!(attempts, 3);
+
+        // Errors from a reachable daemon are not retried
+        let mut attempts = 0;
+        let result: Result<()> = policy.run("docker save", || {
+            attempts += 1;
+            Err(SquashError::DockerError("No such image: app:missing".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cli_backend_keeps_non_utf8_paths() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let archive_path = Path::new(OsStr::from_bytes(b"/tmp/squash-\xff/image.tar"));
+        let command = CliBackend::command("load", [OsStr::new("-i"), archive_path.as_os_str()]);
+
+        assert_eq!(command.get_program(), "docker");
+        let args: Vec<&OsStr> = command.get_args().collect();
+        assert_eq!(args, vec![OsStr::new("load"), OsStr::new("-i"), archive_path.as_os_str()]);
+    }
+
+    #[cfg(not(feature = "api"))]
+    #[test]
+    fn test_api_backend_requires_feature() {
+        match Backend::Api.connect() {
+            Err(SquashError::InvalidInput(msg)) => assert!(msg.contains("`api` feature")),
+            _ => panic!("Expected InvalidInput error without the api feature"),
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use crate::error::{Result, SquashError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Exit code of a process stopped by SIGINT, as shells report it
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Request to stop a running merge, save or load
+///
+/// Clones share one flag. Long operations check it between layers and while
+/// copying layer data, then clean up and fail with `SquashError::Cancelled`.
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    /// A cancellation that has not been requested
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask operations checking this flag to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether cancellation has been requested
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Fail with `SquashError::Cancelled` once cancellation has been requested
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(SquashError::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Cancel on SIGINT (Ctrl-C)
+    ///
+    /// A second SIGINT after the first exits the process at once with
+    /// `INTERRUPTED_EXIT_CODE`, for when cleanup itself hangs.
+    pub fn cancel_on_interrupt(&self) -> Result<()> {
+        use signal_hook::consts::SIGINT;
+
+        // Registered first so it sees the flag as set by an earlier SIGINT only
+        signal_hook::flag::register_conditional_shutdown(SIGINT, INTERRUPTED_EXIT_CODE, Arc::clone(&self.0))?;
+        signal_hook::flag::register(SIGINT, Arc::clone(&self.0))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancellation_is_shared_by_clones() {
+        let cancellation = Cancellation::new();
+        let clone = cancellation.clone();
+        assert!(clone.check().is_ok());
+
+        cancellation.cancel();
+        assert!(clone.is_cancelled());
+        assert!(matches!(clone.check(), Err(SquashError::Cancelled)));
+    }
+}
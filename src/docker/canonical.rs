@@ -0,0 +1,94 @@
+use crate::error::Result;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Serialize `value` as canonical JSON: object keys sorted, no insignificant whitespace
+///
+/// The output only depends on the data, not on struct field order or on how
+/// serde_json orders maps, so digests of manifests and configs are reproducible.
+pub fn to_canonical_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    write_canonical(&serde_json::to_value(value)?, &mut out)?;
+    Ok(out)
+}
+
+/// Append the canonical encoding of `value` to `out`
+fn write_canonical(value: &Value, out: &mut Vec<u8>) -> Result<()> {
+    match value {
+        Value::Array(items) => {
+            out.push(b'[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                write_canonical(item, out)?;
+            }
+            out.push(b']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+
+            out.push(b'{');
+            for (i, (key, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                serde_json::to_writer(&mut *out, key)?;
+                out.push(b':');
+                write_canonical(item, out)?;
+            }
+            out.push(b'}');
+        }
+        scalar => serde_json::to_writer(&mut *out, scalar)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+
+    #[derive(Serialize)]
+    struct Unordered {
+        zeta: u32,
+        alpha: Vec<&'static str>,
+        labels: HashMap<String, String>,
+    }
+
+    fn sample() -> Unordered {
+        Unordered {
+            zeta: 1,
+            alpha: vec!["b", "a"],
+            labels: (0..32).map(|i| (format!("key{:02}", 31 - i), format!("v \"{}\"", i))).collect(),
+        }
+    }
+
+    #[test]
+    fn test_canonical_json_sorts_keys_without_whitespace() {
+        let json = String::from_utf8(to_canonical_vec(&serde_json::json!({
+            "b": {"y": 1, "x": [3, {"d": null, "c": true}]},
+            "a": "text with spaces",
+        }))
+        .unwrap())
+        .unwrap();
+
+        assert_eq!(json, r#"{"a":"text with spaces","b":{"x":[3,{"c":true,"d":null}],"y":1}}"#);
+    }
+
+    #[test]
+    fn test_canonical_json_digest_is_stable() {
+        let first = to_canonical_vec(&sample()).unwrap();
+        let text = String::from_utf8(first.clone()).unwrap();
+        assert!(text.starts_with(r#"{"alpha":["b","a"],"labels":{"key00":"v \"31\"","key01":"#));
+        assert!(text.ends_with(r#""zeta":1}"#));
+
+        // HashMap iteration order changes between instances; the digest must not
+        let digest = Sha256::digest(&first);
+        for _ in 0..10 {
+            assert_eq!(Sha256::digest(to_canonical_vec(&sample()).unwrap()), digest);
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use crate::docker::layer::{entry_whiteout, LayerInfo, Whiteout};
+use crate::error::Result;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, EntryType};
+
+/// Content of a single path after all layers have been applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlattenedEntry {
+    /// Tar entry type (regular file, directory, symlink, ...)
+    pub entry_type: EntryType,
+    /// Permission bits
+    pub mode: u32,
+    /// Target of symlinks and hard links
+    pub link_name: Option<PathBuf>,
+    /// Size of the file contents in bytes
+    pub size: u64,
+    /// SHA256 digest of the file contents
+    pub digest: String,
+}
+
+/// Final filesystem of an image keyed by normalized path
+pub type FlattenedFilesystem = BTreeMap<PathBuf, FlattenedEntry>;
+
+/// A content difference between two flattened filesystems
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathChange {
+    /// Present only in the new filesystem
+    Added { path: PathBuf, entry: FlattenedEntry },
+    /// Present only in the old filesystem
+    Removed { path: PathBuf, entry: FlattenedEntry },
+    /// Present in both with different type, mode, link target or contents
+    Modified { path: PathBuf, old: FlattenedEntry, new: FlattenedEntry },
+}
+
+impl PathChange {
+    /// Path the change applies to
+    pub fn path(&self) -> &Path {
+        match self {
+            PathChange::Added { path, .. } | PathChange::Removed { path, .. } | PathChange::Modified { path, .. } => path,
+        }
+    }
+
+    /// Entry before the change, if the path existed
+    pub fn old_entry(&self) -> Option<&FlattenedEntry> {
+        match self {
+            PathChange::Added { .. } => None,
+            PathChange::Removed { entry, .. } => Some(entry),
+            PathChange::Modified { old, .. } => Some(old),
+        }
+    }
+
+    /// Entry after the change, if the path still exists
+    pub fn new_entry(&self) -> Option<&FlattenedEntry> {
+        match self {
+            PathChange::Added { entry, .. } => Some(entry),
+            PathChange::Removed { .. } => None,
+            PathChange::Modified { new, .. } => Some(new),
+        }
+    }
+}
+
+impl fmt::Display for PathChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathChange::Added { path, .. } => write!(f, "+ {}", path.display()),
+            PathChange::Removed { path, .. } => write!(f, "- {}", path.display()),
+            PathChange::Modified { path, .. } => write!(f, "~ {}", path.display()),
+        }
+    }
+}
+
+/// Apply layers in order, honoring whiteouts, and summarize the result
+///
+/// Only content is recorded: entry order, timestamps and tar encoding
+/// details do not affect the summary.
+pub fn flatten_layers(layers: &[LayerInfo]) -> Result<FlattenedFilesystem> {
+    let mut files = FlattenedFilesystem::new();
+
+    for layer in layers {
+        let mut archive = Archive::new(layer.open()?);
+        // Like the merger, entries from this layer survive its own whiteouts, whatever their order
+        let mut layer_paths = HashSet::new();
+
+        for entry_result in archive.entries()? {
+            let mut entry = entry_result?;
+            let path = normalize_path(&entry.path()?);
+
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+
+            match entry_whiteout(&mut entry, &path)? {
+                Some(Whiteout::Opaque(dir_path)) => {
+                    remove_descendants(&mut files, &dir_path, &layer_paths);
+                    continue;
+                }
+                Some(Whiteout::File(original_path)) => {
+                    remove_descendants(&mut files, &original_path, &layer_paths);
+                    files.remove(&original_path);
+                    continue;
+                }
+                Some(Whiteout::OpaqueDirectory(dir_path)) => {
+                    remove_descendants(&mut files, &dir_path, &layer_paths);
+                }
+                None => {}
+            }
+
+            let entry_type = entry.header().entry_type();
+            let mode = entry.header().mode()?;
+            let link_name = entry.link_name()?.map(|link| link.into_owned());
+
+            let mut hasher = Sha256::new();
+            let size = std::io::copy(&mut entry, &mut hasher)?;
+
+            files.insert(path.clone(), FlattenedEntry {
+                entry_type,
+                mode,
+                link_name,
+                size,
+                digest: format!("sha256:{:x}", hasher.finalize()),
+            });
+            layer_paths.insert(path);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Remove every path strictly below `dir_path` that `current_layer` did not add
+fn remove_descendants(files: &mut FlattenedFilesystem, dir_path: &Path, current_layer: &HashSet<PathBuf>) {
+    files.retain(|path, _| path == dir_path || !path.starts_with(dir_path) || current_layer.contains(path));
+}
+
+/// Bytes of file contents in each layer that later layers of `layers` hide
+///
+/// A file is hidden when a later layer writes the same path, replaces a parent
+/// directory with a non-directory, or deletes it with a whiteout. Merging layers
+/// `i..` drops the hidden bytes of each of them, so summing the result from the
+/// end gives what merging the top layers would save, before tar overhead.
+pub fn shadowed_bytes(layers: &[LayerInfo]) -> Result<Vec<u64>> {
+    // Paths written by later layers, and paths whose descendants they hide
+    let mut replaced = BTreeSet::new();
+    let mut hidden_below = BTreeSet::new();
+    let mut shadowed = vec![0; layers.len()];
+
+    for (index, layer) in layers.iter().enumerate().rev() {
+        let mut archive = Archive::new(layer.open()?);
+        let mut written = Vec::new();
+        let mut hides = Vec::new();
+
+        for entry_result in archive.entries()? {
+            let mut entry = entry_result?;
+            let path = normalize_path(&entry.path()?);
+
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+
+            match entry_whiteout(&mut entry, &path)? {
+                Some(Whiteout::Opaque(dir_path)) => {
+                    hides.push(dir_path);
+                    continue;
+                }
+                Some(Whiteout::File(original_path)) => {
+                    written.push(original_path.clone());
+                    hides.push(original_path);
+                    continue;
+                }
+                Some(Whiteout::OpaqueDirectory(dir_path)) => hides.push(dir_path),
+                None => {}
+            }
+
+            let is_hidden = replaced.contains(&path)
+                || path.ancestors().skip(1).any(|ancestor| hidden_below.contains(ancestor));
+            if is_hidden && entry.header().entry_type().is_file() {
+                shadowed[index] += entry.size();
+            }
+
+            if !entry.header().entry_type().is_dir() {
+                hides.push(path.clone());
+            }
+            written.push(path);
+        }
+
+        // Entries of one layer do not hide each other
+        replaced.extend(written);
+        hidden_below.extend(hides);
+    }
+
+    Ok(shadowed)
+}
+
+/// How a later layer hid a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WasteKind {
+    /// Removed by a whiteout
+    Deleted,
+    /// Replaced by another entry at the same path or above it
+    Overwritten,
+}
+
+impl fmt::Display for WasteKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WasteKind::Deleted => write!(f, "deleted"),
+            WasteKind::Overwritten => write!(f, "overwritten"),
+        }
+    }
+}
+
+/// A file whose contents stay in a layer although a later layer hides them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WastedFile {
+    pub path: PathBuf,
+    /// Index of the layer holding the contents
+    pub layer: usize,
+    /// Index of the layer that hides them
+    pub hidden_by: usize,
+    pub kind: WasteKind,
+    /// Size of the file contents in bytes
+    pub size: u64,
+}
+
+/// Regular files of `layers` that a later layer deletes or overwrites
+///
+/// Applies the layers in order like `flatten_layers` and records each hidden
+/// file together with the layer that hid it, in the order they were hidden.
+/// Squashing the range from `layer` to `hidden_by` drops those contents.
+pub fn wasted_files(layers: &[LayerInfo]) -> Result<Vec<WastedFile>> {
+    // Live regular files with the layer that wrote them and their size
+    let mut live: BTreeMap<PathBuf, (usize, u64)> = BTreeMap::new();
+    let mut wasted = Vec::new();
+
+    // Drop `path` itself and/or the files below it, which sort right after it
+    let mut hide = |live: &mut BTreeMap<PathBuf, (usize, u64)>, index, kind, path: &Path, itself, below| {
+        let hidden: Vec<PathBuf> = live
+            .range::<Path, _>((Bound::Included(path), Bound::Unbounded))
+            .map(|(p, _)| p)
+            .take_while(|p| p.starts_with(path))
+            .filter(|p| if *p == path { itself } else { below })
+            .cloned()
+            .collect();
+        for hidden_path in hidden {
+            let (layer, size) = live.remove(&hidden_path).unwrap_or_default();
+            // Entries of one layer do not hide each other
+            if layer < index {
+                wasted.push(WastedFile { path: hidden_path, layer, hidden_by: index, kind, size });
+            }
+        }
+    };
+
+    for (index, layer) in layers.iter().enumerate() {
+        let mut archive = Archive::new(layer.open()?);
+
+        for entry_result in archive.entries()? {
+            let mut entry = entry_result?;
+            let path = normalize_path(&entry.path()?);
+
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+
+            match entry_whiteout(&mut entry, &path)? {
+                Some(Whiteout::Opaque(dir_path)) => {
+                    hide(&mut live, index, WasteKind::Deleted, &dir_path, false, true);
+                    continue;
+                }
+                Some(Whiteout::File(original_path)) => {
+                    hide(&mut live, index, WasteKind::Deleted, &original_path, true, true);
+                    continue;
+                }
+                Some(Whiteout::OpaqueDirectory(dir_path)) => {
+                    hide(&mut live, index, WasteKind::Deleted, &dir_path, false, true);
+                }
+                None => {}
+            }
+
+            // A directory keeps what is below it, anything else replaces it
+            let entry_type = entry.header().entry_type();
+            hide(&mut live, index, WasteKind::Overwritten, &path, true, !entry_type.is_dir());
+            if entry_type.is_file() {
+                live.insert(path, (index, entry.size()));
+            }
+        }
+    }
+
+    Ok(wasted)
+}
+
+/// List the paths that differ between two flattened filesystems, sorted by path
+pub fn diff_filesystems(old: &FlattenedFilesystem, new: &FlattenedFilesystem) -> Vec<PathChange> {
+    let mut changes = Vec::new();
+
+    for (path, old_entry) in old {
+        match new.get(path) {
+            None => changes.push(PathChange::Removed { path: path.clone(), entry: old_entry.clone() }),
+            Some(new_entry) if new_entry != old_entry => changes.push(PathChange::Modified {
+                path: path.clone(),
+                old: old_entry.clone(),
+                new: new_entry.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+
+    for (path, new_entry) in new.iter().filter(|(path, _)| !old.contains_key(*path)) {
+        changes.push(PathChange::Added { path: path.clone(), entry: new_entry.clone() });
+    }
+
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    changes
+}
+
+/// Drop `./` prefixes and trailing slashes so equivalent tar paths compare equal
+fn normalize_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(digest: &str) -> FlattenedEntry {
+        FlattenedEntry {
+            entry_type: EntryType::Regular,
+            mode: 0o644,
+            link_name: None,
+            size: 1,
+            digest: digest.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path(Path::new("./etc/passwd")), PathBuf::from("etc/passwd"));
+        assert_eq!(normalize_path(Path::new("usr/bin/")), PathBuf::from("usr/bin"));
+        assert!(normalize_path(Path::new("./")).as_os_str().is_empty());
+    }
+
+    #[test]
+    fn test_diff_filesystems() {
+        let old = FlattenedFilesystem::from([
+            (PathBuf::from("a"), entry("sha256:1")),
+            (PathBuf::from("b"), entry("sha256:2")),
+            (PathBuf::from("c"), entry("sha256:3")),
+        ]);
+        let new = FlattenedFilesystem::from([
+            (PathBuf::from("b"), entry("sha256:2")),
+            (PathBuf::from("c"), entry("sha256:changed")),
+            (PathBuf::from("d"), entry("sha256:4")),
+        ]);
+
+        assert_eq!(diff_filesystems(&old, &new), vec![
+            PathChange::Removed { path: PathBuf::from("a"), entry: entry("sha256:1") },
+            PathChange::Modified { path: PathBuf::from("c"), old: entry("sha256:3"), new: entry("sha256:changed") },
+            PathChange::Added { path: PathBuf::from("d"), entry: entry("sha256:4") },
+        ]);
+        assert!(diff_filesystems(&old, &old).is_empty());
+    }
+}
@@ -1,10 +1,40 @@
 use crate::error::{Result, SquashError};
-use crate::docker::{TarExtractor, LayerMerger, LayerInfo};
+use crate::docker::{calculate_file_digest, StreamCompression, TarBuilder, Zstd, TarExtractor, LayerMerger, LayerInfo, LayerMergeStats, MergeOptions};
+use crate::docker::diff::{
+    diff_filesystems, flatten_layers, shadowed_bytes, wasted_files, FlattenedFilesystem, PathChange, WastedFile,
+};
+use crate::docker::oci::*;
+use crate::docker::backend::{Backend, CliBackend, DockerBackend, RetryPolicy};
+use crate::docker::cancel::Cancellation;
+use crate::docker::canonical::to_canonical_vec;
+use crate::docker::permissions::PermissionAudit;
+use crate::docker::progress::{NoopObserver, ProgressObserver};
+use crate::docker::reference::{ImageReference, ImageSource, SourceFormat};
+use crate::docker::runtime::{download_foreign_layer, Runtime, Skopeo};
+use flate2::read::GzDecoder;
+use log::{debug, info, trace, warn};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use tempfile::TempDir;
+use tar::Header;
+use tempfile::{NamedTempFile, TempDir};
+
+/// File name of a layer wrapped in its own directory (`<id>/layer.tar`)
+const LAYER_TAR_NAME: &str = "layer.tar";
+/// Contents of the `VERSION` file written next to a wrapped layer
+const LAYER_VERSION: &str = "1.0";
+
+/// Legacy top-level file mapping repositories and tags to the top layer id
+const REPOSITORIES_FILE: &str = "repositories";
+
+/// Label recording how many source layers were merged into the output
+pub const MERGED_LAYERS_LABEL: &str = "org.squash.merged-layers";
+/// Label recording the image ID (config digest) of the squashed source
+pub const SOURCE_DIGEST_LABEL: &str = "org.squash.source-digest";
 
 /// Docker image manifest structure as found in manifest.json
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -18,6 +48,12 @@ pub struct DockerManifest {
     /// List of layer tar files
     #[serde(rename = "Layers")]
     pub layers: Vec<String>,
+    /// Descriptors of foreign layers, keyed by diff_id
+    #[serde(rename = "LayerSources", default, skip_serializing_if = "Option::is_none")]
+    pub layer_sources: Option<BTreeMap<String, OciDescriptor>>,
+    /// Attestation manifests attached to the image in an OCI index (not serialized)
+    #[serde(skip)]
+    pub attestations: Vec<Attestation>,
 }
 
 /// Docker image configuration structure as found in config.json
@@ -25,15 +61,37 @@ pub struct DockerManifest {
 pub struct DockerConfig {
     /// Target architecture (e.g., "amd64")
     pub architecture: String,
+    /// Target operating system (e.g., "linux")
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    /// Architecture variant (e.g., "v8")
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
     /// Container configuration details
+    #[serde(default)]
     pub config: ConfigDetails,
     /// Root filesystem information
     pub rootfs: RootFs,
     /// Layer history information
+    #[serde(default)]
     pub history: Vec<HistoryEntry>,
+    /// Fields not modelled above (`created`, `author`, ...), written back verbatim
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+impl DockerConfig {
+    /// Platform this image was built for, assuming linux when no OS is recorded
+    pub fn platform(&self) -> Platform {
+        Platform {
+            os: self.os.clone().unwrap_or_else(|| "linux".to_string()),
+            architecture: self.architecture.clone(),
+            variant: self.variant.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct ConfigDetails {
     #[serde(rename = "Env")]
     pub env: Option<Vec<String>>,
@@ -43,6 +101,13 @@ pub struct ConfigDetails {
     pub working_dir: Option<String>,
     #[serde(rename = "ExposedPorts")]
     pub exposed_ports: Option<HashMap<String, serde_json::Value>>,
+    #[serde(rename = "Entrypoint", default, skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(rename = "Labels", default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<BTreeMap<String, String>>,
+    /// Fields not modelled above (`User`, `Volumes`, `Healthcheck`, ...), written back verbatim
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -52,11 +117,428 @@ pub struct RootFs {
     pub diff_ids: Vec<String>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct HistoryEntry {
+    #[serde(default)]
     pub created: String,
+    #[serde(default)]
     pub created_by: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
     pub empty_layer: Option<bool>,
+    /// Commands of the layers folded into this entry by a squash (not serialized)
+    #[serde(skip)]
+    pub merged_from: Vec<String>,
+}
+
+impl HistoryEntry {
+    /// Command that created the layer, or those of every layer a squash replaced
+    pub fn layer_command(&self) -> String {
+        if self.merged_from.is_empty() {
+            self.created_by.clone()
+        } else {
+            self.merged_from.join("\n")
+        }
+    }
+}
+
+/// On-disk layout of an image archive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ImageFormat {
+    /// Classic `docker save` layout with manifest.json
+    #[default]
+    #[value(name = "docker")]
+    DockerArchive,
+    /// OCI image layout with index.json and a blob store
+    #[value(name = "oci")]
+    OciArchive,
+}
+
+/// Compression applied to layer blobs written by squash
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LayerCompression {
+    /// Plain tar
+    #[default]
+    None,
+    /// Gzip compressed tar
+    Gzip,
+    /// Zstd compressed tar; needs the `zstd` feature
+    Zstd,
+}
+
+impl LayerCompression {
+    /// Fail unless `level` is a compression level this format accepts
+    pub fn check_level(self, level: u32) -> Result<()> {
+        let range = match self {
+            LayerCompression::None => {
+                return Err(SquashError::InvalidInput(
+                    "A compression level needs --output-compression gzip or zstd".to_string(),
+                ))
+            }
+            LayerCompression::Gzip => 0..=9,
+            LayerCompression::Zstd => 1..=19,
+        };
+        if !range.contains(&level) {
+            return Err(SquashError::InvalidInput(format!(
+                "Compression level {} is out of range for {:?}, expected {} to {}",
+                level,
+                self,
+                range.start(),
+                range.end()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// How layers kept as-is by a partial squash are compressed on output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RetainedLayerCompression {
+    /// Copy retained layers exactly as they were stored
+    #[default]
+    Keep,
+    /// Recompress retained layers to the output layer compression
+    Match,
+}
+
+/// Reader reporting the bytes read through it as saved layer content
+///
+/// Reads fail once `cancellation` is set, which stops the copy into the archive.
+struct SaveProgressReader<'a, R> {
+    inner: R,
+    observer: &'a mut dyn ProgressObserver,
+    cancellation: &'a Cancellation,
+}
+
+impl<R: std::io::Read> std::io::Read for SaveProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cancellation.is_cancelled() {
+            return Err(std::io::Error::other("save cancelled"));
+        }
+        let read = self.inner.read(buf)?;
+        self.observer.on_save_bytes(read as u64);
+        Ok(read)
+    }
+}
+
+/// Layer content as written to an output archive
+struct LayerBlob {
+    compression: LayerCompression,
+    /// Converted copy, deleted when the blob is dropped; `None` writes the layer as stored
+    converted: Option<NamedTempFile>,
+}
+
+impl LayerBlob {
+    /// SHA256 digest of the blob as written
+    fn digest(&self, layer: &LayerInfo) -> Result<String> {
+        match &self.converted {
+            Some(converted) => calculate_file_digest(converted.path()),
+            None => layer.blob_digest(),
+        }
+    }
+
+    /// Size of the blob as written
+    fn size(&self, layer: &LayerInfo) -> Result<u64> {
+        match &self.converted {
+            Some(converted) => Ok(converted.as_file().metadata()?.len()),
+            None => Ok(layer.size),
+        }
+    }
+}
+
+/// Options controlling how the squashed image is written
+#[derive(Debug, Clone, Default)]
+pub struct SaveOptions {
+    /// Annotate OCI layer descriptors with the history command that created them
+    pub layer_annotations_from_history: bool,
+    /// Carry attestation manifests (SBOMs, provenance) over to the squashed image
+    pub sbom_passthrough: bool,
+    /// Compression of layers produced by squashing
+    pub layer_compression: LayerCompression,
+    /// Whether retained layers follow `layer_compression` too
+    pub retained_layer_compression: RetainedLayerCompression,
+    /// Write manifest.json and the config of Docker archives as canonical JSON
+    pub canonical_json: bool,
+    /// mediaType of the OCI image manifest instead of the OCI default
+    pub manifest_media_type: Option<String>,
+    /// mediaType of the OCI config descriptor instead of the OCI default
+    pub config_media_type: Option<String>,
+    /// Add the legacy `repositories` file to Docker archives
+    pub legacy_repositories: bool,
+    /// Leave the temporary tag created by `load_into_docker` in place
+    pub keep_load_tag: bool,
+    /// Fail with [`SquashError::OutputExists`] instead of replacing an existing output file
+    pub no_clobber: bool,
+    /// Checked while layers are copied into the archive and after loading;
+    /// once set, saving stops with [`SquashError::Cancelled`] and no output is left
+    pub cancellation: Cancellation,
+}
+
+/// Changes to the runtime config of the output image; unset fields keep the original value
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    /// Replacement for `Cmd`
+    pub cmd: Option<Vec<String>>,
+    /// Replacement for `Entrypoint`
+    pub entrypoint: Option<Vec<String>>,
+    /// Variables replacing same-named entries of `Env`, appended when not yet set
+    pub env: Vec<(String, String)>,
+    /// Replacement for `WorkingDir`
+    pub working_dir: Option<String>,
+    /// Entries added to `Labels`, replacing existing ones with the same key
+    pub labels: Vec<(String, String)>,
+}
+
+/// What became of the temporary tag used to load an image into Docker
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum TempTagCleanup {
+    /// The tag was removed
+    Removed,
+    /// Removal was skipped on request
+    Kept,
+    /// Removal failed; the tag is still present
+    Failed { error: String },
+}
+
+/// Outcome of loading the squashed image into Docker
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DockerLoadReport {
+    /// Temporary tag the image was loaded under before being tagged
+    pub temp_tag: String,
+    /// Whether the temporary tag was cleaned up afterwards
+    pub cleanup: TempTagCleanup,
+}
+
+/// How layers that reference external URLs (foreign layers) are handled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ForeignLayerPolicy {
+    /// Keep foreign layers and their sources untouched
+    #[default]
+    Preserve,
+    /// Abort loading when the image contains foreign layers
+    Fail,
+    /// Download foreign layers from their URLs so they can be squashed
+    Download,
+}
+
+/// Options controlling how an image archive is loaded
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// Tag of the image to use when the archive contains several images
+    pub image_ref: Option<String>,
+    /// Platform to use when the archive contains images for several platforms
+    pub platform: Option<Platform>,
+    /// Backend used to fetch sources that are not local files
+    pub runtime: Runtime,
+    /// Handling of foreign layers found in the image
+    pub foreign_layers: ForeignLayerPolicy,
+    /// Recompute each layer digest and compare it with the config diff_id
+    pub verify: bool,
+    /// How image names are exported from the Docker daemon
+    pub backend: Backend,
+    /// Retries of the export when the daemon cannot be reached
+    pub docker_retry: RetryPolicy,
+    /// Leave the extraction directory on disk for inspection instead of deleting it
+    pub keep_temp: bool,
+    /// Directory keeping `docker save` archives for reuse, keyed by image ID
+    pub cache_dir: Option<PathBuf>,
+    /// Layer media types accepted on input; empty accepts any type
+    pub allowed_media_types: Vec<String>,
+    /// Layer media types rejected on input
+    pub denied_media_types: Vec<String>,
+    /// How to read the source; `None` autodetects files, image names and the archive layout
+    pub source_format: Option<SourceFormat>,
+    /// Read large files such as layers in place from an uncompressed source
+    /// archive instead of extracting them; the archive must not change
+    /// until the image is saved
+    pub layers_in_place: bool,
+}
+
+impl LoadOptions {
+    /// Enforce the media type allowlist and denylist for one input layer
+    pub fn check_layer_media_type(&self, layer: &str, media_type: &str) -> Result<()> {
+        let reason = if self.denied_media_types.iter().any(|denied| denied == media_type) {
+            "denied"
+        } else if !self.allowed_media_types.is_empty()
+            && !self.allowed_media_types.iter().any(|allowed| allowed == media_type)
+        {
+            "not allowed"
+        } else {
+            return Ok(());
+        };
+
+        Err(SquashError::PolicyViolation {
+            layer: layer.to_string(),
+            media_type: media_type.to_string(),
+            reason: reason.to_string(),
+        })
+    }
+}
+
+/// What a squash would do, computed without merging anything
+#[derive(Debug, Clone)]
+pub struct SquashPlan {
+    /// Index of the first layer to merge (0 is the base layer)
+    pub start: usize,
+    /// Index of the last layer to merge, inclusive
+    pub end: usize,
+    /// The layers that would be merged, base first
+    pub layers: Vec<LayerInfo>,
+    /// Combined size of the merged layers in bytes
+    pub merged_size: u64,
+    /// Indexes of preserved layers in the range; the merge is split around them
+    pub preserved: Vec<usize>,
+    /// Layer count before and after the squash
+    pub layer_count: (usize, usize),
+    /// History entry count before and after the squash
+    pub history_count: (usize, usize),
+}
+
+impl std::fmt::Display for SquashPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<7} {:<71} {:>12}", "LAYER", "DIGEST", "SIZE")?;
+        for (offset, layer) in self.layers.iter().enumerate() {
+            writeln!(f, "{:<7} {:<71} {:>12}", self.start + offset, layer.digest, layer.size)?;
+        }
+        writeln!(f, "{:<7} {:<71} {:>12}", "TOTAL", "", self.merged_size)?;
+        writeln!(f)?;
+        if !self.preserved.is_empty() {
+            let preserved: Vec<String> = self.preserved.iter().map(|index| index.to_string()).collect();
+            writeln!(f, "Preserved: {}", preserved.join(", "))?;
+        }
+        writeln!(f, "Layers:  {} -> {}", self.layer_count.0, self.layer_count.1)?;
+        write!(f, "History: {} -> {}", self.history_count.0, self.history_count.1)
+    }
+}
+
+/// Size of each layer of an image and what merging the top layers would save
+#[derive(Debug, Clone, Serialize)]
+pub struct SizeReport {
+    /// Every layer, base first
+    pub layers: Vec<LayerSizeEntry>,
+    /// Combined size of all layers in bytes
+    pub total_size: u64,
+    /// Savings of merging the top 2, 3, ... layers; stops below a foreign layer
+    pub tail_savings: Vec<TailSavings>,
+}
+
+/// One layer of a `SizeReport`
+#[derive(Debug, Clone, Serialize)]
+pub struct LayerSizeEntry {
+    /// Index of the layer, 0 is the base layer
+    pub index: usize,
+    /// diff_id of the layer
+    pub digest: String,
+    /// History command that created the layer, if history has one
+    pub created_by: Option<String>,
+    /// Size of the layer in bytes
+    pub size: u64,
+}
+
+/// Effect of merging the top `layers` layers, as with `--layers <layers>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TailSavings {
+    /// Number of top layers merged
+    pub layers: usize,
+    /// Combined size of those layers in bytes
+    pub merged_size: u64,
+    /// File content bytes the merge drops because later layers hide them
+    pub savings: u64,
+}
+
+impl std::fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<7} {:<12} {:>12}  CREATED BY", "LAYER", "DIGEST", "SIZE")?;
+        for layer in &self.layers {
+            let digest: String = layer.digest.trim_start_matches("sha256:").chars().take(12).collect();
+            let created_by = layer.created_by.as_deref().and_then(|c| c.lines().next()).unwrap_or("");
+            writeln!(f, "{:<7} {:<12} {:>12}  {}", layer.index, digest, layer.size, created_by)?;
+        }
+        write!(f, "{:<7} {:<12} {:>12}", "TOTAL", "", self.total_size)?;
+
+        if !self.tail_savings.is_empty() {
+            writeln!(f)?;
+            writeln!(f)?;
+            write!(f, "{:<9} {:>12} {:>12}", "--layers", "MERGED SIZE", "SAVINGS")?;
+            for tail in &self.tail_savings {
+                write!(f, "\n{:<9} {:>12} {:>12}", tail.layers, tail.merged_size, tail.savings)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Files that later layers of an image delete or overwrite
+#[derive(Debug, Clone, Serialize)]
+pub struct WasteReport {
+    /// Hidden files, largest first
+    pub files: Vec<WastedFile>,
+    /// Combined size of all layers in bytes
+    pub total_size: u64,
+    /// File content bytes that squashing all layers would drop
+    pub reclaimable: u64,
+}
+
+impl std::fmt::Display for WasteReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<7} {:<9} {:<11} {:>12}  PATH", "LAYER", "HIDDEN BY", "EVENT", "SIZE")?;
+        for file in &self.files {
+            writeln!(
+                f,
+                "{:<7} {:<9} {:<11} {:>12}  /{}",
+                file.layer,
+                file.hidden_by,
+                file.kind,
+                file.size,
+                file.path.display()
+            )?;
+        }
+        writeln!(f, "{:<7} {:<9} {:<11} {:>12}", "TOTAL", "", "", self.reclaimable)?;
+        writeln!(f)?;
+        let percent = match self.total_size {
+            0 => 0.0,
+            total => self.reclaimable as f64 * 100.0 / total as f64,
+        };
+        write!(
+            f,
+            "Reclaimable: {} bytes in {} files ({:.1}% of {} layer bytes)",
+            self.reclaimable,
+            self.files.len(),
+            percent,
+            self.total_size
+        )
+    }
+}
+
+/// Outcome of one check made while verifying an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerificationCheck {
+    /// What was checked, such as `diff_ids` or `layer <path>`
+    pub name: String,
+    pub passed: bool,
+    /// What was found, or why the check failed
+    pub detail: String,
+}
+
+impl VerificationCheck {
+    fn new(name: impl Into<String>, passed: bool, detail: impl Into<String>) -> Self {
+        VerificationCheck { name: name.into(), passed, detail: detail.into() }
+    }
+}
+
+impl std::fmt::Display for VerificationCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = if self.passed { "PASS" } else { "FAIL" };
+        write!(f, "{} {}: {}", status, self.name, self.detail)
+    }
+}
+
+/// An image found in an archive, with what is needed to select it
+struct ImageCandidate<T> {
+    item: T,
+    tags: Vec<String>,
+    platform: Option<Platform>,
 }
 
 pub struct DockerImage {
@@ -65,351 +547,2207 @@ pub struct DockerImage {
     pub source_path: PathBuf,
     pub layers: Vec<LayerInfo>,
     pub temp_dir: Option<TempDir>,
+    pub format: ImageFormat,
+    pub merge_options: MergeOptions,
+    pub save_options: SaveOptions,
+    /// diff_ids of the layers produced by squashing, as opposed to retained ones
+    pub squashed_layers: BTreeSet<String>,
+    /// Indexes of the layers that merges leave intact, see `preserve_layers`
+    ///
+    /// Layers are kept by position rather than diff_id, since identical
+    /// layers (empty ones in particular) may appear more than once.
+    pub preserved_layers: BTreeSet<usize>,
+    /// Stats of each merge performed on this image, in order
+    pub merge_stats: Vec<LayerMergeStats>,
+    /// Digest of the config the image was loaded with, i.e. the source image ID
+    pub source_digest: Option<String>,
+}
+
+impl Clone for DockerImage {
+    fn clone(&self) -> Self {
+        DockerImage {
+            manifest: self.manifest.clone(),
+            config: self.config.clone(),
+            source_path: self.source_path.clone(),
+            layers: self.layers.clone(),
+            temp_dir: None, // Don't clone temp_dir as it's not cloneable and not needed for the clone
+            format: self.format,
+            merge_options: self.merge_options.clone(),
+            save_options: self.save_options.clone(),
+            squashed_layers: self.squashed_layers.clone(),
+            preserved_layers: self.preserved_layers.clone(),
+            merge_stats: self.merge_stats.clone(),
+            source_digest: self.source_digest.clone(),
+        }
+    }
 }
 
-impl Clone for DockerImage {
-    fn clone(&self) -> Self {
-        DockerImage {
-            manifest: self.manifest.clone(),
-            config: self.config.clone(),
-            source_path: self.source_path.clone(),
-            layers: self.layers.clone(),
-            temp_dir: None, // Don't clone temp_dir as it's not cloneable and not needed for the clone
+impl DockerImage {
+    /// Load a Docker image from a file or export from Docker
+    pub fn load(source: &str, temp_dir: Option<&Path>) -> Result<Self> {
+        Self::load_with_options(source, temp_dir, &LoadOptions::default())
+    }
+
+    /// Load a Docker image using the given load options
+    pub fn load_with_options(source: &str, temp_dir: Option<&Path>, options: &LoadOptions) -> Result<Self> {
+        let source_path = if options.runtime == Runtime::Skopeo
+            && options.source_format.is_none()
+            && !Path::new(source).exists()
+        {
+            // Any skopeo transport, copied into a local OCI archive
+            let temp_dir = temp_dir.unwrap_or_else(|| Path::new("/tmp"));
+            Skopeo::new().fetch(source, temp_dir)?
+        } else {
+            match ImageSource::parse_as(source, options.source_format).map_err(SquashError::InvalidInput)? {
+                // An image reference, export it from the daemon first
+                ImageSource::Reference(reference) => {
+                    let backend = options.backend.connect_with_retry(options.docker_retry)?;
+                    if backend.streams_archives() && options.cache_dir.is_none() {
+                        return Self::export_from_daemon(backend.as_ref(), &reference.to_string(), temp_dir, options);
+                    }
+                    Self::export_image(backend.as_ref(), &reference, temp_dir, options)?
+                }
+                ImageSource::File(path) => path,
+                #[cfg(feature = "http")]
+                ImageSource::Url(url) => {
+                    crate::docker::runtime::download_source(&url, temp_dir.unwrap_or_else(|| Path::new("/tmp")))?
+                }
+                #[cfg(not(feature = "http"))]
+                ImageSource::Url(_) => {
+                    return Err(SquashError::InvalidInput(
+                        "Reading the source from a URL requires squash to be built with the `http` feature".to_string(),
+                    ))
+                }
+            }
+        };
+
+        if !source_path.exists() {
+            return Err(SquashError::InvalidInput(format!(
+                "Source file does not exist: {}",
+                source_path.display()
+            )));
+        }
+
+        info!("Extracting Docker image: {}", source_path.display());
+
+        // Extract the Docker image tar file, which may be compressed as by `docker save | xz`
+        let extractor = match StreamCompression::of_file(&source_path)? {
+            StreamCompression::None if options.layers_in_place => TarExtractor::index(&source_path)?,
+            StreamCompression::None => TarExtractor::extract(&source_path)?,
+            StreamCompression::Gzip => {
+                debug!("Source archive is gzip compressed");
+                TarExtractor::extract_gz(&source_path)?
+            }
+            compression => {
+                debug!("Source archive is {} compressed", compression);
+                TarExtractor::extract_compressed(&source_path, compression)?
+            }
+        };
+        Self::from_extracted(extractor, source_path, options)
+    }
+
+    /// Load a Docker or OCI archive read from a stream
+    ///
+    /// The archive, plain or gzipped, is unpacked straight into a directory
+    /// under `temp_dir` (the system temporary directory when `None`) with no
+    /// named archive file in between; together with `save_to_writer` this
+    /// keeps a whole squash off named files. `source_path` is left empty.
+    pub fn from_reader(reader: impl Read, temp_dir: Option<&Path>) -> Result<Self> {
+        Self::from_reader_with_options(reader, temp_dir, &LoadOptions::default())
+    }
+
+    /// Load an archive read from a stream using the given load options
+    ///
+    /// Options about fetching the source, such as the runtime or the Docker
+    /// backend, do not apply.
+    pub fn from_reader_with_options(reader: impl Read, temp_dir: Option<&Path>, options: &LoadOptions) -> Result<Self> {
+        info!("Extracting Docker image from a stream");
+        let extractor = TarExtractor::extract_reader(reader, temp_dir)?;
+        Self::from_extracted(extractor, PathBuf::new(), options)
+    }
+
+    /// Parse an extracted archive into an image that owns the extraction directory
+    fn from_extracted(extractor: TarExtractor, source_path: PathBuf, options: &LoadOptions) -> Result<Self> {
+        let (manifest, config, source_digest, layers, format, mut temp_dir) = Self::parse_image(extractor, options)?;
+        if options.keep_temp {
+            temp_dir.disable_cleanup(true);
+            info!("Keeping temporary files in {}", temp_dir.path().display());
+        }
+
+        Ok(DockerImage {
+            manifest,
+            config,
+            source_path,
+            layers,
+            temp_dir: Some(temp_dir),
+            format,
+            merge_options: MergeOptions::default(),
+            save_options: SaveOptions::default(),
+            squashed_layers: BTreeSet::new(),
+            preserved_layers: BTreeSet::new(),
+            merge_stats: Vec::new(),
+            source_digest: Some(source_digest),
+        })
+    }
+
+    /// Export a Docker image using docker save
+    fn export_image(
+        backend: &dyn DockerBackend,
+        reference: &ImageReference,
+        temp_dir: Option<&Path>,
+        options: &LoadOptions,
+    ) -> Result<PathBuf> {
+        let image_name = reference.to_string();
+        let temp_dir = temp_dir.unwrap_or_else(|| Path::new("/tmp"));
+        let output_path = temp_dir.join(format!("{}.tar", image_name.replace([':', '/', '@'], "_")));
+
+        let Some(cache_dir) = &options.cache_dir else {
+            backend.save(&image_name, &output_path)?;
+            return Ok(output_path);
+        };
+
+        // A rebuilt image has a new ID and so misses the cache
+        let image_id = backend.image_id(&image_name)?;
+        let cached_path = cache_dir.join(export_cache_key(&image_id)?);
+        if cached_path.exists() {
+            info!("Using cached export of {} ({})", image_name, image_id);
+            return Ok(cached_path);
+        }
+
+        // Save next to the cache entry and move it in place once complete
+        std::fs::create_dir_all(cache_dir)?;
+        let partial = NamedTempFile::new_in(cache_dir)?;
+        backend.save(&image_name, partial.path())?;
+
+        // The tag may have moved to another image while saving
+        if backend.image_id(&image_name)? != image_id {
+            warn!("{} changed while it was exported, not caching it", image_name);
+            partial.persist(&output_path).map_err(|e| e.error)?;
+            return Ok(output_path);
+        }
+        partial.persist(&cached_path).map_err(|e| e.error)?;
+        debug!("Cached export of {} as {}", image_name, cached_path.display());
+
+        Ok(cached_path)
+    }
+
+    /// Load image `image_name` from the daemon's export stream through `backend`
+    ///
+    /// The archive is unpacked as it arrives, like [`DockerImage::from_reader`],
+    /// so no `docker save` file is written. `load_with_options` takes this path
+    /// for image names when the backend streams archives and no cache
+    /// directory is used.
+    pub fn export_from_daemon(
+        backend: &dyn DockerBackend,
+        image_name: &str,
+        temp_dir: Option<&Path>,
+        options: &LoadOptions,
+    ) -> Result<Self> {
+        info!("Streaming {} from the Docker daemon", image_name);
+        let (reader, mut writer) = std::io::pipe()?;
+
+        std::thread::scope(|scope| {
+            let extraction = scope.spawn(move || Self::from_reader_with_options(reader, temp_dir, options));
+            let exported = backend.export(image_name, &mut writer);
+            drop(writer);
+            let image = extraction
+                .join()
+                .map_err(|_| SquashError::DockerError(format!("Extracting {} panicked", image_name)))?;
+            Self::first_stream_error(exported, image)
+        })
+    }
+
+    /// Result of a transfer through a pipe given the results of both of its ends
+    ///
+    /// An end that stops early leaves the other one with a broken pipe or a
+    /// truncated archive, so a broken pipe is only reported when nothing else
+    /// went wrong, and `writer`'s own failure comes before `reader`'s.
+    fn first_stream_error<T>(writer: Result<()>, reader: Result<T>) -> Result<T> {
+        let is_broken_pipe = |e: &SquashError| {
+            matches!(e, SquashError::IoError(io) if io.kind() == std::io::ErrorKind::BrokenPipe)
+        };
+        match (writer, reader) {
+            (Ok(()), reader) => reader,
+            (Err(e), Err(reader_error)) if is_broken_pipe(&e) => Err(reader_error),
+            (Err(e), _) => Err(e),
+        }
+    }
+
+    /// Parse manifest and config from an extracted Docker image tar
+    fn parse_image(
+        extractor: TarExtractor,
+        options: &LoadOptions,
+    ) -> Result<(DockerManifest, DockerConfig, String, Vec<LayerInfo>, ImageFormat, TempDir)> {
+        // Classic Docker archives carry manifest.json, OCI layouts carry index.json;
+        // newer `docker save` output has both, so an explicit format picks one
+        let format = match options.source_format {
+            Some(SourceFormat::DockerArchive) => ImageFormat::DockerArchive,
+            Some(SourceFormat::OciArchive) => ImageFormat::OciArchive,
+            _ if extractor.file_exists("manifest.json") => ImageFormat::DockerArchive,
+            _ if extractor.file_exists(OCI_INDEX_FILE) => ImageFormat::OciArchive,
+            _ => {
+                return Err(SquashError::InvalidInput(
+                    "Neither manifest.json nor index.json found in Docker image".to_string()
+                ))
+            }
+        };
+        let (layout, layout_file) = match format {
+            ImageFormat::DockerArchive => ("a Docker", "manifest.json"),
+            ImageFormat::OciArchive => ("an OCI", OCI_INDEX_FILE),
+        };
+        if !extractor.file_exists(layout_file) {
+            return Err(SquashError::InvalidInput(format!(
+                "Source is not {} archive: {} not found",
+                layout, layout_file
+            )));
+        }
+        let mut manifest = match format {
+            ImageFormat::DockerArchive => Self::parse_docker_manifest(&extractor, options)?,
+            ImageFormat::OciArchive => Self::parse_oci_layout(&extractor, options)?,
+        };
+
+        // Read and parse the config file
+        Self::ensure_archive_file(&extractor, "Config", &manifest.config)?;
+        let config: DockerConfig = Self::read_archive_json(&extractor, &manifest.config)?;
+        let source_digest = format!("sha256:{:x}", Sha256::digest(extractor.read_file(&manifest.config)?));
+
+        // Create layer info from manifest layers
+        let mut layers = Vec::new();
+        let mut downloaded = Vec::new();
+        for (i, layer_path) in manifest.layers.iter().enumerate() {
+            let layer_tar_path = extractor.get_file_path(layer_path);
+
+            // Use diff_id from config if available, otherwise generate from layer path
+            let digest = if i < config.rootfs.diff_ids.len() {
+                config.rootfs.diff_ids[i].clone()
+            } else {
+                format!("sha256:{}", layer_path.replace(".tar", "").replace("/", ""))
+            };
+
+            let foreign = manifest.layer_sources.as_ref().and_then(|sources| sources.get(&digest));
+            if let Some(source) = foreign {
+                // Checked before anything is downloaded
+                if format == ImageFormat::DockerArchive {
+                    options.check_layer_media_type(&digest, &source.media_type)?;
+                }
+                match options.foreign_layers {
+                    ForeignLayerPolicy::Preserve => {
+                        info!("Preserving foreign layer: {}", digest);
+                    }
+                    ForeignLayerPolicy::Fail => {
+                        return Err(SquashError::InvalidInput(format!(
+                            "Image contains foreign layer {} (urls: {})",
+                            digest,
+                            source.urls.as_deref().unwrap_or_default().join(", ")
+                        )));
+                    }
+                    ForeignLayerPolicy::Download => {
+                        download_foreign_layer(source, &layer_tar_path)?;
+                        downloaded.push(digest.clone());
+                    }
+                }
+            }
+
+            Self::ensure_archive_file(&extractor, "Layer", layer_path)?;
+
+            // Only plain layer tars are read in place; compressed ones are extracted after all
+            let section = match extractor.section(layer_path) {
+                Some(section) if !section.is_compressed()? => Some(section.clone()),
+                Some(_) => {
+                    extractor.materialize(layer_path)?;
+                    None
+                }
+                None => None,
+            };
+
+            // OCI layouts are checked against their descriptors while parsing
+            let mut compression = StreamCompression::None;
+            if section.is_some() {
+                if format == ImageFormat::DockerArchive {
+                    options.check_layer_media_type(&digest, MEDIA_TYPE_DOCKER_LAYER)?;
+                }
+            } else if format == ImageFormat::DockerArchive && foreign.is_none() {
+                compression = StreamCompression::of_file(&layer_tar_path)?;
+                let media_type = match compression {
+                    StreamCompression::Zstd => MEDIA_TYPE_OCI_LAYER_ZSTD,
+                    StreamCompression::Gzip => MEDIA_TYPE_DOCKER_LAYER_GZIP,
+                    // docker load accepts bzip2 and xz layers, which have no media type
+                    _ => MEDIA_TYPE_DOCKER_LAYER,
+                };
+                options.check_layer_media_type(&digest, media_type)?;
+            }
+
+            // Archives written with gzip layers (including our own), saved from the
+            // containerd image store with zstd layers, or hand-built with bzip2 or xz
+            // layers are merged from a decompressed copy, like OCI blobs
+            let layer_tar_path = match compression {
+                StreamCompression::Gzip | StreamCompression::Bzip2 | StreamCompression::Xz | StreamCompression::Zstd => {
+                    debug!("Layer {} is {} compressed", layer_path, compression);
+                    let decompressed = extractor.get_file_path(&format!("{}.tar", layer_path));
+                    let mut decoder = compression.decoder(File::open(&layer_tar_path)?)?;
+                    std::io::copy(&mut decoder, &mut File::create(&decompressed)?)?;
+                    decompressed
+                }
+                _ => layer_tar_path,
+            };
+
+            let layer = match section {
+                Some(section) => LayerInfo {
+                    digest,
+                    size: section.size,
+                    tar_path: section.archive,
+                    data: None,
+                    archive_offset: Some(section.offset),
+                },
+                None => LayerInfo {
+                    digest,
+                    size: std::fs::metadata(&layer_tar_path)?.len(),
+                    tar_path: layer_tar_path,
+                    data: None,
+                    archive_offset: None,
+                },
+            };
+
+            // diff_ids are digests of the uncompressed layer tar
+            if options.verify && i < config.rootfs.diff_ids.len() {
+                let actual = layer.diff_id()?;
+                if actual != layer.digest {
+                    return Err(SquashError::DigestMismatch {
+                        layer: layer_path.clone(),
+                        expected: layer.digest,
+                        actual,
+                    });
+                }
+            }
+
+            layers.push(layer);
+        }
+
+        // Downloaded layers are regular layers from now on
+        if let Some(sources) = manifest.layer_sources.as_mut() {
+            sources.retain(|diff_id, _| !downloaded.contains(diff_id));
+            if sources.is_empty() {
+                manifest.layer_sources = None;
+            }
+        }
+
+        debug!("Parsed {} layers from Docker image", layers.len());
+        debug!("Config has {} diff_ids", config.rootfs.diff_ids.len());
+        debug!("Config has {} history entries", config.history.len());
+
+        // Count non-empty history entries
+        let non_empty_history_count = config.history.iter()
+            .filter(|h| h.empty_layer != Some(true))
+            .count();
+        debug!("Config has {} non-empty history entries", non_empty_history_count);
+
+        // Debug: show all history entries
+        trace!("=== History entries ===");
+        for (i, entry) in config.history.iter().enumerate() {
+            let empty_status = if entry.empty_layer == Some(true) { " (EMPTY)" } else { "" };
+            trace!("  {}: {}{}", i + 1, entry.created_by.chars().take(60).collect::<String>(), empty_status);
+        }
+        trace!("=== End history entries ===");
+
+        Ok((manifest, config, source_digest, layers, format, extractor.temp_dir))
+    }
+
+    /// Read and parse a JSON file of the extracted archive, naming it on failure
+    fn read_archive_json<T: DeserializeOwned>(extractor: &TarExtractor, path: &str) -> Result<T> {
+        serde_json::from_str(&extractor.read_file(path)?).map_err(|source| SquashError::ManifestParse {
+            path: path.to_string(),
+            source,
+        })
+    }
+
+    /// Fail with a clear message when a file named by the manifest is missing
+    fn ensure_archive_file(extractor: &TarExtractor, kind: &str, path: &str) -> Result<()> {
+        if extractor.file_exists(path) {
+            return Ok(());
+        }
+
+        Err(SquashError::InvalidInput(format!(
+            "{} file {} referenced by the manifest is missing from the archive; the archive may be truncated or corrupt",
+            kind, path
+        )))
+    }
+
+    /// Read the selected image manifest from a classic Docker archive
+    ///
+    /// Archives holding several images are narrowed down with the image
+    /// reference and platform from the load options.
+    fn parse_docker_manifest(extractor: &TarExtractor, options: &LoadOptions) -> Result<DockerManifest> {
+        let manifests: Vec<DockerManifest> = Self::read_archive_json(extractor, "manifest.json")?;
+
+        if manifests.is_empty() {
+            return Err(SquashError::InvalidInput(
+                "No manifests found in manifest.json".to_string()
+            ));
+        }
+
+        let candidates = manifests
+            .into_iter()
+            .map(|manifest| {
+                let platform = extractor
+                    .read_file(&manifest.config)
+                    .ok()
+                    .and_then(|content| serde_json::from_str::<DockerConfig>(&content).ok())
+                    .map(|config| config.platform());
+
+                ImageCandidate {
+                    tags: manifest.repo_tags.clone().unwrap_or_default(),
+                    platform,
+                    item: manifest,
+                }
+            })
+            .collect();
+
+        Self::select_candidate(candidates, options)
+    }
+
+    /// Narrow several candidate images down to exactly one
+    ///
+    /// A single candidate is always used as-is. Otherwise the image
+    /// reference and then the platform must leave a single match.
+    fn select_candidate<T>(mut candidates: Vec<ImageCandidate<T>>, options: &LoadOptions) -> Result<T> {
+        if let Some(image_ref) = options.image_ref.as_deref() {
+            let available = Self::describe_tags(&candidates);
+            candidates.retain(|candidate| candidate.tags.iter().any(|tag| tag == image_ref));
+
+            if candidates.is_empty() {
+                return Err(SquashError::InvalidInput(format!(
+                    "Image {} not found in archive. Available tags: {}",
+                    image_ref, available
+                )));
+            }
+        }
+
+        if candidates.len() > 1 {
+            let available = Self::describe_platforms(&candidates);
+
+            match &options.platform {
+                Some(platform) => {
+                    candidates.retain(|candidate| {
+                        candidate.platform.as_ref().is_some_and(|p| platform.matches(p))
+                    });
+
+                    if candidates.is_empty() {
+                        return Err(SquashError::InvalidInput(format!(
+                            "No image for platform {} in archive. Available platforms: {}",
+                            platform, available.join(", ")
+                        )));
+                    }
+                }
+                None if available.len() > 1 => {
+                    return Err(SquashError::InvalidInput(format!(
+                        "Archive contains images for multiple platforms, select one with --platform. Available platforms: {}",
+                        available.join(", ")
+                    )));
+                }
+                None => {}
+            }
+        }
+
+        if candidates.len() > 1 {
+            return Err(SquashError::InvalidInput(format!(
+                "Archive contains {} images, select one with --image-ref. Available tags: {}",
+                candidates.len(),
+                Self::describe_tags(&candidates)
+            )));
+        }
+
+        Ok(candidates.remove(0).item)
+    }
+
+    /// List every tag among the candidates for error messages
+    fn describe_tags<T>(candidates: &[ImageCandidate<T>]) -> String {
+        candidates
+            .iter()
+            .map(|candidate| {
+                if candidate.tags.is_empty() {
+                    "<untagged>".to_string()
+                } else {
+                    candidate.tags.join(", ")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Distinct platforms among the candidates, in archive order
+    fn describe_platforms<T>(candidates: &[ImageCandidate<T>]) -> Vec<String> {
+        let mut platforms: Vec<String> = Vec::new();
+        for platform in candidates.iter().filter_map(|candidate| candidate.platform.as_ref()) {
+            let platform = platform.to_string();
+            if !platforms.contains(&platform) {
+                platforms.push(platform);
+            }
+        }
+        platforms
+    }
+
+    /// Resolve an OCI image layout into the equivalent Docker manifest
+    ///
+    /// Config and layer paths point into the `blobs/` store. Compressed
+    /// layer blobs are decompressed next to the original blob so the merger
+    /// can read them as plain tar files.
+    fn parse_oci_layout(extractor: &TarExtractor, options: &LoadOptions) -> Result<DockerManifest> {
+        debug!("Detected OCI image layout");
+
+        let index: OciIndex = Self::read_archive_json(extractor, OCI_INDEX_FILE)?;
+
+        // The reference name lives on the top-level descriptor
+        let mut siblings = index.manifests.clone();
+        let mut descriptor = Self::select_oci_descriptor(extractor, index, options)?;
+        let repo_tags = descriptor.ref_name().map(|name| vec![name.to_string()]);
+
+        // Follow nested indexes until an image manifest is reached
+        let nested_options = LoadOptions {
+            image_ref: None,
+            ..options.clone()
+        };
+        while descriptor.is_index() {
+            let nested_index: OciIndex = Self::read_archive_json(extractor, &descriptor.digest)?;
+            siblings = nested_index.manifests.clone();
+            descriptor = Self::select_oci_descriptor(extractor, nested_index, &nested_options)?;
+        }
+
+        let attestations = Self::find_attestations(extractor, &siblings, &descriptor.digest)?;
+
+        let oci_manifest: OciManifest = Self::read_archive_json(extractor, &descriptor.digest)?;
+
+        let config = Self::oci_blob_path(&oci_manifest.config.digest)?;
+
+        // Foreign layers are keyed by diff_id like Docker's LayerSources
+        let mut layer_sources = BTreeMap::new();
+        if oci_manifest.layers.iter().any(OciDescriptor::is_foreign) {
+            Self::ensure_archive_file(extractor, "Config", &config)?;
+            let image_config: DockerConfig = Self::read_archive_json(extractor, &config)?;
+            for (layer, diff_id) in oci_manifest.layers.iter().zip(&image_config.rootfs.diff_ids) {
+                if layer.is_foreign() {
+                    layer_sources.insert(diff_id.clone(), layer.clone());
+                }
+            }
+        }
+
+        let mut layers = Vec::new();
+        for layer in &oci_manifest.layers {
+            options.check_layer_media_type(&layer.digest, &layer.media_type)?;
+            let blob = Self::oci_blob_path(&layer.digest)?;
+
+            if layer.is_foreign() && !extractor.file_exists(&blob) {
+                // Not exported; downloaded later if requested
+                layers.push(if layer.is_gzip() { format!("{}.tar", blob) } else { blob });
+            } else if layer.is_gzip() {
+                let decompressed = format!("{}.tar", blob);
+                let mut decoder = GzDecoder::new(extractor.open_file(&blob)?);
+                let mut output = File::create(extractor.get_file_path(&decompressed))?;
+                std::io::copy(&mut decoder, &mut output)?;
+                layers.push(decompressed);
+            } else if layer.is_zstd() {
+                let decompressed = format!("{}.tar", blob);
+                let output = File::create(extractor.get_file_path(&decompressed))?;
+                Zstd::new().decompress(extractor.open_file(&blob)?, &output)?;
+                layers.push(decompressed);
+            } else {
+                layers.push(blob);
+            }
+        }
+
+        Ok(DockerManifest {
+            config,
+            repo_tags,
+            layers,
+            layer_sources: (!layer_sources.is_empty()).then_some(layer_sources),
+            attestations,
+        })
+    }
+
+    /// Attestation manifests in an index level that describe the manifest `digest`
+    fn find_attestations(extractor: &TarExtractor, descriptors: &[OciDescriptor], digest: &str) -> Result<Vec<Attestation>> {
+        let mut attestations = Vec::new();
+
+        for descriptor in descriptors {
+            if descriptor.digest == digest || descriptor.is_index() || !extractor.file_exists(&descriptor.digest) {
+                continue;
+            }
+
+            let manifest: serde_json::Value = Self::read_archive_json(extractor, &descriptor.digest)?;
+            if !Attestation::references(descriptor, &manifest, digest) {
+                continue;
+            }
+
+            let blobs = std::iter::once(&manifest["config"])
+                .chain(manifest["layers"].as_array().into_iter().flatten())
+                .filter_map(|blob| blob["digest"].as_str())
+                .filter_map(|blob_digest| {
+                    let path = extractor.file_exists(blob_digest).then(|| extractor.materialize(blob_digest))?;
+                    Some(path.map(|path| (blob_digest.to_string(), path)))
+                })
+                .collect::<Result<_>>()?;
+
+            let attestation = Attestation {
+                descriptor: descriptor.clone(),
+                manifest,
+                blobs,
+            };
+            info!("Found attestation {} ({})", descriptor.digest, attestation.predicate_types().join(", "));
+            attestations.push(attestation);
+        }
+
+        Ok(attestations)
+    }
+
+    /// Pick one descriptor from an OCI index level
+    ///
+    /// Descriptors whose blobs were not exported and attestation manifests
+    /// are skipped before selecting by reference name and platform.
+    fn select_oci_descriptor(extractor: &TarExtractor, index: OciIndex, options: &LoadOptions) -> Result<OciDescriptor> {
+        let candidates: Vec<_> = index
+            .manifests
+            .into_iter()
+            .filter(|d| extractor.file_exists(&d.digest))
+            .filter(|d| !d.platform.as_ref().is_some_and(Platform::is_unknown))
+            .filter(|d| !d.annotations.as_ref().is_some_and(|a| a.contains_key(ANNOTATION_REFERENCE_DIGEST)))
+            .map(|descriptor| ImageCandidate {
+                tags: descriptor.ref_name().map(str::to_string).into_iter().collect(),
+                platform: Self::oci_descriptor_platform(extractor, &descriptor),
+                item: descriptor,
+            })
+            .collect();
+
+        if candidates.is_empty() {
+            return Err(SquashError::InvalidInput("No manifests found in OCI index".to_string()));
+        }
+
+        Self::select_candidate(candidates, options)
+    }
+
+    /// Platform of an OCI descriptor, read from its config when not declared
+    fn oci_descriptor_platform(extractor: &TarExtractor, descriptor: &OciDescriptor) -> Option<Platform> {
+        if descriptor.platform.is_some() || descriptor.is_index() {
+            return descriptor.platform.clone();
+        }
+
+        let manifest: OciManifest = serde_json::from_str(&extractor.read_file(&descriptor.digest).ok()?).ok()?;
+        let config: DockerConfig = serde_json::from_str(&extractor.read_file(&manifest.config.digest).ok()?).ok()?;
+        Some(config.platform())
+    }
+
+    /// Map a digest to its blob path, rejecting malformed digests
+    fn oci_blob_path(digest: &str) -> Result<String> {
+        blob_path(digest).ok_or_else(|| {
+            SquashError::InvalidInput(format!("Invalid digest in OCI manifest: {}", digest))
+        })
+    }
+
+    /// Squash layers according to the specification
+    ///
+    /// A number merges that many of the latest layers; anything else is a
+    /// layer ID prefix to merge from through the latest layer. `to:<id>`
+    /// (or `last:<id>`) merges the top layers down to and including `<id>`;
+    /// layers below `<id>` are never touched.
+    pub fn squash_layers(&mut self, layer_spec: &str) -> Result<()> {
+        self.squash_layers_with_observer(layer_spec, &mut NoopObserver)
+    }
+
+    /// Squash layers according to the specification, reporting progress to `observer`
+    pub fn squash_layers_with_observer(
+        &mut self,
+        layer_spec: &str,
+        observer: &mut dyn ProgressObserver,
+    ) -> Result<()> {
+        let (start, end) = self.resolve_layer_spec(layer_spec)?;
+        self.squash_range_with_observer(start, end, observer)
+    }
+
+    /// Describe what `squash_layers(layer_spec)` would do without merging
+    pub fn plan_squash(&self, layer_spec: &str) -> Result<SquashPlan> {
+        let (start, end) = self.resolve_layer_spec(layer_spec)?;
+        self.plan_range(start, end)
+    }
+
+    /// Describe what `squash_layer_range(from, to)` would do without merging
+    pub fn plan_layer_range(&self, from: Option<&str>, to: Option<&str>) -> Result<SquashPlan> {
+        let (start, end) = self.resolve_layer_range(from, to)?;
+        self.plan_range(start, end)
+    }
+
+    /// Break down layer sizes and estimate what merging the top layers would save
+    ///
+    /// Reads the layers but changes nothing. Merges that would include a
+    /// foreign layer are left out of the savings.
+    pub fn size_report(&self) -> Result<SizeReport> {
+        let owners = self.layer_history_indices();
+        let layers: Vec<LayerSizeEntry> = self.layers
+            .iter()
+            .zip(owners)
+            .enumerate()
+            .map(|(index, (layer, owner))| LayerSizeEntry {
+                index,
+                digest: layer.digest.clone(),
+                created_by: owner.map(|owner| self.config.history[owner].layer_command()),
+                size: layer.size,
+            })
+            .collect();
+
+        // Only layers above the newest foreign layer can be merged from the top
+        let mergeable_from = match &self.manifest.layer_sources {
+            Some(sources) => self.layers
+                .iter()
+                .rposition(|layer| sources.contains_key(&layer.digest))
+                .map_or(0, |foreign| foreign + 1),
+            None => 0,
+        };
+        let mergeable = &self.layers[mergeable_from..];
+        let shadowed = shadowed_bytes(mergeable)?;
+
+        let mut tail_savings = Vec::new();
+        let (mut merged_size, mut savings) = (0, 0);
+        for (count, (layer, shadowed)) in mergeable.iter().zip(shadowed).rev().enumerate() {
+            merged_size += layer.size;
+            savings += shadowed;
+            if count > 0 {
+                tail_savings.push(TailSavings { layers: count + 1, merged_size, savings });
+            }
+        }
+
+        Ok(SizeReport {
+            total_size: self.layers.iter().map(|layer| layer.size).sum(),
+            layers,
+            tail_savings,
+        })
+    }
+
+    /// List the files that a later layer deletes or overwrites
+    ///
+    /// Their contents stay in the image until the layers from the one holding
+    /// them to the one hiding them are squashed. Reads the layers but changes
+    /// nothing.
+    pub fn waste_report(&self) -> Result<WasteReport> {
+        let mut files = wasted_files(&self.layers)?;
+        files.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+
+        Ok(WasteReport {
+            reclaimable: files.iter().map(|file| file.size).sum(),
+            total_size: self.layers.iter().map(|layer| layer.size).sum(),
+            files,
+        })
+    }
+
+    /// Describe what `squash_range(start, end)` would do without merging
+    pub fn plan_range(&self, start: usize, end: usize) -> Result<SquashPlan> {
+        if start > end || end >= self.layers.len() {
+            return Err(SquashError::InvalidInput(format!(
+                "Invalid layer range {}..={}, image has {} layers",
+                start,
+                end,
+                self.layers.len()
+            )));
+        }
+
+        let runs = self.unpreserved_runs(start, end)?;
+        let (mut merged_size, mut merged_layers, mut merged_history) = (0, 0, 0);
+        for &(run_start, run_end) in &runs {
+            self.ensure_no_foreign_layers(&self.layers[run_start..=run_end])?;
+            merged_size += self.layers[run_start..=run_end].iter().map(|layer| layer.size).sum::<u64>();
+            merged_layers += run_end - run_start;
+            merged_history += self.merged_history_indices(run_start, run_end).len();
+        }
+        let history_len = self.config.history.len();
+
+        Ok(SquashPlan {
+            start,
+            end,
+            layers: self.layers[start..=end].to_vec(),
+            merged_size,
+            preserved: (start..=end).filter(|&index| self.is_preserved(index)).collect(),
+            layer_count: (self.layers.len(), self.layers.len() - merged_layers),
+            history_count: (history_len, history_len + runs.len() - merged_history),
+        })
+    }
+
+    /// Keep the layers given by `layer_refs` out of every later merge
+    ///
+    /// References are layer indexes or ID prefixes as in `squash_layer_range`.
+    /// A merge whose range includes a preserved layer is split around it into
+    /// one merge per run of two or more layers left on either side.
+    pub fn preserve_layers(&mut self, layer_refs: &[String]) -> Result<()> {
+        let merger = self.merger()?;
+        let indexes = layer_refs
+            .iter()
+            .map(|layer_ref| merger.resolve_layer_ref(layer_ref))
+            .collect::<Result<Vec<_>>>()?;
+        for index in indexes {
+            debug!("Preserving layer {} ({})", index, self.layers[index].digest);
+            self.preserved_layers.insert(index);
+        }
+        Ok(())
+    }
+
+    /// Whether the layer at `index` is preserved
+    fn is_preserved(&self, index: usize) -> bool {
+        self.preserved_layers.contains(&index)
+    }
+
+    /// The parts of `start..=end` to merge when preserved layers are left out
+    ///
+    /// Without a preserved layer in the range this is the range itself.
+    /// Otherwise it is every run of two or more layers between preserved ones;
+    /// a single layer has nothing to be merged with and is left intact. Fails
+    /// when no such run remains.
+    fn unpreserved_runs(&self, start: usize, end: usize) -> Result<Vec<(usize, usize)>> {
+        let preserved: Vec<usize> = (start..=end.min(self.layers.len().saturating_sub(1)))
+            .filter(|&index| self.is_preserved(index))
+            .collect();
+        if preserved.is_empty() {
+            return Ok(vec![(start, end)]);
+        }
+
+        let mut runs = Vec::new();
+        let mut run_start = start;
+        for &index in preserved.iter().chain([end + 1].iter()) {
+            if index > run_start + 1 {
+                runs.push((run_start, index - 1));
+            }
+            run_start = index + 1;
+        }
+
+        if runs.is_empty() {
+            return Err(SquashError::InvalidInput(format!(
+                "Preserving layer(s) {} leaves no two adjacent layers to merge in layers {} to {}",
+                preserved.iter().map(|index| index.to_string()).collect::<Vec<_>>().join(", "),
+                start,
+                end
+            )));
+        }
+        Ok(runs)
+    }
+
+    /// Resolve a `squash_layers` specification into an inclusive layer range
+    fn resolve_layer_spec(&self, layer_spec: &str) -> Result<(usize, usize)> {
+        if self.layers.is_empty() {
+            return Err(SquashError::InvalidInput("No layers to merge".to_string()));
+        }
+
+        let last = self.layers.len() - 1;
+
+        // Explicit indexes of the layers to merge
+        if layer_spec.contains(',') {
+            return parse_layer_indexes(layer_spec, self.layers.len());
+        }
+
+        // Merge latest n layers, but never below the named layer
+        if let Some((count, stop_id)) = layer_spec.split_once(":to:") {
+            if let Ok(count) = count.parse::<usize>() {
+                let start = self.latest_layers_start(count)?;
+                let stop = self.merger()?.find_layer_index(stop_id)?;
+                if start < stop {
+                    return Err(SquashError::InvalidInput(format!(
+                        "Merging the latest {} layers would pass layer {} ({}), which bounds the merge at {} layers",
+                        count,
+                        stop,
+                        stop_id,
+                        self.layers.len() - stop
+                    )));
+                }
+                return Ok((start, last));
+            }
+        }
+
+        // Parse layer specification into an inclusive range of layers
+        let start = if let Ok(count) = layer_spec.parse::<usize>() {
+            // Merge latest n layers
+            self.latest_layers_start(count)?
+        } else if let Some(stop_id) = layer_spec.strip_prefix("to:").or_else(|| layer_spec.strip_prefix("last:")) {
+            // Merge the top layers down to the named layer, inclusive
+            self.merger()?.find_layer_index(stop_id)?
+        } else {
+            // Find layer by ID and merge from that layer to latest
+            self.merger()?.find_layer_index(layer_spec)?
+        };
+
+        Ok((start, last))
+    }
+
+    /// Index of the oldest of the latest `count` layers
+    fn latest_layers_start(&self, count: usize) -> Result<usize> {
+        if count == 0 {
+            return Err(SquashError::InvalidInput("Cannot merge 0 layers".to_string()));
+        }
+        if count > self.layers.len() {
+            return Err(SquashError::InvalidInput(format!(
+                "Cannot merge {} layers, image only has {} layers",
+                count,
+                self.layers.len()
+            )));
+        }
+        Ok(self.layers.len() - count)
+    }
+
+    /// Squash the layers between two layer references, inclusive
+    ///
+    /// References are layer indexes (0 is the base layer) or layer ID
+    /// prefixes. A missing `from` starts at the base layer and a missing
+    /// `to` ends at the latest layer.
+    pub fn squash_layer_range(&mut self, from: Option<&str>, to: Option<&str>) -> Result<()> {
+        self.squash_layer_range_with_observer(from, to, &mut NoopObserver)
+    }
+
+    /// Squash the layers between two layer references, reporting progress to `observer`
+    pub fn squash_layer_range_with_observer(
+        &mut self,
+        from: Option<&str>,
+        to: Option<&str>,
+        observer: &mut dyn ProgressObserver,
+    ) -> Result<()> {
+        let (start, end) = self.resolve_layer_range(from, to)?;
+        self.squash_range_with_observer(start, end, observer)
+    }
+
+    /// Resolve optional `from`/`to` layer references into an inclusive layer range
+    fn resolve_layer_range(&self, from: Option<&str>, to: Option<&str>) -> Result<(usize, usize)> {
+        if self.layers.is_empty() {
+            return Err(SquashError::InvalidInput("No layers to merge".to_string()));
+        }
+
+        let merger = self.merger()?;
+        let start = from.map(|r| merger.resolve_layer_ref(r)).transpose()?.unwrap_or(0);
+        let end = to.map(|r| merger.resolve_layer_ref(r)).transpose()?.unwrap_or(self.layers.len() - 1);
+
+        Ok((start, end))
+    }
+
+    /// Index of the layer whose history `created_by` contains `substring`
+    ///
+    /// Only history entries that created a layer are searched. Fails when no
+    /// entry matches or when several do, listing the matching commands so the
+    /// substring can be narrowed down.
+    pub fn find_layer_by_command(&self, substring: &str) -> Result<usize> {
+        let matches: Vec<(usize, &str)> = self
+            .layer_history_indices()
+            .into_iter()
+            .enumerate()
+            .filter_map(|(layer, entry)| Some((layer, self.config.history[entry?].created_by.as_str())))
+            .filter(|(_, created_by)| created_by.contains(substring))
+            .collect();
+
+        match matches.as_slice() {
+            [] => Err(SquashError::InvalidInput(format!(
+                "No layer was created by a command containing '{}'",
+                substring
+            ))),
+            [(layer, _)] => Ok(*layer),
+            _ => Err(SquashError::InvalidInput(format!(
+                "Command '{}' matches {} layers: {}",
+                substring,
+                matches.len(),
+                matches
+                    .iter()
+                    .map(|(layer, created_by)| format!("{} ({})", layer, created_by))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))),
+        }
+    }
+
+    /// Merge layers `start..=end` and splice the result into the image
+    ///
+    /// Layers, manifest entries, diff_ids and history before and after the
+    /// range are left intact.
+    pub fn squash_range(&mut self, start: usize, end: usize) -> Result<()> {
+        self.squash_range_with_observer(start, end, &mut NoopObserver)
+    }
+
+    /// Merge layers `start..=end`, reporting progress to `observer`
+    pub fn squash_range_with_observer(
+        &mut self,
+        start: usize,
+        end: usize,
+        observer: &mut dyn ProgressObserver,
+    ) -> Result<()> {
+        let runs = self.unpreserved_runs(start, end)?;
+        if runs.len() > 1 || runs[0] != (start, end) {
+            info!("Merging layers {} to {} as {} parts around preserved layers", start, end, runs.len());
+        }
+
+        // Merge from the top so the indexes of lower runs stay valid
+        for &(run_start, run_end) in runs.iter().rev() {
+            self.ensure_no_foreign_layers(self.layers.get(run_start..=run_end).unwrap_or_default())?;
+            let (merged_layer, stats) = self.merger()?.merge_range_with_observer(run_start, run_end, observer)?;
+            self.apply_merge(run_start, run_end, merged_layer, stats)?;
+        }
+        Ok(())
+    }
+
+    /// Squash every layer into a single layer
+    pub fn squash_all(&mut self) -> Result<()> {
+        self.squash_all_with_observer(&mut NoopObserver)
+    }
+
+    /// Squash every layer into a single layer, reporting progress to `observer`
+    pub fn squash_all_with_observer(&mut self, observer: &mut dyn ProgressObserver) -> Result<()> {
+        if self.layers.is_empty() {
+            return Err(SquashError::InvalidInput("No layers to merge".to_string()));
+        }
+
+        self.squash_range_with_observer(0, self.layers.len() - 1, observer)
+    }
+
+    /// Squash the first `count` layers, keeping the layers above them intact
+    pub fn squash_earliest_layers(&mut self, count: usize) -> Result<()> {
+        self.squash_earliest_layers_with_observer(count, &mut NoopObserver)
+    }
+
+    /// Squash the first `count` layers, reporting progress to `observer`
+    pub fn squash_earliest_layers_with_observer(
+        &mut self,
+        count: usize,
+        observer: &mut dyn ProgressObserver,
+    ) -> Result<()> {
+        // A preserved layer splits the merge like any other range
+        if (0..count.min(self.layers.len())).any(|index| self.is_preserved(index)) {
+            return self.squash_range_with_observer(0, count - 1, observer);
+        }
+
+        self.ensure_no_foreign_layers(&self.layers[..count.min(self.layers.len())])?;
+        let (merged_layer, stats) = self.merger()?.merge_earliest_layers_with_observer(count, observer)?;
+        self.apply_merge(0, count - 1, merged_layer, stats)
+    }
+
+    /// Splice the merge of layers `start..=end` into the image and record its stats
+    ///
+    /// A merged layer larger than the layers it replaces, e.g. when they were
+    /// stored compressed, is logged; under `fail_on_grow` it is rejected with
+    /// [`SquashError::LayerGrew`] and the image is left unchanged.
+    fn apply_merge(&mut self, start: usize, end: usize, merged_layer: LayerInfo, stats: LayerMergeStats) -> Result<()> {
+        if stats.merged_size > stats.input_size {
+            if self.merge_options.fail_on_grow {
+                return Err(SquashError::LayerGrew {
+                    start,
+                    end,
+                    input_size: stats.input_size,
+                    merged_size: stats.merged_size,
+                });
+            }
+            warn!(
+                "Merging layers {} to {} grew them by {} bytes ({} -> {} bytes)",
+                start,
+                end,
+                stats.merged_size - stats.input_size,
+                stats.input_size,
+                stats.merged_size
+            );
+        }
+
+        self.splice_merged_layer(start, end, merged_layer)?;
+        self.merge_stats.push(stats);
+        Ok(())
+    }
+
+    /// Squash every run of adjacent layers built within `window` seconds of each other
+    ///
+    /// Layers are grouped by the `created` time of their history entries; a
+    /// gap longer than the window, or a layer without a usable timestamp,
+    /// ends a group. Layers that do not share a group with a neighbour are
+    /// left intact.
+    pub fn squash_merge_window(&mut self, window: u64) -> Result<()> {
+        self.squash_merge_window_with_observer(window, &mut NoopObserver)
+    }
+
+    /// Squash layers grouped by build time, reporting progress to `observer`
+    pub fn squash_merge_window_with_observer(&mut self, window: u64, observer: &mut dyn ProgressObserver) -> Result<()> {
+        let groups = self.merge_window_groups(window);
+        if groups.is_empty() {
+            info!("No layers were created within {} seconds of each other", window);
+        }
+
+        // Merge from the top so the indexes of lower groups stay valid
+        for &(start, end) in groups.iter().rev() {
+            debug!("Squashing layers {}..={} built within {} seconds", start, end, window);
+            self.squash_range_with_observer(start, end, observer)?;
+        }
+        Ok(())
+    }
+
+    /// Describe what `squash_merge_window(window)` would do, one plan per group
+    pub fn plan_merge_window(&self, window: u64) -> Result<Vec<SquashPlan>> {
+        self.merge_window_groups(window)
+            .into_iter()
+            .map(|(start, end)| self.plan_range(start, end))
+            .collect()
+    }
+
+    /// Squash the trailing run of layers that are each smaller than `max_size` bytes
+    ///
+    /// Selection walks down from the latest layer and stops at the first
+    /// layer of `max_size` bytes or more, which is kept along with everything
+    /// below it. Nothing is merged unless at least two layers qualify.
+    pub fn squash_layers_below_size(&mut self, max_size: u64) -> Result<()> {
+        self.squash_layers_below_size_with_observer(max_size, &mut NoopObserver)
+    }
+
+    /// Squash the trailing run of small layers, reporting progress to `observer`
+    pub fn squash_layers_below_size_with_observer(&mut self, max_size: u64, observer: &mut dyn ProgressObserver) -> Result<()> {
+        match self.trailing_layers_below_size(max_size) {
+            Some((start, end)) => self.squash_range_with_observer(start, end, observer),
+            None => {
+                info!("Fewer than two trailing layers are smaller than {} bytes, nothing to merge", max_size);
+                Ok(())
+            }
+        }
+    }
+
+    /// Describe what `squash_layers_below_size(max_size)` would do, if anything
+    pub fn plan_layers_below_size(&self, max_size: u64) -> Result<Option<SquashPlan>> {
+        self.trailing_layers_below_size(max_size)
+            .map(|(start, end)| self.plan_range(start, end))
+            .transpose()
+    }
+
+    /// Squash as many of the latest layers as fit within `budget` bytes combined
+    ///
+    /// Fails when the latest layer alone is over the budget; a budget that
+    /// only fits the latest layer rewrites it as a single merged layer.
+    pub fn squash_up_to_size(&mut self, budget: u64) -> Result<()> {
+        self.squash_up_to_size_with_observer(budget, &mut NoopObserver)
+    }
+
+    /// Squash the latest layers within `budget` bytes, reporting progress to `observer`
+    pub fn squash_up_to_size_with_observer(&mut self, budget: u64, observer: &mut dyn ProgressObserver) -> Result<()> {
+        let count = self.merger()?.trailing_layers_within_size(budget)?;
+        let end = self.layers.len() - 1;
+        self.squash_range_with_observer(end + 1 - count, end, observer)
+    }
+
+    /// Describe what `squash_up_to_size(budget)` would do
+    pub fn plan_up_to_size(&self, budget: u64) -> Result<SquashPlan> {
+        let count = self.merger()?.trailing_layers_within_size(budget)?;
+        let end = self.layers.len() - 1;
+        self.plan_range(end + 1 - count, end)
+    }
+
+    /// Inclusive range of the two or more latest layers each smaller than `max_size` bytes
+    pub fn trailing_layers_below_size(&self, max_size: u64) -> Option<(usize, usize)> {
+        let count = self.layers.iter().rev().take_while(|layer| layer.size < max_size).count();
+        (count >= 2).then(|| (self.layers.len() - count, self.layers.len() - 1))
+    }
+
+    /// Inclusive ranges of two or more adjacent layers built within `window` seconds of each other
+    pub fn merge_window_groups(&self, window: u64) -> Vec<(usize, usize)> {
+        let times = self.layer_created_times();
+        let mut groups = Vec::new();
+        let mut start = 0;
+
+        for layer in 1..=times.len() {
+            let close = match (times[layer - 1], times.get(layer).copied().flatten()) {
+                (Some(previous), Some(current)) => (current - previous).num_seconds().unsigned_abs() <= window,
+                _ => false,
+            };
+            if !close {
+                if layer - 1 > start {
+                    groups.push((start, layer - 1));
+                }
+                start = layer;
+            }
+        }
+        groups
+    }
+
+    /// Creation time of each layer from its history entry, in layer order
+    ///
+    /// History is aligned from the newest layer as in `layer_history_indices`; layers
+    /// without an entry or with an unparsable timestamp map to `None`.
+    pub fn layer_created_times(&self) -> Vec<Option<chrono::DateTime<chrono::FixedOffset>>> {
+        let created: Vec<&str> = self.config
+            .history
+            .iter()
+            .filter(|h| h.empty_layer != Some(true))
+            .map(|h| h.created.as_str())
+            .collect();
+        let offset = created.len() as isize - self.layers.len() as isize;
+
+        (0..self.layers.len())
+            .map(|layer| {
+                usize::try_from(layer as isize + offset)
+                    .ok()
+                    .and_then(|position| created.get(position))
+                    .and_then(|created| chrono::DateTime::parse_from_rfc3339(created).ok())
+            })
+            .collect()
+    }
+
+    /// Refuse to merge preserved foreign layers
+    fn ensure_no_foreign_layers(&self, layers: &[LayerInfo]) -> Result<()> {
+        let Some(sources) = &self.manifest.layer_sources else {
+            return Ok(());
+        };
+
+        match layers.iter().find(|layer| sources.contains_key(&layer.digest)) {
+            Some(layer) => Err(SquashError::InvalidInput(format!(
+                "Cannot merge foreign layer {}, use --download-foreign-layers to squash it",
+                layer.digest
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Index of the history entry that created each layer, in layer order
+    ///
+    /// Walks history forward pairing non-empty entries with layers. Pairing is
+    /// aligned from the newest layer, so when history is short the oldest
+    /// layers map to `None`, and when it is long the oldest entries own no layer.
+    fn layer_history_indices(&self) -> Vec<Option<usize>> {
+        let non_empty = Self::non_empty_history_count(&self.config.history);
+        let skipped = non_empty.saturating_sub(self.layers.len());
+        let mut owners = vec![None; self.layers.len()];
+        let non_empty_entries = self.config.history
+            .iter()
+            .enumerate()
+            .filter(|(_, h)| h.empty_layer != Some(true))
+            .skip(skipped);
+
+        let first_layer = self.layers.len().saturating_sub(non_empty);
+        for (owner, (index, _)) in owners[first_layer..].iter_mut().zip(non_empty_entries) {
+            *owner = Some(index);
+        }
+        owners
+    }
+
+    /// History entries created by layers `start..=end`, in history order
+    ///
+    /// Only the non-empty entries of the merged layers are returned; empty
+    /// entries between or around them describe config changes and stay put.
+    fn merged_history_indices(&self, start: usize, end: usize) -> Vec<usize> {
+        self.layer_history_indices()[start..=end].iter().flatten().copied().collect()
+    }
+
+    /// Replace layers `start..=end` with `merged_layer`, updating the manifest and config
+    fn splice_merged_layer(&mut self, start: usize, end: usize, merged_layer: LayerInfo) -> Result<()> {
+        let layers_to_merge_count = end - start + 1;
+
+        // Update config history to match the new layer structure
+        // Docker expects the number of non-empty history entries to match the number of layers
+        debug!("Before squash: {} layers, {} history entries, {} non-empty history entries",
+                 self.layers.len(),
+                 self.config.history.len(),
+                 Self::non_empty_history_count(&self.config.history));
+
+        // Rewrite a copy, so a rewrite that fails the check below leaves the image untouched
+        let mut history = self.config.history.clone();
+        let merged_indices = self.merged_history_indices(start, end);
+        let mut removed_history = Vec::with_capacity(merged_indices.len());
+        for &index in merged_indices.iter().rev() {
+            removed_history.insert(0, history.remove(index));
+        }
+
+        // The merged entry takes the place of the newest merged layer's entry,
+        // after any empty entries in between; layers without history are the
+        // oldest, so a merge of only those goes first
+        let history_position = merged_indices
+            .last()
+            .map_or(0, |&last| last + 1 - merged_indices.len());
+
+        // Add a new history entry for the merged layer, remembering what it replaced
+        let merged_history_entry = HistoryEntry {
+            created: self.creation_time(),
+            created_by: self.merge_options.history_comment.clone()
+                .unwrap_or_else(|| format!("squash: merged {} layers", layers_to_merge_count)),
+            author: self.merge_options.history_author.clone(),
+            empty_layer: Some(false),
+            merged_from: removed_history.into_iter().map(|h| h.created_by).collect(),
+        };
+        history.insert(history_position, merged_history_entry);
+
+        // docker load rejects an image whose non-empty history entries do not match its layers
+        let layer_count = self.layers.len() - layers_to_merge_count + 1;
+        if Self::non_empty_history_count(&self.config.history) == self.layers.len() {
+            Self::check_history_matches_layers(&history, layer_count).map_err(|e| {
+                SquashError::InvalidInput(format!("Merging layers {} to {}: {}", start, end, e))
+            })?;
+        }
+        self.config.history = history;
+
+        // Replace the merged layers with the new merged layer
+        let merged_digest = merged_layer.digest.clone();
+        self.squashed_layers.insert(merged_digest.clone());
+        self.layers.splice(start..=end, [merged_layer]);
+
+        // Preserved layers above the range move down with the layers around them
+        self.preserved_layers = self.preserved_layers
+            .iter()
+            .filter(|&&index| index < start || index > end)
+            .map(|&index| if index > end { index - (end - start) } else { index })
+            .collect();
+
+        // Update manifest layers
+        let merged_name = self.merged_layer_name(&merged_digest);
+        let manifest_end = end.min(self.manifest.layers.len().saturating_sub(1));
+        self.manifest.layers.splice(start..=manifest_end, [merged_name]);
+
+        // Update config diff_ids
+        let diff_ids = &mut self.config.rootfs.diff_ids;
+        let diff_id_end = (end + 1).min(diff_ids.len());
+        diff_ids.splice(start.min(diff_id_end)..diff_id_end, [merged_digest]);
+
+        debug!("After squash: {} layers, {} history entries, {} non-empty history entries",
+                 self.layers.len(),
+                 self.config.history.len(),
+                 Self::non_empty_history_count(&self.config.history));
+
+        info!("Successfully merged layers. New layer count: {}", self.layers.len());
+
+        Ok(())
+    }
+
+    /// Archive name of a merged layer, keeping the `<id>/layer.tar` layout when the input used it
+    ///
+    /// Names derive from the diff_id, so the layers of repeated merges never
+    /// share an entry in the saved archive.
+    fn merged_layer_name(&self, digest: &str) -> String {
+        let id = digest.trim_start_matches("sha256:");
+        if self.uses_layer_directories() {
+            format!("{}/{}", id, LAYER_TAR_NAME)
+        } else {
+            format!("{}.tar", id)
+        }
+    }
+
+    /// Number of history entries that created a layer
+    fn non_empty_history_count(history: &[HistoryEntry]) -> usize {
+        history.iter().filter(|h| h.empty_layer != Some(true)).count()
+    }
+
+    /// Fail unless `history` has exactly one non-empty entry per layer
+    fn check_history_matches_layers(history: &[HistoryEntry], layer_count: usize) -> std::result::Result<(), String> {
+        let non_empty = Self::non_empty_history_count(history);
+        if non_empty == layer_count {
+            return Ok(());
+        }
+        Err(format!(
+            "history would have {} non-empty entries for {} layers ({} entries in total), which docker load rejects",
+            non_empty,
+            layer_count,
+            history.len()
+        ))
+    }
+
+    /// Whether every layer is stored as `<id>/layer.tar` (Docker v1.x/v2.1 archives)
+    fn uses_layer_directories(&self) -> bool {
+        !self.manifest.layers.is_empty()
+            && self.manifest.layers.iter().all(|name| Self::layer_directory(name).is_some())
+    }
+
+    /// Directory wrapping a `<id>/layer.tar` layer entry
+    fn layer_directory(name: &str) -> Option<&str> {
+        name.strip_suffix(LAYER_TAR_NAME)?
+            .strip_suffix('/')
+            .filter(|dir| !dir.is_empty() && !dir.contains('/'))
+    }
+
+    /// Replace the runtime config (env, cmd, entrypoint, labels, ...) with `source`'s
+    ///
+    /// Layers, diff_ids and history are kept. Both images must target the
+    /// same OS and architecture, and the same variant when both record one.
+    pub fn apply_config_from(&mut self, source: &DockerImage) -> Result<()> {
+        let ours = self.config.platform();
+        let theirs = source.config.platform();
+        let variants_differ = matches!((&ours.variant, &theirs.variant), (Some(a), Some(b)) if a != b);
+
+        if ours.os != theirs.os || ours.architecture != theirs.architecture || variants_differ {
+            return Err(SquashError::InvalidInput(format!(
+                "Cannot combine images for different platforms: filesystem is {}, config is {}",
+                ours, theirs
+            )));
+        }
+
+        self.config.config = source.config.config.clone();
+        Ok(())
+    }
+
+    /// Apply `overrides` to the runtime config written with the image
+    pub fn apply_config_overrides(&mut self, overrides: &ConfigOverrides) {
+        let config = &mut self.config.config;
+        if let Some(cmd) = &overrides.cmd {
+            config.cmd = Some(cmd.clone());
+        }
+        if let Some(entrypoint) = &overrides.entrypoint {
+            config.entrypoint = Some(entrypoint.clone());
+        }
+        if let Some(working_dir) = &overrides.working_dir {
+            config.working_dir = Some(working_dir.clone());
+        }
+        if !overrides.labels.is_empty() {
+            let labels = config.labels.get_or_insert_with(BTreeMap::new);
+            for (key, value) in &overrides.labels {
+                labels.insert(key.clone(), value.clone());
+            }
+        }
+
+        if overrides.env.is_empty() {
+            return;
+        }
+        let env = config.env.get_or_insert_with(Vec::new);
+        for (key, value) in &overrides.env {
+            let entry = format!("{}={}", key, value);
+            let existing = env.iter_mut()
+                .find(|var| var.split_once('=').map_or(var.as_str(), |(name, _)| name) == key);
+            match existing {
+                Some(var) => *var = entry,
+                None => env.push(entry),
+            }
+        }
+    }
+
+    /// Labels describing how this image was squashed from its source
+    ///
+    /// Records the number of source layers merged so far and, when known,
+    /// the source image ID.
+    pub fn provenance_labels(&self) -> Vec<(String, String)> {
+        let merged: usize = self.merge_stats.iter().map(|stats| stats.input_layers).sum();
+        let mut labels = vec![(MERGED_LAYERS_LABEL.to_string(), merged.to_string())];
+        if let Some(digest) = &self.source_digest {
+            labels.push((SOURCE_DIGEST_LABEL.to_string(), digest.clone()));
+        }
+        labels
+    }
+
+    /// Layer merger over this image's layers using its temp directory and merge options
+    fn merger(&self) -> Result<LayerMerger> {
+        let temp_dir = self.temp_dir.as_ref()
+            .ok_or_else(|| SquashError::InvalidInput("No temp directory available".to_string()))?
+            .path().to_path_buf();
+
+        Ok(LayerMerger::new(self.layers.clone(), temp_dir).with_options(self.merge_options.clone()))
+    }
+
+    /// Final filesystem content of the image with all layers applied
+    pub fn flatten(&self) -> Result<FlattenedFilesystem> {
+        flatten_layers(&self.layers)
+    }
+
+    /// Run `audit` over every layer produced by squashing
+    ///
+    /// Retained layers are not audited; they are copied as they were.
+    pub fn audit_permissions(&self, audit: &mut PermissionAudit) -> Result<()> {
+        for layer in self.layers.iter().filter(|layer| self.squashed_layers.contains(&layer.digest)) {
+            audit.audit(&flatten_layers(std::slice::from_ref(layer))?);
         }
+        Ok(())
     }
-}
 
-impl DockerImage {
-    /// Load a Docker image from a file or export from Docker
-    pub fn load(source: &str, temp_dir: Option<&Path>) -> Result<Self> {
-        let source_path = if source.contains(':') && !Path::new(source).exists() {
-            // Assume it's an image name:tag, export it first
-            Self::export_image(source, temp_dir)?
-        } else {
-            // Assume it's a file path
-            PathBuf::from(source)
+    /// Load the image at `source` and verify it without squashing anything
+    ///
+    /// An archive that cannot be loaded, for example because the manifest
+    /// names a config or layer that is missing, yields a single failed
+    /// `archive` check.
+    pub fn verify_archive(source: &str, temp_dir: Option<&Path>) -> Vec<VerificationCheck> {
+        Self::verify_archive_with_options(source, temp_dir, &LoadOptions::default())
+    }
+
+    /// Load the image at `source` with the given options and verify it
+    pub fn verify_archive_with_options(source: &str, temp_dir: Option<&Path>, options: &LoadOptions) -> Vec<VerificationCheck> {
+        let image = match Self::load_with_options(source, temp_dir, options) {
+            Ok(image) => image,
+            Err(err) => return vec![VerificationCheck::new("archive", false, err.to_string())],
         };
 
-        if !source_path.exists() {
-            return Err(SquashError::InvalidInput(format!(
-                "Source file does not exist: {}",
-                source_path.display()
-            )));
+        let mut checks = vec![VerificationCheck::new(
+            "archive",
+            true,
+            format!("config {} and {} layer(s) present", image.manifest.config, image.layers.len()),
+        )];
+        checks.extend(image.verify());
+        checks
+    }
+
+    /// Check that the config describes the layers and each layer matches its diff_id
+    ///
+    /// Every check is run, so one failure does not hide another. Foreign
+    /// layers are not part of the archive and their content is not checked.
+    pub fn verify(&self) -> Vec<VerificationCheck> {
+        let diff_ids = &self.config.rootfs.diff_ids;
+        let non_empty_history = self.config.history.iter().filter(|h| h.empty_layer != Some(true)).count();
+
+        let mut checks = vec![
+            VerificationCheck::new(
+                "diff_ids",
+                diff_ids.len() == self.layers.len(),
+                format!("{} diff_id(s) for {} layer(s)", diff_ids.len(), self.layers.len()),
+            ),
+            VerificationCheck::new(
+                "history",
+                non_empty_history == self.layers.len(),
+                format!("{} non-empty history entry(s) for {} layer(s)", non_empty_history, self.layers.len()),
+            ),
+        ];
+
+        let foreign = self.manifest.layer_sources.as_ref();
+        for (i, layer) in self.layers.iter().enumerate() {
+            let name = format!("layer {}", self.manifest.layers.get(i).map_or(layer.digest.as_str(), String::as_str));
+            let check = match diff_ids.get(i) {
+                None => VerificationCheck::new(name, false, "no diff_id in the config"),
+                Some(expected) if foreign.is_some_and(|sources| sources.contains_key(expected)) => {
+                    VerificationCheck::new(name, true, format!("foreign layer {}, content not checked", expected))
+                }
+                Some(expected) => match layer.diff_id() {
+                    Ok(actual) if &actual == expected => VerificationCheck::new(name, true, actual),
+                    Ok(actual) => VerificationCheck::new(name, false, format!("expected {}, got {}", expected, actual)),
+                    Err(err) => VerificationCheck::new(name, false, err.to_string()),
+                },
+            };
+            checks.push(check);
         }
 
-        // Extract and parse the image
-        let (manifest, config, layers, temp_dir) = Self::parse_image(&source_path)?;
+        checks
+    }
 
-        Ok(DockerImage {
-            manifest,
-            config,
-            source_path,
-            layers,
-            temp_dir: Some(temp_dir),
-        })
+    /// Compare the flattened content of this image against a reference image
+    ///
+    /// Changes are reported from the reference's point of view: `Added`
+    /// paths exist only in this image.
+    pub fn compare_content(&self, reference: &DockerImage) -> Result<Vec<PathChange>> {
+        self.diff_against(reference)
     }
 
-    /// Export a Docker image using docker save
-    fn export_image(image_name: &str, temp_dir: Option<&Path>) -> Result<PathBuf> {
-        let temp_dir = temp_dir.unwrap_or_else(|| Path::new("/tmp"));
-        let output_path = temp_dir.join(format!("{}.tar", image_name.replace(':', "_")));
+    /// Content changes that turn `other` into this image, sorted by path
+    ///
+    /// `Added` paths exist only in this image and `Removed` paths only in
+    /// `other`; each change carries the size and digest of the entries
+    /// involved. Layer boundaries, entry order and timestamps are ignored.
+    pub fn diff_against(&self, other: &DockerImage) -> Result<Vec<PathChange>> {
+        Ok(diff_filesystems(&other.flatten()?, &self.flatten()?))
+    }
 
-        let output = Command::new("docker")
-            .args(["save", "-o", output_path.to_str().unwrap(), image_name])
-            .output()
-            .map_err(|e| SquashError::DockerError(format!("Failed to run docker save: {}", e)))?;
+    /// Output path that selects stdout instead of a file
+    pub const STDOUT_PATH: &'static str = "-";
 
-        if !output.status.success() {
-            return Err(SquashError::DockerError(format!(
-                "docker save failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            )));
+    /// Save the squashed image to a file in the image's format
+    ///
+    /// An output path of `-` streams the archive to stdout.
+    pub fn save_to_file(&self, output_path: &Path) -> Result<()> {
+        self.save_to_file_with_observer(output_path, &mut NoopObserver)
+    }
+
+    /// Save the squashed image to a file, reporting layer bytes written to `observer`
+    pub fn save_to_file_with_observer(&self, output_path: &Path, observer: &mut dyn ProgressObserver) -> Result<()> {
+        if output_path == Path::new(Self::STDOUT_PATH) {
+            self.build_archive(observer)?.build_to_writer(std::io::stdout().lock())?;
+            observer.on_save_done();
+            return Ok(());
+        }
+
+        if self.save_options.no_clobber && output_path.exists() {
+            return Err(SquashError::OutputExists(output_path.to_path_buf()));
+        }
+
+        info!("Saving squashed image to: {}", output_path.display());
+        let archive = self.build_archive(observer)?;
+        self.save_options.cancellation.check()?;
+        let expected = archive.build(output_path)?;
+
+        // A full disk or a concurrent writer can leave a different file behind
+        let actual = std::fs::metadata(output_path)?.len();
+        if actual != expected {
+            let _ = std::fs::remove_file(output_path);
+            return Err(SquashError::OutputSizeMismatch { path: output_path.to_path_buf(), expected, actual });
         }
+        observer.on_save_done();
+        info!("Successfully saved squashed image to: {}", output_path.display());
+        Ok(())
+    }
 
-        Ok(output_path)
+    /// Stream the squashed image in the image's format into any writer
+    pub fn save_to_writer<W: Write>(&self, writer: W) -> Result<()> {
+        self.build_archive(&mut NoopObserver)?.build_to_writer(writer)
     }
 
-    /// Parse manifest and config from Docker image tar
-    fn parse_image(image_path: &Path) -> Result<(DockerManifest, DockerConfig, Vec<LayerInfo>, TempDir)> {
-        println!("Extracting Docker image: {}", image_path.display());
+    /// Return the squashed image archive as an in-memory buffer
+    ///
+    /// The whole archive, layers included, is held in memory; prefer
+    /// `save_to_writer` for large images.
+    pub fn save_to_vec(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        self.save_to_writer(&mut buffer)?;
+        Ok(buffer)
+    }
 
-        // Extract the Docker image tar file
-        let extractor = TarExtractor::extract(image_path)?;
+    /// Stage the archive matching the image's format
+    fn build_archive(&self, observer: &mut dyn ProgressObserver) -> Result<TarBuilder> {
+        observer.on_save_start(self.layers.iter().map(|layer| layer.size).sum());
+        match self.format {
+            ImageFormat::DockerArchive => self.build_docker_archive(observer),
+            ImageFormat::OciArchive => self.build_oci_archive(observer),
+        }
+    }
 
-        // Read and parse manifest.json
-        if !extractor.file_exists("manifest.json") {
-            return Err(SquashError::InvalidInput(
-                "manifest.json not found in Docker image".to_string()
-            ));
+    /// Append a layer blob as `name`, reporting the stored layer bytes to `observer`
+    ///
+    /// A converted blob differs in size from the stored layer, so its
+    /// bytes are reported in one step once it has been appended.
+    fn add_layer_blob(
+        builder: &TarBuilder,
+        name: &str,
+        layer: &LayerInfo,
+        blob: &LayerBlob,
+        observer: &mut dyn ProgressObserver,
+        cancellation: &Cancellation,
+    ) -> Result<()> {
+        cancellation.check()?;
+        if let Some(converted) = &blob.converted {
+            builder.add_file_from_path(name, converted.path())?;
+            observer.on_save_bytes(layer.size);
+            return Ok(());
         }
 
-        let manifest_content = extractor.read_file("manifest.json")?;
-        let manifests: Vec<DockerManifest> = serde_json::from_str(&manifest_content)?;
+        let reader = SaveProgressReader {
+            inner: std::io::BufReader::new(layer.open()?),
+            observer,
+            cancellation,
+        };
+        builder.add_reader(name, layer.size, reader).map_err(|e| match cancellation.check() {
+            Ok(()) => e,
+            Err(cancelled) => cancelled,
+        })
+    }
 
-        if manifests.is_empty() {
-            return Err(SquashError::InvalidInput(
-                "No manifests found in manifest.json".to_string()
-            ));
+    /// Stage a `docker save` compatible archive
+    fn build_docker_archive(&self, observer: &mut dyn ProgressObserver) -> Result<TarBuilder> {
+        if self.save_options.manifest_media_type.is_some() || self.save_options.config_media_type.is_some() {
+            warn!("Docker archives do not record media types, ignoring the media type overrides");
+        }
+        if self.save_options.sbom_passthrough && !self.manifest.attestations.is_empty() {
+            warn!("Docker archives cannot carry attestations, dropping {}", self.manifest.attestations.len());
         }
 
-        let manifest = manifests[0].clone();
+        // Create a new tar builder
+        let builder = TarBuilder::new()?;
 
-        // Read and parse the config file
-        let config_content = extractor.read_file(&manifest.config)?;
-        let config: DockerConfig = serde_json::from_str(&config_content)?;
+        // A content-addressed config name must follow the edited config
+        let config_json = self.archive_json(&self.config)?;
+        let mut manifest = self.manifest.clone();
+        manifest.config = Self::docker_config_name(&self.manifest.config, &config_json);
 
-        // Create layer info from manifest layers
-        let mut layers = Vec::new();
-        for (i, layer_path) in manifest.layers.iter().enumerate() {
-            let layer_tar_path = extractor.get_file_path(layer_path);
+        // Add the updated manifest.json
+        let manifest_json = self.archive_json(&vec![&manifest])?;
+        Self::add_archive_file(&builder, "manifest.json", &manifest_json)?;
 
-            if !layer_tar_path.exists() {
-                return Err(SquashError::InvalidInput(format!(
-                    "Layer file not found: {}", layer_path
-                )));
+        if self.save_options.legacy_repositories {
+            match self.legacy_repositories()? {
+                Some(repositories) => {
+                    Self::add_archive_file(&builder, REPOSITORIES_FILE, &self.archive_json(&repositories)?)?
+                }
+                None => warn!("Image has no repository tags, not writing a {} file", REPOSITORIES_FILE),
             }
+        }
 
-            // Use diff_id from config if available, otherwise generate from layer path
-            let digest = if i < config.rootfs.diff_ids.len() {
-                config.rootfs.diff_ids[i].clone()
-            } else {
-                format!("sha256:{}", layer_path.replace(".tar", "").replace("/", ""))
-            };
+        // Add the updated config file
+        Self::add_archive_file(&builder, &manifest.config, &config_json)?;
 
-            let size = std::fs::metadata(&layer_tar_path)?.len();
+        // Add all layer files
+        let mut parent: Option<&str> = None;
+        let mut written: HashMap<&str, &str> = HashMap::new();
+        for (layer, layer_filename) in self.layers.iter().zip(&self.manifest.layers) {
+            // A layer listed twice is written once; different content under one name is an error
+            if let Some(digest) = written.insert(layer_filename, &layer.digest) {
+                if digest != layer.digest {
+                    return Err(SquashError::InvalidInput(format!(
+                        "Layers {} and {} would both be written as {}",
+                        digest, layer.digest, layer_filename
+                    )));
+                }
+                observer.on_save_bytes(layer.size);
+                parent = Self::layer_directory(layer_filename).or(parent);
+                continue;
+            }
 
-            layers.push(LayerInfo {
-                digest,
-                size,
-                tar_path: layer_tar_path,
-            });
-        }
+            // Stream the layer tar file
+            let blob = self.layer_blob(layer)?;
+            Self::add_layer_blob(&builder, layer_filename, layer, &blob, observer, &self.save_options.cancellation)?;
 
-        println!("Parsed {} layers from Docker image", layers.len());
-        println!("Config has {} diff_ids", config.rootfs.diff_ids.len());
-        println!("Config has {} history entries", config.history.len());
+            // Directory-wrapped layers carry their legacy metadata next to layer.tar
+            if let Some(dir) = Self::layer_directory(layer_filename) {
+                self.add_layer_metadata(&builder, layer, dir, parent)?;
+                parent = Some(dir);
+            }
+        }
 
-        // Count non-empty history entries
-        let non_empty_history_count = config.history.iter()
-            .filter(|h| h.empty_layer != Some(true))
-            .count();
-        println!("Config has {} non-empty history entries", non_empty_history_count);
+        Ok(builder)
+    }
 
-        // Debug: show all history entries
-        println!("=== History entries ===");
-        for (i, entry) in config.history.iter().enumerate() {
-            let empty_status = if entry.empty_layer == Some(true) { " (EMPTY)" } else { "" };
-            println!("  {}: {}{}", i + 1, entry.created_by.chars().take(60).collect::<String>(), empty_status);
+    /// Content of the legacy `repositories` file, `None` without repository tags
+    ///
+    /// Every RepoTags entry maps its repository and tag to the id of the top
+    /// layer: the `<id>` directory of a `<id>/layer.tar` layer, otherwise the
+    /// hex digest of the layer as newer `docker save` versions write it.
+    fn legacy_repositories(&self) -> Result<Option<BTreeMap<String, BTreeMap<String, String>>>> {
+        let tags = self.manifest.repo_tags.as_deref().unwrap_or_default();
+        let Some(top_layer) = self.layers.last().filter(|_| !tags.is_empty()) else {
+            return Ok(None);
+        };
+        let top_layer_id = self.manifest.layers
+            .last()
+            .and_then(|name| Self::layer_directory(name))
+            .unwrap_or(top_layer.digest.trim_start_matches("sha256:"))
+            .to_string();
+
+        let mut repositories: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+        for tag in tags {
+            let reference: ImageReference = tag.parse().map_err(SquashError::InvalidInput)?;
+            let repository = ImageReference { tag: None, digest: None, ..reference.clone() }.to_string();
+            repositories
+                .entry(repository)
+                .or_default()
+                .insert(reference.tag.unwrap_or_else(|| "latest".to_string()), top_layer_id.clone());
         }
-        println!("=== End history entries ===");
+        Ok(Some(repositories))
+    }
 
-        Ok((manifest, config, layers, extractor.temp_dir))
+    /// Date of history entries and metadata created by squashing
+    ///
+    /// The source date epoch when one is set, so repeated runs match; otherwise now.
+    fn creation_time(&self) -> String {
+        let created = self
+            .merge_options
+            .source_date_epoch
+            .and_then(|epoch| chrono::DateTime::from_timestamp(i64::try_from(epoch).ok()?, 0))
+            .unwrap_or_else(chrono::Utc::now);
+        created.to_rfc3339()
     }
 
-    /// Squash layers according to the specification
-    pub fn squash_layers(&mut self, layer_spec: &str) -> Result<()> {
-        if self.layers.is_empty() {
-            return Err(SquashError::InvalidInput("No layers to merge".to_string()));
+    /// Name of the config file of a Docker archive once it holds `config_json`
+    ///
+    /// Names made of the config digest, `<hex>.json` or `blobs/sha256/<hex>`,
+    /// are renamed after the digest of the new content, in the same
+    /// directory. Other names such as `config.json` are kept.
+    fn docker_config_name(name: &str, config_json: &[u8]) -> String {
+        let file = name.rsplit('/').next().unwrap_or(name);
+        let stem = file.strip_suffix(".json").unwrap_or(file);
+        if stem.len() != 64 || !stem.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return name.to_string();
         }
 
-        // Create a temporary directory for the merge operation
-        let temp_dir = self.temp_dir.as_ref()
-            .ok_or_else(|| SquashError::InvalidInput("No temp directory available".to_string()))?
-            .path().to_path_buf();
-
-        let merger = LayerMerger::new(self.layers.clone(), temp_dir);
+        let prefix = &name[..name.len() - file.len()];
+        let suffix = &file[stem.len()..];
+        format!("{}{:x}{}", prefix, Sha256::digest(config_json), suffix)
+    }
 
-        // Parse layer specification and merge layers
-        let merged_layer = if let Ok(count) = layer_spec.parse::<usize>() {
-            // Merge latest n layers
-            if count > self.layers.len() {
-                return Err(SquashError::InvalidInput(format!(
-                    "Cannot merge {} layers, image only has {} layers",
-                    count,
-                    self.layers.len()
-                )));
-            }
-            merger.merge_latest_layers(count)?
+    /// Encode a Docker archive JSON file, canonical when requested
+    fn archive_json<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        if self.save_options.canonical_json {
+            to_canonical_vec(value)
         } else {
-            // Find layer by ID and merge from that layer to latest
-            merger.merge_from_layer_id(layer_spec)?
-        };
+            Ok(serde_json::to_string_pretty(value)?.into_bytes())
+        }
+    }
 
-        // Update the image with the merged layer
-        let layers_to_merge_count = if let Ok(count) = layer_spec.parse::<usize>() {
-            count
-        } else {
-            // Find the layer and count from there
-            let start_index = self.layers
-                .iter()
-                .position(|layer| layer.digest.starts_with(layer_spec))
-                .ok_or_else(|| SquashError::LayerNotFound(layer_spec.to_string()))?;
-            self.layers.len() - start_index
+    /// Write the `VERSION` and `json` files of a `<id>/layer.tar` layer
+    ///
+    /// Files extracted with the source layer are copied verbatim; the merged
+    /// layer gets freshly generated ones.
+    fn add_layer_metadata(&self, builder: &TarBuilder, layer: &LayerInfo, dir: &str, parent: Option<&str>) -> Result<()> {
+        let extracted_dir = match layer.archive_offset {
+            // Only the small files next to a layer read in place were extracted
+            Some(_) => self.temp_dir.as_ref().map(|temp_dir| temp_dir.path().join(dir)),
+            None => layer.tar_path.parent()
+                .filter(|path| path.file_name().is_some_and(|name| name == dir))
+                .map(Path::to_path_buf),
         };
 
-        // Remove the merged layers and add the new merged layer
-        self.layers.truncate(self.layers.len() - layers_to_merge_count);
-        self.layers.push(merged_layer);
+        let version = match extracted_dir.as_ref().map(|path| path.join("VERSION")).filter(|path| path.exists()) {
+            Some(path) => std::fs::read(path)?,
+            None => LAYER_VERSION.as_bytes().to_vec(),
+        };
+        Self::add_archive_file(builder, &format!("{}/VERSION", dir), &version)?;
+
+        let json = match extracted_dir.as_ref().map(|path| path.join("json")).filter(|path| path.exists()) {
+            Some(path) => std::fs::read(path)?,
+            None => {
+                let mut json = serde_json::json!({
+                    "id": dir,
+                    "created": self.creation_time(),
+                });
+                if let Some(parent) = parent {
+                    json["parent"] = parent.into();
+                }
+                serde_json::to_vec(&json)?
+            }
+        };
+        Self::add_archive_file(builder, &format!("{}/json", dir), &json)
+    }
 
-        // Update manifest layers
-        let remaining_layers = self.manifest.layers.len() - layers_to_merge_count;
-        self.manifest.layers.truncate(remaining_layers);
-        self.manifest.layers.push("merged_layer.tar".to_string());
+    /// Stage an OCI image layout archive with uncompressed layer blobs
+    fn build_oci_archive(&self, observer: &mut dyn ProgressObserver) -> Result<TarBuilder> {
+        if self.save_options.legacy_repositories {
+            warn!("OCI archives do not use a {} file, ignoring --legacy-repositories", REPOSITORIES_FILE);
+        }
 
-        // Update config diff_ids
-        self.config.rootfs.diff_ids.truncate(remaining_layers);
-        self.config.rootfs.diff_ids.push(self.layers.last().unwrap().digest.clone());
+        let builder = TarBuilder::new()?;
+        let layer_annotations = self.layer_created_by();
 
-        // Update config history to match the new layer structure
-        // Docker expects the number of non-empty history entries to match the number of layers
-        println!("Before squash: {} layers, {} history entries, {} non-empty history entries",
-                 self.layers.len(),
-                 self.config.history.len(),
-                 self.config.history.iter().filter(|h| h.empty_layer != Some(true)).count());
-
-        // Find the history entries that correspond to the layers being merged
-        // We need to work backwards from the end of the history
-        let mut non_empty_count = 0;
-        let mut history_entries_to_remove = 0;
-
-        // Count backwards through history to find entries corresponding to merged layers
-        for history_entry in self.config.history.iter().rev() {
-            if history_entry.empty_layer != Some(true) {
-                non_empty_count += 1;
-                if non_empty_count <= layers_to_merge_count {
-                    history_entries_to_remove += 1;
-                } else {
-                    break;
-                }
+        let mut layer_descriptors = Vec::new();
+        let mut written = BTreeSet::new();
+        for (i, layer) in self.layers.iter().enumerate() {
+            // Descriptors address the blob as stored, not the diff_id
+            let blob = self.layer_blob(layer)?;
+            let digest = blob.digest(layer)?;
+            if written.insert(digest.clone()) {
+                Self::add_layer_blob(
+                    &builder,
+                    &Self::oci_blob_path(&digest)?,
+                    layer,
+                    &blob,
+                    observer,
+                    &self.save_options.cancellation,
+                )?;
             } else {
-                // This is an empty layer, we might need to remove it too
-                // if it's part of the layers being merged
-                if non_empty_count < layers_to_merge_count {
-                    history_entries_to_remove += 1;
-                }
+                observer.on_save_bytes(layer.size);
             }
+
+            let annotations = layer_annotations[i]
+                .as_ref()
+                .filter(|_| self.save_options.layer_annotations_from_history)
+                .map(|created_by| BTreeMap::from([(ANNOTATION_CREATED_BY.to_string(), created_by.clone())]));
+
+            let media_type = match blob.compression {
+                LayerCompression::None => MEDIA_TYPE_OCI_LAYER,
+                LayerCompression::Gzip => MEDIA_TYPE_OCI_LAYER_GZIP,
+                LayerCompression::Zstd => MEDIA_TYPE_OCI_LAYER_ZSTD,
+            };
+            layer_descriptors.push(OciDescriptor {
+                media_type: media_type.to_string(),
+                digest,
+                size: blob.size(layer)?,
+                annotations,
+                platform: None,
+                urls: None,
+            });
         }
 
-        // Remove the history entries for merged layers
-        let new_history_len = self.config.history.len() - history_entries_to_remove;
-        self.config.history.truncate(new_history_len);
+        // Some registries only accept specific media types, so both can be overridden
+        let manifest_media_type = self.save_options.manifest_media_type.as_deref().unwrap_or(MEDIA_TYPE_OCI_MANIFEST);
+        let config_media_type = self.save_options.config_media_type.as_deref().unwrap_or(MEDIA_TYPE_OCI_CONFIG);
 
-        // Add a new history entry for the merged layer
-        let merged_history_entry = HistoryEntry {
-            created: chrono::Utc::now().to_rfc3339(),
-            created_by: format!("squash: merged {} layers", layers_to_merge_count),
-            empty_layer: Some(false),
+        let config_json = to_canonical_vec(&self.config)?;
+        let config_descriptor = Self::add_oci_blob(&builder, config_media_type, &config_json)?;
+
+        let manifest = OciManifest {
+            schema_version: 2,
+            media_type: Some(manifest_media_type.to_string()),
+            config: config_descriptor,
+            layers: layer_descriptors,
+            annotations: None,
         };
-        self.config.history.push(merged_history_entry);
+        let manifest_json = to_canonical_vec(&manifest)?;
+        let mut manifest_descriptor = Self::add_oci_blob(&builder, manifest_media_type, &manifest_json)?;
+
+        if let Some(tag) = self.manifest.repo_tags.as_ref().and_then(|tags| tags.first()) {
+            manifest_descriptor.annotations = Some(BTreeMap::from([
+                (ANNOTATION_CONTAINERD_IMAGE_NAME.to_string(), tag.clone()),
+                (ANNOTATION_REF_NAME.to_string(), tag.clone()),
+            ]));
+        }
 
-        println!("After squash: {} layers, {} history entries, {} non-empty history entries",
-                 self.layers.len(),
-                 self.config.history.len(),
-                 self.config.history.iter().filter(|h| h.empty_layer != Some(true)).count());
+        // Attestations are re-pointed at the squashed manifest
+        let mut manifests = Vec::new();
+        if self.save_options.sbom_passthrough {
+            for attestation in &self.manifest.attestations {
+                for (digest, path) in &attestation.blobs {
+                    Self::add_archive_file(&builder, &Self::oci_blob_path(digest)?, &std::fs::read(path)?)?;
+                }
+
+                let (attestation_manifest, mut descriptor) = attestation.with_subject(&manifest_descriptor);
+                let attestation_json = to_canonical_vec(&attestation_manifest)?;
+                let stored = Self::add_oci_blob(&builder, &descriptor.media_type, &attestation_json)?;
+                descriptor.digest = stored.digest;
+                descriptor.size = stored.size;
+                manifests.push(descriptor);
+            }
+        }
+        manifests.insert(0, manifest_descriptor);
+
+        let index = OciIndex {
+            schema_version: 2,
+            media_type: Some(MEDIA_TYPE_OCI_INDEX.to_string()),
+            manifests,
+            annotations: None,
+        };
+        Self::add_archive_file(&builder, OCI_INDEX_FILE, &to_canonical_vec(&index)?)?;
 
-        println!("Successfully merged layers. New layer count: {}", self.layers.len());
+        let layout = serde_json::json!({ "imageLayoutVersion": OCI_LAYOUT_VERSION });
+        Self::add_archive_file(&builder, OCI_LAYOUT_FILE, layout.to_string().as_bytes())?;
 
-        Ok(())
+        Ok(builder)
     }
 
-    /// Save the squashed image to a file
-    pub fn save_to_file(&self, output_path: &Path) -> Result<()> {
-        use crate::docker::TarBuilder;
+    /// Layer content as it should be written, with its compression
+    ///
+    /// Squashed layers always use the output layer compression; retained
+    /// layers keep their stored bytes unless asked to match it. The diff_id
+    /// is unaffected either way since it covers the uncompressed tar. Layers
+    /// that need converting are streamed into a temporary file.
+    fn layer_blob(&self, layer: &LayerInfo) -> Result<LayerBlob> {
+        let stored = if layer.is_gzip()? { LayerCompression::Gzip } else { LayerCompression::None };
+
+        let target = if self.squashed_layers.contains(&layer.digest)
+            || self.save_options.retained_layer_compression == RetainedLayerCompression::Match
+        {
+            self.save_options.layer_compression
+        } else {
+            stored
+        };
+
+        if stored == target {
+            return Ok(LayerBlob {
+                compression: target,
+                converted: None,
+            });
+        }
 
-        println!("Saving squashed image to: {}", output_path.display());
+        let mut converted = match &self.temp_dir {
+            Some(temp_dir) => NamedTempFile::new_in(temp_dir.path())?,
+            None => NamedTempFile::new()?,
+        };
+        let source = std::io::BufReader::new(layer.open()?);
+        let plain: Box<dyn Read> = match stored {
+            LayerCompression::Gzip => Box::new(GzDecoder::new(source)),
+            _ => Box::new(source),
+        };
+        self.merge_options.compress_layer(target, plain, converted.as_file_mut())?;
 
-        // Create a new tar builder
-        let builder = TarBuilder::new()?;
+        Ok(LayerBlob {
+            compression: target,
+            converted: Some(converted),
+        })
+    }
 
-        // Add the updated manifest.json
-        let manifest_json = serde_json::to_string_pretty(&vec![&self.manifest])?;
-        builder.add_file("manifest.json", manifest_json.as_bytes())?;
+    /// Append a regular file with fixed, controlled metadata to an image archive
+    fn add_archive_file(builder: &TarBuilder, name: &str, content: &[u8]) -> Result<()> {
+        let mut header = Header::new_gnu();
+        header.set_path(name)?;
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        header.set_cksum();
+        builder.add_entry(&header, content)
+    }
 
-        // Add the updated config file
-        let config_json = serde_json::to_string_pretty(&self.config)?;
-        builder.add_file(&self.manifest.config, config_json.as_bytes())?;
+    /// Store `content` in the blob store and return its descriptor
+    fn add_oci_blob(builder: &TarBuilder, media_type: &str, content: &[u8]) -> Result<OciDescriptor> {
+        let digest = format!("sha256:{:x}", Sha256::digest(content));
+        Self::add_archive_file(builder, &Self::oci_blob_path(&digest)?, content)?;
+
+        Ok(OciDescriptor {
+            media_type: media_type.to_string(),
+            digest,
+            size: content.len() as u64,
+            annotations: None,
+            platform: None,
+            urls: None,
+        })
+    }
 
-        // Add all layer files
-        for (i, layer) in self.layers.iter().enumerate() {
-            let layer_filename = if i == self.layers.len() - 1 {
-                // This is the merged layer
-                "merged_layer.tar"
-            } else {
-                &self.manifest.layers[i]
-            };
+    /// History command for each layer, in layer order
+    ///
+    /// Non-empty history entries are paired with layers from the newest, as
+    /// in `layer_history_indices`; layers without an entry map to `None`.
+    /// Squashed entries report the commands of every layer they replaced.
+    pub fn layer_created_by(&self) -> Vec<Option<String>> {
+        self.layer_history_indices()
+            .into_iter()
+            .map(|owner| owner.map(|owner| self.config.history[owner].layer_command()))
+            .collect()
+    }
 
-            // Copy the layer tar file
-            let layer_content = std::fs::read(&layer.tar_path)?;
-            builder.add_file(layer_filename, &layer_content)?;
-        }
+    /// Copy the squashed image to a skopeo destination transport
+    pub fn push_with_skopeo(&self, skopeo: &Skopeo, destination: &str) -> Result<()> {
+        let mut oci_image = self.clone();
+        oci_image.format = ImageFormat::OciArchive;
+
+        let temp_file = tempfile::NamedTempFile::new()?;
+        oci_image.save_to_file(temp_file.path())?;
 
-        // Build the final tar file
-        builder.build(output_path)?;
+        info!("Copying squashed image to: {}", destination);
+        skopeo.push(temp_file.path(), destination)?;
 
-        println!("Successfully saved squashed image to: {}", output_path.display());
+        info!("Successfully copied squashed image to: {}", destination);
         Ok(())
     }
 
     /// Load the squashed image into Docker
-    pub fn load_into_docker(&self, image_name: &str) -> Result<()> {
+    pub fn load_into_docker(&self, image_name: &str) -> Result<DockerLoadReport> {
+        self.load_into_docker_with(&CliBackend, image_name)
+    }
+
+    /// Load the squashed image into Docker through the given backend
+    ///
+    /// The image is loaded under a temporary tag and then tagged `image_name`.
+    /// Failing to remove the temporary tag afterwards is not an error; it is
+    /// reported in the returned `DockerLoadReport`.
+    pub fn load_into_docker_with(&self, backend: &dyn DockerBackend, image_name: &str) -> Result<DockerLoadReport> {
         // Create a modified version with a temporary tag to avoid overwriting the original image
         let mut modified_image = self.clone();
+        modified_image.format = ImageFormat::DockerArchive;
 
-        // Generate a unique temporary tag to avoid conflicts
-        // Docker tag format: [hostname[:port]/]name[:tag]
-        // Name must be lowercase and can contain letters, digits, underscores, periods and dashes
-        let temp_tag = format!("squash-temp-{}:latest", uuid::Uuid::new_v4().to_string()[..8].to_lowercase());
+        let temp_tag = Self::unused_temp_tag(backend)?;
         modified_image.manifest.repo_tags = Some(vec![temp_tag.clone()]);
 
-        // Save the modified image to a temporary file
-        let temp_file = tempfile::NamedTempFile::new()?;
-        let temp_path = temp_file.path();
-
-        modified_image.save_to_file(temp_path)?;
-
-        println!("Loading squashed image into Docker as: {}", image_name);
+        // Import the image with its temporary tag, straight from the archive
+        // writer when the backend streams, through a temporary file otherwise
+        if backend.streams_archives() {
+            info!("Streaming squashed image into Docker as: {}", image_name);
+            let (reader, mut writer) = std::io::pipe()?;
+            std::thread::scope(|scope| {
+                let image = &modified_image;
+                let saving = scope.spawn(move || image.save_to_writer(&mut writer));
+                let imported = backend.import(Box::new(reader));
+                let saved = saving
+                    .join()
+                    .map_err(|_| SquashError::DockerError("Writing the squashed image panicked".to_string()))?;
+                Self::first_stream_error(saved, imported)
+            })?;
+        } else {
+            let temp_file = tempfile::NamedTempFile::new()?;
+            let temp_path = temp_file.path();
 
-        // Use docker load to import the image with temporary tag
-        let output = Command::new("docker")
-            .args(["load", "-i", temp_path.to_str().unwrap()])
-            .output()
-            .map_err(|e| SquashError::DockerError(format!("Failed to run docker load: {}", e)))?;
+            modified_image.save_to_file(temp_path)?;
 
-        if !output.status.success() {
-            return Err(SquashError::DockerError(format!(
-                "docker load failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            )));
+            info!("Loading squashed image into Docker as: {}", image_name);
+            backend.load(temp_path)?;
         }
 
-        // Tag the loaded image with the desired name
-        let tag_output = Command::new("docker")
-            .args(["tag", &temp_tag, image_name])
-            .output()
-            .map_err(|e| SquashError::DockerError(format!("Failed to run docker tag: {}", e)))?;
+        // An interrupt during the load leaves only the temporary image behind
+        if let Err(cancelled) = self.save_options.cancellation.check() {
+            if self.save_options.keep_load_tag {
+                info!("Keeping temporary image {}", temp_tag);
+            } else if let Err(cleanup) = backend.remove_image(&temp_tag) {
+                warn!("Failed to remove temporary image {}: {}", temp_tag, cleanup);
+            }
+            return Err(cancelled);
+        }
 
-        if !tag_output.status.success() {
-            return Err(SquashError::DockerError(format!(
-                "docker tag failed: {}",
-                String::from_utf8_lossy(&tag_output.stderr)
-            )));
+        // Tag the loaded image with the desired name, removing the temporary
+        // image again if that fails so the daemon is left as it was
+        if let Err(e) = backend.tag(&temp_tag, image_name) {
+            if self.save_options.keep_load_tag {
+                info!("Keeping temporary image {}", temp_tag);
+            } else if let Err(cleanup) = backend.remove_image(&temp_tag) {
+                warn!("Failed to roll back temporary image {}: {}", temp_tag, cleanup);
+            }
+            return Err(e);
         }
 
         // Clean up the temporary tag
-        let cleanup_output = Command::new("docker")
-            .args(["rmi", &temp_tag])
-            .output()
-            .map_err(|e| SquashError::DockerError(format!("Failed to run docker rmi: {}", e)))?;
+        let cleanup = if self.save_options.keep_load_tag {
+            info!("Keeping temporary tag {}", temp_tag);
+            TempTagCleanup::Kept
+        } else {
+            match backend.remove_image(&temp_tag) {
+                Ok(()) => TempTagCleanup::Removed,
+                Err(e) => {
+                    warn!("Failed to clean up temporary tag {}: {}", temp_tag, e);
+                    TempTagCleanup::Failed { error: e.to_string() }
+                }
+            }
+        };
 
-        if !cleanup_output.status.success() {
-            println!("Warning: Failed to clean up temporary tag {}: {}",
-                     temp_tag,
-                     String::from_utf8_lossy(&cleanup_output.stderr));
+        info!("Successfully loaded squashed image into Docker as: {}", image_name);
+        Ok(DockerLoadReport { temp_tag, cleanup })
+    }
+
+    /// A temporary tag for `load_into_docker` that the daemon does not have yet
+    fn unused_temp_tag(backend: &dyn DockerBackend) -> Result<String> {
+        const ATTEMPTS: usize = 3;
+
+        for _ in 0..ATTEMPTS {
+            // Docker tag format: [hostname[:port]/]name[:tag]
+            // Name must be lowercase and can contain letters, digits, underscores, periods and dashes
+            let temp_tag = format!("squash-temp-{}:latest", uuid::Uuid::new_v4().to_string()[..8].to_lowercase());
+            if !backend.image_exists(&temp_tag)? {
+                return Ok(temp_tag);
+            }
+            debug!("Temporary tag {} already exists, picking another", temp_tag);
         }
+        Err(SquashError::DockerError(format!(
+            "No unused temporary tag found after {} attempts",
+            ATTEMPTS
+        )))
+    }
+}
 
-        println!("Successfully loaded squashed image into Docker as: {}", image_name);
-        Ok(())
+/// File name of the cached `docker save` archive of the image with ID `image_id`
+///
+/// Image IDs are config digests, `sha256:<hex>`, so the key changes whenever
+/// the image does. IDs of any other form are rejected rather than used as a path.
+pub fn export_cache_key(image_id: &str) -> Result<String> {
+    let invalid = || SquashError::DockerError(format!("Unexpected image ID: {}", image_id));
+    let (algorithm, hex) = image_id.split_once(':').ok_or_else(invalid)?;
+
+    let valid = !algorithm.is_empty()
+        && algorithm.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && !hex.is_empty()
+        && hex.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase());
+    if !valid {
+        return Err(invalid());
+    }
+    Ok(format!("{}-{}.tar", algorithm, hex))
+}
+
+/// Path of the checksum file written next to `output_path`: `<output>.sha256`
+pub fn checksum_path(output_path: &Path) -> PathBuf {
+    let mut path = output_path.as_os_str().to_owned();
+    path.push(".sha256");
+    PathBuf::from(path)
+}
+
+/// Hash the file at `output_path` as written and record it in `<output>.sha256`
+///
+/// The file has the `sha256sum` format, `<hex>  <file name>`, so it can be
+/// checked with `sha256sum -c` from the output's directory. Returns the
+/// digest in `sha256:<hex>` form.
+pub fn write_checksum_file(output_path: &Path) -> Result<String> {
+    let digest = calculate_file_digest(output_path)?;
+    let file_name = output_path.file_name().unwrap_or(output_path.as_os_str()).to_string_lossy();
+    let hex = digest.trim_start_matches("sha256:");
+
+    let sidecar = checksum_path(output_path);
+    std::fs::write(&sidecar, format!("{}  {}\n", hex, file_name))
+        .map_err(|source| SquashError::FileWrite { path: sidecar, source })?;
+    Ok(digest)
+}
+
+/// Parse a comma-separated list of layer indexes such as `2,3,4` into an inclusive range
+///
+/// Layers only stack on their neighbours, so the indexes must be ascending and
+/// contiguous.
+fn parse_layer_indexes(layer_spec: &str, layer_count: usize) -> Result<(usize, usize)> {
+    let indexes = layer_spec
+        .split(',')
+        .map(|index| {
+            let index = index.trim();
+            match index.parse::<usize>() {
+                Ok(index) if index < layer_count => Ok(index),
+                Ok(_) => Err(SquashError::InvalidInput(format!(
+                    "Layer index {} in {} is out of range, image has {} layers",
+                    index, layer_spec, layer_count
+                ))),
+                Err(_) => Err(SquashError::InvalidInput(format!(
+                    "Invalid layer index '{}' in {}, expected comma-separated numbers",
+                    index, layer_spec
+                ))),
+            }
+        })
+        .collect::<Result<Vec<usize>>>()?;
+
+    if indexes.windows(2).any(|pair| pair[1] != pair[0] + 1) {
+        return Err(SquashError::InvalidInput(format!(
+            "Layers {} are not a contiguous ascending run; only neighbouring layers can be merged, \
+             list every layer in between (e.g. 2,3,4) or use --from/--to",
+            layer_spec
+        )));
     }
+
+    Ok((indexes[0], indexes[indexes.len() - 1]))
 }
 
 #[cfg(test)]
@@ -417,6 +2755,175 @@ mod tests {
     use super::*;
     use tempfile::TempDir;
 
+    /// Image with `layer_count` layers and the given history, without layer files
+    fn image_with_history(layer_count: usize, history: Vec<HistoryEntry>) -> DockerImage {
+        DockerImage {
+            manifest: DockerManifest {
+                config: "config.json".to_string(),
+                repo_tags: None,
+                layers: Vec::new(),
+                layer_sources: None,
+                attestations: Vec::new(),
+            },
+            config: DockerConfig {
+                architecture: "amd64".to_string(),
+                os: None,
+                variant: None,
+                config: ConfigDetails::default(),
+                rootfs: RootFs { fs_type: "layers".to_string(), diff_ids: Vec::new() },
+                history,
+                extra: serde_json::Map::new(),
+            },
+            source_path: PathBuf::from("test.tar"),
+            layers: (0..layer_count)
+                .map(|i| LayerInfo {
+                    digest: format!("sha256:layer{}", i),
+                    size: 0,
+                    tar_path: PathBuf::from(format!("layer{}.tar", i)),
+                    data: None,
+                    archive_offset: None,
+                })
+                .collect(),
+            temp_dir: None,
+            format: ImageFormat::DockerArchive,
+            merge_options: MergeOptions::default(),
+            save_options: SaveOptions::default(),
+            squashed_layers: BTreeSet::new(),
+            preserved_layers: BTreeSet::new(),
+            merge_stats: Vec::new(),
+            source_digest: None,
+        }
+    }
+
+    #[test]
+    fn test_export_cache_key() {
+        let hex = "0123456789abcdef".repeat(4);
+        assert_eq!(export_cache_key(&format!("sha256:{}", hex)).unwrap(), format!("sha256-{}.tar", hex));
+
+        // Another image gets another entry
+        let other = "fedcba9876543210".repeat(4);
+        assert_ne!(
+            export_cache_key(&format!("sha256:{}", hex)).unwrap(),
+            export_cache_key(&format!("sha256:{}", other)).unwrap()
+        );
+
+        for image_id in ["", "sha256:", "0123456789abcdef", "sha256:../../etc", "sha256:ABCDEF", "sha/256:abcd", ":abcd"] {
+            assert!(matches!(export_cache_key(image_id), Err(SquashError::DockerError(_))), "{}", image_id);
+        }
+    }
+
+    #[test]
+    fn test_layer_index_list() {
+        let image = image_with_history(6, Vec::new());
+
+        assert_eq!(image.resolve_layer_spec("2,3,4").unwrap(), (2, 4));
+        assert_eq!(image.resolve_layer_spec("4, 5").unwrap(), (4, 5));
+        // A bare number still merges the latest layers
+        assert_eq!(image.resolve_layer_spec("5").unwrap(), (1, 5));
+
+        match image.resolve_layer_spec("2,4") {
+            Err(SquashError::InvalidInput(msg)) => {
+                assert!(msg.contains("not a contiguous ascending run"), "{}", msg);
+                assert!(msg.contains("--from/--to"), "{}", msg);
+            }
+            other => panic!("Expected InvalidInput error, got {:?}", other),
+        }
+        assert!(image.resolve_layer_spec("4,3").is_err());
+        assert!(image.resolve_layer_spec("3,3").is_err());
+        assert!(image.resolve_layer_spec("5,6").is_err());
+        assert!(image.resolve_layer_spec("2,x").is_err());
+        assert!(image.resolve_layer_spec("2,").is_err());
+    }
+
+    #[test]
+    fn test_check_history_matches_layers() {
+        let entry = |created_by: &str, empty_layer: Option<bool>| HistoryEntry {
+            created_by: created_by.to_string(),
+            empty_layer,
+            ..Default::default()
+        };
+        let history = vec![
+            entry("ADD base", None),
+            entry("ENV A=1", Some(true)),
+            entry("RUN build", Some(false)),
+            entry("CMD serve", Some(true)),
+        ];
+
+        assert!(DockerImage::check_history_matches_layers(&history, 2).is_ok());
+        let err = DockerImage::check_history_matches_layers(&history, 3).unwrap_err();
+        assert!(err.contains("2 non-empty entries for 3 layers"), "{}", err);
+        assert!(err.contains("4 entries in total"), "{}", err);
+    }
+
+    #[test]
+    fn test_layer_history_indices() {
+        let entry = |created_by: &str, empty_layer: Option<bool>| HistoryEntry {
+            created_by: created_by.to_string(),
+            empty_layer,
+            ..Default::default()
+        };
+        let history = vec![
+            entry("ARG VERSION", Some(true)),
+            entry("ADD base", None),
+            entry("ENV A=1", Some(true)),
+            entry("RUN one", Some(false)),
+            entry("LABEL team=x", Some(true)),
+            entry("RUN two", None),
+            entry("CMD serve", Some(true)),
+        ];
+
+        let image = image_with_history(3, history.clone());
+        assert_eq!(image.layer_history_indices(), vec![Some(1), Some(3), Some(5)]);
+        assert_eq!(image.merged_history_indices(1, 2), vec![3, 5]);
+
+        // Short history leaves the oldest layers without an entry
+        assert_eq!(image_with_history(4, history.clone()).layer_history_indices(), vec![None, Some(1), Some(3), Some(5)]);
+
+        // Long history leaves the oldest entries without a layer
+        assert_eq!(image_with_history(2, history).layer_history_indices(), vec![Some(3), Some(5)]);
+    }
+
+    #[test]
+    fn test_find_layer_by_command() {
+        let entry = |created_by: &str, empty_layer: Option<bool>| HistoryEntry {
+            created_by: created_by.to_string(),
+            empty_layer,
+            ..Default::default()
+        };
+        let history = vec![
+            entry("ADD rootfs.tar /", None),
+            entry("ENV DEBIAN_FRONTEND=noninteractive", Some(true)),
+            entry("RUN apt-get update && apt-get install -y curl", None),
+            entry("RUN apt-get install -y git", None),
+            entry("COPY app /app", None),
+            entry("CMD [\"/app/run\"]", Some(true)),
+        ];
+        let image = image_with_history(4, history.clone());
+
+        assert_eq!(image.find_layer_by_command("ADD rootfs").unwrap(), 0);
+        assert_eq!(image.find_layer_by_command("install -y git").unwrap(), 2);
+        assert_eq!(image.find_layer_by_command("COPY app").unwrap(), 3);
+
+        // Several matches are ambiguous and name every candidate
+        match image.find_layer_by_command("apt-get install") {
+            Err(SquashError::InvalidInput(msg)) => {
+                assert!(msg.contains("matches 2 layers"), "{}", msg);
+                assert!(msg.contains("1 (RUN apt-get update"), "{}", msg);
+                assert!(msg.contains("2 (RUN apt-get install -y git)"), "{}", msg);
+            }
+            other => panic!("Expected InvalidInput error, got: {:?}", other),
+        }
+
+        // Empty entries own no layer and cannot be selected
+        assert!(matches!(image.find_layer_by_command("DEBIAN_FRONTEND"), Err(SquashError::InvalidInput(_))));
+        assert!(matches!(image.find_layer_by_command("CMD"), Err(SquashError::InvalidInput(_))));
+        assert!(matches!(image.find_layer_by_command("yarn"), Err(SquashError::InvalidInput(_))));
+
+        // Layers older than the history have no command to match
+        let image = image_with_history(5, history);
+        assert_eq!(image.find_layer_by_command("COPY app").unwrap(), 4);
+    }
+
     #[test]
     fn test_history_update_during_squash() {
         // Create a mock DockerImage with multiple history entries
@@ -430,16 +2937,15 @@ mod tests {
                 "layer2.tar".to_string(),
                 "layer3.tar".to_string(),
             ],
+            layer_sources: None,
+            attestations: Vec::new(),
         };
 
         let config = DockerConfig {
             architecture: "amd64".to_string(),
-            config: ConfigDetails {
-                env: None,
-                cmd: None,
-                working_dir: None,
-                exposed_ports: None,
-            },
+            os: Some("linux".to_string()),
+            variant: None,
+            config: ConfigDetails::default(),
             rootfs: RootFs {
                 fs_type: "layers".to_string(),
                 diff_ids: vec![
@@ -453,18 +2959,22 @@ mod tests {
                     created: "2023-01-01T00:00:00Z".to_string(),
                     created_by: "layer1 command".to_string(),
                     empty_layer: Some(false),
+                    ..Default::default()
                 },
                 HistoryEntry {
                     created: "2023-01-02T00:00:00Z".to_string(),
                     created_by: "layer2 command".to_string(),
                     empty_layer: Some(false),
+                    ..Default::default()
                 },
                 HistoryEntry {
                     created: "2023-01-03T00:00:00Z".to_string(),
                     created_by: "layer3 command".to_string(),
                     empty_layer: Some(false),
+                    ..Default::default()
                 },
             ],
+            extra: serde_json::Map::new(),
         };
 
         // Create mock layer files
@@ -481,16 +2991,22 @@ mod tests {
                 digest: "sha256:layer1".to_string(),
                 size: 14,
                 tar_path: layer1_path,
+                data: None,
+                archive_offset: None,
             },
             LayerInfo {
                 digest: "sha256:layer2".to_string(),
                 size: 14,
                 tar_path: layer2_path,
+                data: None,
+                archive_offset: None,
             },
             LayerInfo {
                 digest: "sha256:layer3".to_string(),
                 size: 14,
                 tar_path: layer3_path,
+                data: None,
+                archive_offset: None,
             },
         ];
 
@@ -500,6 +3016,13 @@ mod tests {
             source_path: PathBuf::from("test.tar"),
             layers,
             temp_dir: Some(temp_dir),
+            format: ImageFormat::DockerArchive,
+            merge_options: MergeOptions::default(),
+            save_options: SaveOptions::default(),
+            squashed_layers: BTreeSet::new(),
+            preserved_layers: BTreeSet::new(),
+            merge_stats: Vec::new(),
+            source_digest: None,
         };
 
         // Verify initial state
@@ -520,6 +3043,7 @@ mod tests {
                 created: chrono::Utc::now().to_rfc3339(),
                 created_by: format!("squash: merged {} layers", layers_to_merge_count),
                 empty_layer: Some(false),
+                ..Default::default()
             };
             image.config.history.push(merged_history_entry);
         }
@@ -528,4 +3052,18 @@ mod tests {
         assert_eq!(image.config.history.len(), 2); // 3 - 2 + 1 = 2
         assert!(image.config.history.last().unwrap().created_by.contains("squash: merged 2 layers"));
     }
+
+    #[test]
+    fn test_docker_config_name() {
+        let old = "a".repeat(64);
+        let new = format!("{:x}", Sha256::digest(b"{}"));
+
+        assert_eq!(DockerImage::docker_config_name(&format!("{}.json", old), b"{}"), format!("{}.json", new));
+        assert_eq!(
+            DockerImage::docker_config_name(&format!("blobs/sha256/{}", old), b"{}"),
+            format!("blobs/sha256/{}", new)
+        );
+        assert_eq!(DockerImage::docker_config_name("config.json", b"{}"), "config.json");
+        assert_eq!(DockerImage::docker_config_name("configs/app.json", b"{}"), "configs/app.json");
+    }
 }
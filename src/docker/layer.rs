@@ -1,21 +1,135 @@
+use crate::docker::cancel::Cancellation;
+use crate::docker::image::LayerCompression;
+use crate::docker::progress::{NoopObserver, ProgressObserver};
+use crate::docker::tar::Zstd;
+use crate::docker::tar::{append_entry_with_pax, LongPathnameMode, SectionReader};
 use crate::error::{Result, SquashError};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use log::{debug, info, trace, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::{Path, PathBuf};
-use tar::{Archive, Builder, Header};
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tar::{Archive, Builder, EntryType, Header};
 use uuid::Uuid;
 
 /// Information about a Docker image layer
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct LayerInfo {
-    /// SHA256 digest of the layer
+    /// diff_id of the layer: SHA256 of the uncompressed tar
     pub digest: String,
     /// Size of the layer in bytes
     pub size: u64,
     /// Path to the layer's tar file
     pub tar_path: PathBuf,
+    /// Content of a layer merged in memory; `tar_path` is never written then
+    pub data: Option<Arc<[u8]>>,
+    /// Start of the layer within `tar_path` when it is read in place from
+    /// the image archive, `size` bytes long
+    pub archive_offset: Option<u64>,
+}
+
+impl std::fmt::Debug for LayerInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LayerInfo")
+            .field("digest", &self.digest)
+            .field("size", &self.size)
+            .field("tar_path", &self.tar_path)
+            .field("in_memory", &self.data.is_some())
+            .field("archive_offset", &self.archive_offset)
+            .finish()
+    }
+}
+
+impl LayerInfo {
+    /// Whether the layer content is held in memory rather than at `tar_path`
+    pub fn is_in_memory(&self) -> bool {
+        self.data.is_some()
+    }
+
+    /// Reader over the layer content as stored
+    pub fn open(&self) -> Result<LayerReader> {
+        match (&self.data, self.archive_offset) {
+            (Some(data), _) => Ok(LayerReader::Memory(Cursor::new(data.clone()))),
+            (None, Some(offset)) => Ok(LayerReader::Section(SectionReader::new(File::open(&self.tar_path)?, offset, self.size)?)),
+            (None, None) => Ok(LayerReader::File(File::open(&self.tar_path)?)),
+        }
+    }
+
+    /// Whether the stored content starts with the gzip magic bytes
+    pub fn is_gzip(&self) -> Result<bool> {
+        let mut magic = [0u8; 2];
+        Ok(self.open()?.read(&mut magic)? == 2 && magic == GZIP_MAGIC)
+    }
+
+    /// diff_id of the stored content, see [`calculate_diff_id`]
+    pub fn diff_id(&self) -> Result<String> {
+        diff_id_reader(self.open()?)
+    }
+
+    /// SHA256 digest of the content as stored, see [`calculate_file_digest`]
+    pub fn blob_digest(&self) -> Result<String> {
+        digest_reader(self.open()?)
+    }
+}
+
+/// Content of a layer, read from its tar file, the image archive or memory
+pub enum LayerReader {
+    File(File),
+    Section(SectionReader),
+    Memory(Cursor<Arc<[u8]>>),
+}
+
+impl Read for LayerReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            LayerReader::File(file) => file.read(buf),
+            LayerReader::Section(section) => section.read(buf),
+            LayerReader::Memory(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+impl Seek for LayerReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            LayerReader::File(file) => file.seek(pos),
+            LayerReader::Section(section) => section.seek(pos),
+            LayerReader::Memory(cursor) => cursor.seek(pos),
+        }
+    }
+}
+
+/// Counts describing what a merge did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LayerMergeStats {
+    /// Number of layers folded into the merged layer
+    pub input_layers: usize,
+    /// Entries written to the merged layer tar
+    pub files_written: usize,
+    /// Paths deleted by `.wh.` whiteout markers
+    pub files_whiteouted: usize,
+    /// Directories cleared by opaque whiteouts
+    pub opaque_dirs_cleared: usize,
+    /// Content bytes of the entries written to the merged layer
+    pub total_bytes: u64,
+    /// Entries dropped by the exclude patterns
+    pub files_excluded: usize,
+    /// Content bytes of the entries dropped by the exclude patterns
+    pub bytes_excluded: u64,
+    /// Stored size of the layers folded into the merged layer
+    pub input_size: u64,
+    /// Size of the merged layer; larger than `input_size` when merging grew the layers
+    pub merged_size: u64,
 }
 
 /// Represents the data storage strategy for a file
@@ -26,30 +140,420 @@ enum FileData {
     /// Large files referenced by their source location
     OnDisk {
         /// Path to the source tar file
-        #[allow(dead_code)] // Reserved for future streaming implementation
         source_tar: PathBuf,
-        /// Offset in the tar file where this entry starts
-        #[allow(dead_code)] // Reserved for future streaming implementation
+        /// Offset in the tar file where the entry data starts
+        offset: u64,
+        /// Size of the entry data
+        size: u64,
+    },
+    /// Large files of a layer held in memory, referenced within its content
+    Shared {
+        /// Content of the layer the entry was read from
+        layer: Arc<[u8]>,
+        /// Offset in the layer content where the entry data starts
         offset: u64,
         /// Size of the entry data
         size: u64,
     },
 }
 
+impl FileData {
+    /// Size of the file content in bytes
+    fn size(&self) -> u64 {
+        match self {
+            FileData::InMemory(data) => data.len() as u64,
+            FileData::OnDisk { size, .. } | FileData::Shared { size, .. } => *size,
+        }
+    }
+}
+
 /// Represents a file entry in the virtual filesystem
 #[derive(Debug, Clone)]
 struct FileEntry {
     header: Header,
+    /// Full link target for symlinks and hard links
+    link_name: Option<PathBuf>,
+    /// PAX records re-emitted with the entry, such as file capabilities
+    pax: Vec<(String, Vec<u8>)>,
     data: FileData,
 }
 
-/// Maximum size for files to be stored in memory (1MB)
-const MAX_MEMORY_FILE_SIZE: u64 = 1024 * 1024;
+/// One entry of a layer tar, read but not yet applied
+#[derive(Debug)]
+struct LayerChange {
+    path: PathBuf,
+    /// Size recorded in the entry header
+    size: u64,
+    whiteout: Option<Whiteout>,
+    /// Entry to add; absent for `.wh.` and 0/0 character device markers
+    entry: Option<FileEntry>,
+}
 
-/// Virtual filesystem state for tracking layer changes
+/// Largest file kept in memory while merging unless configured otherwise (1MB)
+pub const DEFAULT_MEM_FILE_THRESHOLD: u64 = 1024 * 1024;
+
+/// Leading bytes of a gzip stream
+pub(crate) const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Leading bytes of a zstd frame
+pub(crate) const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Per-file log lines written for each merge phase unless configured otherwise
+pub const DEFAULT_FILE_LOG_LIMIT: usize = 100;
+
+/// Files between progress summaries once per-file logging has stopped
+const FILE_LOG_SUMMARY_INTERVAL: usize = 100_000;
+
+/// What to log for the next file of a merge phase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileLog {
+    /// Log the file itself
+    Detail,
+    /// Log how many files have been processed so far
+    Summary(usize),
+    /// Log nothing
+    Quiet,
+}
+
+/// Caps per-file trace output, switching to periodic summaries past the limit
 #[derive(Debug)]
+struct FileLogThrottle {
+    limit: usize,
+    count: usize,
+}
+
+impl FileLogThrottle {
+    fn new(limit: usize) -> Self {
+        FileLogThrottle { limit, count: 0 }
+    }
+
+    /// Count one more file and decide what to log for it
+    fn tick(&mut self) -> FileLog {
+        self.count += 1;
+        if self.count <= self.limit {
+            FileLog::Detail
+        } else if self.count == self.limit + 1 || self.count.is_multiple_of(FILE_LOG_SUMMARY_INTERVAL) {
+            FileLog::Summary(self.count)
+        } else {
+            FileLog::Quiet
+        }
+    }
+
+    /// Trace `message` for this file, or a summary once the limit is reached
+    fn trace(&mut self, message: impl FnOnce() -> String) {
+        match self.tick() {
+            FileLog::Detail => trace!("{}", message()),
+            FileLog::Summary(count) => {
+                trace!("  Processed {} files (per-file logging limited to {})", count, self.limit)
+            }
+            FileLog::Quiet => {}
+        }
+    }
+}
+
+/// Virtual filesystem state for tracking layer changes
+#[derive(Debug, Default)]
 struct VirtualFilesystem {
-    files: HashMap<PathBuf, Option<FileEntry>>, // None means deleted by whiteout
+    files: BTreeMap<PathBuf, Option<FileEntry>>, // None means deleted by whiteout
+    /// Hard links by the (normalized) path they point to
+    hardlinks: HashMap<PathBuf, Vec<PathBuf>>,
+    /// Directories whose content from lower layers is hidden, by an opaque
+    /// whiteout or by being deleted and created again
+    opaque: BTreeSet<PathBuf>,
+}
+
+impl VirtualFilesystem {
+    /// Add, replace or (with `None`) delete the entry at `path`
+    ///
+    /// Hard links to a file that is replaced or deleted are first turned
+    /// into regular files holding its current content, as the link shares
+    /// the old inode rather than following the path.
+    fn insert(&mut self, path: PathBuf, entry: Option<FileEntry>) {
+        if matches!(self.files.get(&path), Some(Some(_))) {
+            self.detach_hardlinks(&path);
+        }
+
+        if let Some(target) = entry.as_ref().and_then(FileEntry::hardlink_target) {
+            self.hardlinks.entry(normalize_link_path(target)).or_default().push(path.clone());
+        }
+        match &entry {
+            // A directory created where a whiteout deleted one starts out empty
+            Some(entry) if entry.header.entry_type().is_dir() => {
+                if matches!(self.files.get(&path), Some(None)) {
+                    self.opaque.insert(path.clone());
+                }
+            }
+            _ => {
+                self.opaque.remove(&path);
+            }
+        }
+        self.files.insert(path, entry);
+    }
+
+    /// Whether `path` holds an entry that is not a directory
+    ///
+    /// A whiteout of such a path deletes only that entry. Anything else may
+    /// be a directory, possibly one implied by its contents alone, whose
+    /// whiteout deletes everything below it as well.
+    fn is_non_directory(&self, path: &Path) -> bool {
+        matches!(self.files.get(path), Some(Some(entry)) if !entry.header.entry_type().is_dir())
+    }
+
+    /// Materialize the hard links that still point to `target`
+    fn detach_hardlinks(&mut self, target: &Path) {
+        let Some(links) = self.hardlinks.remove(&normalize_link_path(target)) else {
+            return;
+        };
+        let Some(Some(target_entry)) = self.files.get(target).cloned() else {
+            return;
+        };
+
+        for link in links {
+            let Some(Some(entry)) = self.files.get_mut(&link) else {
+                continue;
+            };
+            // The link may have been replaced since it was indexed
+            if entry.hardlink_target().map(normalize_link_path) != Some(normalize_link_path(target)) {
+                continue;
+            }
+
+            let size = target_entry.data.size();
+            entry.header.set_entry_type(EntryType::Regular);
+            entry.header.set_size(size);
+            entry.header.as_old_mut().linkname.fill(0);
+            entry.link_name = None;
+            entry.data = target_entry.data.clone();
+            trace!("  Hard link {} to replaced {} kept as a regular file", link.display(), target.display());
+        }
+    }
+
+    /// Remove entries matching `exclude`, and everything below a matching directory
+    ///
+    /// The target of a symlink that is kept is kept as well, so excluding a
+    /// path never leaves a dangling link; hard links to a removed file
+    /// become regular files. Returns the entries and content bytes removed.
+    fn exclude(&mut self, exclude: &GlobSet) -> (usize, u64) {
+        // Keyed by normalized path, so link targets can be looked up
+        let mut excluded: HashMap<PathBuf, PathBuf> = self.files
+            .iter()
+            .filter(|(_, entry)| entry.is_some())
+            .map(|(path, _)| (normalize_link_path(path), path))
+            .filter(|(normalized, _)| {
+                normalized.ancestors().any(|path| !path.as_os_str().is_empty() && exclude.is_match(path))
+            })
+            .map(|(normalized, path)| (normalized, path.clone()))
+            .collect();
+
+        // Rescuing a target can keep another symlink, so repeat until nothing changes
+        loop {
+            let mut rescued = Vec::new();
+            for (path, entry) in &self.files {
+                let Some(target) = entry.as_ref().and_then(FileEntry::symlink_target) else {
+                    continue;
+                };
+                if excluded.contains_key(&normalize_link_path(path)) {
+                    continue;
+                }
+                let target = resolve_symlink_target(path, target);
+                rescued.extend(target.ancestors().filter(|path| excluded.contains_key(*path)).map(Path::to_path_buf));
+            }
+            if rescued.is_empty() {
+                break;
+            }
+            for path in rescued {
+                if let Some(path) = excluded.remove(&path) {
+                    warn!("Keeping excluded {} because a symlink points to it", path.display());
+                }
+            }
+        }
+
+        for path in excluded.values() {
+            self.detach_hardlinks(path);
+        }
+        self.opaque.retain(|path| !path.ancestors().any(|path| !path.as_os_str().is_empty() && exclude.is_match(path)));
+        let mut bytes = 0;
+        for path in excluded.values() {
+            if let Some(Some(entry)) = self.files.remove(path) {
+                trace!("  Excluded {}", path.display());
+                bytes += entry.data.size();
+            }
+        }
+        (excluded.len(), bytes)
+    }
+}
+
+impl FileEntry {
+    /// Target of a hard link entry
+    fn hardlink_target(&self) -> Option<&Path> {
+        if self.header.entry_type() == EntryType::Link {
+            self.link_name.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// Target of a symlink entry, as written in the link
+    fn symlink_target(&self) -> Option<&Path> {
+        if self.header.entry_type() == EntryType::Symlink {
+            self.link_name.as_deref()
+        } else {
+            None
+        }
+    }
+}
+
+/// Archive path without leading `./` or `/`, for comparing link targets
+fn normalize_link_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| !matches!(component, Component::CurDir | Component::RootDir))
+        .collect()
+}
+
+/// Archive path that a symlink at `link` points to, resolved lexically
+///
+/// Relative targets are taken from the link's directory and `..` cannot
+/// climb above the root; other symlinks along the way are not followed.
+pub(crate) fn resolve_symlink_target(link: &Path, target: &Path) -> PathBuf {
+    let mut resolved = if target.has_root() {
+        PathBuf::new()
+    } else {
+        normalize_link_path(link).parent().map(Path::to_path_buf).unwrap_or_default()
+    };
+    for component in target.components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(name) => resolved.push(name),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    resolved
+}
+
+/// Split `sha256:<hex>` into its algorithm and hex parts; a bare hex string has no algorithm
+fn split_digest(digest: &str) -> (Option<&str>, &str) {
+    match digest.split_once(':') {
+        Some((algorithm, hex)) => (Some(algorithm), hex),
+        None => (None, digest),
+    }
+}
+
+/// Compile glob patterns into the matcher used by `MergeOptions::exclude`
+///
+/// Patterns match archive paths without a leading `/`, so `/var/cache/**`
+/// and `var/cache/**` are the same. `*` stays within one directory while
+/// `**` spans any number of them.
+pub fn build_exclude_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern.trim_start_matches('/'))
+            .literal_separator(true)
+            .build()
+            .map_err(|e| SquashError::InvalidInput(format!("Invalid exclude pattern {}: {}", pattern, e)))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map_err(|e| SquashError::InvalidInput(format!("Invalid exclude patterns: {}", e)))
+}
+
+/// Options controlling how merged layers are written
+#[derive(Debug, Clone)]
+pub struct MergeOptions {
+    /// Encoding used for paths and link names that do not fit in a tar header
+    pub long_pathname_mode: LongPathnameMode,
+    /// Copy headers of unmodified entries verbatim instead of rebuilding them
+    pub skip_cksum_recompute: bool,
+    /// Files logged individually per merge phase before switching to summaries
+    pub file_log_limit: usize,
+    /// Directory for merge checkpoints; when set, progress is saved there after
+    /// each layer and a checkpoint matching the same layers is resumed
+    pub checkpoint_dir: Option<PathBuf>,
+    /// Layer tars read in parallel; 0 uses one thread per available core
+    pub jobs: usize,
+    /// Leave intermediate files such as a failed merged layer on disk
+    pub keep_temp: bool,
+    /// Files up to this size are held in memory; larger ones are streamed
+    /// from their layer tar when the merged layer is written. 0 streams
+    /// every file from disk.
+    pub mem_file_threshold: u64,
+    /// Fail on entries whose path climbs out of the layer root with `..`
+    /// instead of skipping them with a warning
+    pub strict_paths: bool,
+    /// Paths left out of the merged layer, see [`build_exclude_set`]
+    pub exclude: GlobSet,
+    /// Assemble merged layers in memory instead of writing them to the temp directory
+    pub in_memory: bool,
+    /// Reject a merged layer larger than the layers it replaces instead of warning
+    pub fail_on_grow: bool,
+    /// Checked before each layer is read and applied; once set the merge
+    /// stops with [`SquashError::Cancelled`]
+    pub cancellation: Cancellation,
+    /// Unix time that later entry mtimes are clamped to and that merged
+    /// history entries are dated at, for byte-identical output across runs
+    /// (see `SOURCE_DATE_EPOCH`); `None` keeps mtimes and dates merges now
+    pub source_date_epoch: Option<u64>,
+    /// `created_by` of merged history entries; `None` writes
+    /// `squash: merged <n> layers`
+    pub history_comment: Option<String>,
+    /// `author` of merged history entries
+    pub history_author: Option<String>,
+    /// Level that layers are compressed at on output: 0 to 9 for gzip,
+    /// 1 to 19 for zstd; `None` uses the format's default
+    pub compression_level: Option<u32>,
+}
+
+impl MergeOptions {
+    /// Number of layers read at once, resolving 0 to the available cores
+    pub fn effective_jobs(&self) -> usize {
+        match self.jobs {
+            0 => std::thread::available_parallelism().map_or(1, |cores| cores.get()),
+            jobs => jobs,
+        }
+    }
+
+    /// Write the plain layer tar read from `input` to `output` with `compression`
+    ///
+    /// Uses `compression_level`; zstd needs the `zstd` feature.
+    pub fn compress_layer(&self, compression: LayerCompression, mut input: impl Read, output: &mut File) -> Result<()> {
+        match compression {
+            LayerCompression::None => {
+                std::io::copy(&mut input, output)?;
+            }
+            LayerCompression::Gzip => {
+                let level = self.compression_level.map_or_else(Compression::default, Compression::new);
+                let mut encoder = GzEncoder::new(output, level);
+                std::io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?;
+            }
+            LayerCompression::Zstd => Zstd::new().with_level(self.compression_level).compress(input, output)?,
+        }
+        Ok(())
+    }
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        MergeOptions {
+            long_pathname_mode: LongPathnameMode::default(),
+            skip_cksum_recompute: false,
+            file_log_limit: DEFAULT_FILE_LOG_LIMIT,
+            checkpoint_dir: None,
+            jobs: 0,
+            keep_temp: false,
+            mem_file_threshold: DEFAULT_MEM_FILE_THRESHOLD,
+            strict_paths: false,
+            exclude: GlobSet::empty(),
+            in_memory: false,
+            fail_on_grow: false,
+            cancellation: Cancellation::default(),
+            source_date_epoch: None,
+            history_comment: None,
+            history_author: None,
+            compression_level: None,
+        }
+    }
 }
 
 /// Handles merging of Docker image layers
@@ -59,40 +563,37 @@ pub struct LayerMerger {
     pub layers: Vec<LayerInfo>,
     /// Temporary directory for intermediate files
     pub temp_dir: PathBuf,
+    /// Options applied when writing the merged layer
+    pub options: MergeOptions,
 }
 
 impl LayerMerger {
     pub fn new(layers: Vec<LayerInfo>, temp_dir: PathBuf) -> Self {
-        LayerMerger { layers, temp_dir }
+        LayerMerger {
+            layers,
+            temp_dir,
+            options: MergeOptions::default(),
+        }
     }
 
-    /// Stream data from a large file stored on disk
-    /// Reserved for future streaming implementation
-    #[allow(dead_code)]
-    fn stream_file_data(&self, source_tar: &Path, offset: u64, size: u64, writer: &mut dyn Write) -> Result<()> {
-        let mut file = File::open(source_tar)?;
-        file.seek(SeekFrom::Start(offset))?;
-
-        let mut remaining = size;
-        let mut buffer = [0; 8192];
-
-        while remaining > 0 {
-            let to_read = std::cmp::min(buffer.len() as u64, remaining) as usize;
-            let bytes_read = file.read(&mut buffer[..to_read])?;
-
-            if bytes_read == 0 {
-                break;
-            }
-
-            writer.write_all(&buffer[..bytes_read])?;
-            remaining -= bytes_read as u64;
-        }
+    /// Replace the merge options
+    pub fn with_options(mut self, options: MergeOptions) -> Self {
+        self.options = options;
+        self
+    }
 
-        Ok(())
+    /// Reader over the data of a large file left in its source tar
+    fn open_file_data(source_tar: &Path, offset: u64, size: u64) -> Result<impl Read> {
+        let mut file = File::open(source_tar).map_err(|source| SquashError::FileNotFound {
+            path: source_tar.to_path_buf(),
+            source,
+        })?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(file.take(size))
     }
     
     /// Merge the specified number of latest layers
-    pub fn merge_latest_layers(&self, count: usize) -> Result<LayerInfo> {
+    pub fn merge_latest_layers(&self, count: usize) -> Result<(LayerInfo, LayerMergeStats)> {
         if count == 0 {
             return Err(SquashError::InvalidInput(
                 "Cannot merge 0 layers".to_string()
@@ -109,21 +610,160 @@ impl LayerMerger {
         // Get the layers to merge (latest n layers)
         let layers_to_merge = &self.layers[self.layers.len() - count..];
         
-        println!("Merging {} layers:", count);
+        info!("Merging {} layers:", count);
         for layer in layers_to_merge {
-            println!("  - {}", layer.digest);
+            debug!("  - {}", layer.digest);
         }
         
-        self.merge_layers(layers_to_merge)
+        self.merge_layers(layers_to_merge, count < self.layers.len(), &mut NoopObserver)
     }
     
+    /// Merge the specified number of earliest layers
+    pub fn merge_earliest_layers(&self, count: usize) -> Result<(LayerInfo, LayerMergeStats)> {
+        self.merge_earliest_layers_with_observer(count, &mut NoopObserver)
+    }
+
+    /// Merge the earliest `count` layers, reporting progress to `observer`
+    pub fn merge_earliest_layers_with_observer(
+        &self,
+        count: usize,
+        observer: &mut dyn ProgressObserver,
+    ) -> Result<(LayerInfo, LayerMergeStats)> {
+        if count == 0 {
+            return Err(SquashError::InvalidInput(
+                "Cannot merge 0 layers".to_string()
+            ));
+        }
+
+        if count > self.layers.len() {
+            return Err(SquashError::InvalidInput(format!(
+                "Cannot merge {} layers, only {} layers available",
+                count, self.layers.len()
+            )));
+        }
+
+        // Get the layers to merge (earliest n layers)
+        let layers_to_merge = &self.layers[..count];
+
+        info!("Merging earliest {} layers:", count);
+        for layer in layers_to_merge {
+            debug!("  - {}", layer.digest);
+        }
+
+        self.merge_layers(layers_to_merge, false, observer)
+    }
+
     /// Merge layers from a specific layer ID to the latest
-    pub fn merge_from_layer_id(&self, layer_id: &str) -> Result<LayerInfo> {
+    pub fn merge_from_layer_id(&self, layer_id: &str) -> Result<(LayerInfo, LayerMergeStats)> {
+        let start_index = self.find_layer_index(layer_id)?;
+        
+        let layers_to_merge = &self.layers[start_index..];
+        
+        info!("Merging layers from {} to latest:", layer_id);
+        for layer in layers_to_merge {
+            debug!("  - {}", layer.digest);
+        }
+        
+        self.merge_layers(layers_to_merge, start_index > 0, &mut NoopObserver)
+    }
+
+    /// Merge as many of the latest layers as fit within `budget` bytes combined
+    ///
+    /// Layer sizes are those of the stored layer tars. The number of layers
+    /// merged is the `input_layers` count of the returned stats.
+    pub fn merge_up_to_size(&self, budget: u64) -> Result<(LayerInfo, LayerMergeStats)> {
+        let count = self.trailing_layers_within_size(budget)?;
+        info!("Merging the latest {} layers, within {} bytes", count, budget);
+        self.merge_layers(&self.layers[self.layers.len() - count..], count < self.layers.len(), &mut NoopObserver)
+    }
+
+    /// Number of latest layers whose sizes sum to at most `budget` bytes
+    ///
+    /// Fails when the latest layer alone is over the budget.
+    pub fn trailing_layers_within_size(&self, budget: u64) -> Result<usize> {
+        let Some(latest) = self.layers.last() else {
+            return Err(SquashError::InvalidInput("No layers to merge".to_string()));
+        };
+
+        let mut total: u64 = 0;
+        let count = self.layers
+            .iter()
+            .rev()
+            .take_while(|layer| {
+                total = total.saturating_add(layer.size);
+                total <= budget
+            })
+            .count();
+
+        if count == 0 {
+            return Err(SquashError::InvalidInput(format!(
+                "The latest layer {} is {} bytes, over the merged size budget of {} bytes",
+                latest.digest, latest.size, budget
+            )));
+        }
+        Ok(count)
+    }
+
+    /// Merge the contiguous layers `start..=end` (0 is the base layer)
+    pub fn merge_range(&self, start: usize, end: usize) -> Result<(LayerInfo, LayerMergeStats)> {
+        self.merge_range_with_observer(start, end, &mut NoopObserver)
+    }
+
+    /// Merge the contiguous layers `start..=end`, reporting progress to `observer`
+    pub fn merge_range_with_observer(
+        &self,
+        start: usize,
+        end: usize,
+        observer: &mut dyn ProgressObserver,
+    ) -> Result<(LayerInfo, LayerMergeStats)> {
+        if start > end {
+            return Err(SquashError::InvalidInput(format!(
+                "Invalid layer range: start {} is after end {}",
+                start, end
+            )));
+        }
+
+        if end >= self.layers.len() {
+            return Err(SquashError::InvalidInput(format!(
+                "Invalid layer range: end {} is out of bounds, only {} layers available",
+                end, self.layers.len()
+            )));
+        }
+
+        let layers_to_merge = &self.layers[start..=end];
+
+        info!("Merging layers {} to {}:", start, end);
+        for layer in layers_to_merge {
+            debug!("  - {}", layer.digest);
+        }
+
+        self.merge_layers(layers_to_merge, start > 0, observer)
+    }
+
+    /// Resolve a layer reference given as an index or a layer ID prefix
+    pub fn resolve_layer_ref(&self, layer_ref: &str) -> Result<usize> {
+        match layer_ref.parse::<usize>() {
+            Ok(index) if index < self.layers.len() => Ok(index),
+            Ok(index) => Err(SquashError::InvalidInput(format!(
+                "Layer index {} is out of bounds, only {} layers available",
+                index, self.layers.len()
+            ))),
+            Err(_) => self.find_layer_index(layer_ref),
+        }
+    }
+
+    /// Index of the first layer whose digest starts with `layer_id`
+    ///
+    /// The `sha256:` prefix is optional, so the short IDs shown by
+    /// `docker history` match as well as full digests.
+    pub fn find_layer_index(&self, layer_id: &str) -> Result<usize> {
+        let (algorithm, hex) = split_digest(layer_id);
+
         // Validate layer ID length to avoid ambiguous matches
-        if layer_id.len() < 8 {
+        if hex.len() < 8 {
             return Err(SquashError::InvalidInput(format!(
-                "Layer ID must be at least 8 characters long, got: {}",
-                layer_id.len()
+                "Layer ID must be at least 8 characters long, not counting the algorithm prefix, got: {}",
+                hex.len()
             )));
         }
 
@@ -131,7 +771,10 @@ impl LayerMerger {
         let matching_layers: Vec<_> = self.layers
             .iter()
             .enumerate()
-            .filter(|(_, layer)| layer.digest.starts_with(layer_id))
+            .filter(|(_, layer)| {
+                let (layer_algorithm, layer_hex) = split_digest(&layer.digest);
+                algorithm.is_none_or(|algorithm| Some(algorithm) == layer_algorithm) && layer_hex.starts_with(hex)
+            })
             .collect();
 
         if matching_layers.is_empty() {
@@ -139,27 +782,26 @@ impl LayerMerger {
         }
 
         if matching_layers.len() > 1 {
-            println!("Warning: Multiple layers match '{}'. Using the first match:", layer_id);
+            warn!("Multiple layers match '{}'. Using the first match:", layer_id);
             for (_, layer) in &matching_layers {
-                println!("  - {}", layer.digest);
+                warn!("  - {}", layer.digest);
             }
         }
 
-        let start_index = matching_layers[0].0;
-        
-        let layers_to_merge = &self.layers[start_index..];
-        
-        println!("Merging layers from {} to latest:", layer_id);
-        for layer in layers_to_merge {
-            println!("  - {}", layer.digest);
-        }
-        
-        self.merge_layers(layers_to_merge)
+        Ok(matching_layers[0].0)
     }
     
     /// Merge a slice of layers into a single layer
-    fn merge_layers(&self, layers: &[LayerInfo]) -> Result<LayerInfo> {
-        println!("Starting layer merge process...");
+    ///
+    /// With `lower_layers` the merged layer sits on top of other layers, so
+    /// what the merged ones deleted from below is written as whiteouts.
+    fn merge_layers(
+        &self,
+        layers: &[LayerInfo],
+        lower_layers: bool,
+        observer: &mut dyn ProgressObserver,
+    ) -> Result<(LayerInfo, LayerMergeStats)> {
+        debug!("Starting layer merge process...");
 
         // Validate temp directory exists and is writable
         if !self.temp_dir.exists() {
@@ -167,134 +809,447 @@ impl LayerMerger {
         }
 
         // Initialize virtual filesystem
-        let mut vfs = VirtualFilesystem {
-            files: HashMap::new(),
+        let mut vfs = VirtualFilesystem::default();
+        let mut stats = LayerMergeStats {
+            input_layers: layers.len(),
+            input_size: layers.iter().map(|layer| layer.size).sum(),
+            ..LayerMergeStats::default()
+        };
+
+        // Process each layer in order, skipping those a checkpoint already holds
+        let mut file_log = FileLogThrottle::new(self.options.file_log_limit);
+        let checkpoint = self.options.checkpoint_dir.as_deref().map(|dir| MergeCheckpoint::paths(dir, layers));
+        let completed = match &checkpoint {
+            Some(paths) => self.restore_checkpoint(paths, layers, &mut vfs, &mut file_log)?,
+            None => 0,
         };
 
-        // Process each layer in order
-        for (i, layer) in layers.iter().enumerate() {
-            println!("Processing layer {}/{}: {}", i + 1, layers.len(), layer.digest);
+        // Validate that the layer tar files exist
+        let pending = &layers[completed..];
+        if let Some(missing) = pending.iter().find(|layer| !layer.is_in_memory() && !layer.tar_path.exists()) {
+            return Err(SquashError::InvalidInput(format!(
+                "Layer tar file does not exist: {}",
+                missing.tar_path.display()
+            )));
+        }
+
+        // Read up to `jobs` layers at once, then apply them in order so later
+        // layers still override and white out earlier ones
+        let jobs = self.options.effective_jobs().min(pending.len()).max(1);
+        let pool = if jobs > 1 {
+            debug!("Reading up to {} layers in parallel", jobs);
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs)
+                .build()
+                .map_err(|e| SquashError::InvalidInput(format!("Failed to start {} worker threads: {}", jobs, e)))?;
+            Some(pool)
+        } else {
+            None
+        };
+
+        for (chunk_index, chunk) in pending.chunks(jobs).enumerate() {
+            self.options.cancellation.check()?;
+            let read: Vec<Result<Vec<LayerChange>>> = match &pool {
+                Some(pool) => pool.install(|| chunk.par_iter().map(|layer| self.read_layer(layer)).collect()),
+                None => chunk.iter().map(|layer| self.read_layer(layer)).collect(),
+            };
 
-            // Validate that the layer tar file exists
-            if !layer.tar_path.exists() {
-                return Err(SquashError::InvalidInput(format!(
-                    "Layer tar file does not exist: {}",
-                    layer.tar_path.display()
-                )));
+            for (offset, (layer, changes)) in chunk.iter().zip(read).enumerate() {
+                self.options.cancellation.check()?;
+                let i = completed + chunk_index * jobs + offset;
+                debug!("Processing layer {}/{}: {}", i + 1, layers.len(), layer.digest);
+                observer.on_layer_start(i + 1, layers.len(), layer);
+
+                self.apply_layer_changes(&mut vfs, changes?, observer, &mut file_log, &mut stats);
+                if let Some(paths) = &checkpoint {
+                    self.save_checkpoint(paths, layers, i + 1, &vfs)?;
+                }
+                observer.on_layer_done(i + 1, layers.len(), layer);
             }
+        }
 
-            self.process_layer_tar(&layer.tar_path, &mut vfs)?;
+        if !self.options.exclude.is_empty() {
+            let (files, bytes) = vfs.exclude(&self.options.exclude);
+            info!("Excluded {} files ({} bytes) from the merged layer", files, bytes);
+            stats.files_excluded = files;
+            stats.bytes_excluded = bytes;
         }
 
         // Create the merged layer tar file with unique name to avoid conflicts
         let unique_id = Uuid::new_v4();
         let merged_tar_path = self.temp_dir.join(format!("merged_layer_{}.tar", unique_id));
-        self.create_merged_tar_from_vfs(&vfs, &merged_tar_path)?;
+        let (digest, size, data) = if self.options.in_memory {
+            let content = self.write_vfs_tar(&vfs, Vec::new(), lower_layers)?;
+            let digest = format!("sha256:{:x}", Sha256::digest(&content));
+            (digest, content.len() as u64, Some(Arc::from(content)))
+        } else {
+            self.create_merged_tar_from_vfs(&vfs, &merged_tar_path, lower_layers)?;
 
-        // Calculate the digest of the merged layer
-        let digest = self.calculate_layer_digest(&merged_tar_path).inspect_err(|_| {
-            // Clean up the temporary file on error
-            let _ = std::fs::remove_file(&merged_tar_path);
-        })?;
+            // Calculate the digest of the merged layer
+            let digest = self.calculate_layer_digest(&merged_tar_path).inspect_err(|_| {
+                // Clean up the temporary file on error unless it is kept for inspection
+                if !self.options.keep_temp {
+                    let _ = std::fs::remove_file(&merged_tar_path);
+                }
+            })?;
+            (digest, std::fs::metadata(&merged_tar_path)?.len(), None)
+        };
+
+        info!("Layer merge completed. Final size: {} bytes", size);
+        stats.merged_size = size;
 
-        let size = std::fs::metadata(&merged_tar_path)?.len();
+        if let Some(paths) = &checkpoint {
+            paths.remove();
+        }
 
-        println!("Layer merge completed. Final size: {} bytes", size);
+        for file_entry in vfs.files.values().flatten() {
+            stats.files_written += 1;
+            stats.total_bytes += file_entry.data.size();
+        }
 
-        Ok(LayerInfo {
+        let merged = LayerInfo {
             digest,
             size,
             tar_path: merged_tar_path,
-        })
+            data,
+            archive_offset: None,
+        };
+        observer.on_complete(&merged);
+
+        Ok((merged, stats))
     }
     
     /// Process a layer tar file and update the virtual filesystem
-    fn process_layer_tar(&self, tar_path: &Path, vfs: &mut VirtualFilesystem) -> Result<()> {
-        let file = File::open(tar_path)?;
-        let mut archive = Archive::new(file);
-
-        for entry_result in archive.entries()? {
-            let mut entry = entry_result?;
-            let header = entry.header().clone();
-            let path = entry.path()?.to_path_buf();
-
-            // Validate path to prevent directory traversal attacks
-            if path.to_string_lossy().contains("..") {
-                println!("Warning: Skipping potentially unsafe path: {}", path.display());
+    ///
+    /// Whiteouts replayed this way are not counted in the merge stats.
+    fn process_layer_tar(
+        &self,
+        tar_path: &Path,
+        vfs: &mut VirtualFilesystem,
+        observer: &mut dyn ProgressObserver,
+        file_log: &mut FileLogThrottle,
+    ) -> Result<()> {
+        let changes = self.read_layer_tar(tar_path, LayerReader::File(File::open(tar_path)?), 0, None)?;
+        self.apply_layer_changes(vfs, changes, observer, file_log, &mut LayerMergeStats::default());
+        Ok(())
+    }
+
+    /// Read the changes `layer` makes, from memory when it is held there
+    fn read_layer(&self, layer: &LayerInfo) -> Result<Vec<LayerChange>> {
+        self.read_layer_tar(&layer.tar_path, layer.open()?, layer.archive_offset.unwrap_or(0), layer.data.as_ref())
+    }
+
+    /// Read the changes a layer tar makes, in archive order
+    ///
+    /// Reading touches no shared state, so several layers can be read in
+    /// parallel and applied in order afterwards. Large files are referenced
+    /// in `tar_path` at `base_offset` plus their position in `reader`, or in
+    /// `data` when the layer is held in memory.
+    fn read_layer_tar(
+        &self,
+        tar_path: &Path,
+        reader: LayerReader,
+        base_offset: u64,
+        data: Option<&Arc<[u8]>>,
+    ) -> Result<Vec<LayerChange>> {
+        let mut archive = Archive::new(reader);
+        let mut changes = Vec::new();
+
+        for entry_result in archive.entries().map_err(SquashError::TarError)? {
+            let mut entry = entry_result.map_err(SquashError::TarError)?;
+            let mut header = entry.header().clone();
+            let path = entry.path().map_err(SquashError::TarError)?.to_path_buf();
+
+            // Pre-POSIX tars mark directories only by a trailing slash on a regular
+            // entry; write them back as directories so extraction does not make files
+            if header.entry_type() == EntryType::Regular && entry.path_bytes().ends_with(b"/") {
+                header.set_entry_type(EntryType::Directory);
+                header.set_cksum();
+            }
+            let link_name = entry.link_name().map_err(SquashError::TarError)?.map(|link| link.into_owned());
+            let pax = preserved_pax_records(&mut entry)?;
+
+            // Validate path to prevent directory traversal attacks; names such as
+            // `.wh..wh..opq` merely contain dots and are not parent references
+            if path.components().any(|component| component == Component::ParentDir) {
+                if self.options.strict_paths {
+                    return Err(SquashError::InvalidInput(format!(
+                        "Layer {} contains a directory traversal path: {}",
+                        tar_path.display(),
+                        path.display()
+                    )));
+                }
+                warn!("Skipping potentially unsafe path: {}", path.display());
                 continue;
             }
 
-            let entry_size = header.size()?;
+            // A PAX `size` record overrides the header for very large entries
+            let entry_size = entry.size();
 
-            // Choose storage strategy based on file size
-            let file_data = if entry_size <= MAX_MEMORY_FILE_SIZE {
+            // Choose storage strategy based on file size; sparse entries are
+            // not stored contiguously, so they are always expanded in memory.
+            // Directories carry no content, whatever size their header claims
+            let file_data = if header.entry_type().is_dir() {
+                FileData::InMemory(Vec::new())
+            } else if entry_size <= self.options.mem_file_threshold || header.entry_type() == EntryType::GNUSparse {
                 // Small files: store in memory
                 let mut data = Vec::new();
-                entry.read_to_end(&mut data)?;
+                entry.read_to_end(&mut data).map_err(SquashError::TarError)?;
                 FileData::InMemory(data)
             } else {
                 // Large files: store reference to source
-                println!("  Large file detected ({}MB), using disk reference", entry_size / (1024 * 1024));
-                FileData::OnDisk {
-                    source_tar: tar_path.to_path_buf(),
-                    offset: 0, // We'll need to track this properly in a real implementation
-                    size: entry_size,
+                debug!("  Large file detected ({}MB), using a reference to its layer", entry_size / (1024 * 1024));
+                match data {
+                    Some(layer) => FileData::Shared {
+                        layer: layer.clone(),
+                        offset: entry.raw_file_position(),
+                        size: entry_size,
+                    },
+                    None => FileData::OnDisk {
+                        source_tar: tar_path.to_path_buf(),
+                        offset: base_offset + entry.raw_file_position(),
+                        size: entry_size,
+                    },
                 }
             };
 
-            // Handle whiteout files (Docker deletion markers)
-            if let Some(filename) = path.file_name() {
-                if let Some(filename_str) = filename.to_str() {
-                    if let Some(original_name) = filename_str.strip_prefix(".wh.") {
-                        if filename_str == ".wh..wh..opq" {
-                            // Opaque whiteout - remove all files in this directory
-                            let dir_path = path.parent().unwrap_or_else(|| Path::new(""));
-                            self.apply_opaque_whiteout(vfs, dir_path);
-                        } else {
-                            // Regular whiteout - remove specific file
-                            let original_path = path.parent()
-                                .unwrap_or_else(|| Path::new(""))
-                                .join(original_name);
-
-                            println!("  Whiteout: removing {}", original_path.display());
-                            vfs.files.insert(original_path, None);
-                        }
-                        continue;
-                    }
+            let whiteout = entry_whiteout(&mut entry, &path)?;
+            let file_entry = match whiteout {
+                Some(Whiteout::Opaque(_) | Whiteout::File(_)) => None,
+                _ => Some(FileEntry {
+                    header,
+                    link_name,
+                    pax,
+                    data: file_data,
+                }),
+            };
+            changes.push(LayerChange {
+                path,
+                size: entry_size,
+                whiteout,
+                entry: file_entry,
+            });
+        }
+
+        Ok(changes)
+    }
+
+    /// Apply the changes read from one layer to the virtual filesystem
+    fn apply_layer_changes(
+        &self,
+        vfs: &mut VirtualFilesystem,
+        changes: Vec<LayerChange>,
+        observer: &mut dyn ProgressObserver,
+        file_log: &mut FileLogThrottle,
+        stats: &mut LayerMergeStats,
+    ) {
+        // Entries from this layer survive its own opaque whiteouts, whatever their order
+        let mut layer_paths = HashSet::new();
+
+        for change in changes {
+            observer.on_bytes(change.size);
+
+            // Handle whiteout files (Docker deletion markers)
+            match &change.whiteout {
+                Some(Whiteout::Opaque(dir_path)) => {
+                    // Opaque whiteout - hide everything lower layers put in this directory
+                    self.apply_opaque_whiteout(vfs, dir_path, &layer_paths);
+                    stats.opaque_dirs_cleared += 1;
+                    continue;
+                }
+                Some(Whiteout::File(original_path)) => {
+                    // Regular whiteout - remove the path, and its contents when it is a directory
+                    file_log.trace(|| format!("  Whiteout: removing {}", original_path.display()));
+                    if !vfs.is_non_directory(original_path) {
+                        self.remove_descendants(vfs, original_path, &layer_paths);
+                    }
+                    vfs.insert(original_path.clone(), None);
+                    stats.files_whiteouted += 1;
+                    continue;
+                }
+                Some(Whiteout::OpaqueDirectory(dir_path)) => {
+                    // Overlayfs opaque directory - clear lower content, then add the directory
+                    self.apply_opaque_whiteout(vfs, dir_path, &layer_paths);
+                    stats.opaque_dirs_cleared += 1;
                 }
+                None => {}
             }
 
-            // Add or update file in virtual filesystem
-            let size_display = match &file_data {
-                FileData::InMemory(data) => data.len(),
-                FileData::OnDisk { size, .. } => *size as usize,
+            let Some(file_entry) = change.entry else {
+                continue;
             };
-            println!("  Adding file: {} ({} bytes)", path.display(), size_display);
+            let path = change.path;
 
-            let file_entry = FileEntry {
-                header,
-                data: file_data,
-            };
-            vfs.files.insert(path, Some(file_entry));
+            // Add or update file in virtual filesystem
+            file_log.trace(|| format!("  Adding file: {} ({} bytes)", path.display(), file_entry.data.size()));
+
+            if let FileData::InMemory(data) = &file_entry.data {
+                if file_entry.header.entry_type().is_file() {
+                    observer.on_entry(&path, data);
+                }
+            }
+
+            layer_paths.insert(path.clone());
+            vfs.insert(path, Some(file_entry));
         }
+    }
 
-        Ok(())
+    /// Apply opaque whiteout - remove every entry strictly below `dir_path`
+    ///
+    /// The directory entry itself is kept, as are entries added by the
+    /// layer carrying the whiteout (`current_layer`). Paths are compared by
+    /// component, so `var/log` does not cover `var/log2`.
+    fn apply_opaque_whiteout(&self, vfs: &mut VirtualFilesystem, dir_path: &Path, current_layer: &HashSet<PathBuf>) {
+        self.remove_descendants(vfs, dir_path, current_layer);
+        vfs.opaque.insert(dir_path.to_path_buf());
+        trace!("  Opaque whiteout: cleared directory {}", dir_path.display());
     }
 
-    /// Apply opaque whiteout - remove all files in the specified directory
-    fn apply_opaque_whiteout(&self, vfs: &mut VirtualFilesystem, dir_path: &Path) {
-        // Use proper path comparison instead of string comparison
-        vfs.files.retain(|path, _| {
-            // Keep files that are not under the directory being cleared
-            !path.starts_with(dir_path) || path == dir_path
-        });
-        println!("  Opaque whiteout: cleared directory {}", dir_path.display());
+    /// Remove every entry strictly below `dir_path` that `current_layer` did not add
+    ///
+    /// Paths are ordered component by component, so the descendants of
+    /// `dir_path` are the keys right after it and only those are visited.
+    fn remove_descendants(&self, vfs: &mut VirtualFilesystem, dir_path: &Path, current_layer: &HashSet<PathBuf>) {
+        let below = (Bound::Excluded(dir_path), Bound::Unbounded);
+        let hidden = |path: &&PathBuf| path.starts_with(dir_path);
+
+        let removed: Vec<PathBuf> = vfs.files
+            .range::<Path, _>(below)
+            .map(|(path, _)| path)
+            .take_while(hidden)
+            .filter(|path| !current_layer.contains(*path))
+            .cloned()
+            .collect();
+        if !vfs.hardlinks.is_empty() {
+            for path in &removed {
+                vfs.detach_hardlinks(path);
+            }
+        }
+        for path in &removed {
+            vfs.files.remove(path);
+        }
+
+        let removed: Vec<PathBuf> = vfs.opaque
+            .range::<Path, _>(below)
+            .take_while(hidden)
+            .filter(|path| !current_layer.contains(*path))
+            .cloned()
+            .collect();
+        for path in &removed {
+            vfs.opaque.remove(path);
+        }
     }
     
+    /// Continue from the checkpoint at `paths`, returning how many layers it covers
+    ///
+    /// A checkpoint whose layers, sizes or snapshot do not match is ignored
+    /// and the merge starts over.
+    fn restore_checkpoint(
+        &self,
+        paths: &CheckpointPaths,
+        layers: &[LayerInfo],
+        vfs: &mut VirtualFilesystem,
+        file_log: &mut FileLogThrottle,
+    ) -> Result<usize> {
+        let Ok(content) = std::fs::read_to_string(&paths.state) else {
+            return Ok(0);
+        };
+
+        let checkpoint = match serde_json::from_str::<MergeCheckpoint>(&content) {
+            Ok(checkpoint) if checkpoint.matches(layers) => checkpoint,
+            _ => {
+                warn!("Ignoring merge checkpoint {} that does not match these layers", paths.state.display());
+                return Ok(0);
+            }
+        };
+
+        if !paths.snapshot.exists() || calculate_diff_id(&paths.snapshot)? != checkpoint.snapshot_digest {
+            warn!("Ignoring merge checkpoint {} with a missing or modified snapshot", paths.state.display());
+            return Ok(0);
+        }
+
+        // Large files stay in the snapshot and are read from it when the merged
+        // layer is written; later checkpoints replace the snapshot, so replay a
+        // link to it that lives until the merge completes
+        let _ = std::fs::remove_file(&paths.restored);
+        if std::fs::hard_link(&paths.snapshot, &paths.restored).is_err() {
+            std::fs::copy(&paths.snapshot, &paths.restored)?;
+        }
+
+        info!("Resuming merge after {} of {} layers", checkpoint.completed, layers.len());
+        self.process_layer_tar(&paths.restored, vfs, &mut NoopObserver, file_log)?;
+        Ok(checkpoint.completed)
+    }
+
+    /// Record that the first `completed` layers are folded into `vfs`
+    fn save_checkpoint(
+        &self,
+        paths: &CheckpointPaths,
+        layers: &[LayerInfo],
+        completed: usize,
+        vfs: &VirtualFilesystem,
+    ) -> Result<()> {
+        std::fs::create_dir_all(&paths.dir)?;
+
+        // Write under temporary names so an interruption never leaves a torn checkpoint
+        let snapshot_tmp = paths.snapshot.with_extension("tar.partial");
+        self.write_vfs_tar(vfs, File::create(&snapshot_tmp)?, true)?;
+        let checkpoint = MergeCheckpoint {
+            layers: layers.iter().map(|layer| (layer.digest.clone(), layer.size)).collect(),
+            completed,
+            snapshot_digest: calculate_diff_id(&snapshot_tmp)?,
+        };
+        let state_tmp = paths.state.with_extension("json.partial");
+        std::fs::write(&state_tmp, serde_json::to_vec(&checkpoint)?)?;
+
+        std::fs::rename(&snapshot_tmp, &paths.snapshot)?;
+        std::fs::rename(&state_tmp, &paths.state)?;
+        debug!("Checkpointed merge after {} of {} layers", completed, layers.len());
+        Ok(())
+    }
+
     /// Create a tar file from the virtual filesystem
-    fn create_merged_tar_from_vfs(&self, vfs: &VirtualFilesystem, output_path: &Path) -> Result<()> {
-        let output_file = File::create(output_path)?;
-        let mut builder = Builder::new(output_file);
+    fn create_merged_tar_from_vfs(&self, vfs: &VirtualFilesystem, output_path: &Path, whiteouts: bool) -> Result<()> {
+        self.write_vfs_tar(vfs, File::create(output_path)?, whiteouts)?;
+        Ok(())
+    }
+
+    /// Write the virtual filesystem as a tar to `output` and hand it back
+    ///
+    /// Deleted paths are skipped, or written as `.wh.` whiteouts when
+    /// `whiteouts` is set, along with `.wh..wh..opq` markers for opaque
+    /// directories. The tar then also hides what layers below the merged
+    /// ones hold, and a checkpoint can be replayed into the same state.
+    fn write_vfs_tar<W: Write>(&self, vfs: &VirtualFilesystem, output: W, whiteouts: bool) -> Result<W> {
+        let mut builder = Builder::new(output);
+
+        if whiteouts {
+            let mut whiteout_paths: Vec<PathBuf> = vfs.files
+                .iter()
+                .filter(|(_, entry)| entry.is_none())
+                .map(|(path, _)| {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    path.with_file_name(format!(".wh.{}", name))
+                })
+                .chain(vfs.opaque.iter().map(|dir| dir.join(".wh..wh..opq")))
+                .collect();
+            whiteout_paths.sort();
+
+            for whiteout_path in whiteout_paths {
+                let mut header = Header::new_gnu();
+                header.set_mode(0o644);
+                header.set_size(0);
+                append_entry_with_pax(
+                    &mut builder,
+                    &mut header,
+                    &whiteout_path,
+                    None,
+                    &[],
+                    &[] as &[u8],
+                    self.options.long_pathname_mode,
+                )?;
+            }
+        }
 
         // Collect all valid (non-deleted) files and sort them for consistent output
         let mut valid_files: Vec<_> = vfs.files
@@ -307,69 +1262,318 @@ impl LayerMerger {
         // Sort by path for deterministic output
         valid_files.sort_by_key(|(path, _)| *path);
 
-        println!("Creating merged tar with {} files", valid_files.len());
+        debug!("Creating merged tar with {} files", valid_files.len());
 
-        for (path, file_entry) in valid_files {
-            // Validate path length for tar format compatibility
-            if path.to_string_lossy().len() > 255 {
-                println!("Warning: Skipping file with path too long: {}", path.display());
-                continue;
-            }
+        let mode = self.options.long_pathname_mode;
+        let mut file_log = FileLogThrottle::new(self.options.file_log_limit);
 
+        for (path, file_entry) in valid_files {
             // Create a new header preserving original metadata
             let mut header = file_entry.header.clone();
-            header.set_path(path)?;
+            let link_name = file_entry.link_name.as_deref();
+            let mut pax = Cow::Borrowed(file_entry.pax.as_slice());
+            let clamped = match self.options.source_date_epoch {
+                Some(epoch) => clamp_entry_times(&mut header, pax.to_mut(), epoch),
+                None => false,
+            };
+            let pax = pax.as_ref();
 
             match &file_entry.data {
+                FileData::InMemory(data) if self.options.skip_cksum_recompute
+                    && !clamped
+                    && pax.is_empty()
+                    && Self::is_header_unmodified(&header, path, link_name, data.len() as u64) =>
+                {
+                    // The source header is already valid, copy it through untouched
+                    builder.append(&header, data.as_slice()).map_err(SquashError::TarError)?;
+                    file_log.trace(|| format!("  Added: {} ({} bytes)", path.display(), data.len()));
+                }
                 FileData::InMemory(data) => {
+                    // set_size must precede the checksum computed by append_entry
                     header.set_size(data.len() as u64);
-                    header.set_cksum();
-                    builder.append(&header, data.as_slice())?;
-                    println!("  Added: {} ({} bytes)", path.display(), data.len());
+                    append_entry_with_pax(&mut builder, &mut header, path, link_name, pax, data.as_slice(), mode)?;
+                    file_log.trace(|| format!("  Added: {} ({} bytes)", path.display(), data.len()));
+                }
+                FileData::OnDisk { source_tar, offset, size } => {
+                    // Stream large files straight from their source tar
+                    header.set_size(*size);
+                    let data = Self::open_file_data(source_tar, *offset, *size)?;
+                    append_entry_with_pax(&mut builder, &mut header, path, link_name, pax, data, mode)?;
+                    file_log.trace(|| format!("  Added: {} ({} bytes, streamed)", path.display(), size));
                 }
-                FileData::OnDisk { size, .. } => {
-                    // For large files, we need to stream from the source
-                    // This is a simplified implementation - in practice, we'd need to
-                    // track exact offsets in the source tar file
-                    println!("  Warning: Large file streaming not fully implemented: {} ({} bytes)",
-                             path.display(), size);
-
-                    // For now, create an empty entry as a placeholder
-                    header.set_size(0);
-                    header.set_cksum();
-                    builder.append(&header, &[] as &[u8])?;
+                FileData::Shared { layer, offset, size } => {
+                    header.set_size(*size);
+                    let data = &layer[*offset as usize..(*offset + *size) as usize];
+                    append_entry_with_pax(&mut builder, &mut header, path, link_name, pax, data, mode)?;
+                    file_log.trace(|| format!("  Added: {} ({} bytes, shared)", path.display(), size));
                 }
             }
         }
 
-        builder.finish()?;
-        println!("Merged tar created successfully");
-        Ok(())
+        let output = builder.into_inner().map_err(SquashError::TarError)?;
+        debug!("Merged tar created successfully");
+        Ok(output)
     }
     
-    /// Calculate the SHA256 digest of a layer tar file
+    /// Whether a source header can be written as-is for this entry
+    ///
+    /// True when the header itself holds the full path and link name (no
+    /// extension pseudo-entries were involved) and the size is unchanged.
+    fn is_header_unmodified(header: &Header, path: &Path, link_name: Option<&Path>, size: u64) -> bool {
+        let path_matches = header.path().is_ok_and(|p| p == path);
+        let link_matches = header.link_name().is_ok_and(|link| link.as_deref() == link_name);
+        let size_matches = header.size().is_ok_and(|s| s == size);
+        path_matches && link_matches && size_matches
+    }
+
+    /// Calculate the diff_id of a layer tar file
     fn calculate_layer_digest(&self, tar_path: &Path) -> Result<String> {
-        let mut file = File::open(tar_path)?;
+        calculate_diff_id(tar_path)
+    }
+}
+
+/// Clamp the mtime of an entry and its PAX time records to `epoch`
+///
+/// Earlier times are kept. Returns whether anything changed.
+fn clamp_entry_times(header: &mut Header, pax: &mut [(String, Vec<u8>)], epoch: u64) -> bool {
+    let mut changed = false;
+    if header.mtime().is_ok_and(|mtime| mtime > epoch) {
+        header.set_mtime(epoch);
+        changed = true;
+    }
+
+    // PAX times are decimal seconds with an optional fraction, e.g. `1700000000.25`
+    for (_, value) in pax.iter_mut().filter(|(key, _)| matches!(key.as_str(), "mtime" | "atime" | "ctime")) {
+        let later = std::str::from_utf8(value)
+            .ok()
+            .and_then(|time| time.parse::<f64>().ok())
+            .is_some_and(|time| time > epoch as f64);
+        if later {
+            *value = epoch.to_string().into_bytes();
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Progress of a merge that can be resumed, stored next to a VFS snapshot
+#[derive(Debug, Serialize, Deserialize)]
+struct MergeCheckpoint {
+    /// diff_id and size of every layer of the merge, in order
+    layers: Vec<(String, u64)>,
+    /// Number of leading layers folded into the snapshot
+    completed: usize,
+    /// diff_id of the snapshot tar
+    snapshot_digest: String,
+}
+
+impl MergeCheckpoint {
+    /// Files of the checkpoint for merging `layers` inside `dir`
+    ///
+    /// The name is derived from the layer digests, so checkpoints of
+    /// different merges can share a directory.
+    fn paths(dir: &Path, layers: &[LayerInfo]) -> CheckpointPaths {
         let mut hasher = Sha256::new();
-        let mut buffer = [0; 8192];
-        
-        loop {
-            let bytes_read = file.read(&mut buffer)?;
-            if bytes_read == 0 {
-                break;
+        for layer in layers {
+            hasher.update(layer.digest.as_bytes());
+            hasher.update(b"\n");
+        }
+        let key = format!("{:x}", hasher.finalize());
+        let stem = format!("merge-{}", &key[..16]);
+
+        CheckpointPaths {
+            dir: dir.to_path_buf(),
+            state: dir.join(format!("{}.json", stem)),
+            snapshot: dir.join(format!("{}.tar", stem)),
+            restored: dir.join(format!("{}.restored.tar", stem)),
+        }
+    }
+
+    /// Whether this checkpoint was written for exactly these layers
+    fn matches(&self, layers: &[LayerInfo]) -> bool {
+        self.completed <= layers.len()
+            && self.layers.len() == layers.len()
+            && self.layers.iter().zip(layers).all(|((digest, size), layer)| *digest == layer.digest && *size == layer.size)
+    }
+}
+
+/// Locations of a merge checkpoint
+#[derive(Debug)]
+struct CheckpointPaths {
+    dir: PathBuf,
+    /// Serialized [`MergeCheckpoint`]
+    state: PathBuf,
+    /// Tar replaying the virtual filesystem, deletions included
+    snapshot: PathBuf,
+    /// Snapshot a resumed merge was restored from, still referenced by large files
+    restored: PathBuf,
+}
+
+impl CheckpointPaths {
+    /// Discard the checkpoint once the merge has completed
+    fn remove(&self) {
+        let _ = std::fs::remove_file(&self.state);
+        let _ = std::fs::remove_file(&self.snapshot);
+        let _ = std::fs::remove_file(&self.restored);
+    }
+}
+
+/// Deletion marker found in a layer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Whiteout {
+    /// `.wh..wh..opq`: hide everything below the directory in lower layers
+    Opaque(PathBuf),
+    /// `.wh.<name>` or an overlayfs `0/0` character device: delete the named path
+    File(PathBuf),
+    /// Directory carrying the overlayfs opaque xattr: hide what lower layers put
+    /// below it, but keep the directory entry itself
+    OpaqueDirectory(PathBuf),
+}
+
+/// PAX record holding the overlayfs opaque directory xattr
+const OVERLAY_OPAQUE_XATTR: &str = "SCHILY.xattr.trusted.overlay.opaque";
+
+/// PAX records describing the entry layout, rewritten when the entry is written
+const REGENERATED_PAX_RECORDS: &[&str] = &["path", "linkpath", "size"];
+
+/// Prefix of PAX records describing sparse file maps, which are not reproduced
+const SPARSE_PAX_PREFIX: &str = "GNU.sparse.";
+
+/// Collect the PAX records of `entry` that must be written back with it
+///
+/// Extended attributes (`SCHILY.xattr.*`, e.g. `security.capability` on a
+/// setcap'd `ping`) and metadata such as sub-second mtimes or long owner
+/// names are kept. Records the writer derives from the entry itself, and
+/// the overlayfs opaque marker consumed as a whiteout, are dropped.
+fn preserved_pax_records<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<Vec<(String, Vec<u8>)>> {
+    let Some(extensions) = entry.pax_extensions().map_err(SquashError::TarError)? else {
+        return Ok(Vec::new());
+    };
+
+    let mut records = Vec::new();
+    for extension in extensions {
+        let extension = extension.map_err(SquashError::TarError)?;
+        let Ok(key) = extension.key() else {
+            warn!("Dropping PAX record with a non UTF-8 key");
+            continue;
+        };
+        if REGENERATED_PAX_RECORDS.contains(&key) || key.starts_with(SPARSE_PAX_PREFIX) || key == OVERLAY_OPAQUE_XATTR {
+            continue;
+        }
+        records.push((key.to_string(), extension.value_bytes().to_vec()));
+    }
+    Ok(records)
+}
+
+/// Recognize AUFS-style `.wh.` files as well as overlayfs whiteouts
+///
+/// Overlayfs deletes a path with a character device numbered `0/0` in its
+/// place and marks opaque directories with `trusted.overlay.opaque=y`.
+pub(crate) fn entry_whiteout<R: Read>(entry: &mut tar::Entry<'_, R>, path: &Path) -> Result<Option<Whiteout>> {
+    if let Some(whiteout) = parse_whiteout(path) {
+        return Ok(Some(whiteout));
+    }
+
+    let header = entry.header();
+    match header.entry_type() {
+        EntryType::Char
+            if header.device_major().map_err(SquashError::TarError)? == Some(0)
+                && header.device_minor().map_err(SquashError::TarError)? == Some(0) =>
+        {
+            Ok(Some(Whiteout::File(path.to_path_buf())))
+        }
+        EntryType::Directory => {
+            let Some(extensions) = entry.pax_extensions().map_err(SquashError::TarError)? else {
+                return Ok(None);
+            };
+            for extension in extensions {
+                let extension = extension.map_err(SquashError::TarError)?;
+                if extension.key_bytes() == OVERLAY_OPAQUE_XATTR.as_bytes() && extension.value_bytes() == b"y" {
+                    return Ok(Some(Whiteout::OpaqueDirectory(path.to_path_buf())));
+                }
             }
-            hasher.update(&buffer[..bytes_read]);
+            Ok(None)
         }
-        
-        let digest = hasher.finalize();
-        Ok(format!("sha256:{:x}", digest))
+        _ => Ok(None),
+    }
+}
+
+/// Recognize Docker whiteout files and return what they delete
+pub(crate) fn parse_whiteout(path: &Path) -> Option<Whiteout> {
+    let filename = path.file_name()?.to_str()?;
+    let original_name = filename.strip_prefix(".wh.")?;
+    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+
+    if filename == ".wh..wh..opq" {
+        Some(Whiteout::Opaque(parent.to_path_buf()))
+    } else {
+        Some(Whiteout::File(parent.join(original_name)))
     }
 }
 
+/// Calculate the SHA256 digest of a file in `sha256:<hex>` form
+///
+/// This is the digest of the bytes on disk, as used for manifest and OCI
+/// descriptor digests of (possibly compressed) layer blobs.
+pub fn calculate_file_digest(path: &Path) -> Result<String> {
+    digest_reader(File::open(path)?)
+}
+
+/// Calculate the diff_id of a layer: the SHA256 of its uncompressed tar
+///
+/// Gzip compressed blobs are decompressed on the fly, so the result matches
+/// the config `diff_ids` whether or not the layer is stored compressed.
+pub fn calculate_diff_id(path: &Path) -> Result<String> {
+    diff_id_reader(File::open(path)?)
+}
+
+/// diff_id of the layer content read from `reader`, decompressing gzip
+fn diff_id_reader<R: Read>(reader: R) -> Result<String> {
+    let mut reader = BufReader::new(reader);
+    let is_gzip = reader.fill_buf()?.starts_with(&GZIP_MAGIC);
+
+    if is_gzip {
+        digest_reader(GzDecoder::new(reader))
+    } else {
+        digest_reader(reader)
+    }
+}
+
+/// Whether the file at `path` starts with the gzip magic bytes
+pub fn is_gzip_file(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 2];
+    Ok(File::open(path)?.read(&mut magic)? == 2 && magic == GZIP_MAGIC)
+}
+
+/// Whether the file at `path` starts with the zstd magic bytes
+pub fn is_zstd_file(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    Ok(File::open(path)?.read(&mut magic)? == 4 && magic == ZSTD_MAGIC)
+}
+
+/// SHA256 digest of everything read from `reader`, in `sha256:<hex>` form
+fn digest_reader<R: Read>(mut reader: R) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 8192];
+
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+
+    let digest = hasher.finalize();
+    Ok(format!("sha256:{:x}", digest))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::BTreeMap;
     use std::fs;
+    use std::io::Write;
     use tempfile::TempDir;
 
     #[test]
@@ -382,6 +1586,8 @@ mod tests {
             digest: "sha256:test123".to_string(),
             size: 9,
             tar_path: tar_path.clone(),
+            data: None,
+            archive_offset: None,
         };
 
         assert_eq!(layer_info.digest, "sha256:test123");
@@ -397,11 +1603,15 @@ mod tests {
                 digest: "sha256:layer1".to_string(),
                 size: 100,
                 tar_path: temp_dir.path().join("layer1.tar"),
+                data: None,
+                archive_offset: None,
             },
             LayerInfo {
                 digest: "sha256:layer2".to_string(),
                 size: 200,
                 tar_path: temp_dir.path().join("layer2.tar"),
+                data: None,
+                archive_offset: None,
             },
         ];
 
@@ -419,6 +1629,8 @@ mod tests {
                 digest: "sha256:layer1".to_string(),
                 size: 100,
                 tar_path: temp_dir.path().join("layer1.tar"),
+                data: None,
+                archive_offset: None,
             },
         ];
 
@@ -444,6 +1656,70 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_merge_up_to_size() {
+        let temp_dir = TempDir::new().unwrap();
+        let tar_paths: Vec<PathBuf> = [&["base"][..], &["app/one"], &["app/two", "app/three"]]
+            .iter()
+            .enumerate()
+            .map(|(i, entries)| {
+                let tar_path = temp_dir.path().join(format!("layer{}.tar", i));
+                write_layer(&tar_path, entries);
+                tar_path
+            })
+            .collect();
+        let layers = layers_from_tars(&tar_paths);
+        let merger = LayerMerger::new(layers.clone(), temp_dir.path().to_path_buf());
+        let top_two = layers[1].size + layers[2].size;
+
+        // The budget is inclusive and counts from the latest layer down
+        assert_eq!(merger.trailing_layers_within_size(top_two).unwrap(), 2);
+        assert_eq!(merger.trailing_layers_within_size(top_two - 1).unwrap(), 1);
+        assert_eq!(merger.trailing_layers_within_size(u64::MAX).unwrap(), 3);
+
+        let (merged, stats) = merger.merge_up_to_size(top_two).unwrap();
+        assert_eq!(stats.input_layers, 2);
+        assert_eq!(stats.files_written, 3);
+        let mut archive = Archive::new(File::open(&merged.tar_path).unwrap());
+        let paths: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(paths, vec!["app/one", "app/three", "app/two"]);
+
+        match merger.merge_up_to_size(layers[2].size - 1) {
+            Err(SquashError::InvalidInput(msg)) => assert!(msg.contains("sha256:layer2"), "{}", msg),
+            other => panic!("Expected InvalidInput, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_merge_earliest_layers_validation() {
+        let temp_dir = TempDir::new().unwrap();
+        let layers = vec![
+            LayerInfo {
+                digest: "sha256:layer1".to_string(),
+                size: 100,
+                tar_path: temp_dir.path().join("layer1.tar"),
+                data: None,
+                archive_offset: None,
+            },
+        ];
+
+        let merger = LayerMerger::new(layers, temp_dir.path().to_path_buf());
+
+        match merger.merge_earliest_layers(0) {
+            Err(SquashError::InvalidInput(msg)) => assert!(msg.contains("Cannot merge 0 layers")),
+            _ => panic!("Expected InvalidInput error for 0 layers"),
+        }
+
+        match merger.merge_earliest_layers(2) {
+            Err(SquashError::InvalidInput(msg)) => assert!(msg.contains("Cannot merge 2 layers, only 1 layers available")),
+            _ => panic!("Expected InvalidInput error"),
+        }
+    }
+
     #[test]
     fn test_layer_id_validation() {
         let temp_dir = TempDir::new().unwrap();
@@ -452,6 +1728,8 @@ mod tests {
                 digest: "sha256:abcdef123456".to_string(),
                 size: 100,
                 tar_path: temp_dir.path().join("layer1.tar"),
+                data: None,
+                archive_offset: None,
             },
         ];
 
@@ -465,5 +1743,1068 @@ mod tests {
         } else {
             panic!("Expected InvalidInput error for short layer ID");
         }
+
+        // The algorithm prefix does not count towards the minimum
+        assert!(matches!(merger.find_layer_index("sha256:abc"), Err(SquashError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_layer_id_with_and_without_prefix() {
+        let temp_dir = TempDir::new().unwrap();
+        let layers = ["sha256:0123456789ab", "sha256:abcdef123456", "sha256:fedcba654321"]
+            .iter()
+            .enumerate()
+            .map(|(i, digest)| LayerInfo {
+                digest: digest.to_string(),
+                size: 100,
+                tar_path: temp_dir.path().join(format!("layer{}.tar", i)),
+                data: None,
+                archive_offset: None,
+            })
+            .collect();
+        let merger = LayerMerger::new(layers, temp_dir.path().to_path_buf());
+
+        assert_eq!(merger.find_layer_index("sha256:abcdef12").unwrap(), 1);
+        assert_eq!(merger.find_layer_index("abcdef12").unwrap(), 1);
+        assert_eq!(merger.find_layer_index("abcdef123456").unwrap(), 1);
+        assert_eq!(merger.resolve_layer_ref("fedcba65").unwrap(), 2);
+
+        // Only the start of the hex part matches, and only under the same algorithm
+        assert!(matches!(merger.find_layer_index("23456789"), Err(SquashError::LayerNotFound(_))));
+        assert!(matches!(merger.find_layer_index("sha512:abcdef12"), Err(SquashError::LayerNotFound(_))));
+    }
+
+    #[test]
+    fn test_merge_range_validation() {
+        let temp_dir = TempDir::new().unwrap();
+        let layers = vec![
+            LayerInfo {
+                digest: "sha256:abcdef123456".to_string(),
+                size: 100,
+                tar_path: temp_dir.path().join("layer1.tar"),
+                data: None,
+                archive_offset: None,
+            },
+            LayerInfo {
+                digest: "sha256:fedcba654321".to_string(),
+                size: 100,
+                tar_path: temp_dir.path().join("layer2.tar"),
+                data: None,
+                archive_offset: None,
+            },
+        ];
+
+        let merger = LayerMerger::new(layers, temp_dir.path().to_path_buf());
+
+        // Test error when start is after end
+        match merger.merge_range(1, 0) {
+            Err(SquashError::InvalidInput(msg)) => assert!(msg.contains("start 1 is after end 0")),
+            _ => panic!("Expected InvalidInput error for reversed range"),
+        }
+
+        // Test error when end is out of bounds
+        match merger.merge_range(0, 2) {
+            Err(SquashError::InvalidInput(msg)) => assert!(msg.contains("end 2 is out of bounds")),
+            _ => panic!("Expected InvalidInput error for out of bounds range"),
+        }
+
+        // Layer references resolve by index or by ID prefix
+        assert_eq!(merger.resolve_layer_ref("1").unwrap(), 1);
+        assert_eq!(merger.resolve_layer_ref("sha256:fedcba65").unwrap(), 1);
+        assert!(merger.resolve_layer_ref("2").is_err());
+    }
+
+    #[test]
+    fn test_diff_id_of_compressed_layer() {
+        use flate2::{write::GzEncoder, Compression};
+
+        let temp_dir = TempDir::new().unwrap();
+        let tar_path = temp_dir.path().join("layer.tar");
+        let gz_path = temp_dir.path().join("layer.tar.gz");
+
+        let mut builder = Builder::new(File::create(&tar_path).unwrap());
+        let mut header = Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "file", &b"data"[..]).unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        let mut encoder = GzEncoder::new(File::create(&gz_path).unwrap(), Compression::default());
+        encoder.write_all(&fs::read(&tar_path).unwrap()).unwrap();
+        encoder.finish().unwrap();
+
+        // The diff_id is over the uncompressed tar, the blob digest over the bytes on disk
+        let diff_id = calculate_diff_id(&tar_path).unwrap();
+        assert_eq!(diff_id, calculate_file_digest(&tar_path).unwrap());
+        assert_eq!(calculate_diff_id(&gz_path).unwrap(), diff_id);
+        assert_ne!(calculate_file_digest(&gz_path).unwrap(), diff_id);
+    }
+
+    /// Merge a single layer containing `path` and return the raw entries of the result
+    fn merge_long_path_layer(path: &str, mode: LongPathnameMode) -> Vec<(tar::EntryType, Vec<u8>)> {
+        let temp_dir = TempDir::new().unwrap();
+        let tar_path = temp_dir.path().join("layer.tar");
+
+        let mut builder = Builder::new(File::create(&tar_path).unwrap());
+        let mut header = Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, path, &b"data"[..]).unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        let layers = vec![LayerInfo {
+            digest: "sha256:longpath".to_string(),
+            size: fs::metadata(&tar_path).unwrap().len(),
+            tar_path,
+            data: None,
+            archive_offset: None,
+        }];
+
+        let options = MergeOptions {
+            long_pathname_mode: mode,
+            ..Default::default()
+        };
+        let merger = LayerMerger::new(layers, temp_dir.path().to_path_buf()).with_options(options);
+        let (merged, _) = merger.merge_latest_layers(1).unwrap();
+
+        // Entries with their full (resolved) path must round-trip
+        let mut archive = Archive::new(File::open(&merged.tar_path).unwrap());
+        let paths: Vec<PathBuf> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().into_owned())
+            .collect();
+        assert_eq!(paths, vec![PathBuf::from(path)]);
+
+        let mut archive = Archive::new(File::open(&merged.tar_path).unwrap());
+        archive
+            .entries()
+            .unwrap()
+            .raw(true)
+            .map(|e| {
+                let mut entry = e.unwrap();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                (entry.header().entry_type(), data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_long_pathname_mode_pax() {
+        let path = format!("usr/share/{}/file.txt", "a".repeat(120));
+        let entries = merge_long_path_layer(&path, LongPathnameMode::Pax);
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, tar::EntryType::XHeader);
+        let record = String::from_utf8(entries[0].1.clone()).unwrap();
+        assert!(record.ends_with(&format!(" path={}\n", path)));
+        let len: usize = record.split(' ').next().unwrap().parse().unwrap();
+        assert_eq!(len, record.len());
+        assert_eq!(entries[1].0, tar::EntryType::Regular);
+    }
+
+    #[test]
+    fn test_clamp_entry_times() {
+        let mut header = Header::new_gnu();
+        header.set_mtime(1_700_000_000);
+        let mut pax = vec![
+            ("mtime".to_string(), b"1600000000.25".to_vec()),
+            ("atime".to_string(), b"1500000000.5".to_vec()),
+            ("SCHILY.xattr.user.note".to_string(), b"1700000000".to_vec()),
+        ];
+
+        assert!(clamp_entry_times(&mut header, &mut pax, 1_600_000_000));
+        assert_eq!(header.mtime().unwrap(), 1_600_000_000);
+        assert_eq!(pax[0].1, b"1600000000");
+        assert_eq!(pax[1].1, b"1500000000.5");
+        assert_eq!(pax[2].1, b"1700000000");
+
+        // Times at or before the epoch are left alone
+        assert!(!clamp_entry_times(&mut header, &mut pax, 1_600_000_000));
+    }
+
+    #[test]
+    fn test_merge_keeps_paths_beyond_ustar_limit() {
+        // Nested node_modules easily exceed the 255 bytes a ustar name and prefix can hold
+        let path = format!("app/{}index.js", "node_modules/some-package/".repeat(12));
+        assert!(path.len() > 300);
+
+        for mode in [LongPathnameMode::Pax, LongPathnameMode::Gnu] {
+            let entries = merge_long_path_layer(&path, mode);
+            let (entry_type, data) = entries.last().unwrap();
+            assert_eq!(*entry_type, tar::EntryType::Regular);
+            assert_eq!(data, b"data");
+        }
+    }
+
+    #[test]
+    fn test_skip_cksum_recompute_passes_layer_through() {
+        let temp_dir = TempDir::new().unwrap();
+        let tar_path = temp_dir.path().join("layer.tar");
+
+        // Entries already in sorted order so the merged output can match byte for byte
+        let mut builder = Builder::new(File::create(&tar_path).unwrap());
+        for (path, content, mode) in [("bin/app", &b"binary"[..], 0o755), ("etc/app.conf", &b"key=value"[..], 0o600)] {
+            let mut header = Header::new_ustar();
+            header.set_path(path).unwrap();
+            header.set_size(content.len() as u64);
+            header.set_mode(mode);
+            header.set_mtime(1_700_000_000);
+            header.set_cksum();
+            builder.append(&header, content).unwrap();
+        }
+        builder.finish().unwrap();
+        drop(builder);
+
+        let layers = vec![LayerInfo {
+            digest: "sha256:passthrough".to_string(),
+            size: fs::metadata(&tar_path).unwrap().len(),
+            tar_path: tar_path.clone(),
+            data: None,
+            archive_offset: None,
+        }];
+        let options = MergeOptions {
+            skip_cksum_recompute: true,
+            ..Default::default()
+        };
+        let merger = LayerMerger::new(layers, temp_dir.path().to_path_buf()).with_options(options);
+        let (merged, _) = merger.merge_latest_layers(1).unwrap();
+
+        assert_eq!(fs::read(&merged.tar_path).unwrap(), fs::read(&tar_path).unwrap());
+    }
+
+    /// Write a layer tar whose entries are directories (trailing `/`), files or whiteouts
+    fn write_layer(path: &Path, entries: &[&str]) {
+        let mut builder = Builder::new(File::create(path).unwrap());
+        for name in entries {
+            let mut header = Header::new_gnu();
+            if name.ends_with('/') {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+            } else {
+                header.set_mode(0o644);
+                header.set_size(name.len() as u64);
+            }
+            let content = if name.ends_with('/') { &b""[..] } else { name.as_bytes() };
+            builder.append_data(&mut header, name, content).unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    /// Layers backed by the tar files at `paths`, oldest first
+    fn layers_from_tars(paths: &[PathBuf]) -> Vec<LayerInfo> {
+        paths
+            .iter()
+            .enumerate()
+            .map(|(i, tar_path)| LayerInfo {
+                digest: format!("sha256:layer{}", i),
+                size: fs::metadata(tar_path).unwrap().len(),
+                tar_path: tar_path.clone(),
+                data: None,
+                archive_offset: None,
+            })
+            .collect()
+    }
+
+    /// Merge layers built from `entries` and return the paths in the result
+    fn merged_paths(layer_entries: &[&[&str]]) -> (Vec<String>, LayerMergeStats) {
+        let temp_dir = TempDir::new().unwrap();
+        let tar_paths: Vec<PathBuf> = layer_entries
+            .iter()
+            .enumerate()
+            .map(|(i, entries)| {
+                let tar_path = temp_dir.path().join(format!("layer{}.tar", i));
+                write_layer(&tar_path, entries);
+                tar_path
+            })
+            .collect();
+        merged_tar_paths(&temp_dir, tar_paths)
+    }
+
+    /// Merge the given layer tars and return the paths in the result
+    fn merged_tar_paths(temp_dir: &TempDir, tar_paths: Vec<PathBuf>) -> (Vec<String>, LayerMergeStats) {
+        let count = tar_paths.len();
+        let layers = layers_from_tars(&tar_paths);
+
+        let merger = LayerMerger::new(layers, temp_dir.path().to_path_buf());
+        let (merged, stats) = merger.merge_latest_layers(count).unwrap();
+        assert_eq!(stats.input_layers, count);
+
+        let mut archive = Archive::new(File::open(&merged.tar_path).unwrap());
+        let paths: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().trim_end_matches('/').to_string())
+            .collect();
+        assert_eq!(stats.files_written, paths.len());
+        (paths, stats)
+    }
+
+    #[test]
+    fn test_opaque_whiteout_respects_directory_boundaries() {
+        let (paths, stats) = merged_paths(&[
+            &["var/log/", "var/log/old.log", "var/log-old", "var/log2/", "var/log2/keep.log", "var/logfile"],
+            &["var/log/.wh..wh..opq"],
+        ]);
+        assert_eq!(paths, vec!["var/log", "var/log-old", "var/log2", "var/log2/keep.log", "var/logfile"]);
+        assert_eq!(stats.opaque_dirs_cleared, 1);
+        assert_eq!(stats.files_whiteouted, 0);
+        assert_eq!(stats.total_bytes, ("var/log-old".len() + "var/log2/keep.log".len() + "var/logfile".len()) as u64);
+    }
+
+    #[test]
+    fn test_opaque_whiteout_clears_nested_directories() {
+        let (paths, _) = merged_paths(&[
+            &["var/log/", "var/log/app/", "var/log/app/deep/", "var/log/app/deep/trace.log", "var/other"],
+            // Entries of the whiteout layer survive even when listed before the marker
+            &["var/log/new.log", "var/log/.wh..wh..opq", "var/log/app/"],
+        ]);
+        assert_eq!(paths, vec!["var/log", "var/log/app", "var/log/new.log", "var/other"]);
+    }
+
+    #[test]
+    fn test_merged_directories_keep_type_and_mode() {
+        let temp_dir = TempDir::new().unwrap();
+        let append_dir = |builder: &mut Builder<File>, mut header: Header, name: &str, mode: u32| {
+            header.set_mode(mode);
+            header.set_size(0);
+            builder.append_data(&mut header, name, &b""[..]).unwrap();
+        };
+        let directory = || {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Directory);
+            header
+        };
+
+        let base_path = temp_dir.path().join("layer0.tar");
+        let mut builder = Builder::new(File::create(&base_path).unwrap());
+        append_dir(&mut builder, directory(), "usr/", 0o755);
+        append_dir(&mut builder, directory(), "usr/lib/", 0o755);
+        append_dir(&mut builder, directory(), "usr/lib/app/", 0o750);
+        // Pre-POSIX tars mark directories only by a trailing slash on a regular entry
+        append_dir(&mut builder, Header::new_old(), "srv/", 0o711);
+        let mut header = Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_size(4);
+        builder.append_data(&mut header, "usr/lib/app/data", &b"data"[..]).unwrap();
+        builder.finish().unwrap();
+
+        // A later layer changes the mode of a nested directory
+        let top_path = temp_dir.path().join("layer1.tar");
+        let mut builder = Builder::new(File::create(&top_path).unwrap());
+        append_dir(&mut builder, directory(), "usr/lib/app/", 0o700);
+        append_dir(&mut builder, directory(), "usr/lib/app/cache/", 0o777);
+        builder.finish().unwrap();
+
+        let layers = layers_from_tars(&[base_path, top_path]);
+        // Headers copied through untouched need a valid checksum too
+        for skip_cksum_recompute in [false, true] {
+            let options = MergeOptions { skip_cksum_recompute, ..Default::default() };
+            let merger = LayerMerger::new(layers.clone(), temp_dir.path().to_path_buf()).with_options(options);
+            let (merged, _) = merger.merge_latest_layers(2).unwrap();
+
+            let mut archive = Archive::new(File::open(&merged.tar_path).unwrap());
+            let entries: Vec<(String, EntryType, u64, u32)> = archive
+                .entries()
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    let header = entry.header();
+                    let path = entry.path().unwrap().to_string_lossy().into_owned();
+                    (path, header.entry_type(), header.size().unwrap(), header.mode().unwrap())
+                })
+                .collect();
+            assert_eq!(entries, vec![
+                ("srv/".to_string(), EntryType::Directory, 0, 0o711),
+                ("usr/".to_string(), EntryType::Directory, 0, 0o755),
+                ("usr/lib/".to_string(), EntryType::Directory, 0, 0o755),
+                ("usr/lib/app/".to_string(), EntryType::Directory, 0, 0o700),
+                ("usr/lib/app/cache/".to_string(), EntryType::Directory, 0, 0o777),
+                ("usr/lib/app/data".to_string(), EntryType::Regular, 4, 0o644),
+            ]);
+        }
+    }
+
+    #[test]
+    fn test_directory_whiteout_removes_descendants() {
+        let (paths, stats) = merged_paths(&[
+            &["usr/", "usr/bin/", "usr/bin/x", "usr2/", "usr2/y", "usrfile"],
+            &[".wh.usr", ".wh.usrfile"],
+        ]);
+        assert_eq!(paths, vec!["usr2", "usr2/y"]);
+        assert_eq!(stats.files_whiteouted, 2);
+
+        // Contents alone imply the directory, and the whiteout layer's own entries survive
+        let (paths, _) = merged_paths(&[
+            &["opt/app/bin/tool", "opt/app/lib/libx.so", "opt/keep"],
+            &["opt/app/new", "opt/.wh.app"],
+        ]);
+        assert_eq!(paths, vec!["opt/app/new", "opt/keep"]);
+    }
+
+    #[test]
+    fn test_overlay_whiteouts() {
+        let temp_dir = TempDir::new().unwrap();
+        let lower = temp_dir.path().join("layer0.tar");
+        write_layer(&lower, &["etc/", "etc/passwd", "etc/shadow", "var/cache/", "var/cache/old", "var/keep"]);
+
+        let upper = temp_dir.path().join("layer1.tar");
+        let mut builder = Builder::new(File::create(&upper).unwrap());
+
+        // overlayfs records a deleted file as a 0/0 character device
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Char);
+        header.set_device_major(0).unwrap();
+        header.set_device_minor(0).unwrap();
+        header.set_mode(0o644);
+        header.set_size(0);
+        builder.append_data(&mut header, "etc/shadow", &b""[..]).unwrap();
+
+        // and an opaque directory with the trusted.overlay.opaque xattr
+        builder
+            .append_pax_extensions([(OVERLAY_OPAQUE_XATTR, b"y".as_slice())])
+            .unwrap();
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+        builder.append_data(&mut header, "var/cache/", &b""[..]).unwrap();
+        builder.finish().unwrap();
+
+        let (paths, stats) = merged_tar_paths(&temp_dir, vec![lower, upper]);
+        assert_eq!(paths, vec!["etc", "etc/passwd", "var/cache", "var/keep"]);
+        assert_eq!(stats.files_whiteouted, 1);
+        assert_eq!(stats.opaque_dirs_cleared, 1);
+    }
+
+    #[test]
+    fn test_file_capabilities_survive_merge() {
+        const CAPABILITY: &str = "SCHILY.xattr.security.capability";
+        // vfs_cap_data revision 2 granting cap_net_raw
+        let capability: Vec<u8> = vec![1, 0, 0, 2, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        for mode in [LongPathnameMode::Pax, LongPathnameMode::Gnu] {
+            let temp_dir = TempDir::new().unwrap();
+            let lower = temp_dir.path().join("layer0.tar");
+            let long_path = format!("usr/lib/{}/ping", "c".repeat(120));
+            let mut builder = Builder::new(File::create(&lower).unwrap());
+            for path in ["bin/ping", long_path.as_str()] {
+                builder
+                    .append_pax_extensions([(CAPABILITY, capability.as_slice()), ("SCHILY.xattr.user.note", b"x".as_slice())])
+                    .unwrap();
+                let mut header = Header::new_gnu();
+                header.set_mode(0o755);
+                header.set_size(4);
+                builder.append_data(&mut header, path, &b"ping"[..]).unwrap();
+            }
+            builder.finish().unwrap();
+            drop(builder);
+
+            let upper = temp_dir.path().join("layer1.tar");
+            write_layer(&upper, &["etc/hosts"]);
+
+            let layers = layers_from_tars(&[lower, upper]);
+            let options = MergeOptions {
+                long_pathname_mode: mode,
+                skip_cksum_recompute: true,
+                ..Default::default()
+            };
+            let merger = LayerMerger::new(layers, temp_dir.path().to_path_buf()).with_options(options);
+            let (merged, _) = merger.merge_latest_layers(2).unwrap();
+
+            let mut archive = Archive::new(File::open(&merged.tar_path).unwrap());
+            let mut capabilities = BTreeMap::new();
+            for entry in archive.entries().unwrap() {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+                let records: Vec<(String, Vec<u8>)> = entry
+                    .pax_extensions()
+                    .unwrap()
+                    .map(|extensions| {
+                        extensions
+                            .map(|e| e.unwrap())
+                            .filter(|e| e.key().unwrap().starts_with("SCHILY.xattr."))
+                            .map(|e| (e.key().unwrap().to_string(), e.value_bytes().to_vec()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                capabilities.insert(path, records);
+            }
+
+            let expected = vec![
+                (CAPABILITY.to_string(), capability.clone()),
+                ("SCHILY.xattr.user.note".to_string(), b"x".to_vec()),
+            ];
+            assert_eq!(capabilities["bin/ping"], expected, "{:?}", mode);
+            assert_eq!(capabilities[&long_path], expected, "{:?}", mode);
+            assert!(capabilities["etc/hosts"].is_empty());
+        }
+    }
+
+    #[test]
+    fn test_pax_metadata_survives_merge() {
+        let owner = "o".repeat(40);
+        let temp_dir = TempDir::new().unwrap();
+        let lower = temp_dir.path().join("layer0.tar");
+        let mut builder = Builder::new(File::create(&lower).unwrap());
+        builder
+            .append_pax_extensions([
+                ("path", b"etc/app.conf".as_slice()),
+                ("mtime", b"1700000000.25".as_slice()),
+                ("uname", owner.as_bytes()),
+                ("SCHILY.xattr.user.mime_type", b"text/plain".as_slice()),
+            ])
+            .unwrap();
+        append_file(&mut builder, "etc/app.conf", b"key=value");
+        builder.finish().unwrap();
+        drop(builder);
+
+        let upper = temp_dir.path().join("layer1.tar");
+        write_layer(&upper, &["etc/hosts"]);
+
+        let layers = layers_from_tars(&[lower, upper]);
+        let (merged, _) = LayerMerger::new(layers, temp_dir.path().to_path_buf()).merge_latest_layers(2).unwrap();
+
+        let mut archive = Archive::new(File::open(&merged.tar_path).unwrap());
+        let mut entries = archive.entries().unwrap().map(|entry| entry.unwrap());
+        let mut entry = entries.find(|entry| entry.path().unwrap() == Path::new("etc/app.conf")).unwrap();
+
+        // Layout records are regenerated rather than copied
+        let records: Vec<(String, Vec<u8>)> = entry
+            .pax_extensions()
+            .unwrap()
+            .unwrap()
+            .map(|e| e.unwrap())
+            .map(|e| (e.key().unwrap().to_string(), e.value_bytes().to_vec()))
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                ("mtime".to_string(), b"1700000000.25".to_vec()),
+                ("uname".to_string(), owner.as_bytes().to_vec()),
+                ("SCHILY.xattr.user.mime_type".to_string(), b"text/plain".to_vec()),
+            ]
+        );
+        let mut data = String::new();
+        entry.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "key=value");
+    }
+
+    /// Observer that aborts the merge when layer `index` starts
+    struct InterruptAt(usize);
+
+    impl ProgressObserver for InterruptAt {
+        fn on_layer_start(&mut self, index: usize, _total: usize, _layer: &LayerInfo) {
+            assert_ne!(index, self.0, "interrupted");
+        }
+    }
+
+    /// Observer recording which layers were read
+    #[derive(Default)]
+    struct StartedLayers(Vec<usize>);
+
+    impl ProgressObserver for StartedLayers {
+        fn on_layer_start(&mut self, index: usize, _total: usize, _layer: &LayerInfo) {
+            self.0.push(index);
+        }
+    }
+
+    #[test]
+    fn test_resume_interrupted_merge() {
+        let temp_dir = TempDir::new().unwrap();
+        let layer_entries: [&[&str]; 4] = [
+            &["etc/", "etc/passwd", "var/cache/", "var/cache/a", "tmp/scratch"],
+            &["etc/.wh.passwd", "var/cache/.wh..wh..opq", "var/cache/b"],
+            &["etc/passwd", "tmp/.wh.scratch", "opt/app"],
+            &["opt/.wh.app", "opt/tool"],
+        ];
+        let tar_paths: Vec<PathBuf> = layer_entries
+            .iter()
+            .enumerate()
+            .map(|(i, entries)| {
+                let tar_path = temp_dir.path().join(format!("layer{}.tar", i));
+                write_layer(&tar_path, entries);
+                tar_path
+            })
+            .collect();
+        let layers = layers_from_tars(&tar_paths);
+
+        let (uninterrupted, uninterrupted_stats) = LayerMerger::new(layers.clone(), temp_dir.path().to_path_buf())
+            .merge_range(0, 3)
+            .unwrap();
+
+        // With a zero threshold the resumed merge streams files from the restored snapshot
+        for mem_file_threshold in [DEFAULT_MEM_FILE_THRESHOLD, 0] {
+            let checkpoint_dir = temp_dir.path().join(format!("checkpoints-{}", mem_file_threshold));
+            let options = MergeOptions {
+                checkpoint_dir: Some(checkpoint_dir.clone()),
+                mem_file_threshold,
+                ..Default::default()
+            };
+            let merger = LayerMerger::new(layers.clone(), temp_dir.path().to_path_buf()).with_options(options);
+
+            let interrupted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                merger.merge_range_with_observer(0, 3, &mut InterruptAt(3))
+            }));
+            assert!(interrupted.is_err());
+            assert_eq!(fs::read_dir(&checkpoint_dir).unwrap().count(), 2);
+
+            let mut started = StartedLayers::default();
+            let (resumed, stats) = merger.merge_range_with_observer(0, 3, &mut started).unwrap();
+            assert_eq!(started.0, vec![3, 4]);
+            assert_eq!(stats.files_written, uninterrupted_stats.files_written);
+            assert_eq!(stats.total_bytes, uninterrupted_stats.total_bytes);
+            assert_eq!(resumed.digest, uninterrupted.digest);
+            assert_eq!(fs::read(&resumed.tar_path).unwrap(), fs::read(&uninterrupted.tar_path).unwrap());
+
+            // A finished merge leaves no checkpoint behind
+            assert_eq!(fs::read_dir(&checkpoint_dir).unwrap().count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_parallel_reading_matches_sequential() {
+        let temp_dir = TempDir::new().unwrap();
+        let tar_paths: Vec<PathBuf> = (0..7)
+            .map(|i| {
+                let tar_path = temp_dir.path().join(format!("layer{}.tar", i));
+                // Each layer overrides, deletes or clears what earlier ones wrote
+                let entries = match i % 3 {
+                    0 => vec!["app/".to_string(), format!("app/v{}", i), "app/current".to_string()],
+                    1 => vec![format!("app/.wh.v{}", i - 1), format!("data/{}", i)],
+                    _ => vec!["data/.wh..wh..opq".to_string(), format!("data/{}", i), "app/current".to_string()],
+                };
+                write_layer(&tar_path, &entries.iter().map(String::as_str).collect::<Vec<_>>());
+                tar_path
+            })
+            .collect();
+        let layers = layers_from_tars(&tar_paths);
+
+        let merge = |jobs: usize| {
+            let options = MergeOptions { jobs, ..Default::default() };
+            let merger = LayerMerger::new(layers.clone(), temp_dir.path().to_path_buf()).with_options(options);
+            let mut started = StartedLayers::default();
+            let (merged, stats) = merger.merge_range_with_observer(0, layers.len() - 1, &mut started).unwrap();
+            assert_eq!(started.0, (1..=layers.len()).collect::<Vec<_>>());
+            (fs::read(merged.tar_path).unwrap(), stats)
+        };
+
+        let sequential = merge(1);
+        assert_eq!(merge(3), sequential);
+        assert_eq!(merge(16), sequential);
+    }
+
+    #[test]
+    fn test_checkpoint_for_other_layers_is_ignored() {
+        let temp_dir = TempDir::new().unwrap();
+        let checkpoint_dir = temp_dir.path().join("checkpoints");
+        let tar_paths: Vec<PathBuf> = (0..2)
+            .map(|i| {
+                let tar_path = temp_dir.path().join(format!("layer{}.tar", i));
+                write_layer(&tar_path, &[format!("file{}", i).as_str()]);
+                tar_path
+            })
+            .collect();
+        let layers = layers_from_tars(&tar_paths);
+
+        // A checkpoint claiming both layers are done, recorded for different sizes
+        let paths = MergeCheckpoint::paths(&checkpoint_dir, &layers);
+        fs::create_dir_all(&checkpoint_dir).unwrap();
+        write_layer(&paths.snapshot, &["stale"]);
+        let stale = MergeCheckpoint {
+            layers: layers.iter().map(|layer| (layer.digest.clone(), layer.size + 1)).collect(),
+            completed: 2,
+            snapshot_digest: calculate_diff_id(&paths.snapshot).unwrap(),
+        };
+        fs::write(&paths.state, serde_json::to_vec(&stale).unwrap()).unwrap();
+
+        let options = MergeOptions {
+            checkpoint_dir: Some(checkpoint_dir),
+            ..Default::default()
+        };
+        let merger = LayerMerger::new(layers, temp_dir.path().to_path_buf()).with_options(options);
+        let mut started = StartedLayers::default();
+        let (merged, _) = merger.merge_range_with_observer(0, 1, &mut started).unwrap();
+        assert_eq!(started.0, vec![1, 2]);
+
+        let mut archive = Archive::new(File::open(&merged.tar_path).unwrap());
+        let paths: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(paths, vec!["file0", "file1"]);
+    }
+
+    /// Append a symlink or hard link entry
+    fn append_link(builder: &mut Builder<File>, entry_type: EntryType, path: &str, target: &str) {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(0o777);
+        header.set_size(0);
+        builder.append_link(&mut header, path, target).unwrap();
+    }
+
+    /// Append a regular file entry
+    fn append_file(builder: &mut Builder<File>, path: &str, content: &[u8]) {
+        let mut header = Header::new_gnu();
+        header.set_mode(0o755);
+        header.set_size(content.len() as u64);
+        builder.append_data(&mut header, path, content).unwrap();
+    }
+
+    /// Entry type, link name and content of every entry of a merged layer
+    fn merged_entries(
+        temp_dir: &TempDir,
+        tar_paths: Vec<PathBuf>,
+        options: MergeOptions,
+    ) -> BTreeMap<String, (EntryType, Option<String>, Vec<u8>)> {
+        let count = tar_paths.len();
+        let layers = layers_from_tars(&tar_paths);
+        let (merged, _) = LayerMerger::new(layers, temp_dir.path().to_path_buf())
+            .with_options(options)
+            .merge_latest_layers(count)
+            .unwrap();
+
+        let mut archive = Archive::new(File::open(&merged.tar_path).unwrap());
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+                let link = entry.link_name().unwrap().map(|link| link.to_string_lossy().into_owned());
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                (path, (entry.header().entry_type(), link, data))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_links_survive_merge() {
+        let temp_dir = TempDir::new().unwrap();
+        let lower = temp_dir.path().join("layer0.tar");
+        let mut builder = Builder::new(File::create(&lower).unwrap());
+        append_file(&mut builder, "bin/busybox", b"busybox");
+        append_link(&mut builder, EntryType::Symlink, "bin/sh", "busybox");
+        append_link(&mut builder, EntryType::Link, "bin/ls", "bin/busybox");
+        builder.finish().unwrap();
+        drop(builder);
+
+        let upper = temp_dir.path().join("layer1.tar");
+        write_layer(&upper, &["etc/motd"]);
+
+        let entries = merged_entries(&temp_dir, vec![lower, upper], MergeOptions::default());
+        assert_eq!(entries["bin/busybox"], (EntryType::Regular, None, b"busybox".to_vec()));
+        assert_eq!(entries["bin/sh"], (EntryType::Symlink, Some("busybox".to_string()), Vec::new()));
+        assert_eq!(entries["bin/ls"], (EntryType::Link, Some("bin/busybox".to_string()), Vec::new()));
+    }
+
+    #[test]
+    fn test_hardlink_to_replaced_file_becomes_regular_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let lower = temp_dir.path().join("layer0.tar");
+        let mut builder = Builder::new(File::create(&lower).unwrap());
+        append_file(&mut builder, "usr/bin/python3.11", b"python");
+        append_link(&mut builder, EntryType::Link, "usr/bin/python3", "./usr/bin/python3.11");
+        append_file(&mut builder, "usr/lib/libfoo.so.1", b"foo v1");
+        append_link(&mut builder, EntryType::Link, "usr/lib/libfoo.so", "usr/lib/libfoo.so.1");
+        append_file(&mut builder, "opt/data/blob", b"blob");
+        append_link(&mut builder, EntryType::Link, "srv/blob", "opt/data/blob");
+        builder.finish().unwrap();
+        drop(builder);
+
+        // Delete one target, overwrite another and hide the third behind an opaque directory
+        let upper = temp_dir.path().join("layer1.tar");
+        let mut builder = Builder::new(File::create(&upper).unwrap());
+        append_file(&mut builder, "usr/bin/.wh.python3.11", b"");
+        append_file(&mut builder, "usr/lib/libfoo.so.1", b"foo v2");
+        append_file(&mut builder, "opt/data/.wh..wh..opq", b"");
+        builder.finish().unwrap();
+        drop(builder);
+
+        let entries = merged_entries(&temp_dir, vec![lower, upper], MergeOptions::default());
+        assert!(!entries.contains_key("usr/bin/python3.11"));
+        assert!(!entries.contains_key("opt/data/blob"));
+        assert_eq!(entries["usr/bin/python3"], (EntryType::Regular, None, b"python".to_vec()));
+        assert_eq!(entries["usr/lib/libfoo.so"], (EntryType::Regular, None, b"foo v1".to_vec()));
+        assert_eq!(entries["usr/lib/libfoo.so.1"], (EntryType::Regular, None, b"foo v2".to_vec()));
+        assert_eq!(entries["srv/blob"], (EntryType::Regular, None, b"blob".to_vec()));
+    }
+
+    #[test]
+    fn test_exclude_patterns() {
+        let temp_dir = TempDir::new().unwrap();
+        let lower = temp_dir.path().join("layer0.tar");
+        let mut builder = Builder::new(File::create(&lower).unwrap());
+        append_file(&mut builder, "app/main.py", b"main");
+        append_file(&mut builder, "app/main.pyc", b"compiled");
+        append_file(&mut builder, "app/lib/util.pyc", b"util");
+        append_file(&mut builder, "var/cache/apt/pkgcache.bin", b"packages");
+        append_file(&mut builder, "var/cache/fonts/default.ttf", b"font");
+        append_file(&mut builder, "var/cachefile", b"kept");
+        builder.finish().unwrap();
+        drop(builder);
+
+        // Links into excluded paths: a symlink keeps its target, a hard link gets a copy
+        let upper = temp_dir.path().join("layer1.tar");
+        let mut builder = Builder::new(File::create(&upper).unwrap());
+        append_link(&mut builder, EntryType::Symlink, "usr/share/fonts/default.ttf", "../../../var/cache/fonts/default.ttf");
+        append_link(&mut builder, EntryType::Link, "srv/util", "app/lib/util.pyc");
+        builder.finish().unwrap();
+        drop(builder);
+
+        let options = MergeOptions {
+            exclude: build_exclude_set(&["**/*.pyc".to_string(), "/var/cache/**".to_string()]).unwrap(),
+            ..Default::default()
+        };
+        let entries = merged_entries(&temp_dir, vec![lower.clone(), upper.clone()], options.clone());
+        let paths: Vec<&str> = entries.keys().map(String::as_str).collect();
+        assert_eq!(paths, vec![
+            "app/main.py",
+            "srv/util",
+            "usr/share/fonts/default.ttf",
+            "var/cache/fonts/default.ttf",
+            "var/cachefile",
+        ]);
+        assert_eq!(entries["srv/util"], (EntryType::Regular, None, b"util".to_vec()));
+
+        let layers = layers_from_tars(&[lower, upper]);
+        let (_, stats) = LayerMerger::new(layers, temp_dir.path().to_path_buf())
+            .with_options(options)
+            .merge_latest_layers(2)
+            .unwrap();
+        assert_eq!(stats.files_excluded, 3);
+        assert_eq!(stats.bytes_excluded, ("compiled".len() + "util".len() + "packages".len()) as u64);
+        assert_eq!(stats.files_written, 5);
+
+        // A matching directory drops everything below it
+        let options = MergeOptions {
+            exclude: build_exclude_set(&["var/cache".to_string()]).unwrap(),
+            ..Default::default()
+        };
+        let lower = temp_dir.path().join("layer2.tar");
+        write_layer(&lower, &["var/", "var/cache/", "var/cache/apt/", "var/cache/apt/lists", "var/log"]);
+        let entries = merged_entries(&temp_dir, vec![lower], options);
+        let paths: Vec<&str> = entries.keys().map(String::as_str).collect();
+        assert_eq!(paths, vec!["var/", "var/log"]);
+
+        assert!(build_exclude_set(&["app/[".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_resolve_symlink_target() {
+        assert_eq!(resolve_symlink_target(Path::new("usr/bin/python"), Path::new("python3")), Path::new("usr/bin/python3"));
+        assert_eq!(resolve_symlink_target(Path::new("./etc/alt"), Path::new("../usr/./lib")), Path::new("usr/lib"));
+        assert_eq!(resolve_symlink_target(Path::new("a/b"), Path::new("/opt/app")), Path::new("opt/app"));
+        assert_eq!(resolve_symlink_target(Path::new("a"), Path::new("../../x")), Path::new("x"));
+    }
+
+    #[test]
+    fn test_files_around_mem_file_threshold_round_trip() {
+        const THRESHOLD: usize = 4096;
+        let below: Vec<u8> = (0..THRESHOLD).map(|i| (i % 251) as u8).collect();
+        let above: Vec<u8> = (0..=THRESHOLD).map(|i| (i % 241) as u8).collect();
+
+        for mem_file_threshold in [THRESHOLD as u64, 0] {
+            let temp_dir = TempDir::new().unwrap();
+            let lower = temp_dir.path().join("layer0.tar");
+            let mut builder = Builder::new(File::create(&lower).unwrap());
+            append_file(&mut builder, "data/below", &below);
+            append_file(&mut builder, "data/above", &above);
+            append_file(&mut builder, "data/replaced", &above);
+            builder.finish().unwrap();
+            drop(builder);
+
+            let upper = temp_dir.path().join("layer1.tar");
+            let mut builder = Builder::new(File::create(&upper).unwrap());
+            append_file(&mut builder, "data/replaced", b"small");
+            append_file(&mut builder, "data/upper", &above);
+            builder.finish().unwrap();
+            drop(builder);
+
+            let options = MergeOptions { mem_file_threshold, ..Default::default() };
+            let entries = merged_entries(&temp_dir, vec![lower, upper], options);
+            let content = |path: &str| &entries[path].2;
+            assert_eq!(content("data/below"), &below, "threshold {}", mem_file_threshold);
+            assert_eq!(content("data/above"), &above, "threshold {}", mem_file_threshold);
+            assert_eq!(content("data/replaced"), b"small", "threshold {}", mem_file_threshold);
+            assert_eq!(content("data/upper"), &above, "threshold {}", mem_file_threshold);
+        }
+    }
+
+    #[test]
+    fn test_in_memory_merge() {
+        let temp_dir = TempDir::new().unwrap();
+        let layers: Vec<LayerInfo> = [&["etc/", "etc/hosts"][..], &["etc/passwd", "usr/"], &["usr/bin"]]
+            .iter()
+            .enumerate()
+            .map(|(i, entries)| {
+                let tar_path = temp_dir.path().join(format!("layer{}.tar", i));
+                write_layer(&tar_path, entries);
+                LayerInfo {
+                    digest: calculate_diff_id(&tar_path).unwrap(),
+                    size: fs::metadata(&tar_path).unwrap().len(),
+                    tar_path,
+                    data: None,
+                    archive_offset: None,
+                }
+            })
+            .collect();
+
+        // Every file is referenced in its layer rather than copied
+        let in_memory = MergeOptions { in_memory: true, mem_file_threshold: 0, ..Default::default() };
+        let merger = LayerMerger::new(layers[..2].to_vec(), temp_dir.path().to_path_buf()).with_options(in_memory.clone());
+        let (merged, stats) = merger.merge_latest_layers(2).unwrap();
+        let data = merged.data.clone().unwrap();
+        assert!(merged.is_in_memory());
+        assert!(!merged.tar_path.exists());
+        assert_eq!(merged.size, data.len() as u64);
+        assert_eq!(merged.diff_id().unwrap(), merged.digest);
+        assert_eq!(stats.files_written, 4);
+
+        let on_disk = LayerMerger::new(layers[..2].to_vec(), temp_dir.path().to_path_buf());
+        let (written, _) = on_disk.merge_latest_layers(2).unwrap();
+        assert_eq!(&data[..], fs::read(&written.tar_path).unwrap());
+        assert_eq!(written.digest, merged.digest);
+
+        // A layer held in memory can be merged again
+        let merger = LayerMerger::new(vec![merged, layers[2].clone()], temp_dir.path().to_path_buf())
+            .with_options(in_memory);
+        let (remerged, _) = merger.merge_latest_layers(2).unwrap();
+        let mut archive = Archive::new(remerged.open().unwrap());
+        let entries: Vec<(String, Vec<u8>)> = archive
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let mut entry = e.unwrap();
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content).unwrap();
+                (entry.path().unwrap().to_string_lossy().into_owned(), content)
+            })
+            .collect();
+        assert_eq!(entries, vec![
+            ("etc/".to_string(), vec![]),
+            ("etc/hosts".to_string(), b"etc/hosts".to_vec()),
+            ("etc/passwd".to_string(), b"etc/passwd".to_vec()),
+            ("usr/".to_string(), vec![]),
+            ("usr/bin".to_string(), b"usr/bin".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_corrupt_layer_reports_tar_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let tar_path = temp_dir.path().join("layer.tar");
+        write_layer(&tar_path, &["etc/", "etc/passwd"]);
+
+        // Flip a byte of the first header so its checksum no longer matches
+        let mut content = fs::read(&tar_path).unwrap();
+        content[0] ^= 0xff;
+        fs::write(&tar_path, content).unwrap();
+
+        let layer = LayerInfo {
+            digest: "sha256:corrupt".to_string(),
+            size: fs::metadata(&tar_path).unwrap().len(),
+            tar_path,
+            data: None,
+            archive_offset: None,
+        };
+        let merger = LayerMerger::new(vec![layer], temp_dir.path().to_path_buf());
+        match merger.merge_latest_layers(1) {
+            Err(SquashError::TarError(err)) => assert!(err.to_string().contains("checksum")),
+            other => panic!("Expected TarError, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_directory_traversal_paths() {
+        // The tar builder refuses `..` components, so write the names into the header directly
+        let write_raw_layer = |tar_path: &Path, names: &[&str]| {
+            let mut builder = Builder::new(File::create(tar_path).unwrap());
+            for name in names {
+                let mut header = Header::new_gnu();
+                header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+                header.set_mode(0o644);
+                header.set_size(4);
+                header.set_cksum();
+                builder.append(&header, &b"data"[..]).unwrap();
+            }
+            builder.finish().unwrap();
+        };
+        let merge = |names: &[&str], strict_paths: bool| {
+            let temp_dir = TempDir::new().unwrap();
+            let tar_path = temp_dir.path().join("layer.tar");
+            write_raw_layer(&tar_path, names);
+            let layer = LayerInfo {
+                digest: "sha256:traversal".to_string(),
+                size: fs::metadata(&tar_path).unwrap().len(),
+                tar_path,
+                data: None,
+                archive_offset: None,
+            };
+            let options = MergeOptions { strict_paths, ..Default::default() };
+            let merged = LayerMerger::new(vec![layer], temp_dir.path().to_path_buf())
+                .with_options(options)
+                .merge_latest_layers(1)?
+                .0;
+            let mut archive = Archive::new(File::open(&merged.tar_path).unwrap());
+            let paths: Vec<String> = archive
+                .entries()
+                .unwrap()
+                .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+                .collect();
+            Ok::<_, SquashError>(paths)
+        };
+
+        // Names that merely contain dots are kept in either mode
+        assert_eq!(merge(&["foo..bar", "a/b..c"], true).unwrap(), vec!["a/b..c", "foo..bar"]);
+
+        // Real parent references are dropped by default and rejected when strict
+        assert_eq!(merge(&["a/../b", "..", "foo..bar"], false).unwrap(), vec!["foo..bar"]);
+        for name in ["a/../b", ".."] {
+            match merge(&[name], true) {
+                Err(SquashError::InvalidInput(message)) => assert!(message.contains(name), "{}", message),
+                other => panic!("Expected InvalidInput for {}, got {:?}", name, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_file_log_throttle_caps_per_file_lines() {
+        let mut throttle = FileLogThrottle::new(100);
+        let decisions: Vec<FileLog> = (0..250_000).map(|_| throttle.tick()).collect();
+
+        let details = decisions.iter().filter(|d| **d == FileLog::Detail).count();
+        let summaries: Vec<usize> = decisions
+            .iter()
+            .filter_map(|d| match d {
+                FileLog::Summary(count) => Some(*count),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(details, 100);
+        assert_eq!(summaries, vec![101, 100_000, 200_000]);
+
+        // A limit of 0 turns per-file logging off entirely
+        let mut silent = FileLogThrottle::new(0);
+        assert_eq!(silent.tick(), FileLog::Summary(1));
+        assert_eq!(silent.tick(), FileLog::Quiet);
+    }
+
+    #[test]
+    fn test_long_pathname_mode_gnu() {
+        let path = format!("usr/share/{}/file.txt", "b".repeat(120));
+        let entries = merge_long_path_layer(&path, LongPathnameMode::Gnu);
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, tar::EntryType::GNULongName);
+        assert_eq!(entries[0].1, format!("{}\0", path).into_bytes());
+        assert_eq!(entries[1].0, tar::EntryType::Regular);
     }
 }
@@ -9,7 +9,37 @@ pub mod image;
 pub mod tar;
 /// Layer merging and squashing functionality
 pub mod layer;
+/// OCI image layout structures
+pub mod oci;
+/// Filesystem flattening and content comparison
+pub mod diff;
+/// Image transport backends such as skopeo
+pub mod runtime;
+/// Progress reporting hooks for library embedders
+pub mod progress;
+/// Docker daemon access through the CLI or the Engine API
+pub mod backend;
+/// Image reference parsing and source classification
+pub mod reference;
+/// Secret detection in merged layer content
+pub mod secrets;
+/// World-writable permission audit of merged layer content
+pub mod permissions;
+/// Canonical JSON encoding for digested manifests and configs
+pub mod canonical;
+/// Cancellation of a running squash, e.g. on Ctrl-C
+pub mod cancel;
 
 pub use image::*;
 pub use tar::*;
 pub use layer::*;
+pub use oci::*;
+pub use diff::*;
+pub use runtime::*;
+pub use progress::*;
+pub use backend::*;
+pub use reference::*;
+pub use secrets::*;
+pub use permissions::*;
+pub use canonical::*;
+pub use cancel::*;
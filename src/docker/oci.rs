@@ -0,0 +1,320 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Marker file present at the root of every OCI image layout
+pub const OCI_LAYOUT_FILE: &str = "oci-layout";
+/// Entry point of an OCI image layout
+pub const OCI_INDEX_FILE: &str = "index.json";
+
+/// Media type of an OCI image index
+pub const MEDIA_TYPE_OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+/// Media type of an OCI image manifest
+pub const MEDIA_TYPE_OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+/// Media type of an OCI image config blob
+pub const MEDIA_TYPE_OCI_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+/// Media type of an uncompressed OCI layer blob
+pub const MEDIA_TYPE_OCI_LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+/// Media type of a gzip compressed OCI layer blob
+pub const MEDIA_TYPE_OCI_LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+/// Media type of a zstd compressed OCI layer blob
+pub const MEDIA_TYPE_OCI_LAYER_ZSTD: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+/// Media type of a Docker image manifest (schema 2)
+pub const MEDIA_TYPE_DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+/// Media type of a Docker image config blob
+pub const MEDIA_TYPE_DOCKER_CONFIG: &str = "application/vnd.docker.container.image.v1+json";
+/// Media type of an uncompressed layer in a Docker archive
+pub const MEDIA_TYPE_DOCKER_LAYER: &str = "application/vnd.docker.image.rootfs.diff.tar";
+/// Media type of a gzip compressed layer in a Docker archive
+pub const MEDIA_TYPE_DOCKER_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+/// Media type of a Docker manifest list (treated like an OCI index)
+pub const MEDIA_TYPE_DOCKER_MANIFEST_LIST: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+/// Media type of a Docker foreign layer fetched from external URLs
+pub const MEDIA_TYPE_DOCKER_FOREIGN_LAYER: &str =
+    "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip";
+
+/// Annotation carrying the reference name of an image in an OCI index
+pub const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+/// Annotation used by containerd to record the full image name
+pub const ANNOTATION_CONTAINERD_IMAGE_NAME: &str = "io.containerd.image.name";
+/// Annotation linking an attestation manifest to the image manifest it describes
+pub const ANNOTATION_REFERENCE_DIGEST: &str = "vnd.docker.reference.digest";
+/// Annotation carrying the in-toto predicate type of an attestation layer
+pub const ANNOTATION_PREDICATE_TYPE: &str = "in-toto.io/predicate-type";
+/// Annotation carrying the history command that produced a layer
+pub const ANNOTATION_CREATED_BY: &str = "org.squash.created-by";
+
+/// Version written to the oci-layout marker file
+pub const OCI_LAYOUT_VERSION: &str = "1.0.0";
+
+/// Content descriptor pointing at a blob in the OCI blob store
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciDescriptor {
+    /// Media type of the referenced content
+    pub media_type: String,
+    /// Digest of the referenced content (e.g., "sha256:...")
+    pub digest: String,
+    /// Size of the referenced content in bytes
+    pub size: u64,
+    /// Optional annotations attached to the descriptor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<BTreeMap<String, String>>,
+    /// Platform the referenced manifest is built for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+    /// Locations the content can be downloaded from (foreign layers)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub urls: Option<Vec<String>>,
+}
+
+impl OciDescriptor {
+    /// Whether this descriptor references another index rather than an image manifest
+    pub fn is_index(&self) -> bool {
+        self.media_type == MEDIA_TYPE_OCI_INDEX || self.media_type == MEDIA_TYPE_DOCKER_MANIFEST_LIST
+    }
+
+    /// Whether the referenced blob is gzip compressed
+    pub fn is_gzip(&self) -> bool {
+        self.media_type.ends_with("+gzip") || self.media_type.ends_with(".gzip")
+    }
+
+    /// Whether the referenced blob is zstd compressed
+    pub fn is_zstd(&self) -> bool {
+        self.media_type.ends_with("+zstd")
+    }
+
+    /// Whether this descriptor references a foreign (non-distributable) layer
+    pub fn is_foreign(&self) -> bool {
+        self.media_type == MEDIA_TYPE_DOCKER_FOREIGN_LAYER
+            || self.media_type.contains(".nondistributable.")
+            || self.urls.as_ref().is_some_and(|urls| !urls.is_empty())
+    }
+
+    /// Image reference name recorded in the descriptor annotations, if any
+    pub fn ref_name(&self) -> Option<&str> {
+        let annotations = self.annotations.as_ref()?;
+        annotations
+            .get(ANNOTATION_CONTAINERD_IMAGE_NAME)
+            .or_else(|| annotations.get(ANNOTATION_REF_NAME))
+            .map(String::as_str)
+    }
+}
+
+/// Target platform of an image, written as `<os>/<arch>[/<variant>]`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// Whether `other` satisfies this platform; a missing variant matches any variant
+    pub fn matches(&self, other: &Platform) -> bool {
+        self.os == other.os
+            && self.architecture == other.architecture
+            && (self.variant.is_none() || self.variant == other.variant)
+    }
+
+    /// Placeholder platform used by attestation manifests in multi-arch indexes
+    pub fn is_unknown(&self) -> bool {
+        self.os == "unknown" && self.architecture == "unknown"
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split('/').collect();
+        if parts.iter().any(|part| part.is_empty()) {
+            return Err(format!("Invalid platform '{}', expected <os>/<arch>[/<variant>]", s));
+        }
+
+        match parts.as_slice() {
+            [os, architecture] | [os, architecture, _] => Ok(Platform {
+                os: os.to_string(),
+                architecture: architecture.to_string(),
+                variant: parts.get(2).map(|variant| variant.to_string()),
+            }),
+            _ => Err(format!("Invalid platform '{}', expected <os>/<arch>[/<variant>]", s)),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+/// OCI image index as found in index.json
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciIndex {
+    pub schema_version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub manifests: Vec<OciDescriptor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<BTreeMap<String, String>>,
+}
+
+/// OCI image manifest referencing a config blob and layer blobs
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciManifest {
+    pub schema_version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub config: OciDescriptor,
+    pub layers: Vec<OciDescriptor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<BTreeMap<String, String>>,
+}
+
+/// Attestation manifest (e.g. an SBOM) attached to an image manifest
+#[derive(Debug, Clone)]
+pub struct Attestation {
+    /// Descriptor of the attestation manifest in the index
+    pub descriptor: OciDescriptor,
+    /// Attestation manifest, kept as raw JSON to preserve unknown fields
+    pub manifest: serde_json::Value,
+    /// Extracted config and layer blobs of the attestation, by digest
+    pub blobs: Vec<(String, PathBuf)>,
+}
+
+impl Attestation {
+    /// Whether the attestation describes the manifest with the given digest,
+    /// either through the reference annotation or an OCI `subject`
+    pub fn references(descriptor: &OciDescriptor, manifest: &serde_json::Value, digest: &str) -> bool {
+        let annotated = descriptor
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(ANNOTATION_REFERENCE_DIGEST))
+            .is_some_and(|reference| reference == digest);
+
+        annotated || manifest["subject"]["digest"].as_str() == Some(digest)
+    }
+
+    /// Predicate types of the attestation layers (e.g. an SPDX SBOM)
+    pub fn predicate_types(&self) -> Vec<&str> {
+        self.manifest["layers"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|layer| layer["annotations"][ANNOTATION_PREDICATE_TYPE].as_str())
+            .collect()
+    }
+
+    /// Point the attestation at a new subject manifest
+    ///
+    /// Returns the rewritten manifest JSON and the descriptor to place in
+    /// the index; its digest and size are left for the caller to fill in.
+    pub fn with_subject(&self, subject: &OciDescriptor) -> (serde_json::Value, OciDescriptor) {
+        let mut manifest = self.manifest.clone();
+        if manifest.get("subject").is_some() {
+            manifest["subject"] = serde_json::json!({
+                "mediaType": subject.media_type,
+                "digest": subject.digest,
+                "size": subject.size,
+            });
+        }
+
+        let mut descriptor = self.descriptor.clone();
+        if let Some(annotations) = descriptor.annotations.as_mut() {
+            if annotations.contains_key(ANNOTATION_REFERENCE_DIGEST) {
+                annotations.insert(ANNOTATION_REFERENCE_DIGEST.to_string(), subject.digest.clone());
+            }
+        }
+
+        (manifest, descriptor)
+    }
+}
+
+/// Path of a blob inside an OCI image layout for the given digest
+pub fn blob_path(digest: &str) -> Option<String> {
+    let (algorithm, encoded) = digest.split_once(':')?;
+    let valid = !algorithm.is_empty()
+        && !encoded.is_empty()
+        && algorithm.chars().all(|c| c.is_ascii_alphanumeric())
+        && encoded.chars().all(|c| c.is_ascii_hexdigit());
+
+    if valid {
+        Some(format!("blobs/{}/{}", algorithm, encoded))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blob_path() {
+        assert_eq!(blob_path("sha256:abc123").unwrap(), "blobs/sha256/abc123");
+        assert!(blob_path("layer1.tar").is_none());
+        assert!(blob_path("sha256:not-hex").is_none());
+        assert!(blob_path("abc/layer.tar").is_none());
+    }
+
+    #[test]
+    fn test_descriptor_ref_name() {
+        let descriptor: OciDescriptor = serde_json::from_str(r#"{
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "digest": "sha256:abc123",
+            "size": 10,
+            "annotations": {"org.opencontainers.image.ref.name": "latest"}
+        }"#).unwrap();
+
+        assert_eq!(descriptor.ref_name(), Some("latest"));
+        assert!(!descriptor.is_index());
+        assert!(!descriptor.is_gzip());
+        assert!(!descriptor.is_foreign());
+    }
+
+    #[test]
+    fn test_descriptor_is_foreign() {
+        let descriptor: OciDescriptor = serde_json::from_str(r#"{
+            "mediaType": "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip",
+            "digest": "sha256:abc123",
+            "size": 10,
+            "urls": ["https://example.com/layer"]
+        }"#).unwrap();
+
+        assert!(descriptor.is_foreign());
+        assert!(descriptor.is_gzip());
+        assert!(!descriptor.is_zstd());
+
+        let descriptor = OciDescriptor { media_type: MEDIA_TYPE_OCI_LAYER_ZSTD.to_string(), ..descriptor };
+        assert!(descriptor.is_zstd());
+        assert!(!descriptor.is_gzip());
+    }
+
+    #[test]
+    fn test_platform_parsing() {
+        let platform: Platform = "linux/arm64/v8".parse().unwrap();
+        assert_eq!(platform.os, "linux");
+        assert_eq!(platform.architecture, "arm64");
+        assert_eq!(platform.variant.as_deref(), Some("v8"));
+        assert_eq!(platform.to_string(), "linux/arm64/v8");
+
+        let requested: Platform = "linux/arm64".parse().unwrap();
+        assert!(requested.matches(&platform));
+        assert!(!platform.matches(&requested));
+
+        assert!("linux".parse::<Platform>().is_err());
+        assert!("linux//v8".parse::<Platform>().is_err());
+        assert!("linux/arm/v7/extra".parse::<Platform>().is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use crate::docker::diff::{FlattenedEntry, FlattenedFilesystem};
+use crate::error::{Result, SquashError};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tar::EntryType;
+
+/// Write permission for users outside the owner and group
+const WORLD_WRITABLE: u32 = 0o002;
+
+/// Restricts deletion in a directory to the owners of its entries
+const STICKY: u32 = 0o1000;
+
+/// Why a path was flagged by the permission audit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PermissionIssue {
+    /// A regular file anyone may modify
+    WorldWritableFile,
+    /// A directory anyone may add to or delete from, without the sticky bit
+    WorldWritableDirectory,
+}
+
+impl fmt::Display for PermissionIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PermissionIssue::WorldWritableFile => write!(f, "world-writable file"),
+            PermissionIssue::WorldWritableDirectory => write!(f, "world-writable directory without sticky bit"),
+        }
+    }
+}
+
+/// A path flagged by the permission audit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PermissionFinding {
+    /// Path of the entry inside the layer
+    pub path: PathBuf,
+    /// Permission bits of the entry
+    pub mode: u32,
+    pub issue: PermissionIssue,
+}
+
+impl fmt::Display for PermissionFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({:04o}): {}", self.path.display(), self.mode, self.issue)
+    }
+}
+
+/// Audit of merged content for world-writable files
+///
+/// Symlinks, hard links and device nodes are not flagged: their mode bits
+/// do not grant write access to any content of their own. Directories are
+/// only checked when enabled, as `/tmp`-style directories are legitimately
+/// world-writable with the sticky bit set.
+#[derive(Debug, Clone, Default)]
+pub struct PermissionAudit {
+    directories: bool,
+    findings: Vec<PermissionFinding>,
+}
+
+impl PermissionAudit {
+    /// Audit regular files only
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also flag world-writable directories that lack the sticky bit
+    pub fn with_directories(mut self, directories: bool) -> Self {
+        self.directories = directories;
+        self
+    }
+
+    /// Paths flagged so far, in the order they were seen
+    pub fn findings(&self) -> &[PermissionFinding] {
+        &self.findings
+    }
+
+    /// Fail with VerificationFailed when anything was flagged
+    pub fn check(&self) -> Result<()> {
+        if self.findings.is_empty() {
+            return Ok(());
+        }
+
+        let summary: Vec<String> = self.findings.iter().map(ToString::to_string).collect();
+        Err(SquashError::VerificationFailed(format!(
+            "Found {} insecure permission(s): {}",
+            self.findings.len(),
+            summary.join(", ")
+        )))
+    }
+
+    /// Audit every entry of a flattened filesystem
+    pub fn audit(&mut self, files: &FlattenedFilesystem) {
+        for (path, entry) in files {
+            self.audit_entry(path, entry);
+        }
+    }
+
+    /// Record a finding if `entry` has insecure permissions
+    pub fn audit_entry(&mut self, path: &Path, entry: &FlattenedEntry) {
+        if entry.mode & WORLD_WRITABLE == 0 {
+            return;
+        }
+
+        let issue = match entry.entry_type {
+            EntryType::Regular | EntryType::Continuous => PermissionIssue::WorldWritableFile,
+            EntryType::Directory if self.directories && entry.mode & STICKY == 0 => {
+                PermissionIssue::WorldWritableDirectory
+            }
+            _ => return,
+        };
+        self.findings.push(PermissionFinding { path: path.to_path_buf(), mode: entry.mode, issue });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(entry_type: EntryType, mode: u32) -> FlattenedEntry {
+        FlattenedEntry {
+            entry_type,
+            mode,
+            link_name: None,
+            size: 0,
+            digest: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_world_writable_entries_are_flagged() {
+        let files = FlattenedFilesystem::from([
+            (PathBuf::from("app/cache.db"), entry(EntryType::Regular, 0o666)),
+            (PathBuf::from("app/run.sh"), entry(EntryType::Regular, 0o755)),
+            (PathBuf::from("bin/sh"), entry(EntryType::Symlink, 0o777)),
+            (PathBuf::from("dev/null"), entry(EntryType::Char, 0o666)),
+            (PathBuf::from("tmp"), entry(EntryType::Directory, 0o1777)),
+            (PathBuf::from("var/shared"), entry(EntryType::Directory, 0o777)),
+        ]);
+
+        let mut files_only = PermissionAudit::new();
+        files_only.audit(&files);
+        let flagged: Vec<String> = files_only.findings().iter().map(ToString::to_string).collect();
+        assert_eq!(flagged, vec!["app/cache.db (0666): world-writable file"]);
+        assert!(files_only.check().is_err());
+
+        let mut with_directories = PermissionAudit::new().with_directories(true);
+        with_directories.audit(&files);
+        let flagged: Vec<(&Path, PermissionIssue)> = with_directories
+            .findings()
+            .iter()
+            .map(|finding| (finding.path.as_path(), finding.issue))
+            .collect();
+        assert_eq!(flagged, vec![
+            (Path::new("app/cache.db"), PermissionIssue::WorldWritableFile),
+            (Path::new("var/shared"), PermissionIssue::WorldWritableDirectory),
+        ]);
+
+        assert!(PermissionAudit::new().check().is_ok());
+    }
+}
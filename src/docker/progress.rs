@@ -0,0 +1,207 @@
+use crate::docker::layer::LayerInfo;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::info;
+use std::path::Path;
+
+/// Receives progress events while layers are merged
+///
+/// All methods default to doing nothing, so implementors only override
+/// the events they care about.
+pub trait ProgressObserver {
+    /// A layer (1-based `index` of `total`) is about to be read
+    fn on_layer_start(&mut self, _index: usize, _total: usize, _layer: &LayerInfo) {}
+
+    /// `bytes` of entry content were read from the current layer
+    fn on_bytes(&mut self, _bytes: u64) {}
+
+    /// A regular file small enough to be held in memory was read
+    fn on_entry(&mut self, _path: &Path, _content: &[u8]) {}
+
+    /// A layer (1-based `index` of `total`) has been fully read
+    fn on_layer_done(&mut self, _index: usize, _total: usize, _layer: &LayerInfo) {}
+
+    /// The merged layer has been written
+    fn on_complete(&mut self, _merged: &LayerInfo) {}
+
+    /// Writing an image archive whose layers hold `total_bytes` has started
+    fn on_save_start(&mut self, _total_bytes: u64) {}
+
+    /// `bytes` of layer content were written to the archive
+    fn on_save_bytes(&mut self, _bytes: u64) {}
+
+    /// The image archive has been written
+    fn on_save_done(&mut self) {}
+}
+
+/// An observer borrowed from elsewhere, so it can be paired and used again later
+impl<T: ProgressObserver + ?Sized> ProgressObserver for &mut T {
+    fn on_layer_start(&mut self, index: usize, total: usize, layer: &LayerInfo) {
+        (**self).on_layer_start(index, total, layer);
+    }
+
+    fn on_bytes(&mut self, bytes: u64) {
+        (**self).on_bytes(bytes);
+    }
+
+    fn on_entry(&mut self, path: &Path, content: &[u8]) {
+        (**self).on_entry(path, content);
+    }
+
+    fn on_layer_done(&mut self, index: usize, total: usize, layer: &LayerInfo) {
+        (**self).on_layer_done(index, total, layer);
+    }
+
+    fn on_complete(&mut self, merged: &LayerInfo) {
+        (**self).on_complete(merged);
+    }
+
+    fn on_save_start(&mut self, total_bytes: u64) {
+        (**self).on_save_start(total_bytes);
+    }
+
+    fn on_save_bytes(&mut self, bytes: u64) {
+        (**self).on_save_bytes(bytes);
+    }
+
+    fn on_save_done(&mut self) {
+        (**self).on_save_done();
+    }
+}
+
+/// A pair of observers receives every event in turn
+impl<A: ProgressObserver, B: ProgressObserver> ProgressObserver for (A, B) {
+    fn on_layer_start(&mut self, index: usize, total: usize, layer: &LayerInfo) {
+        self.0.on_layer_start(index, total, layer);
+        self.1.on_layer_start(index, total, layer);
+    }
+
+    fn on_bytes(&mut self, bytes: u64) {
+        self.0.on_bytes(bytes);
+        self.1.on_bytes(bytes);
+    }
+
+    fn on_entry(&mut self, path: &Path, content: &[u8]) {
+        self.0.on_entry(path, content);
+        self.1.on_entry(path, content);
+    }
+
+    fn on_layer_done(&mut self, index: usize, total: usize, layer: &LayerInfo) {
+        self.0.on_layer_done(index, total, layer);
+        self.1.on_layer_done(index, total, layer);
+    }
+
+    fn on_complete(&mut self, merged: &LayerInfo) {
+        self.0.on_complete(merged);
+        self.1.on_complete(merged);
+    }
+
+    fn on_save_start(&mut self, total_bytes: u64) {
+        self.0.on_save_start(total_bytes);
+        self.1.on_save_start(total_bytes);
+    }
+
+    fn on_save_bytes(&mut self, bytes: u64) {
+        self.0.on_save_bytes(bytes);
+        self.1.on_save_bytes(bytes);
+    }
+
+    fn on_save_done(&mut self) {
+        self.0.on_save_done();
+        self.1.on_save_done();
+    }
+}
+
+/// Observer that ignores every event
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopObserver;
+
+impl ProgressObserver for NoopObserver {}
+
+/// Observer that logs per-layer byte counts at info level
+#[derive(Debug, Clone, Default)]
+pub struct ConsoleObserver {
+    layer_bytes: u64,
+    total_bytes: u64,
+}
+
+impl ProgressObserver for ConsoleObserver {
+    fn on_layer_start(&mut self, _index: usize, _total: usize, _layer: &LayerInfo) {
+        self.layer_bytes = 0;
+    }
+
+    fn on_bytes(&mut self, bytes: u64) {
+        self.layer_bytes += bytes;
+        self.total_bytes += bytes;
+    }
+
+    fn on_layer_done(&mut self, index: usize, total: usize, _layer: &LayerInfo) {
+        info!("  Layer {}/{} done: {} bytes read", index, total, self.layer_bytes);
+    }
+
+    fn on_complete(&mut self, merged: &LayerInfo) {
+        info!("Merged {} bytes into layer {}", self.total_bytes, merged.digest);
+    }
+}
+
+/// Observer drawing progress bars on stderr
+///
+/// Shows the layer being merged as N/M and the layer bytes written while
+/// saving. Bars are drawn even when stderr is not a terminal, so callers
+/// decide whether to use this observer at all.
+#[derive(Default)]
+pub struct ProgressBarObserver {
+    merge: Option<ProgressBar>,
+    save: Option<ProgressBar>,
+}
+
+impl ProgressBarObserver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn bar(len: u64, prefix: &'static str, template: &str) -> ProgressBar {
+        let style = ProgressStyle::with_template(template)
+            .unwrap_or_else(|_| ProgressStyle::default_bar())
+            .progress_chars("=> ");
+        ProgressBar::new(len).with_style(style).with_prefix(prefix)
+    }
+}
+
+impl ProgressObserver for ProgressBarObserver {
+    fn on_layer_start(&mut self, index: usize, total: usize, layer: &LayerInfo) {
+        let bar = self.merge.get_or_insert_with(|| {
+            Self::bar(total as u64, "Merging", "{prefix} [{bar:30}] {pos}/{len} layers {msg}")
+        });
+        // A resumed merge starts part-way through
+        bar.set_position(index as u64 - 1);
+        bar.set_message(layer.digest.chars().take(19).collect::<String>());
+    }
+
+    fn on_layer_done(&mut self, index: usize, _total: usize, _layer: &LayerInfo) {
+        if let Some(bar) = &self.merge {
+            bar.set_position(index as u64);
+        }
+    }
+
+    fn on_complete(&mut self, _merged: &LayerInfo) {
+        if let Some(bar) = self.merge.take() {
+            bar.finish_and_clear();
+        }
+    }
+
+    fn on_save_start(&mut self, total_bytes: u64) {
+        self.save = Some(Self::bar(total_bytes, "Saving", "{prefix} [{bar:30}] {bytes}/{total_bytes} ({eta})"));
+    }
+
+    fn on_save_bytes(&mut self, bytes: u64) {
+        if let Some(bar) = &self.save {
+            bar.inc(bytes);
+        }
+    }
+
+    fn on_save_done(&mut self) {
+        if let Some(bar) = self.save.take() {
+            bar.finish_and_clear();
+        }
+    }
+}
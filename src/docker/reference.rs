@@ -0,0 +1,317 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// File extensions that mark a source as an image archive rather than a reference
+const ARCHIVE_EXTENSIONS: &[&str] = &[".tar", ".tar.gz", ".tgz"];
+
+/// Parsed Docker image reference: `[registry[:port]/]repository[:tag][@digest]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageReference {
+    /// Registry host with optional port (e.g., "localhost:5000")
+    pub registry: Option<String>,
+    /// Repository path (e.g., "library/ubuntu")
+    pub repository: String,
+    /// Tag (e.g., "latest")
+    pub tag: Option<String>,
+    /// Content digest (e.g., "sha256:...")
+    pub digest: Option<String>,
+}
+
+impl FromStr for ImageReference {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("Invalid image reference '{}'", s);
+
+        let (name, digest) = match s.split_once('@') {
+            Some((name, digest)) if is_digest(digest) => (name, Some(digest.to_string())),
+            Some(_) => return Err(invalid()),
+            None => (s, None),
+        };
+
+        // A colon after the last slash separates the tag; earlier ones belong to a registry port
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((repo, tag)) if !tag.contains('/') => {
+                if !is_tag(tag) {
+                    return Err(invalid());
+                }
+                (repo, Some(tag.to_string()))
+            }
+            _ => (name, None),
+        };
+
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, rest)) if is_registry_host(host) => (Some(host.to_string()), rest),
+            _ => (None, name),
+        };
+
+        if repository.is_empty() || !repository.split('/').all(is_path_component) {
+            return Err(invalid());
+        }
+        if registry.as_deref().is_some_and(|host| !is_valid_host(host)) {
+            return Err(invalid());
+        }
+
+        Ok(ImageReference {
+            registry,
+            repository: repository.to_string(),
+            tag,
+            digest,
+        })
+    }
+}
+
+impl ImageReference {
+    /// Registry and repository without the tag or digest, e.g. `localhost:5000/app`
+    pub fn name(&self) -> String {
+        match &self.registry {
+            Some(registry) => format!("{}/{}", registry, self.repository),
+            None => self.repository.clone(),
+        }
+    }
+}
+
+impl fmt::Display for ImageReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(registry) = &self.registry {
+            write!(f, "{}/", registry)?;
+        }
+        write!(f, "{}", self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where an image should be read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageSource {
+    /// A `docker save` or OCI archive on disk
+    File(PathBuf),
+    /// An image known to the Docker daemon
+    Reference(ImageReference),
+    /// An image archive to download over HTTP(S)
+    Url(String),
+}
+
+/// How to read a `--source` value instead of guessing from its shape
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SourceFormat {
+    /// A `docker save` archive on disk, read through its manifest.json
+    DockerArchive,
+    /// An OCI image layout archive on disk, read through its index.json
+    OciArchive,
+    /// An image name exported from the Docker daemon with `docker save`
+    DockerDaemon,
+}
+
+impl ImageSource {
+    /// Classify a `--source` value, autodetecting it when `format` is `None`
+    ///
+    /// An explicit format skips the heuristics of [`ImageSource::parse`]:
+    /// archive formats always read a file and `DockerDaemon` always
+    /// exports an image, failing when the value is no valid reference.
+    pub fn parse_as(source: &str, format: Option<SourceFormat>) -> std::result::Result<Self, String> {
+        match format {
+            None => Self::parse(source),
+            Some(SourceFormat::DockerDaemon) => source.parse().map(ImageSource::Reference),
+            Some(SourceFormat::DockerArchive | SourceFormat::OciArchive) => Ok(ImageSource::File(PathBuf::from(source))),
+        }
+    }
+
+    /// Classify a `--source` value
+    ///
+    /// Existing paths, explicit relative/absolute paths and archive file
+    /// names are files; anything else that parses as a reference is an image.
+    /// Digest-pinned references (`name@sha256:...`) are images too, but a
+    /// digest without a repository names no image and is an error.
+    pub fn parse(source: &str) -> std::result::Result<Self, String> {
+        if source.starts_with("http://") || source.starts_with("https://") {
+            return Ok(ImageSource::Url(source.to_string()));
+        }
+
+        let looks_like_path = Path::new(source).exists()
+            || source.starts_with("./")
+            || source.starts_with("../")
+            || source.starts_with('/')
+            || ARCHIVE_EXTENSIONS.iter().any(|ext| source.ends_with(ext));
+
+        if looks_like_path {
+            return Ok(ImageSource::File(PathBuf::from(source)));
+        }
+
+        let digest = source.strip_prefix('@').unwrap_or(source);
+        if is_digest(digest) {
+            return Err(format!(
+                "Source '{}' is a digest without a repository, use <name>@{}",
+                source, digest
+            ));
+        }
+
+        match source.parse() {
+            Ok(reference) => Ok(ImageSource::Reference(reference)),
+            Err(_) => Ok(ImageSource::File(PathBuf::from(source))),
+        }
+    }
+}
+
+/// First path component names a registry when it has a dot, a port or is localhost
+fn is_registry_host(component: &str) -> bool {
+    component.contains('.') || component.contains(':') || component == "localhost"
+}
+
+/// Host name with an optional numeric port
+fn is_valid_host(host: &str) -> bool {
+    let (name, port) = match host.split_once(':') {
+        Some((name, port)) => (name, Some(port)),
+        None => (host, None),
+    };
+
+    !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+        && port.is_none_or(|port| !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Lowercase alphanumerics separated by `.`, `_`, `__` or `-`
+fn is_path_component(component: &str) -> bool {
+    !component.is_empty()
+        && component.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && component.ends_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && component
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '.' | '_' | '-'))
+}
+
+/// Up to 128 word characters, dots and dashes, not starting with either
+fn is_tag(tag: &str) -> bool {
+    tag.len() <= 128
+        && tag.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        && tag.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+/// `<algorithm>:<hex>` with at least 32 hex characters
+fn is_digest(digest: &str) -> bool {
+    digest.split_once(':').is_some_and(|(algorithm, hex)| {
+        !algorithm.is_empty()
+            && algorithm.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+            && hex.len() >= 32
+            && hex.chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference_with_registry_port() {
+        let reference: ImageReference = "localhost:5000/x:1".parse().unwrap();
+        assert_eq!(reference.registry.as_deref(), Some("localhost:5000"));
+        assert_eq!(reference.repository, "x");
+        assert_eq!(reference.tag.as_deref(), Some("1"));
+        assert_eq!(reference.digest, None);
+
+        let reference: ImageReference = "registry.local:5000/team/app".parse().unwrap();
+        assert_eq!(reference.registry.as_deref(), Some("registry.local:5000"));
+        assert_eq!(reference.repository, "team/app");
+        assert_eq!(reference.tag, None);
+        assert_eq!(reference.to_string(), "registry.local:5000/team/app");
+        assert_eq!(reference.name(), "registry.local:5000/team/app");
+
+        let reference: ImageReference = "registry.local:5000/team/app:v1".parse().unwrap();
+        assert_eq!(reference.name(), "registry.local:5000/team/app");
+        assert_eq!("nginx:latest".parse::<ImageReference>().unwrap().name(), "nginx");
+    }
+
+    #[test]
+    fn test_reference_with_digest() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let reference: ImageReference = format!("ubuntu@{}", digest).parse().unwrap();
+        assert_eq!(reference.registry, None);
+        assert_eq!(reference.repository, "ubuntu");
+        assert_eq!(reference.tag, None);
+        assert_eq!(reference.digest.as_deref(), Some(digest.as_str()));
+
+        assert!("ubuntu@sha256:xyz".parse::<ImageReference>().is_err());
+    }
+
+    #[test]
+    fn test_invalid_references() {
+        assert!("Ubuntu:latest".parse::<ImageReference>().is_err());
+        assert!("ubuntu:".parse::<ImageReference>().is_err());
+        assert!("my:file.tar/x".parse::<ImageReference>().is_err());
+    }
+
+    #[test]
+    fn test_source_classification() {
+        assert_eq!(ImageSource::parse("./my:file.tar"), Ok(ImageSource::File(PathBuf::from("./my:file.tar"))));
+        assert_eq!(ImageSource::parse("my:file.tar"), Ok(ImageSource::File(PathBuf::from("my:file.tar"))));
+        assert_eq!(ImageSource::parse("/tmp/image"), Ok(ImageSource::File(PathBuf::from("/tmp/image"))));
+
+        match ImageSource::parse("localhost:5000/x:1") {
+            Ok(ImageSource::Reference(reference)) => assert_eq!(reference.to_string(), "localhost:5000/x:1"),
+            other => panic!("Expected reference, got {:?}", other),
+        }
+        assert!(matches!(ImageSource::parse("nginx"), Ok(ImageSource::Reference(_))));
+
+        assert_eq!(
+            ImageSource::parse("https://artifacts.example.com/builds/image.tar"),
+            Ok(ImageSource::Url("https://artifacts.example.com/builds/image.tar".to_string()))
+        );
+        assert!(matches!(ImageSource::parse("http://localhost:8080/image.tar.gz"), Ok(ImageSource::Url(_))));
+    }
+
+    #[test]
+    fn test_digest_source_classification() {
+        let digest = format!("sha256:{}", "ab12".repeat(16));
+
+        // Digest-pinned sources are images and reach docker save unchanged
+        for source in [
+            format!("ubuntu@{}", digest),
+            format!("ubuntu:22.04@{}", digest),
+            format!("localhost:5000/team/app@{}", digest),
+        ] {
+            match ImageSource::parse(&source) {
+                Ok(ImageSource::Reference(reference)) => {
+                    assert_eq!(reference.digest.as_deref(), Some(digest.as_str()));
+                    assert_eq!(reference.to_string(), source);
+                }
+                other => panic!("Expected reference for {}, got {:?}", source, other),
+            }
+        }
+
+        // A digest alone names no repository to save from
+        for source in [digest.clone(), format!("@{}", digest)] {
+            let err = ImageSource::parse(&source).unwrap_err();
+            assert!(err.contains("digest without a repository"), "{}", err);
+            assert!(err.contains(&format!("<name>@{}", digest)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_source_format_skips_detection() {
+        // Without a format the shape of the value decides
+        assert_eq!(ImageSource::parse_as("app.tar", None), ImageSource::parse("app.tar"));
+        assert!(matches!(ImageSource::parse_as("ubuntu:22.04", None), Ok(ImageSource::Reference(_))));
+
+        // Archive formats read any value as a file, even one shaped like an image name
+        for format in [SourceFormat::DockerArchive, SourceFormat::OciArchive] {
+            assert_eq!(
+                ImageSource::parse_as("ubuntu:22.04", Some(format)),
+                Ok(ImageSource::File(PathBuf::from("ubuntu:22.04")))
+            );
+        }
+
+        // The daemon format reads any valid reference as an image, even one shaped like a file
+        match ImageSource::parse_as("backup.tar", Some(SourceFormat::DockerDaemon)) {
+            Ok(ImageSource::Reference(reference)) => assert_eq!(reference.repository, "backup.tar"),
+            other => panic!("Expected reference, got {:?}", other),
+        }
+        assert!(ImageSource::parse_as("/srv/backup.tar", Some(SourceFormat::DockerDaemon)).is_err());
+    }
+}
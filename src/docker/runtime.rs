@@ -0,0 +1,315 @@
+use crate::docker::oci::OciDescriptor;
+use crate::error::{Result, SquashError};
+use flate2::read::GzDecoder;
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Transports understood by `skopeo copy`
+const SKOPEO_TRANSPORTS: &[&str] = &[
+    "docker://",
+    "docker-archive:",
+    "docker-daemon:",
+    "containers-storage:",
+    "oci:",
+    "oci-archive:",
+    "dir:",
+];
+
+/// Backend used to fetch the source image and publish the squashed result
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Runtime {
+    /// `docker save` / `docker load` against the local Docker daemon
+    #[default]
+    Docker,
+    /// `skopeo copy` between any skopeo transport and a local OCI archive
+    Skopeo,
+}
+
+/// Thin wrapper around the `skopeo` command line tool
+#[derive(Debug, Clone)]
+pub struct Skopeo {
+    program: PathBuf,
+}
+
+impl Default for Skopeo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Skopeo {
+    /// Use the `skopeo` binary found on PATH
+    pub fn new() -> Self {
+        Self::with_program("skopeo")
+    }
+
+    /// Use a specific skopeo binary
+    pub fn with_program(program: impl Into<PathBuf>) -> Self {
+        Skopeo { program: program.into() }
+    }
+
+    /// Image reference with an explicit transport; bare names default to `docker://`
+    pub fn transport_reference(reference: &str) -> String {
+        if SKOPEO_TRANSPORTS.iter().any(|transport| reference.starts_with(transport)) {
+            reference.to_string()
+        } else {
+            format!("docker://{}", reference)
+        }
+    }
+
+    /// Arguments of a `skopeo copy` invocation
+    pub fn copy_args(source: &str, destination: &str) -> Vec<String> {
+        vec![
+            "copy".to_string(),
+            Self::transport_reference(source),
+            Self::transport_reference(destination),
+        ]
+    }
+
+    /// Copy `source` into an OCI archive inside `temp_dir` and return its path
+    pub fn fetch(&self, source: &str, temp_dir: &Path) -> Result<PathBuf> {
+        let file_name: String = source
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+            .collect();
+        let archive_path = temp_dir.join(format!("{}.oci.tar", file_name));
+
+        self.copy(Self::transport_reference(source).as_ref(), &Self::oci_archive_reference(&archive_path))?;
+        Ok(archive_path)
+    }
+
+    /// Copy a local OCI archive to the `destination` transport
+    pub fn push(&self, archive_path: &Path, destination: &str) -> Result<()> {
+        self.copy(&Self::oci_archive_reference(archive_path), Self::transport_reference(destination).as_ref())
+    }
+
+    /// `oci-archive:` reference to a local file, keeping non-UTF-8 paths intact
+    fn oci_archive_reference(archive_path: &Path) -> OsString {
+        let mut reference = OsString::from("oci-archive:");
+        reference.push(archive_path);
+        reference
+    }
+
+    /// Run `skopeo copy` between two references that already carry a transport
+    fn copy(&self, source: &OsStr, destination: &OsStr) -> Result<()> {
+        debug!(
+            "Running: {} copy {} {}",
+            self.program.display(),
+            source.to_string_lossy(),
+            destination.to_string_lossy()
+        );
+
+        let output = Command::new(&self.program)
+            .arg("copy")
+            .arg(source)
+            .arg(destination)
+            .output()
+            .map_err(|e| SquashError::SkopeoError(format!("Failed to run skopeo copy: {}", e)))?;
+
+        if !output.status.success() {
+            return Err(SquashError::SkopeoError(format!(
+                "skopeo copy failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Download a foreign layer from its URLs into `destination` as a plain tar
+///
+/// URLs are tried in order with `curl`. The downloaded blob must match the
+/// descriptor digest and is decompressed when the media type is gzip.
+pub fn download_foreign_layer(descriptor: &OciDescriptor, destination: &Path) -> Result<()> {
+    let urls = descriptor.urls.as_deref().unwrap_or_default();
+    if urls.is_empty() {
+        return Err(SquashError::DownloadError(format!(
+            "Foreign layer {} has no URLs to download from",
+            descriptor.digest
+        )));
+    }
+
+    let blob_path = destination.with_extension("download");
+    let mut errors = Vec::new();
+
+    for url in urls {
+        info!("Downloading foreign layer {} from {}", descriptor.digest, url);
+
+        let output = Command::new("curl")
+            .args(["-fsSL", "-o"])
+            .arg(&blob_path)
+            .arg(url)
+            .output()
+            .map_err(|e| SquashError::DownloadError(format!("Failed to run curl: {}", e)))?;
+
+        if !output.status.success() {
+            errors.push(format!("{}: {}", url, String::from_utf8_lossy(&output.stderr).trim()));
+            continue;
+        }
+
+        let digest = format!("sha256:{:x}", Sha256::digest(std::fs::read(&blob_path)?));
+        if digest != descriptor.digest {
+            errors.push(format!("{}: digest mismatch, got {}", url, digest));
+            continue;
+        }
+
+        if descriptor.is_gzip() {
+            let mut decoder = GzDecoder::new(File::open(&blob_path)?);
+            std::io::copy(&mut decoder, &mut File::create(destination)?)?;
+            std::fs::remove_file(&blob_path)?;
+        } else {
+            std::fs::rename(&blob_path, destination)?;
+        }
+
+        return Ok(());
+    }
+
+    let _ = std::fs::remove_file(&blob_path);
+    Err(SquashError::DownloadError(format!(
+        "Failed to download foreign layer {}: {}",
+        descriptor.digest,
+        errors.join("; ")
+    )))
+}
+
+/// Download an image archive from an `http://` or `https://` URL into `temp_dir`
+///
+/// The body is streamed to disk, so the archive is never held in memory.
+/// Anything but a 200 response is an error.
+#[cfg(feature = "http")]
+pub fn download_source(url: &str, temp_dir: &Path) -> Result<PathBuf> {
+    let mut download = tempfile::Builder::new()
+        .prefix("squash-source-")
+        .suffix(".tar")
+        .tempfile_in(temp_dir)?;
+
+    info!("Downloading source image from {}", url);
+    let status_error = |status: u16| {
+        SquashError::DownloadError(format!(
+            "Failed to download {}: server responded with HTTP status {}",
+            url, status
+        ))
+    };
+    let response = match ureq::get(url).call() {
+        Ok(response) if response.status() == 200 => response,
+        Ok(response) => return Err(status_error(response.status())),
+        Err(ureq::Error::Status(status, _)) => return Err(status_error(status)),
+        Err(e) => return Err(SquashError::DownloadError(format!("Failed to download {}: {}", url, e))),
+    };
+    std::io::copy(&mut response.into_reader(), download.as_file_mut())
+        .map_err(|e| SquashError::DownloadError(format!("Failed to download {}: {}", url, e)))?;
+
+    let path = download.into_temp_path().keep().map_err(|e| e.error)?;
+    debug!("Downloaded source image to {}", path.display());
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Answer one HTTP request on a local port with `status` and `body`, returning the URL
+    #[cfg(feature = "http")]
+    fn serve_once(status: &'static str, body: &'static [u8]) -> String {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/image.tar", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+            let head = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+            stream.write_all(head.as_bytes()).unwrap();
+            stream.write_all(body).unwrap();
+        });
+        url
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_download_source() {
+        let temp_dir = TempDir::new().unwrap();
+
+        let path = download_source(&serve_once("200 OK", b"archive bytes"), temp_dir.path()).unwrap();
+        assert!(path.starts_with(temp_dir.path()));
+        assert_eq!(std::fs::read(&path).unwrap(), b"archive bytes");
+
+        match download_source(&serve_once("404 Not Found", b"missing"), temp_dir.path()) {
+            Err(SquashError::DownloadError(msg)) => assert!(msg.contains("HTTP status 404"), "{}", msg),
+            other => panic!("Expected DownloadError, got {:?}", other),
+        }
+        // A failed download leaves nothing behind
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_transport_reference() {
+        assert_eq!(Skopeo::transport_reference("nginx:latest"), "docker://nginx:latest");
+        assert_eq!(Skopeo::transport_reference("docker://nginx:latest"), "docker://nginx:latest");
+        assert_eq!(Skopeo::transport_reference("containers-storage:nginx:latest"), "containers-storage:nginx:latest");
+        assert_eq!(Skopeo::transport_reference("oci:/tmp/layout:latest"), "oci:/tmp/layout:latest");
+        assert_eq!(Skopeo::transport_reference("dir:/tmp/image"), "dir:/tmp/image");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_skopeo_copy_arguments() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("args.log");
+        let program = temp_dir.path().join("skopeo");
+        std::fs::write(&program, format!("#!/bin/sh\necho \"$@\" >> {}\n", log_path.display())).unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let skopeo = Skopeo::with_program(&program);
+        let archive_path = skopeo.fetch("docker://nginx:latest", temp_dir.path()).unwrap();
+        skopeo.push(&archive_path, "containers-storage:nginx:squashed").unwrap();
+
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        let calls: Vec<&str> = log.lines().collect();
+        assert_eq!(calls, vec![
+            format!("copy docker://nginx:latest oci-archive:{}", archive_path.display()),
+            format!("copy oci-archive:{} containers-storage:nginx:squashed", archive_path.display()),
+        ]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_skopeo_keeps_non_utf8_paths() {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("args.log");
+        let program = temp_dir.path().join("skopeo");
+        std::fs::write(&program, format!("#!/bin/sh\nprintf '%s\\n' \"$3\" >> {}\n", log_path.display())).unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let archive_dir = temp_dir.path().join(OsStr::from_bytes(b"images-\xff"));
+        std::fs::create_dir(&archive_dir).unwrap();
+        let archive_path = Skopeo::with_program(&program).fetch("docker://nginx:latest", &archive_dir).unwrap();
+
+        let mut expected = b"oci-archive:".to_vec();
+        expected.extend_from_slice(archive_path.as_os_str().as_bytes());
+        expected.push(b'\n');
+        assert_eq!(std::fs::read(&log_path).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_skopeo_failure_is_reported() {
+        let skopeo = Skopeo::with_program("/nonexistent/skopeo");
+        let temp_dir = TempDir::new().unwrap();
+        match skopeo.fetch("docker://nginx:latest", temp_dir.path()) {
+            Err(SquashError::SkopeoError(msg)) => assert!(msg.contains("Failed to run skopeo copy")),
+            other => panic!("Expected SkopeoError, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -1,75 +1,609 @@
+use crate::docker::layer::{resolve_symlink_target, GZIP_MAGIC, ZSTD_MAGIC};
+use crate::docker::oci::blob_path;
 use crate::error::{Result, SquashError};
 use flate2::read::GzDecoder;
+use log::debug;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::BufReader;
-use std::path::{Path, PathBuf};
-use tar::Archive;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, Builder, EntryType, Header};
 use tempfile::TempDir;
 
+/// Length of the name and linkname fields in a tar header
+const HEADER_NAME_LEN: usize = 100;
+
+/// Files of at least this size are left in the archive by [`TarExtractor::index`]
+const IN_PLACE_MIN_SIZE: u64 = 1024 * 1024;
+
+/// Leading bytes of a bzip2 stream
+const BZIP2_MAGIC: [u8; 3] = *b"BZh";
+
+/// Leading bytes of an xz stream
+const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+/// Compression of an image archive or layer tar, told apart by its leading bytes
+///
+/// Gzip is always decoded. Zstd, bzip2 and xz are decoded when squash is
+/// built with the `zstd`, `bzip2` and `xz` features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamCompression {
+    None,
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+impl StreamCompression {
+    /// Compression of data starting with `prefix`
+    pub fn detect(prefix: &[u8]) -> Self {
+        if prefix.starts_with(&GZIP_MAGIC) {
+            StreamCompression::Gzip
+        } else if prefix.starts_with(&BZIP2_MAGIC) {
+            StreamCompression::Bzip2
+        } else if prefix.starts_with(&XZ_MAGIC) {
+            StreamCompression::Xz
+        } else if prefix.starts_with(&ZSTD_MAGIC) {
+            StreamCompression::Zstd
+        } else {
+            StreamCompression::None
+        }
+    }
+
+    /// Compression of the file at `path`
+    pub fn of_file(path: &Path) -> Result<Self> {
+        Ok(Self::detect(&read_prefix(File::open(path)?)?))
+    }
+
+    /// Reader over the decompressed content of `input`
+    pub fn decoder(self, input: File) -> Result<Box<dyn Read>> {
+        match self {
+            StreamCompression::None => Ok(Box::new(BufReader::new(input))),
+            StreamCompression::Gzip => Ok(Box::new(GzDecoder::new(BufReader::new(input)))),
+            #[cfg(feature = "bzip2")]
+            StreamCompression::Bzip2 => Ok(Box::new(bzip2::read::MultiBzDecoder::new(BufReader::new(input)))),
+            #[cfg(feature = "xz")]
+            StreamCompression::Xz => Ok(Box::new(xz2::read::XzDecoder::new_multi_decoder(BufReader::new(input)))),
+            StreamCompression::Zstd => Zstd::new().decoder(BufReader::new(input)),
+            #[allow(unreachable_patterns)]
+            format => Err(SquashError::InvalidInput(format!(
+                "Reading {} compressed data requires squash to be built with the `{}` feature",
+                format, format
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for StreamCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            StreamCompression::None => "none",
+            StreamCompression::Gzip => "gzip",
+            StreamCompression::Bzip2 => "bzip2",
+            StreamCompression::Xz => "xz",
+            StreamCompression::Zstd => "zstd",
+        };
+        f.write_str(name)
+    }
+}
+
+/// zstd compression through the `zstd` crate
+///
+/// Needs the `zstd` feature, which is on by default; without it every
+/// operation fails with a [`SquashError::ZstdError`] naming the feature.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Zstd {
+    level: Option<u32>,
+}
+
+impl Zstd {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compress at `level` (1 to 19) instead of the library's default
+    pub fn with_level(mut self, level: Option<u32>) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Fail with a clear error when squash was built without the `zstd` feature
+    pub fn check_available(&self) -> Result<()> {
+        if cfg!(feature = "zstd") {
+            Ok(())
+        } else {
+            Err(Self::missing_feature())
+        }
+    }
+
+    /// Compress everything read from `input` into `output`
+    #[cfg(feature = "zstd")]
+    pub fn compress(&self, mut input: impl Read, output: impl Write) -> Result<()> {
+        // Level 0 selects the library's default
+        let mut encoder = zstd::stream::Encoder::new(output, self.level.unwrap_or(0) as i32)?;
+        std::io::copy(&mut input, &mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    pub fn compress(&self, _input: impl Read, _output: impl Write) -> Result<()> {
+        Err(Self::missing_feature())
+    }
+
+    /// Reader over the decompressed content of the zstd stream `input`
+    #[cfg(feature = "zstd")]
+    pub fn decoder<'a>(&self, input: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(zstd::stream::Decoder::new(input)?))
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    pub fn decoder<'a>(&self, _input: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+        Err(Self::missing_feature())
+    }
+
+    fn missing_feature() -> SquashError {
+        SquashError::ZstdError(
+            "Reading or writing zstd compressed layers requires squash to be built with the `zstd` feature".to_string(),
+        )
+    }
+
+    /// Decompress the zstd stream read from `input` into `output`
+    pub fn decompress(&self, input: impl Read, mut output: impl Write) -> Result<()> {
+        std::io::copy(&mut self.decoder(input)?, &mut output)?;
+        Ok(())
+    }
+}
+
+/// First bytes of `reader`, enough to tell its compression apart
+fn read_prefix(mut reader: impl Read) -> Result<Vec<u8>> {
+    let mut prefix = vec![0u8; XZ_MAGIC.len()];
+    let mut read = 0;
+    while read < prefix.len() {
+        match reader.read(&mut prefix[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    prefix.truncate(read);
+    Ok(prefix)
+}
+
+/// Symlinks followed when looking up a file left in the archive
+const MAX_SYMLINK_HOPS: usize = 8;
+
+/// Encoding used for paths and link names that do not fit in a tar header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LongPathnameMode {
+    /// POSIX PAX extended headers
+    #[default]
+    Pax,
+    /// GNU `././@LongLink` pseudo-entries
+    Gnu,
+}
+
+/// Append an entry to a tar archive, encoding paths and link names longer
+/// than the header fields with the selected long pathname scheme
+pub fn append_entry<W: Write, R: Read>(
+    builder: &mut Builder<W>,
+    header: &mut Header,
+    path: &Path,
+    link_name: Option<&Path>,
+    data: R,
+    mode: LongPathnameMode,
+) -> Result<()> {
+    append_entry_with_pax(builder, header, path, link_name, &[], data, mode)
+}
+
+/// Like [`append_entry`], additionally writing `pax` records (such as
+/// `SCHILY.xattr.*` extended attributes) in a PAX header before the entry
+///
+/// The records are written in either long pathname mode; in PAX mode they
+/// share the extended header with the long path and link name.
+pub fn append_entry_with_pax<W: Write, R: Read>(
+    builder: &mut Builder<W>,
+    header: &mut Header,
+    path: &Path,
+    link_name: Option<&Path>,
+    pax: &[(String, Vec<u8>)],
+    data: R,
+    mode: LongPathnameMode,
+) -> Result<()> {
+    let path_bytes = path.as_os_str().as_encoded_bytes();
+    let link_bytes = link_name.map(|link| link.as_os_str().as_encoded_bytes());
+
+    let long_path = path_bytes.len() > HEADER_NAME_LEN;
+    let long_link = link_bytes.is_some_and(|link| link.len() > HEADER_NAME_LEN);
+
+    let mut records = Vec::new();
+    if mode == LongPathnameMode::Pax {
+        if long_path {
+            records.extend(pax_record("path", path_bytes));
+        }
+        if let Some(link) = link_bytes.filter(|_| long_link) {
+            records.extend(pax_record("linkpath", link));
+        }
+    }
+    for (key, value) in pax {
+        records.extend(pax_record(key, value));
+    }
+
+    if !records.is_empty() {
+        let mut pax_header = pseudo_header(Header::new_ustar(), EntryType::XHeader, b"././@PaxHeader");
+        pax_header.set_size(records.len() as u64);
+        pax_header.set_cksum();
+        builder.append(&pax_header, records.as_slice()).map_err(SquashError::TarError)?;
+    }
+
+    if mode == LongPathnameMode::Gnu {
+        if long_path {
+            append_gnu_long_name(builder, EntryType::GNULongName, path_bytes)?;
+        }
+        if let Some(link) = link_bytes.filter(|_| long_link) {
+            append_gnu_long_name(builder, EntryType::GNULongLink, link)?;
+        }
+    }
+
+    // The header itself keeps a truncated copy when the full value lives in a pseudo-entry
+    if long_path {
+        set_truncated_field(&mut header.as_old_mut().name, path_bytes);
+        if let Some(ustar) = header.as_ustar_mut() {
+            ustar.prefix.fill(0);
+        }
+    } else {
+        header.set_path(path).map_err(SquashError::TarError)?;
+    }
+
+    if let Some(link) = link_name {
+        if long_link {
+            set_truncated_field(&mut header.as_old_mut().linkname, link.as_os_str().as_encoded_bytes());
+        } else {
+            header.set_link_name(link).map_err(SquashError::TarError)?;
+        }
+    }
+
+    header.set_cksum();
+    builder.append(header, data).map_err(SquashError::TarError)?;
+    Ok(())
+}
+
+/// Encode a single PAX record (`<len> <key>=<value>\n`, where len counts itself)
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while rest + len.to_string().len() != len {
+        len = rest + len.to_string().len();
+    }
+
+    let mut record = format!("{} {}=", len, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+/// Append a GNU long name or long link pseudo-entry
+fn append_gnu_long_name<W: Write>(builder: &mut Builder<W>, entry_type: EntryType, value: &[u8]) -> Result<()> {
+    let mut long_header = pseudo_header(Header::new_gnu(), entry_type, b"././@LongLink");
+    long_header.set_size(value.len() as u64 + 1);
+    long_header.set_cksum();
+
+    // The stored name is NUL terminated
+    builder.append(&long_header, value.chain(&[0u8][..])).map_err(SquashError::TarError)?;
+    Ok(())
+}
+
+/// Build the header of a pseudo-entry with a literal name
+fn pseudo_header(mut header: Header, entry_type: EntryType, name: &[u8]) -> Header {
+    header.set_entry_type(entry_type);
+    header.set_mode(0o644);
+    set_truncated_field(&mut header.as_old_mut().name, name);
+    header
+}
+
+/// Copy as much of `value` as fits into a fixed-size header field
+fn set_truncated_field(field: &mut [u8], value: &[u8]) {
+    let len = value.len().min(field.len());
+    field.fill(0);
+    field[..len].copy_from_slice(&value[..len]);
+}
+
+/// Path of an archive entry without `./` or leading `/`
+fn archive_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+/// A regular file stored uncompressed inside an archive, read in place
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveSection {
+    /// Archive holding the file
+    pub archive: PathBuf,
+    /// Offset of the file content in the archive
+    pub offset: u64,
+    /// Size of the file content
+    pub size: u64,
+}
+
+impl ArchiveSection {
+    /// Reader over the file content
+    pub fn open(&self) -> Result<SectionReader> {
+        let file = File::open(&self.archive).map_err(|source| SquashError::FileNotFound {
+            path: self.archive.clone(),
+            source,
+        })?;
+        SectionReader::new(file, self.offset, self.size)
+    }
+
+    /// Whether the content starts with the magic bytes of a [`StreamCompression`]
+    pub fn is_compressed(&self) -> Result<bool> {
+        Ok(StreamCompression::detect(&read_prefix(self.open()?)?) != StreamCompression::None)
+    }
+}
+
+/// Read and seek within `size` bytes of a file starting at `start`
+#[derive(Debug)]
+pub struct SectionReader {
+    file: File,
+    start: u64,
+    size: u64,
+    position: u64,
+}
+
+impl SectionReader {
+    /// Reader positioned at the start of the section
+    pub fn new(mut file: File, start: u64, size: u64) -> Result<Self> {
+        file.seek(SeekFrom::Start(start))?;
+        Ok(SectionReader { file, start, size, position: 0 })
+    }
+}
+
+impl Read for SectionReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.size - self.position;
+        let len = buf.len().min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let read = self.file.read(&mut buf[..len])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for SectionReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+        };
+        let target = target.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start of the section")
+        })?;
+        // Like a file, seeking past the end is allowed and reads nothing
+        self.file.seek(SeekFrom::Start(self.start + target.min(self.size)))?;
+        self.position = target.min(self.size);
+        Ok(target)
+    }
+}
+
 /// Utility for extracting tar archives to temporary directories
 pub struct TarExtractor {
     /// Temporary directory that holds extracted files
     pub temp_dir: TempDir,
     /// Path to the extracted content
     pub extracted_path: PathBuf,
+    /// Large files left in the archive by [`TarExtractor::index`], by path
+    sections: HashMap<PathBuf, ArchiveSection>,
+    /// Symlinks seen while indexing, with their resolved targets
+    links: HashMap<PathBuf, PathBuf>,
 }
 
 impl TarExtractor {
     /// Extract a tar file to a temporary directory
     pub fn extract(tar_path: &Path) -> Result<Self> {
-        let file = File::open(tar_path)?;
+        let file = File::open(tar_path).map_err(|source| SquashError::FileNotFound {
+            path: tar_path.to_path_buf(),
+            source,
+        })?;
         let archive = Archive::new(BufReader::new(file));
-        Self::extract_archive(archive)
+        Self::extract_archive(archive, None)
     }
 
     /// Extract a gzipped tar file
     pub fn extract_gz(tar_gz_path: &Path) -> Result<Self> {
-        let file = File::open(tar_gz_path)?;
+        let file = File::open(tar_gz_path).map_err(|source| SquashError::FileNotFound {
+            path: tar_gz_path.to_path_buf(),
+            source,
+        })?;
         let gz_decoder = GzDecoder::new(BufReader::new(file));
         let archive = Archive::new(gz_decoder);
-        Self::extract_archive(archive)
+        Self::extract_archive(archive, None)
     }
 
-    /// Common extraction logic for both regular and gzipped tar files
-    fn extract_archive<R: std::io::Read>(mut archive: Archive<R>) -> Result<Self> {
-        let temp_dir = TempDir::new()
-            .map_err(SquashError::IoError)?;
+    /// Extract a tar file compressed with `compression`
+    pub fn extract_compressed(tar_path: &Path, compression: StreamCompression) -> Result<Self> {
+        let file = File::open(tar_path).map_err(|source| SquashError::FileNotFound {
+            path: tar_path.to_path_buf(),
+            source,
+        })?;
+        Self::extract_archive(Archive::new(compression.decoder(file)?), None)
+    }
+
+    /// Extract a tar stream, plain or compressed, into a temporary directory
+    ///
+    /// The directory is created inside `temp_dir`, or the system temporary
+    /// directory when `None`. Streams needing a decompression tool are
+    /// spooled to an unnamed temporary file first.
+    pub fn extract_reader<R: Read>(reader: R, temp_dir: Option<&Path>) -> Result<Self> {
+        let mut reader = BufReader::new(reader);
+        match StreamCompression::detect(reader.fill_buf()?) {
+            StreamCompression::None => Self::extract_archive(Archive::new(reader), temp_dir),
+            StreamCompression::Gzip => Self::extract_archive(Archive::new(GzDecoder::new(reader)), temp_dir),
+            compression => {
+                debug!("Spooling {} compressed stream", compression);
+                let mut spool = match temp_dir {
+                    Some(dir) => tempfile::tempfile_in(dir)?,
+                    None => tempfile::tempfile()?,
+                };
+                std::io::copy(&mut reader, &mut spool)?;
+                spool.seek(SeekFrom::Start(0))?;
+                Self::extract_archive(Archive::new(compression.decoder(spool)?), temp_dir)
+            }
+        }
+    }
+
+    /// Scan an uncompressed tar file once, extracting only its small files
+    ///
+    /// Regular files of at least [`IN_PLACE_MIN_SIZE`] bytes, the layers of
+    /// an image archive, are recorded as [`ArchiveSection`]s and read from
+    /// the archive when needed, so they take no space in the temporary
+    /// directory. The archive must stay in place while the extractor is used.
+    pub fn index(tar_path: &Path) -> Result<Self> {
+        let file = File::open(tar_path).map_err(|source| SquashError::FileNotFound {
+            path: tar_path.to_path_buf(),
+            source,
+        })?;
+        let mut archive = Archive::new(BufReader::new(file));
+        let mut extractor = Self::empty(None)?;
 
-        let extracted_path = temp_dir.path().to_path_buf();
+        // Seeking skips over the content of the files left in the archive
+        for entry_result in archive.entries_with_seek().map_err(SquashError::TarError)? {
+            let mut entry = entry_result.map_err(SquashError::TarError)?;
+            let path = archive_path(&entry.path().map_err(SquashError::TarError)?);
+            let entry_type = entry.header().entry_type();
+
+            if entry_type.is_file() && entry.size() >= IN_PLACE_MIN_SIZE {
+                extractor.sections.insert(path, ArchiveSection {
+                    archive: tar_path.to_path_buf(),
+                    offset: entry.raw_file_position(),
+                    size: entry.size(),
+                });
+                continue;
+            }
+            if entry_type.is_symlink() {
+                if let Some(target) = entry.link_name().map_err(SquashError::TarError)? {
+                    extractor.links.insert(path.clone(), resolve_symlink_target(&path, &target));
+                }
+            }
+            entry.unpack_in(&extractor.extracted_path).map_err(SquashError::TarError)?;
+        }
+
+        debug!("Left {} files in {}", extractor.sections.len(), tar_path.display());
+        Ok(extractor)
+    }
+
+    /// Common extraction logic for both regular and gzipped tar files
+    fn extract_archive<R: std::io::Read>(mut archive: Archive<R>, temp_dir: Option<&Path>) -> Result<Self> {
+        let extractor = Self::empty(temp_dir)?;
 
         // Extract all files to the temporary directory
-        archive.unpack(&extracted_path)?;
+        archive.unpack(&extractor.extracted_path).map_err(SquashError::TarError)?;
+
+        Ok(extractor)
+    }
+
+    /// Extractor over a new, empty temporary directory
+    fn empty(temp_dir: Option<&Path>) -> Result<Self> {
+        let temp_dir = match temp_dir {
+            Some(dir) => TempDir::new_in(dir),
+            None => TempDir::new(),
+        }
+        .map_err(SquashError::IoError)?;
 
         Ok(TarExtractor {
+            extracted_path: temp_dir.path().to_path_buf(),
             temp_dir,
-            extracted_path,
+            sections: HashMap::new(),
+            links: HashMap::new(),
         })
     }
     
     /// Get the path to an extracted file
+    ///
+    /// Digest references such as `sha256:<hex>` are mapped to the OCI
+    /// blob store at `blobs/sha256/<hex>`.
     pub fn get_file_path(&self, filename: &str) -> PathBuf {
-        self.extracted_path.join(filename)
+        match blob_path(filename) {
+            Some(blob) => self.extracted_path.join(blob),
+            None => self.extracted_path.join(filename),
+        }
     }
     
-    /// Check if a file exists in the extracted directory
+    /// Check if a file exists in the extracted directory or was left in the archive
     pub fn file_exists(&self, filename: &str) -> bool {
-        self.get_file_path(filename).exists()
+        self.section(filename).is_some() || self.get_file_path(filename).exists()
     }
     
     /// Read a file from the extracted directory
     pub fn read_file(&self, filename: &str) -> Result<String> {
-        let file_path = self.get_file_path(filename);
-        std::fs::read_to_string(file_path)
-            .map_err(SquashError::IoError)
+        let mut content = String::new();
+        self.open_file(filename)?.read_to_string(&mut content)?;
+        Ok(content)
+    }
+
+    /// Open a file from the extracted directory or the archive
+    pub fn open_file(&self, filename: &str) -> Result<Box<dyn Read>> {
+        match self.section(filename) {
+            Some(section) => Ok(Box::new(section.open()?)),
+            None => Ok(Box::new(File::open(self.get_file_path(filename))?)),
+        }
+    }
+
+    /// Where a file left in the archive by [`TarExtractor::index`] is stored
+    ///
+    /// Symlinks to such a file, as `docker save` writes for layers shared by
+    /// several images, are followed.
+    pub fn section(&self, filename: &str) -> Option<&ArchiveSection> {
+        if self.sections.is_empty() {
+            return None;
+        }
+
+        let mut path = archive_path(self.get_file_path(filename).strip_prefix(&self.extracted_path).ok()?);
+        for _ in 0..MAX_SYMLINK_HOPS {
+            if let Some(section) = self.sections.get(&path) {
+                return Some(section);
+            }
+            path = self.links.get(&path)?.clone();
+        }
+        None
+    }
+
+    /// Path of a file on disk, copying it out of the archive if it was left there
+    pub fn materialize(&self, filename: &str) -> Result<PathBuf> {
+        let path = self.get_file_path(filename);
+        let Some(section) = self.section(filename) else {
+            return Ok(path);
+        };
+
+        // Replace a symlink to the file left in the archive
+        if path.is_symlink() {
+            std::fs::remove_file(&path)?;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if !path.exists() {
+            std::io::copy(&mut section.open()?, &mut File::create(&path)?)?;
+        }
+        Ok(path)
     }
 }
 
 /// Utility for building tar archives from files and directories
+///
+/// Entries added with [`TarBuilder::add_entry`] are appended immediately
+/// with their header untouched. Files added with [`TarBuilder::add_file`]
+/// are staged on disk and appended after them when the archive is built.
 pub struct TarBuilder {
     #[allow(dead_code)] // Needed to keep temporary directory alive
     temp_dir: TempDir,
     /// Path where files are staged before building the tar
     build_path: PathBuf,
+    /// Path of the archive being written
+    archive_path: PathBuf,
+    /// Archive receiving header-preserving entries
+    archive: RefCell<Builder<File>>,
 }
 
 impl TarBuilder {
@@ -77,15 +611,59 @@ impl TarBuilder {
     pub fn new() -> Result<Self> {
         let temp_dir = TempDir::new()
             .map_err(SquashError::IoError)?;
-        
-        let build_path = temp_dir.path().to_path_buf();
-        
+
+        let build_path = temp_dir.path().join("staging");
+        std::fs::create_dir_all(&build_path)?;
+
+        let archive_path = temp_dir.path().join("archive.tar");
+        let archive = RefCell::new(Builder::new(File::create(&archive_path)?));
+
         Ok(TarBuilder {
             temp_dir,
             build_path,
+            archive_path,
+            archive,
         })
     }
+
+    /// Append an entry with its header written verbatim
+    ///
+    /// The header must already carry the entry path, size and metadata.
+    pub fn add_entry(&self, header: &Header, data: &[u8]) -> Result<()> {
+        self.archive.borrow_mut().append(header, data).map_err(SquashError::TarError)?;
+        Ok(())
+    }
     
+    /// Append the file at `src` as `name`, streaming its content
+    ///
+    /// Unlike [`TarBuilder::add_file`] the content is neither buffered in
+    /// memory nor staged; the entry gets mode 0644 and a zero mtime.
+    pub fn add_file_from_path(&self, name: &str, src: &Path) -> Result<()> {
+        let file = File::open(src).map_err(|source| SquashError::FileNotFound {
+            path: src.to_path_buf(),
+            source,
+        })?;
+        let size = file.metadata()?.len();
+        self.add_reader(name, size, BufReader::new(file))
+    }
+
+    /// Append `size` bytes read from `data` as `name`
+    ///
+    /// The entry gets the same fixed metadata as [`TarBuilder::add_file_from_path`].
+    pub fn add_reader<R: Read>(&self, name: &str, size: u64, data: R) -> Result<()> {
+        let mut header = Header::new_gnu();
+        header.set_path(name).map_err(SquashError::TarError)?;
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        header.set_cksum();
+        self.archive
+            .borrow_mut()
+            .append(&header, data)
+            .map_err(SquashError::TarError)?;
+        Ok(())
+    }
+
     /// Add a file to the tar archive being built
     pub fn add_file(&self, filename: &str, content: &[u8]) -> Result<()> {
         let file_path = self.build_path.join(filename);
@@ -106,15 +684,45 @@ impl TarBuilder {
         Ok(())
     }
     
-    /// Build the final tar file
-    pub fn build(&self, output_path: &Path) -> Result<()> {
-        let output_file = File::create(output_path)?;
-        let mut archive = tar::Builder::new(output_file);
-        
-        // Add all files from the build directory to the archive
-        archive.append_dir_all(".", &self.build_path)?;
-        archive.finish()?;
-        
+    /// Build the final tar file at `output_path`, returning its size in bytes
+    pub fn build(&self, output_path: &Path) -> Result<u64> {
+        self.finish()?;
+        let size = std::fs::metadata(&self.archive_path)?.len();
+
+        // Fall back to copying when the output lives on another filesystem
+        if std::fs::rename(&self.archive_path, output_path).is_err() {
+            std::fs::copy(&self.archive_path, output_path).map_err(|source| {
+                // Do not leave a truncated archive behind
+                let _ = std::fs::remove_file(output_path);
+                SquashError::FileWrite {
+                    path: output_path.to_path_buf(),
+                    source,
+                }
+            })?;
+        }
+
+        Ok(size)
+    }
+
+    /// Build the final tar and stream it into `sink` (e.g. stdout)
+    pub fn build_to_writer<W: Write>(&self, mut sink: W) -> Result<()> {
+        self.finish()?;
+
+        std::io::copy(&mut File::open(&self.archive_path)?, &mut sink)?;
+        sink.flush()?;
+        Ok(())
+    }
+
+    /// Append the staged files and write the end-of-archive marker
+    fn finish(&self) -> Result<()> {
+        let mut archive = self.archive.borrow_mut();
+
+        // Add all staged files from the build directory to the archive
+        if std::fs::read_dir(&self.build_path)?.next().is_some() {
+            archive.append_dir_all(".", &self.build_path).map_err(SquashError::TarError)?;
+        }
+        archive.finish().map_err(SquashError::TarError)?;
+        archive.get_mut().flush()?;
         Ok(())
     }
     
@@ -176,6 +784,45 @@ mod tests {
         assert_eq!(read_content, content);
     }
 
+    #[test]
+    fn test_stream_compression_detect() {
+        assert_eq!(StreamCompression::detect(&[0x1f, 0x8b, 0x08]), StreamCompression::Gzip);
+        assert_eq!(StreamCompression::detect(b"BZh91AY&SY"), StreamCompression::Bzip2);
+        assert_eq!(StreamCompression::detect(&[0xfd, b'7', b'z', b'X', b'Z', 0x00, 0x00]), StreamCompression::Xz);
+        assert_eq!(StreamCompression::detect(&[0x28, 0xb5, 0x2f, 0xfd]), StreamCompression::Zstd);
+        assert_eq!(StreamCompression::detect(b"manifest.json"), StreamCompression::None);
+        // A truncated magic is not enough
+        assert_eq!(StreamCompression::detect(&[0xfd, b'7', b'z']), StreamCompression::None);
+        assert_eq!(StreamCompression::detect(b""), StreamCompression::None);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_round_trip() {
+        let data = b"layer data ".repeat(64);
+        let mut default_level = Vec::new();
+        Zstd::new().compress(data.as_slice(), &mut default_level).unwrap();
+        let mut best = Vec::new();
+        Zstd::new().with_level(Some(19)).compress(data.as_slice(), &mut best).unwrap();
+        assert_eq!(StreamCompression::detect(&best), StreamCompression::Zstd);
+
+        for compressed in [default_level, best] {
+            let mut decompressed = Vec::new();
+            Zstd::new().decompress(compressed.as_slice(), &mut decompressed).unwrap();
+            assert_eq!(decompressed, data);
+        }
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn test_zstd_requires_feature() {
+        match Zstd::new().check_available() {
+            Err(SquashError::ZstdError(msg)) => assert!(msg.contains("`zstd` feature"), "{}", msg),
+            other => panic!("Expected ZstdError, got {:?}", other),
+        }
+        assert!(Zstd::new().decoder(&[0x28, 0xb5, 0x2f, 0xfd][..]).is_err());
+    }
+
     #[test]
     fn test_tar_builder_build() {
         let builder = TarBuilder::new().unwrap();
@@ -186,11 +833,172 @@ mod tests {
         let temp_dir = TempDir::new().unwrap();
         let output_path = temp_dir.path().join("output.tar");
 
-        builder.build(&output_path).unwrap();
+        let size = builder.build(&output_path).unwrap();
         assert!(output_path.exists());
 
-        // Verify the tar file is not empty
+        // Verify the tar file is not empty and its size is reported
         let metadata = fs::metadata(&output_path).unwrap();
         assert!(metadata.len() > 0);
+        assert_eq!(size, metadata.len());
+    }
+
+    #[test]
+    fn test_tar_builder_build_to_writer() {
+        let builder = TarBuilder::new().unwrap();
+        builder.add_file("test.txt", b"Streamed content").unwrap();
+
+        let mut buffer = Vec::new();
+        builder.build_to_writer(&mut buffer).unwrap();
+
+        let mut archive = tar::Archive::new(buffer.as_slice());
+        let mut entry = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .find(|entry| entry.path().unwrap().ends_with("test.txt"))
+            .expect("test.txt should be in the streamed archive");
+
+        let mut content = String::new();
+        entry.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "Streamed content");
+    }
+
+    #[test]
+    fn test_tar_builder_add_file_from_path() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("layer.tar");
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &content).unwrap();
+
+        let builder = TarBuilder::new().unwrap();
+        builder.add_file_from_path("abc/layer.tar", &source).unwrap();
+        let output = temp_dir.path().join("out.tar");
+        builder.build(&output).unwrap();
+
+        let mut archive = tar::Archive::new(File::open(&output).unwrap());
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap().to_str(), Some("abc/layer.tar"));
+        assert_eq!(entry.header().size().unwrap(), content.len() as u64);
+        assert_eq!(entry.header().mode().unwrap(), 0o644);
+
+        let mut streamed = Vec::new();
+        entry.read_to_end(&mut streamed).unwrap();
+        assert_eq!(streamed, content);
+
+        let missing = temp_dir.path().join("missing.tar");
+        assert!(matches!(
+            builder.add_file_from_path("missing.tar", &missing),
+            Err(SquashError::FileNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_tar_builder_add_entry_preserves_header() {
+        let builder = TarBuilder::new().unwrap();
+
+        let mut header = Header::new_gnu();
+        header.set_path("bin/tool").unwrap();
+        header.set_size(4);
+        header.set_mode(0o4755);
+        header.set_uid(1000);
+        header.set_gid(1000);
+        header.set_mtime(1_700_000_000);
+        header.set_cksum();
+        builder.add_entry(&header, b"tool").unwrap();
+
+        let mut link = Header::new_gnu();
+        link.set_path("bin/alias").unwrap();
+        link.set_entry_type(EntryType::Symlink);
+        link.set_link_name("tool").unwrap();
+        link.set_size(0);
+        link.set_cksum();
+        builder.add_entry(&link, &[]).unwrap();
+
+        builder.add_file("staged.txt", b"staged").unwrap();
+
+        let temp_dir = TempDir::new().unwrap();
+        let output_path = temp_dir.path().join("output.tar");
+        builder.build(&output_path).unwrap();
+
+        let mut archive = Archive::new(File::open(&output_path).unwrap());
+        let entries: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().header().clone())
+            .collect();
+
+        // Header-preserving entries come first, byte for byte
+        assert_eq!(entries[0].as_bytes(), header.as_bytes());
+        assert_eq!(entries[1].as_bytes(), link.as_bytes());
+        assert!(entries.iter().any(|h| h.path().unwrap().ends_with("staged.txt")));
+    }
+
+    #[test]
+    fn test_index_leaves_large_files_in_archive() {
+        let temp_dir = TempDir::new().unwrap();
+        let archive_path = temp_dir.path().join("image.tar");
+        let large = vec![7u8; IN_PLACE_MIN_SIZE as usize + 100];
+
+        let mut builder = Builder::new(File::create(&archive_path).unwrap());
+        let mut header = Header::new_gnu();
+        header.set_size(2);
+        builder.append_data(&mut header, "./manifest.json", &b"[]"[..]).unwrap();
+        let mut header = Header::new_gnu();
+        header.set_size(large.len() as u64);
+        builder.append_data(&mut header, "abc/layer.tar", large.as_slice()).unwrap();
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "def/layer.tar", "../abc/layer.tar").unwrap();
+        builder.into_inner().unwrap();
+
+        let extractor = TarExtractor::index(&archive_path).unwrap();
+        assert_eq!(extractor.read_file("manifest.json").unwrap(), "[]");
+        assert!(extractor.section("manifest.json").is_none());
+        assert!(!extractor.get_file_path("abc/layer.tar").exists());
+
+        // The symlinked copy resolves to the same place in the archive
+        let section = extractor.section("abc/layer.tar").unwrap().clone();
+        assert_eq!(extractor.section("def/layer.tar"), Some(&section));
+        assert!(extractor.file_exists("def/layer.tar"));
+        assert_eq!(section.size, large.len() as u64);
+        assert!(!section.is_compressed().unwrap());
+
+        let mut content = Vec::new();
+        section.open().unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, large);
+
+        // Copied out only on request
+        let path = extractor.materialize("def/layer.tar").unwrap();
+        assert!(!path.is_symlink());
+        assert_eq!(fs::read(path).unwrap(), large);
+    }
+
+    #[test]
+    fn test_section_reader_stays_within_section() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("data");
+        fs::write(&path, b"0123456789").unwrap();
+
+        let mut reader = SectionReader::new(File::open(&path).unwrap(), 2, 5).unwrap();
+        let mut content = String::new();
+        reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "23456");
+
+        assert_eq!(reader.seek(SeekFrom::End(-2)).unwrap(), 3);
+        content.clear();
+        reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "56");
+
+        assert_eq!(reader.seek(SeekFrom::Start(1)).unwrap(), 1);
+        assert_eq!(reader.seek(SeekFrom::Current(1)).unwrap(), 2);
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte).unwrap();
+        assert_eq!(&byte, b"4");
+
+        assert!(reader.seek(SeekFrom::Current(-10)).is_err());
+        assert_eq!(reader.seek(SeekFrom::Start(50)).unwrap(), 50);
+        assert_eq!(reader.read(&mut byte).unwrap(), 0);
     }
 }
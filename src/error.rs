@@ -1,38 +1,85 @@
-use std::fmt;
+use std::path::PathBuf;
+use thiserror::Error;
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum SquashError {
-    IoError(std::io::Error),
-    JsonError(serde_json::Error),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    /// Failure reported by the tar reader or writer (bad checksum, truncated
+    /// archive, corrupt header); `tar` exposes these as `io::Error`
+    #[error("Tar error: {0}")]
+    TarError(#[source] std::io::Error),
+    #[error("JSON error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    /// A file given by the user could not be opened
+    #[error("Cannot open {}: {source}", path.display())]
+    FileNotFound {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// The output file could not be written
+    #[error("Cannot write {}: {source}", path.display())]
+    FileWrite {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// The output file is shorter or longer than the archive written to it
+    #[error("Output file {} has {actual} bytes, expected {expected}", path.display())]
+    OutputSizeMismatch {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    /// The output file exists and may not be replaced
+    #[error("Output file {} already exists", .0.display())]
+    OutputExists(PathBuf),
+    /// A manifest, index or config inside an image archive is not valid JSON
+    #[error("Failed to parse {path}: {source}")]
+    ManifestParse {
+        path: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("Docker error: {0}")]
     DockerError(String),
+    #[error("Skopeo error: {0}")]
+    SkopeoError(String),
+    #[error("Zstd error: {0}")]
+    ZstdError(String),
+    #[error("Download error: {0}")]
+    DownloadError(String),
+    #[error("Invalid input: {0}")]
     InvalidInput(String),
+    #[error("Layer not found: {0}")]
     LayerNotFound(String),
-}
-
-impl fmt::Display for SquashError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            SquashError::IoError(err) => write!(f, "IO error: {}", err),
-            SquashError::JsonError(err) => write!(f, "JSON error: {}", err),
-            SquashError::DockerError(msg) => write!(f, "Docker error: {}", msg),
-            SquashError::InvalidInput(msg) => write!(f, "Invalid input: {}", msg),
-            SquashError::LayerNotFound(id) => write!(f, "Layer not found: {}", id),
-        }
-    }
-}
-
-impl std::error::Error for SquashError {}
-
-impl From<std::io::Error> for SquashError {
-    fn from(err: std::io::Error) -> Self {
-        SquashError::IoError(err)
-    }
-}
-
-impl From<serde_json::Error> for SquashError {
-    fn from(err: serde_json::Error) -> Self {
-        SquashError::JsonError(err)
-    }
+    #[error("Verification failed: {0}")]
+    VerificationFailed(String),
+    /// A layer media type is rejected by the input allowlist or denylist
+    #[error("Layer {layer} has media type {media_type}, which is {reason} by the input policy")]
+    PolicyViolation {
+        layer: String,
+        media_type: String,
+        reason: String,
+    },
+    #[error("Digest mismatch for layer {layer}: expected {expected}, got {actual}")]
+    DigestMismatch {
+        layer: String,
+        expected: String,
+        actual: String,
+    },
+    /// A merged layer came out larger than the layers it replaces
+    #[error("Merging layers {start} to {end} grew them from {input_size} to {merged_size} bytes")]
+    LayerGrew {
+        start: usize,
+        end: usize,
+        input_size: u64,
+        merged_size: u64,
+    },
+    /// The operation was stopped through its `Cancellation`, e.g. by Ctrl-C
+    #[error("Cancelled")]
+    Cancelled,
 }
 
 pub type Result<T> = std::result::Result<T, SquashError>;
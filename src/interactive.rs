@@ -0,0 +1,225 @@
+use crate::docker::DockerImage;
+use crate::error::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+
+/// One layer as listed for selection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerRow {
+    /// History command that created the layer
+    pub created_by: String,
+    /// Size of the layer in bytes
+    pub size: u64,
+    /// diff_id of the layer
+    pub digest: String,
+}
+
+impl LayerRow {
+    /// Rows for every layer of `image`, oldest first
+    pub fn for_image(image: &DockerImage) -> Vec<LayerRow> {
+        let created_by = image.layer_created_by();
+        image
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(i, layer)| LayerRow {
+                created_by: created_by[i].clone().unwrap_or_default(),
+                size: layer.size,
+                digest: layer.digest.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Cursor and marked range of the layer list
+///
+/// Space marks the layer under the cursor as one end of the range, the
+/// cursor is the other end. Without a mark the range is the cursor alone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerSelection {
+    len: usize,
+    cursor: usize,
+    mark: Option<usize>,
+}
+
+impl LayerSelection {
+    /// Selection over `len` layers with the cursor on the latest one
+    pub fn new(len: usize) -> Self {
+        LayerSelection {
+            len,
+            cursor: len.saturating_sub(1),
+            mark: None,
+        }
+    }
+
+    /// Layer under the cursor
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Move the cursor one layer towards the base
+    pub fn up(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    /// Move the cursor one layer towards the latest
+    pub fn down(&mut self) {
+        if self.cursor + 1 < self.len {
+            self.cursor += 1;
+        }
+    }
+
+    /// Mark the layer under the cursor, or clear the mark when it is already there
+    pub fn toggle_mark(&mut self) {
+        self.mark = if self.mark == Some(self.cursor) { None } else { Some(self.cursor) };
+    }
+
+    /// First and last layer of the selected range, inclusive
+    pub fn range(&self) -> (usize, usize) {
+        let mark = self.mark.unwrap_or(self.cursor);
+        (mark.min(self.cursor), mark.max(self.cursor))
+    }
+
+    /// The selected range when it spans at least two layers
+    pub fn mergeable_range(&self) -> Option<(usize, usize)> {
+        let (start, end) = self.range();
+        (end > start).then_some((start, end))
+    }
+}
+
+/// Let the user pick a contiguous range of layers on the terminal
+///
+/// Returns the first and last layer index of the range, or `None` when
+/// the user quits without confirming.
+pub fn select_layers(image: &DockerImage) -> Result<Option<(usize, usize)>> {
+    let rows = LayerRow::for_image(image);
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, &rows);
+    ratatui::restore();
+    result
+}
+
+/// Event loop of the selection UI
+fn run(terminal: &mut DefaultTerminal, rows: &[LayerRow]) -> Result<Option<(usize, usize)>> {
+    let mut selection = LayerSelection::new(rows.len());
+    let mut message = None;
+
+    loop {
+        terminal.draw(|frame| draw(frame, rows, &selection, message))?;
+
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        message = None;
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => selection.up(),
+            KeyCode::Down | KeyCode::Char('j') => selection.down(),
+            KeyCode::Char(' ') => selection.toggle_mark(),
+            KeyCode::Enter => match selection.mergeable_range() {
+                Some(range) => return Ok(Some(range)),
+                None => message = Some("Mark a range of at least two layers to merge"),
+            },
+            KeyCode::Esc | KeyCode::Char('q') => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, rows: &[LayerRow], selection: &LayerSelection, message: Option<&str>) {
+    let [list, footer] = Layout::vertical([Constraint::Min(3), Constraint::Length(1)]).areas(frame.area());
+    let (start, end) = selection.range();
+
+    let table_rows = rows.iter().enumerate().map(|(i, row)| {
+        let digest = row.digest.trim_start_matches("sha256:");
+        let created_by = row.created_by.lines().next().unwrap_or_default();
+        let cells = [
+            i.to_string(),
+            format_size(row.size),
+            digest.chars().take(12).collect(),
+            created_by.to_string(),
+        ];
+        let style = if (start..=end).contains(&i) { Style::new().reversed() } else { Style::new() };
+        Row::new(cells).style(style)
+    });
+    let table = Table::new(
+        table_rows,
+        [Constraint::Length(4), Constraint::Length(10), Constraint::Length(12), Constraint::Fill(1)],
+    )
+    .header(Row::new(["#", "SIZE", "DIGEST", "CREATED BY"]).add_modifier(Modifier::BOLD))
+    .row_highlight_style(Style::new().bold())
+    .highlight_symbol("> ")
+    .block(Block::bordered().title(format!(" Layers {}-{} selected ", start, end)));
+
+    let mut state = TableState::new().with_selected(Some(selection.cursor()));
+    frame.render_stateful_widget(table, list, &mut state);
+
+    let help = message.unwrap_or("up/down move  space mark range end  enter merge  q quit");
+    frame.render_widget(Line::from(help), footer);
+}
+
+/// Byte count in binary units, e.g. `12.5MiB`
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_selection() {
+        let mut selection = LayerSelection::new(5);
+        assert_eq!(selection.cursor(), 4);
+        assert_eq!(selection.range(), (4, 4));
+        assert_eq!(selection.mergeable_range(), None);
+
+        selection.toggle_mark();
+        selection.up();
+        selection.up();
+        assert_eq!(selection.mergeable_range(), Some((2, 4)));
+
+        // The range follows the cursor past the mark in either direction
+        selection.toggle_mark();
+        selection.down();
+        selection.down();
+        selection.down();
+        assert_eq!(selection.cursor(), 4);
+        assert_eq!(selection.range(), (2, 4));
+
+        // Marking the marked layer again clears the mark
+        selection.up();
+        selection.up();
+        selection.toggle_mark();
+        assert_eq!(selection.range(), (2, 2));
+
+        for _ in 0..10 {
+            selection.up();
+        }
+        assert_eq!(selection.cursor(), 0);
+        assert_eq!(LayerSelection::new(0).range(), (0, 0));
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512B");
+        assert_eq!(format_size(1536), "1.5KiB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0MiB");
+    }
+}
@@ -9,6 +9,12 @@
 //! - **Layer Merging Options**:
 //!   - By count: merge latest n layers into one
 //!   - By layer ID: merge from specific layer ID to latest layer
+//!   - From the start: merge the earliest n layers with `--from-start`
+//!   - All layers: collapse the image into a single layer with `--all`
+//!   - By range: merge a contiguous range of layers with `--from`/`--to`
+//!   - By build time: merge layers built close together with `--merge-window`
+//!   - By size: merge the trailing run of small layers with `--merge-layers-matching-size`
+//!   - By size budget: merge as many latest layers as fit within a combined size with `--max-merged-size`
 //! - **Temporary Directory Support**: Configure where intermediate files are stored
 //! - **Verbose Output**: Detailed logging of operations
 //!
@@ -24,6 +30,28 @@
 //! // Load and process Docker image
 //! // (This is a simplified example - see main.rs for complete implementation)
 //! ```
+//!
+//! ## Library Usage
+//!
+//! The squashed image can be written to any `Write` sink or returned as a
+//! buffer instead of a file:
+//!
+//! ```rust,no_run
+//! use squash::docker::DockerImage;
+//!
+//! # fn main() -> squash::Result<()> {
+//! let mut image = DockerImage::load("app.tar", None)?;
+//! image.squash_layers("3")?;
+//!
+//! // Stream into a socket, an HTTP body, a hasher, ...
+//! image.save_to_writer(std::io::stdout().lock())?;
+//!
+//! // Or keep the whole archive in memory
+//! let archive: Vec<u8> = image.save_to_vec()?;
+//! # let _ = archive;
+//! # Ok(())
+//! # }
+//! ```
 
 /// Command line interface definitions
 pub mod cli;
@@ -31,6 +59,9 @@ pub mod cli;
 pub mod docker;
 /// Error types and handling
 pub mod error;
+/// Terminal UI for picking the layers to merge
+#[cfg(feature = "interactive")]
+pub mod interactive;
 
 pub use cli::*;
 pub use error::*;
@@ -59,9 +90,10 @@ mod tests {
             Commands::Squash { source, output, layers, verbose, .. } => {
                 assert_eq!(source, "test.tar");
                 assert_eq!(output.unwrap().to_str().unwrap(), "output.tar");
-                assert_eq!(layers, "2");
+                assert_eq!(layers.as_deref(), Some("2"));
                 assert!(verbose);
             }
+            _ => panic!("Expected the squash subcommand"),
         }
     }
 
@@ -97,8 +129,9 @@ mod tests {
             Commands::Squash { source, load, layers, .. } => {
                 assert_eq!(source, "nginx:latest");
                 assert_eq!(load.unwrap(), "nginx:squashed");
-                assert_eq!(layers, "3");
+                assert_eq!(layers.as_deref(), Some("3"));
             }
+            _ => panic!("Expected the squash subcommand"),
         }
     }
 
@@ -121,9 +154,795 @@ mod tests {
             Commands::Squash { source, output, layers, temp_dir, .. } => {
                 assert_eq!(source, "test.tar");
                 assert_eq!(output.unwrap().to_str().unwrap(), "output.tar");
-                assert_eq!(layers, "2");
+                assert_eq!(layers.as_deref(), Some("2"));
                 assert_eq!(temp_dir.unwrap().to_str().unwrap(), "/tmp/squash");
             }
+            _ => panic!("Expected the squash subcommand"),
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_with_layer_range() {
+        use clap::Parser;
+
+        let args = vec![
+            "squash",
+            "squash",
+            "--source", "test.tar",
+            "--output", "output.tar",
+            "--from", "1",
+            "--to", "abcdef12",
+        ];
+
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        match cli.command {
+            Commands::Squash { layers, from, to, .. } => {
+                assert!(layers.is_none());
+                assert_eq!(from.as_deref(), Some("1"));
+                assert_eq!(to.as_deref(), Some("abcdef12"));
+            }
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        // --layers cannot be combined with a range
+        let args = vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar",
+            "--layers", "2", "--from", "1",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_squash_all() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar", "--squash-all",
+        ]).unwrap();
+
+        match cli.command {
+            Commands::Squash { layers, all, .. } => {
+                assert!(all);
+                assert!(layers.is_none());
+            }
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        // --layers and --all are mutually exclusive
+        let args = vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar",
+            "--layers", "2", "--all",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_merge_window() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar", "--merge-window", "30",
+        ]).unwrap();
+
+        match cli.command {
+            Commands::Squash { layers, merge_window, .. } => {
+                assert_eq!(merge_window, Some(30));
+                assert!(layers.is_none());
+            }
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        // The window is its own layer selection
+        let args = vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar",
+            "--layers", "2", "--merge-window", "30",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_mem_file_threshold() {
+        use clap::Parser;
+
+        let parse = |threshold: &str| -> std::result::Result<u64, clap::Error> {
+            let cli = Cli::try_parse_from(vec![
+                "squash", "squash", "--source", "test.tar", "--output", "output.tar", "--layers", "2",
+                "--mem-file-threshold", threshold,
+            ])?;
+            match cli.command {
+                Commands::Squash { mem_file_threshold, .. } => Ok(mem_file_threshold),
+                _ => panic!("Expected the squash subcommand"),
+            }
+        };
+
+        assert_eq!(parse("0").unwrap(), 0);
+        assert_eq!(parse("4096").unwrap(), 4096);
+        assert_eq!(parse("64K").unwrap(), 64 * 1024);
+        assert_eq!(parse("4M").unwrap(), 4 * 1024 * 1024);
+        assert_eq!(parse("2GiB").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert!(parse("4X").is_err());
+        assert!(parse("M").is_err());
+        assert!(parse("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_completions() {
+        use clap::Parser;
+
+        for (name, shell) in [
+            ("bash", clap_complete::Shell::Bash),
+            ("zsh", clap_complete::Shell::Zsh),
+            ("fish", clap_complete::Shell::Fish),
+            ("powershell", clap_complete::Shell::PowerShell),
+        ] {
+            match Cli::try_parse_from(vec!["squash", "completions", name]).unwrap().command {
+                Commands::Completions { shell: parsed } => assert_eq!(parsed, shell),
+                _ => panic!("Expected the completions subcommand"),
+            }
+        }
+
+        assert!(Cli::try_parse_from(vec!["squash", "completions", "tcsh"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_merge_configs() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "merge-configs", "--filesystem-from", "multi.tar", "--config-from", "base:latest",
+            "--output", "combined.tar", "--filesystem-image-ref", "app:rebuilt", "--config-format", "docker-daemon",
+            "--platform", "linux/arm64",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::MergeConfigs {
+                filesystem_format,
+                config_format,
+                filesystem_image_ref,
+                config_image_ref,
+                platform,
+                ..
+            } => {
+                assert!(filesystem_format.is_none());
+                assert_eq!(config_format, Some(crate::docker::SourceFormat::DockerDaemon));
+                assert_eq!(filesystem_image_ref.as_deref(), Some("app:rebuilt"));
+                assert!(config_image_ref.is_none());
+                assert_eq!(platform.unwrap().to_string(), "linux/arm64");
+            }
+            _ => panic!("Expected the merge-configs subcommand"),
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_verify() {
+        use clap::Parser;
+
+        match Cli::try_parse_from(vec!["squash", "verify", "--source", "squashed.tar"]).unwrap().command {
+            Commands::Verify { source, temp_dir, .. } => {
+                assert_eq!(source, "squashed.tar");
+                assert!(temp_dir.is_none());
+            }
+            _ => panic!("Expected the verify subcommand"),
+        }
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "verify", "--source", "multi.tar", "--image-ref", "app:latest", "--platform", "linux/arm64",
+            "--source-format", "oci-archive",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Verify { image_ref, platform, source_format, .. } => {
+                assert_eq!(image_ref.as_deref(), Some("app:latest"));
+                assert_eq!(platform.unwrap().to_string(), "linux/arm64");
+                assert_eq!(source_format, Some(crate::docker::SourceFormat::OciArchive));
+            }
+            _ => panic!("Expected the verify subcommand"),
+        }
+
+        assert!(Cli::try_parse_from(vec!["squash", "verify"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_merge_layers_matching_size() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar",
+            "--merge-layers-matching-size", "1MB",
+        ]).unwrap();
+
+        match cli.command {
+            Commands::Squash { layers, merge_layers_matching_size, .. } => {
+                assert_eq!(merge_layers_matching_size, Some(1024 * 1024));
+                assert!(layers.is_none());
+            }
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        let args = vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar",
+            "--merge-window", "30", "--merge-layers-matching-size", "1MB",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_max_merged_size() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar",
+            "--max-merged-size", "500M",
+        ]).unwrap();
+
+        match cli.command {
+            Commands::Squash { layers, max_merged_size, .. } => {
+                assert_eq!(max_merged_size, Some(500 * 1024 * 1024));
+                assert!(layers.is_none());
+            }
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        for flag in [&["--layers", "2"][..], &["--all"], &["--merge-layers-matching-size", "1M"]] {
+            let mut args = vec![
+                "squash", "squash", "--source", "test.tar", "--output", "output.tar", "--max-merged-size", "500M",
+            ];
+            args.extend_from_slice(flag);
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_output_stdout() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "test.tar", "--output-stdout", "--layers", "2",
+        ]).unwrap();
+
+        match cli.command {
+            Commands::Squash { output, output_stdout, .. } => {
+                assert!(output_stdout);
+                assert!(output.is_none());
+            }
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        // --output-stdout and --output are mutually exclusive
+        let args = vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar",
+            "--output-stdout", "--layers", "2",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_log_level() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar", "--layers", "2",
+            "--log-level", "trace",
+        ]).unwrap();
+
+        match cli.command {
+            Commands::Squash { log_level, verbose, .. } => {
+                assert_eq!(log_level, Some(LogLevel::Trace));
+                assert_eq!(log::LevelFilter::from(LogLevel::Trace), log::LevelFilter::Trace);
+                assert!(!verbose);
+            }
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        let args = vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar", "--layers", "2",
+            "--log-level", "loud",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_quiet() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar", "--layers", "2", "-q",
+        ]).unwrap();
+
+        match cli.command {
+            Commands::Squash { quiet, verbose, log_level, .. } => {
+                assert!(quiet);
+                assert!(!verbose);
+                assert!(log_level.is_none());
+            }
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        // --quiet cannot be combined with another log level
+        for flag in [&["--verbose"][..], &["--log-level", "debug"]] {
+            let mut args = vec![
+                "squash", "squash", "--source", "test.tar", "--output", "output.tar", "--layers", "2", "--quiet",
+            ];
+            args.extend_from_slice(flag);
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_dry_run() {
+        use clap::Parser;
+
+        // Neither --output nor --load is needed to preview a squash
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "test.tar", "--layers", "2", "--dry-run",
+        ]).unwrap();
+
+        match cli.command {
+            Commands::Squash { dry_run, output, load, .. } => {
+                assert!(dry_run);
+                assert!(output.is_none());
+                assert!(load.is_none());
+            }
+            _ => panic!("Expected the squash subcommand"),
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_report_format() {
+        use clap::Parser;
+
+        let args = vec!["squash", "squash", "--source", "test.tar", "--output", "output.tar", "--layers", "2"];
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Squash { format, .. } => assert_eq!(format, ReportFormat::Text),
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar", "--layers", "2",
+            "--format", "json",
+        ]).unwrap();
+        match cli.command {
+            Commands::Squash { format, .. } => assert_eq!(format, ReportFormat::Json),
+            _ => panic!("Expected the squash subcommand"),
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_secret_scan() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar", "--layers", "2",
+            "--verify-no-secrets", "--fail-on-secret", "--secret-pattern", "token=tk_[0-9]+",
+        ]).unwrap();
+
+        match cli.command {
+            Commands::Squash { verify_no_secrets, fail_on_secret, secret_pattern, .. } => {
+                assert!(verify_no_secrets);
+                assert!(fail_on_secret);
+                assert_eq!(secret_pattern, vec!["token=tk_[0-9]+"]);
+            }
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        // --fail-on-secret only makes sense with the scan enabled
+        let args = vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar", "--layers", "2",
+            "--fail-on-secret",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_config_overrides() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "test.tar", "--output", "output.tar", "--layers", "2",
+            "--set-cmd", r#"["serve","--port","80"]"#, "--set-entrypoint", "exec /app/run",
+            "--set-env", "MODE=prod", "--set-env", "OPTS=a=b", "--set-workdir", "/app",
+        ]).unwrap();
+
+        match cli.command {
+            Commands::Squash { set_cmd, set_entrypoint, set_env, set_workdir, .. } => {
+                assert_eq!(set_cmd.unwrap(), vec!["serve", "--port", "80"]);
+                assert_eq!(set_entrypoint.unwrap(), vec!["/bin/sh", "-c", "exec /app/run"]);
+                assert_eq!(set_env, vec![
+                    ("MODE".to_string(), "prod".to_string()),
+                    ("OPTS".to_string(), "a=b".to_string()),
+                ]);
+                assert_eq!(set_workdir.as_deref(), Some("/app"));
+            }
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        // Nothing is overridden unless asked for
+        let args = vec!["squash", "squash", "--source", "test.tar", "--output", "output.tar", "--layers", "2"];
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Squash { set_cmd, set_entrypoint, set_env, set_workdir, .. } => {
+                assert!(set_cmd.is_none());
+                assert!(set_entrypoint.is_none());
+                assert!(set_env.is_empty());
+                assert!(set_workdir.is_none());
+            }
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        for bad in [["--set-env", "NOVALUE"], ["--set-env", "=value"], ["--set-cmd", "[\"unterminated\""]] {
+            let mut args = vec![
+                "squash", "squash", "--source", "test.tar", "--output", "output.tar", "--layers", "2",
+            ];
+            args.extend_from_slice(&bad);
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_docker_retries() {
+        use clap::Parser;
+        use std::time::Duration;
+
+        let args = vec!["squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2"];
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Squash { docker_retries, docker_retry_delay, .. } => {
+                assert_eq!(docker_retries, crate::docker::DEFAULT_DOCKER_RETRIES);
+                assert_eq!(docker_retry_delay, crate::docker::DEFAULT_DOCKER_RETRY_DELAY);
+            }
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2",
+            "--docker-retries", "5", "--docker-retry-delay", "250ms",
+        ]).unwrap();
+        match cli.command {
+            Commands::Squash { docker_retries, docker_retry_delay, .. } => {
+                assert_eq!(docker_retries, 5);
+                assert_eq!(docker_retry_delay, Duration::from_millis(250));
+            }
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        assert_eq!(parse_duration("2").unwrap(), Duration::from_secs(2));
+        assert_eq!(parse_duration("1m").unwrap(), Duration::from_secs(60));
+        assert!(parse_duration("1h").is_err());
+        assert!(parse_duration("fast").is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_no_load_cleanup() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "app:latest", "--load", "app:squashed", "--layers", "2", "--no-load-cleanup",
+        ]).unwrap();
+        match cli.command {
+            Commands::Squash { no_load_cleanup, .. } => assert!(no_load_cleanup),
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        // Only meaningful together with --load
+        let result = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2", "--no-load-cleanup",
+        ]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_report() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec!["squash", "report", "--source", "app:latest"]).unwrap();
+        match cli.command {
+            Commands::Report { source, format, .. } => {
+                assert_eq!(source, "app:latest");
+                assert_eq!(format, SizeReportFormat::Table);
+            }
+            _ => panic!("Expected the report subcommand"),
+        }
+
+        let cli = Cli::try_parse_from(vec!["squash", "report", "-s", "image.tar", "--format", "json"]).unwrap();
+        assert!(matches!(cli.command, Commands::Report { format: SizeReportFormat::Json, .. }));
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "report", "-s", "multi.tar", "--image-ref", "app:latest", "--platform", "linux/arm64/v8",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Report { image_ref, platform, source_format, .. } => {
+                assert_eq!(image_ref.as_deref(), Some("app:latest"));
+                assert_eq!(platform.unwrap().to_string(), "linux/arm64/v8");
+                assert!(source_format.is_none());
+            }
+            _ => panic!("Expected the report subcommand"),
+        }
+        assert!(Cli::try_parse_from(vec!["squash", "report"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_output_format() {
+        use clap::Parser;
+        use crate::docker::ImageFormat;
+
+        let args = vec!["squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2"];
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Squash { output_format, .. } => assert_eq!(output_format, None),
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        for (value, format) in [("docker", ImageFormat::DockerArchive), ("oci", ImageFormat::OciArchive)] {
+            let cli = Cli::try_parse_from(vec![
+                "squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2", "--output-format", value,
+            ]).unwrap();
+            match cli.command {
+                Commands::Squash { output_format, .. } => assert_eq!(output_format, Some(format)),
+                _ => panic!("Expected the squash subcommand"),
+            }
+        }
+
+        assert!(Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2", "--output-format", "zip",
+        ]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_cache_dir() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2", "--cache-dir", "/var/cache/squash",
+        ]).unwrap();
+        match cli.command {
+            Commands::Squash { cache_dir, .. } => assert_eq!(cache_dir, Some(std::path::PathBuf::from("/var/cache/squash"))),
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        let args = vec!["squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2"];
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Squash { cache_dir, .. } => assert!(cache_dir.is_none()),
+            _ => panic!("Expected the squash subcommand"),
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_since_command() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "app:latest", "--output", "output.tar", "--since-command", "apt-get install",
+        ]).unwrap();
+        match cli.command {
+            Commands::Squash { since_command, layers, .. } => {
+                assert_eq!(since_command.as_deref(), Some("apt-get install"));
+                assert_eq!(layers, None);
+            }
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        // It selects the start of the range, so other selections conflict with it
+        for other in [&["--layers", "2"][..], &["--from", "1"], &["--all"]] {
+            let mut args = vec!["squash", "squash", "--source", "app:latest", "--output", "output.tar", "--since-command", "RUN"];
+            args.extend_from_slice(other);
+            assert!(Cli::try_parse_from(args).is_err(), "{:?}", other);
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_source_format() {
+        use clap::Parser;
+        use crate::docker::SourceFormat;
+
+        let args = vec!["squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2"];
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Squash { source_format, .. } => assert_eq!(source_format, None),
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        for (value, format) in [
+            ("docker-archive", SourceFormat::DockerArchive),
+            ("oci-archive", SourceFormat::OciArchive),
+            ("docker-daemon", SourceFormat::DockerDaemon),
+        ] {
+            let cli = Cli::try_parse_from(vec![
+                "squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2", "--source-format", value,
+            ]).unwrap();
+            match cli.command {
+                Commands::Squash { source_format, .. } => assert_eq!(source_format, Some(format)),
+                _ => panic!("Expected the squash subcommand"),
+            }
+        }
+
+        assert!(Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2", "--source-format", "docker",
+        ]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_reproducible() {
+        use clap::Parser;
+
+        for (extra, expected) in [(None, false), (Some("--reproducible"), true)] {
+            let mut args = vec!["squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2"];
+            args.extend(extra);
+            match Cli::try_parse_from(args).unwrap().command {
+                Commands::Squash { reproducible, .. } => assert_eq!(reproducible, expected),
+                _ => panic!("Expected the squash subcommand"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_fail_on_grow() {
+        use clap::Parser;
+
+        for (extra, expected) in [(None, false), (Some("--fail-on-grow"), true)] {
+            let mut args = vec!["squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2"];
+            args.extend(extra);
+            match Cli::try_parse_from(args).unwrap().command {
+                Commands::Squash { fail_on_grow, .. } => assert_eq!(fail_on_grow, expected),
+                _ => panic!("Expected the squash subcommand"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_label() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from([
+            "squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2",
+            "--label", "org.example.team=platform", "--label", "note=a=b",
+        ]).unwrap();
+        match cli.command {
+            Commands::Squash { label, .. } => assert_eq!(label, vec![
+                ("org.example.team".to_string(), "platform".to_string()),
+                ("note".to_string(), "a=b".to_string()),
+            ]),
+            _ => panic!("Expected the squash subcommand"),
+        }
+
+        assert!(Cli::try_parse_from([
+            "squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2", "--label", "team",
+        ]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_no_extract() {
+        use clap::Parser;
+
+        for (extra, expected) in [(None, false), (Some("--no-extract"), true)] {
+            let mut args = vec!["squash", "squash", "--source", "image.tar", "--output", "output.tar", "--layers", "2"];
+            args.extend(extra);
+            match Cli::try_parse_from(args).unwrap().command {
+                Commands::Squash { no_extract, .. } => assert_eq!(no_extract, expected),
+                _ => panic!("Expected the squash subcommand"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_force() {
+        use clap::Parser;
+
+        for (extra, expected) in [(None, false), (Some("--force"), true)] {
+            let mut args = vec!["squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2"];
+            args.extend(extra);
+            match Cli::try_parse_from(args).unwrap().command {
+                Commands::Squash { force, .. } => assert_eq!(force, expected),
+                _ => panic!("Expected the squash subcommand"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_cli_parsing_analyze() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec!["squash", "analyze", "--source", "app:latest"]).unwrap();
+        match cli.command {
+            Commands::Analyze { source, format, .. } => {
+                assert_eq!(source, "app:latest");
+                assert_eq!(format, SizeReportFormat::Table);
+            }
+            _ => panic!("Expected the analyze subcommand"),
+        }
+
+        let cli = Cli::try_parse_from(vec!["squash", "analyze", "-s", "image.tar", "--format", "json"]).unwrap();
+        assert!(matches!(cli.command, Commands::Analyze { format: SizeReportFormat::Json, .. }));
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "analyze", "-s", "multi.tar", "--platform", "linux/amd64", "--source-format", "docker-archive",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Analyze { image_ref, platform, source_format, .. } => {
+                assert!(image_ref.is_none());
+                assert_eq!(platform.unwrap().to_string(), "linux/amd64");
+                assert_eq!(source_format, Some(crate::docker::SourceFormat::DockerArchive));
+            }
+            _ => panic!("Expected the analyze subcommand"),
+        }
+        assert!(Cli::try_parse_from(vec!["squash", "analyze"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_history_comment() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2",
+            "--history-comment", "stage: runtime", "--history-author", "release team",
+        ]).unwrap();
+        match cli.command {
+            Commands::Squash { history_comment, history_author, .. } => {
+                assert_eq!(history_comment.as_deref(), Some("stage: runtime"));
+                assert_eq!(history_author.as_deref(), Some("release team"));
+            }
+            _ => panic!("Expected Squash command"),
+        }
+
+        let args = vec!["squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert!(matches!(cli.command, Commands::Squash { history_comment: None, history_author: None, .. }));
+    }
+
+    #[test]
+    fn test_cli_parsing_compression_level() {
+        use clap::Parser;
+        use crate::docker::LayerCompression;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2",
+            "--output-compression", "zstd", "--compression-level", "19",
+        ]).unwrap();
+        match cli.command {
+            Commands::Squash { output_compression, compression_level, .. } => {
+                assert_eq!(output_compression, LayerCompression::Zstd);
+                assert_eq!(compression_level, Some(19));
+                assert!(output_compression.check_level(19).is_ok());
+            }
+            _ => panic!("Expected Squash command"),
+        }
+
+        assert!(LayerCompression::Gzip.check_level(9).is_ok());
+        assert!(LayerCompression::Gzip.check_level(10).is_err());
+        assert!(LayerCompression::Zstd.check_level(0).is_err());
+        assert!(LayerCompression::None.check_level(1).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_checksum() {
+        use clap::Parser;
+
+        for (extra, expected) in [(None, false), (Some("--checksum"), true)] {
+            let mut args = vec!["squash", "squash", "--source", "app:latest", "--output", "output.tar", "--layers", "2"];
+            args.extend(extra);
+            match Cli::try_parse_from(args).unwrap().command {
+                Commands::Squash { checksum, .. } => assert_eq!(checksum, expected),
+                _ => panic!("Expected Squash command"),
+            }
+        }
+
+        // There is no output file to hash when streaming to stdout
+        let args = vec!["squash", "squash", "--source", "app:latest", "--output-stdout", "--layers", "2", "--checksum"];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_preserve_layer() {
+        use clap::Parser;
+
+        let cli = Cli::try_parse_from(vec![
+            "squash", "squash", "--source", "app:latest", "--output", "output.tar", "--all",
+            "--preserve-layer", "2", "--preserve-layer", "sha256:abcdef12",
+        ]).unwrap();
+        match cli.command {
+            Commands::Squash { preserve_layer, all, .. } => {
+                assert!(all);
+                assert_eq!(preserve_layer, vec!["2", "sha256:abcdef12"]);
+            }
+            _ => panic!("Expected Squash command"),
         }
     }
 }
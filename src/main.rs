@@ -1,69 +1,674 @@
-use squash::{cli::*, docker::DockerImage, SquashError};
+use clap::CommandFactory;
+use squash::{cli::*, docker::{build_exclude_set, Cancellation, ConfigOverrides, ConsoleObserver, DockerImage, ForeignLayerPolicy, INTERRUPTED_EXIT_CODE, LayerCompression, LayerInfo, DockerLoadReport, LayerMergeStats, LoadOptions, PermissionAudit, ProgressBarObserver, ProgressObserver, RetryPolicy, Runtime, SecretPattern, SecretScanner, Skopeo, Zstd, checksum_path, write_checksum_file}, SquashError};
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::process;
 
+/// Subdirectory of --temp-dir holding merge checkpoints for --resume
+const RESUME_DIR_NAME: &str = "squash-resume";
+
 fn main() {
     if let Err(e) = run() {
         eprintln!("Error: {}", e);
-        process::exit(1);
+        let code = match e {
+            SquashError::Cancelled => INTERRUPTED_EXIT_CODE,
+            _ => 1,
+        };
+        process::exit(code);
+    }
+}
+
+/// Summary of a completed squash for `--format json`
+#[derive(Debug, Serialize)]
+struct SquashReport {
+    original_layer_count: usize,
+    new_layer_count: usize,
+    original_total_size: u64,
+    new_total_size: u64,
+    /// Negative when the squashed layers are larger than the originals
+    bytes_saved: i64,
+    /// diff_id of the layer produced by the squash
+    merged_digest: Option<String>,
+    /// What each merge wrote and deleted, in the order the merges ran
+    merges: Vec<LayerMergeStats>,
+    output_path: Option<PathBuf>,
+    /// sha256 of the output file, with --checksum
+    output_digest: Option<String>,
+    /// Temporary tag used by --load and whether it was removed
+    docker_load: Option<DockerLoadReport>,
+}
+
+impl SquashReport {
+    fn new(
+        original_layers: &[LayerInfo],
+        image: &DockerImage,
+        output_path: Option<PathBuf>,
+        output_digest: Option<String>,
+        docker_load: Option<DockerLoadReport>,
+    ) -> Self {
+        let original_total_size: u64 = original_layers.iter().map(|layer| layer.size).sum();
+        let new_total_size: u64 = image.layers.iter().map(|layer| layer.size).sum();
+
+        SquashReport {
+            original_layer_count: original_layers.len(),
+            new_layer_count: image.layers.len(),
+            original_total_size,
+            new_total_size,
+            bytes_saved: original_total_size as i64 - new_total_size as i64,
+            merged_digest: image
+                .layers
+                .iter()
+                .find(|layer| image.squashed_layers.contains(&layer.digest))
+                .map(|layer| layer.digest.clone()),
+            merges: image.merge_stats.clone(),
+            output_path,
+            output_digest,
+            docker_load,
+        }
+    }
+
+    /// Layer counts and sizes before and after, e.g. `5 -> 2 layers, 900 -> 600 bytes (300 bytes saved)`
+    fn summary(&self) -> String {
+        let delta = if self.bytes_saved < 0 {
+            format!("{} bytes larger", self.bytes_saved.unsigned_abs())
+        } else {
+            format!("{} bytes saved", self.bytes_saved)
+        };
+        format!(
+            "{} -> {} layers, {} -> {} bytes ({})",
+            self.original_layer_count, self.new_layer_count, self.original_total_size, self.new_total_size, delta
+        )
+    }
+}
+
+/// Layer count given to `--layers` together with `--from-start`
+fn parse_from_start_count(layers: &str) -> Result<usize, SquashError> {
+    layers.parse::<usize>().map_err(|_| {
+        SquashError::InvalidInput(format!("--from-start requires a layer count, got: {}", layers))
+    })
+}
+
+/// Time merged layers are clamped to: SOURCE_DATE_EPOCH when set, else 0 with `--reproducible`
+fn source_date_epoch(reproducible: bool) -> Result<Option<u64>, SquashError> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) if !value.is_empty() => value.parse().map(Some).map_err(|_| {
+            SquashError::InvalidInput(format!("SOURCE_DATE_EPOCH must be a Unix time in seconds, got: {}", value))
+        }),
+        _ => Ok(reproducible.then_some(0)),
+    }
+}
+
+/// Let the user pick the layers to merge on the terminal, as `--from`/`--to` indexes
+#[cfg(feature = "interactive")]
+fn pick_layers(image: &DockerImage) -> Result<(Option<String>, Option<String>), SquashError> {
+    if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+        return Err(SquashError::InvalidInput(
+            "No layers selected: pass --layers, --from/--to, --all or a merge strategy, or run on a terminal to pick them".to_string(),
+        ));
+    }
+    match squash::interactive::select_layers(image)? {
+        Some((start, end)) => {
+            info!("Selected layers {} to {}", start, end);
+            Ok((Some(start.to_string()), Some(end.to_string())))
+        }
+        None => Err(SquashError::InvalidInput("Layer selection cancelled".to_string())),
     }
 }
 
+/// Log to stderr at the requested level, falling back to RUST_LOG and then `info`
+fn init_logging(verbose: bool, quiet: bool, log_level: Option<LogLevel>) {
+    let level = log_level
+        .or(quiet.then_some(LogLevel::Error))
+        .or(verbose.then_some(LogLevel::Debug));
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if let Some(level) = level {
+        builder.filter_level(level.into());
+    }
+    builder.target(env_logger::Target::Stderr).init();
+}
+
 fn run() -> Result<(), SquashError> {
     let cli = Cli::parse_args();
 
     match cli.command {
         Commands::Squash {
             source,
+            source_format,
+            no_extract,
+            image_ref,
+            platform,
             output,
+            output_stdout,
+            force,
+            checksum,
+            output_format,
             load,
+            no_load_cleanup,
             temp_dir,
+            runtime,
+            backend,
+            docker_retries,
+            docker_retry_delay,
             layers,
+            from_start,
+            all,
+            merge_window,
+            merge_layers_matching_size,
+            max_merged_size,
+            preserve_layer,
+            from,
+            since_command,
+            to,
+            verify,
+            fail_on_foreign_layer,
+            download_foreign_layers,
+            tar_long_pathname_mode,
+            no_tar_cksum_recompute,
+            output_layer_annotations_from_history,
+            sbom_passthrough,
+            output_compression,
+            compression_level,
+            output_compression_for_retained_layers,
+            canonical_json,
+            reproducible,
+            history_comment,
+            history_author,
+            output_manifest_mediatype,
+            output_config_mediatype,
+            legacy_repositories,
+            verify_no_secrets,
+            secret_pattern,
+            fail_on_secret,
+            audit_permissions,
+            strict_permissions,
+            audit_directories,
+            compare_with_docker_squash,
             verbose,
+            log_level,
+            quiet,
+            file_log_limit,
+            resume,
+            keep_temp,
+            cache_dir,
+            input_allowlist,
+            input_denylist,
+            jobs,
+            mem_file_threshold,
+            in_memory,
+            strict,
+            fail_on_grow,
+            exclude,
+            set_cmd,
+            set_entrypoint,
+            set_env,
+            set_workdir,
+            label,
+            dry_run,
+            format,
         } => {
-            if verbose {
-                println!("Loading Docker image from: {}", source);
-            }
+            init_logging(verbose, quiet, log_level);
+
+            debug!("Loading Docker image from: {}", source);
+
+            let output = if output_stdout {
+                Some(PathBuf::from(DockerImage::STDOUT_PATH))
+            } else {
+                output
+            };
 
             // Validate arguments
-            if output.is_none() && load.is_none() {
+            if output.is_none() && load.is_none() && !dry_run {
                 return Err(SquashError::InvalidInput(
                     "Either --output or --load must be specified".to_string(),
                 ));
             }
+            // Refuse before any work is done; saving checks again
+            if let Some(path) = output.as_deref().filter(|path| *path != Path::new(DockerImage::STDOUT_PATH)) {
+                if !force && !dry_run && path.exists() {
+                    return Err(SquashError::OutputExists(path.to_path_buf()));
+                }
+            }
+            if checksum && output.as_deref().is_none_or(|path| path == Path::new(DockerImage::STDOUT_PATH)) {
+                return Err(SquashError::InvalidInput(
+                    "--checksum needs an --output file".to_string(),
+                ));
+            }
+            if let Some(level) = compression_level {
+                output_compression.check_level(level)?;
+            }
+            // zstd output needs the zstd feature; find out now rather than after merging
+            if output_compression == LayerCompression::Zstd && !dry_run {
+                Zstd::new().check_available()?;
+            }
+            if format == ReportFormat::Json && output.as_deref() == Some(Path::new(DockerImage::STDOUT_PATH)) {
+                return Err(SquashError::InvalidInput(
+                    "--format json cannot be used while streaming the archive to stdout".to_string(),
+                ));
+            }
 
             // Load the Docker image
-            let mut image = DockerImage::load(&source, temp_dir.as_deref())?;
+            let foreign_layers = if fail_on_foreign_layer {
+                ForeignLayerPolicy::Fail
+            } else if download_foreign_layers {
+                ForeignLayerPolicy::Download
+            } else {
+                ForeignLayerPolicy::Preserve
+            };
+            let docker_retry = RetryPolicy { retries: docker_retries, delay: docker_retry_delay };
+            let load_options = LoadOptions {
+                image_ref,
+                platform,
+                runtime,
+                foreign_layers,
+                verify,
+                backend,
+                docker_retry,
+                keep_temp,
+                cache_dir,
+                allowed_media_types: input_allowlist,
+                denied_media_types: input_denylist,
+                source_format,
+                layers_in_place: no_extract,
+            };
+            let mut image = DockerImage::load_with_options(&source, temp_dir.as_deref(), &load_options)?;
 
-            if verbose {
-                println!("Image loaded successfully");
-                println!("Layers: {}", image.manifest.layers.len());
-                if let Some(tags) = &image.manifest.repo_tags {
-                    println!("Tags: {:?}", tags);
+            debug!("Image loaded successfully");
+            debug!("Layers: {}", image.manifest.layers.len());
+            if let Some(tags) = &image.manifest.repo_tags {
+                debug!("Tags: {:?}", tags);
+            }
+
+            // Ctrl-C stops the merge or save at the next layer boundary and cleans up
+            let cancellation = Cancellation::new();
+            cancellation.cancel_on_interrupt()?;
+            image.merge_options.cancellation = cancellation.clone();
+            image.save_options.cancellation = cancellation;
+            image.merge_options.long_pathname_mode = tar_long_pathname_mode;
+            image.merge_options.skip_cksum_recompute = no_tar_cksum_recompute;
+            image.merge_options.file_log_limit = file_log_limit;
+            image.merge_options.jobs = jobs;
+            image.merge_options.mem_file_threshold = mem_file_threshold;
+            image.merge_options.in_memory = in_memory;
+            image.merge_options.source_date_epoch = source_date_epoch(reproducible)?;
+            image.merge_options.history_comment = history_comment;
+            image.merge_options.history_author = history_author;
+            image.merge_options.compression_level = compression_level;
+            image.merge_options.keep_temp = keep_temp;
+            image.merge_options.strict_paths = strict;
+            image.merge_options.fail_on_grow = fail_on_grow;
+            image.merge_options.exclude = build_exclude_set(&exclude)?;
+            image.merge_options.checkpoint_dir = temp_dir
+                .as_deref()
+                .filter(|_| resume)
+                .map(|dir| dir.join(RESUME_DIR_NAME));
+            image.save_options.layer_annotations_from_history = output_layer_annotations_from_history;
+            image.save_options.sbom_passthrough = sbom_passthrough;
+            image.save_options.layer_compression = output_compression;
+            image.save_options.retained_layer_compression = output_compression_for_retained_layers;
+            image.save_options.canonical_json = canonical_json;
+            image.save_options.manifest_media_type = output_manifest_mediatype;
+            image.save_options.config_media_type = output_config_mediatype;
+            image.save_options.legacy_repositories = legacy_repositories;
+            image.save_options.keep_load_tag = no_load_cleanup;
+            image.save_options.no_clobber = !force;
+            if let Some(output_format) = output_format {
+                image.format = output_format;
+            }
+            image.preserve_layers(&preserve_layer)?;
+
+            // A history command selects the first layer of the range by index
+            let from = match since_command {
+                Some(command) => {
+                    let start = image.find_layer_by_command(&command)?;
+                    info!("Layer {} was created by a command containing '{}'", start, command);
+                    Some(start.to_string())
+                }
+                None => from,
+            };
+
+            // Without any selection, let the user pick the layers on the terminal
+            #[cfg(feature = "interactive")]
+            let (from, to) = if layers.is_none()
+                && !all
+                && from.is_none()
+                && to.is_none()
+                && merge_window.is_none()
+                && merge_layers_matching_size.is_none()
+                && max_merged_size.is_none()
+            {
+                pick_layers(&image)?
+            } else {
+                (from, to)
+            };
+
+            // Report the plan and stop before anything is merged or written
+            if dry_run {
+                if let Some(window) = merge_window {
+                    let plans = image.plan_merge_window(window)?;
+                    if plans.is_empty() {
+                        println!("No layers were created within {} seconds of each other", window);
+                    }
+                    for (i, plan) in plans.iter().enumerate() {
+                        if i > 0 {
+                            println!();
+                        }
+                        println!("{}", plan);
+                    }
+                    return Ok(());
+                }
+                if let Some(max_size) = merge_layers_matching_size {
+                    match image.plan_layers_below_size(max_size)? {
+                        Some(plan) => println!("{}", plan),
+                        None => println!("Fewer than two trailing layers are smaller than {} bytes", max_size),
+                    }
+                    return Ok(());
+                }
+                if let Some(budget) = max_merged_size {
+                    println!("{}", image.plan_up_to_size(budget)?);
+                    return Ok(());
                 }
+
+                let plan = match &layers {
+                    _ if all => image.plan_range(0, image.layers.len().saturating_sub(1))?,
+                    Some(layers) if from_start => {
+                        let count = parse_from_start_count(layers)?;
+                        let end = count.checked_sub(1).ok_or_else(|| {
+                            SquashError::InvalidInput("Cannot merge 0 layers".to_string())
+                        })?;
+                        image.plan_range(0, end)?
+                    }
+                    Some(layers) => image.plan_squash(layers)?,
+                    None => image.plan_layer_range(from.as_deref(), to.as_deref())?,
+                };
+                println!("{}", plan);
+                return Ok(());
+            }
+
+            let original_layers = image.layers.clone();
+
+            // Progress bars only make sense on an interactive stderr; otherwise log per layer
+            let mut console = ConsoleObserver::default();
+            let mut bars = ProgressBarObserver::new();
+            let progress: &mut dyn ProgressObserver = if !quiet && std::io::stderr().is_terminal() {
+                &mut bars
+            } else {
+                &mut console
+            };
+
+            // Optionally scan merged content for secrets alongside the progress output
+            let mut patterns = SecretPattern::defaults();
+            for spec in &secret_pattern {
+                patterns.push(SecretPattern::parse(spec)?);
             }
+            let mut scanning = (progress, SecretScanner::new(patterns));
+            let observer: &mut dyn ProgressObserver = if verify_no_secrets { &mut scanning } else { &mut *scanning.0 };
 
             // Squash the layers
-            if verbose {
-                println!("Squashing layers: {}", layers);
+            if let Some(window) = merge_window {
+                debug!("Squashing layers built within {} seconds of each other", window);
+                image.squash_merge_window_with_observer(window, observer)?;
+            } else if let Some(max_size) = merge_layers_matching_size {
+                debug!("Squashing trailing layers smaller than {} bytes", max_size);
+                image.squash_layers_below_size_with_observer(max_size, observer)?;
+            } else if let Some(budget) = max_merged_size {
+                debug!("Squashing the latest layers within {} bytes", budget);
+                image.squash_up_to_size_with_observer(budget, observer)?;
+            } else {
+                match layers {
+                    _ if all => {
+                        debug!("Squashing all layers");
+                        image.squash_all_with_observer(observer)?;
+                    }
+                    Some(layers) if from_start => {
+                        let count = parse_from_start_count(&layers)?;
+                        debug!("Squashing earliest layers: {}", count);
+                        image.squash_earliest_layers_with_observer(count, observer)?;
+                    }
+                    Some(layers) => {
+                        debug!("Squashing layers: {}", layers);
+                        image.squash_layers_with_observer(&layers, observer)?;
+                    }
+                    None => {
+                        debug!(
+                            "Squashing layers from {} to {}",
+                            from.as_deref().unwrap_or("base"),
+                            to.as_deref().unwrap_or("latest")
+                        );
+                        image.squash_layer_range_with_observer(from.as_deref(), to.as_deref(), observer)?;
+                    }
+                }
             }
-            image.squash_layers(&layers)?;
+
+            if verify_no_secrets {
+                let scanner = &scanning.1;
+                for finding in scanner.findings() {
+                    warn!("Potential secret in {}: {}", finding.path.display(), finding.pattern);
+                }
+                if fail_on_secret {
+                    scanner.check()?;
+                } else if scanner.findings().is_empty() {
+                    info!("No secrets found");
+                }
+            }
+
+            // Audit the squashed layers for world-writable content
+            if audit_permissions || strict_permissions || audit_directories {
+                let mut audit = PermissionAudit::new().with_directories(audit_directories);
+                image.audit_permissions(&mut audit)?;
+                for finding in audit.findings() {
+                    warn!("Insecure permissions: {}", finding);
+                }
+                if strict_permissions {
+                    audit.check()?;
+                } else if audit.findings().is_empty() {
+                    info!("No world-writable files found");
+                }
+            }
+
+            // Verify content parity with an image squashed by another tool
+            if let Some(reference_source) = compare_with_docker_squash {
+                info!("Comparing squashed content with: {}", reference_source);
+                let reference = DockerImage::load(&reference_source, temp_dir.as_deref())?;
+                let changes = image.compare_content(&reference)?;
+
+                if !changes.is_empty() {
+                    for change in &changes {
+                        warn!("  {}", change);
+                    }
+                    return Err(SquashError::VerificationFailed(format!(
+                        "{} content differences from {}",
+                        changes.len(),
+                        reference_source
+                    )));
+                }
+                info!("No content differences from {}", reference_source);
+            }
+
+            // Provenance labels first, so --label can replace them
+            let mut labels = image.provenance_labels();
+            labels.extend(label);
+            image.apply_config_overrides(&ConfigOverrides {
+                cmd: set_cmd,
+                entrypoint: set_entrypoint,
+                env: set_env,
+                working_dir: set_workdir,
+                labels,
+            });
 
             // Output the result
-            if let Some(output_path) = output {
-                if verbose {
-                    println!("Saving to file: {}", output_path.display());
+            if let Some(output_path) = &output {
+                debug!("Saving to file: {}", output_path.display());
+                image.save_to_file_with_observer(output_path, &mut *scanning.0)?;
+            }
+
+            let mut output_digest = None;
+            if let Some(output_path) = output.as_deref().filter(|_| checksum) {
+                let digest = write_checksum_file(output_path)?;
+                // The JSON report carries the digest instead
+                if format == ReportFormat::Text {
+                    println!("{}  {}", digest, output_path.display());
                 }
-                image.save_to_file(&output_path)?;
+                info!("Wrote checksum to {}", checksum_path(output_path).display());
+                output_digest = Some(digest);
             }
 
+            let mut docker_load = None;
             if let Some(image_name) = load {
-                if verbose {
-                    println!("Loading into Docker as: {}", image_name);
+                match runtime {
+                    Runtime::Docker => {
+                        debug!("Loading into Docker as: {}", image_name);
+                        let backend = backend.connect_with_retry(docker_retry)?;
+                        docker_load = Some(image.load_into_docker_with(backend.as_ref(), &image_name)?);
+                    }
+                    Runtime::Skopeo => {
+                        debug!("Copying with skopeo to: {}", image_name);
+                        image.push_with_skopeo(&Skopeo::new(), &image_name)?;
+                    }
                 }
-                image.load_into_docker(&image_name)?;
             }
 
-            println!("Image squashing completed successfully!");
+            let report = SquashReport::new(&original_layers, &image, output, output_digest, docker_load);
+            if format == ReportFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            }
+
+            if report.new_layer_count >= report.original_layer_count {
+                warn!("Squashing did not reduce the layer count");
+            }
+            if report.bytes_saved < 0 {
+                warn!("The squashed layers are {} bytes larger than the originals", report.bytes_saved.unsigned_abs());
+            }
+            info!("Image squashing completed successfully: {}", report.summary());
+        }
+        Commands::MergeConfigs {
+            filesystem_from,
+            config_from,
+            filesystem_format,
+            config_format,
+            filesystem_image_ref,
+            config_image_ref,
+            platform,
+            output,
+            temp_dir,
+            verbose,
+            quiet,
+        } => {
+            init_logging(verbose, quiet, None);
+
+            debug!("Loading filesystem image from: {}", filesystem_from);
+            let filesystem_options = LoadOptions {
+                image_ref: filesystem_image_ref,
+                platform: platform.clone(),
+                source_format: filesystem_format,
+                ..Default::default()
+            };
+            let mut image = DockerImage::load_with_options(&filesystem_from, temp_dir.as_deref(), &filesystem_options)?;
+            debug!("Loading config image from: {}", config_from);
+            let config_options = LoadOptions {
+                image_ref: config_image_ref,
+                platform,
+                source_format: config_format,
+                ..Default::default()
+            };
+            let config_image = DockerImage::load_with_options(&config_from, temp_dir.as_deref(), &config_options)?;
+
+            image.apply_config_from(&config_image)?;
+            image.save_to_file(&output)?;
+
+            info!("Combined layers of {} with config of {}", filesystem_from, config_from);
+        }
+        Commands::Verify {
+            source,
+            source_format,
+            image_ref,
+            platform,
+            temp_dir,
+            verbose,
+            quiet,
+        } => {
+            init_logging(verbose, quiet, None);
+
+            let load_options = LoadOptions {
+                image_ref,
+                platform,
+                source_format,
+                ..Default::default()
+            };
+            let checks = DockerImage::verify_archive_with_options(&source, temp_dir.as_deref(), &load_options);
+            for check in &checks {
+                println!("{}", check);
+            }
+
+            let failed = checks.iter().filter(|check| !check.passed).count();
+            if failed > 0 {
+                return Err(SquashError::VerificationFailed(format!(
+                    "{} of {} checks failed for {}",
+                    failed,
+                    checks.len(),
+                    source
+                )));
+            }
+            info!("All {} checks passed for {}", checks.len(), source);
+        }
+        Commands::Report {
+            source,
+            source_format,
+            image_ref,
+            platform,
+            format,
+            temp_dir,
+            verbose,
+            quiet,
+        } => {
+            init_logging(verbose, quiet, None);
+
+            let load_options = LoadOptions {
+                image_ref,
+                platform,
+                source_format,
+                ..Default::default()
+            };
+            let image = DockerImage::load_with_options(&source, temp_dir.as_deref(), &load_options)?;
+            let report = image.size_report()?;
+            match format {
+                SizeReportFormat::Table => println!("{}", report),
+                SizeReportFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+            }
+        }
+        Commands::Analyze {
+            source,
+            source_format,
+            image_ref,
+            platform,
+            format,
+            temp_dir,
+            verbose,
+            quiet,
+        } => {
+            init_logging(verbose, quiet, None);
+
+            let load_options = LoadOptions {
+                image_ref,
+                platform,
+                source_format,
+                ..Default::default()
+            };
+            let image = DockerImage::load_with_options(&source, temp_dir.as_deref(), &load_options)?;
+            let report = image.waste_report()?;
+            match format {
+                SizeReportFormat::Table => println!("{}", report),
+                SizeReportFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+            }
+        }
+        Commands::Completions { shell } => {
+            // Render first so a closed stdout is reported as an error rather than a panic
+            let mut command = Cli::command();
+            let name = command.get_name().to_string();
+            let mut script = Vec::new();
+            clap_complete::generate(shell, &mut command, name, &mut script);
+            std::io::stdout().write_all(&script)?;
         }
     }
 